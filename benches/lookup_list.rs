@@ -11,6 +11,17 @@ fn all(c: &mut Criterion) {
     c.bench_function("one of two", |b| b.iter(|| fh.lookup_list("foobar", 1)));
     c.bench_function("two of two", |b| b.iter(|| fh.lookup_list("foobar", 2)));
     c.bench_function("three of two", |b| b.iter(|| fh.lookup_list("foobar", 3)));
+
+    for i in 0..30 {
+        fh.add_target(format!("node-{}", i), 1);
+    }
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    c.bench_function("10k one at a time", |b| {
+        b.iter(|| keys.iter().map(|k| fh.lookup(k)).collect::<Vec<_>>())
+    });
+    c.bench_function("10k lookup_many", |b| {
+        b.iter(|| fh.lookup_many(&keys).len())
+    });
 }
 
 criterion_group!(benches, all);
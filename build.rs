@@ -0,0 +1,12 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        // the generated client needs the 2021 prelude, and only the server
+        // side is used here anyway
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/flexihash.proto"], &["proto"])
+            .unwrap();
+    }
+}
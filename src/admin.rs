@@ -0,0 +1,253 @@
+use crate::shared::SharedRing;
+use crate::{Error, Target};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/*
+ * A small HTTP API for poking at a live ring:
+ *
+ *   GET    /targets          all targets with their weights, zones and labels
+ *   POST   /targets          add {"name": ..., "weight": ...}
+ *   DELETE /targets/{name}   remove a target
+ *   GET    /lookup?key=K     where K lives (add &count=N for fallbacks)
+ *   GET    /stats            sizes and settings
+ *
+ * There's no authentication, so only bind it somewhere private.
+ */
+pub fn router(ring: Arc<SharedRing>) -> Router {
+    return Router::new()
+        .route("/targets", get(list_targets).post(add_target))
+        .route("/targets/:name", delete(remove_target))
+        .route("/lookup", get(lookup))
+        .route("/stats", get(stats))
+        .with_state(ring);
+}
+
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    ring: Arc<SharedRing>,
+) -> std::io::Result<()> {
+    return axum::serve(listener, router(ring)).await;
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+type ApiError = (StatusCode, Json<ErrorBody>);
+
+fn api_error<S: Into<String>>(status: StatusCode, message: S) -> ApiError {
+    return (
+        status,
+        Json(ErrorBody {
+            error: message.into(),
+        }),
+    );
+}
+
+fn error_response(error: Error) -> ApiError {
+    return match error {
+        Error::TargetExists(_) => api_error(StatusCode::CONFLICT, error.to_string()),
+        Error::TargetMissing(..) => api_error(StatusCode::NOT_FOUND, error.to_string()),
+        Error::TooManyPositions(..) => {
+            api_error(StatusCode::UNPROCESSABLE_ENTITY, error.to_string())
+        }
+    };
+}
+
+#[derive(Debug, Serialize)]
+struct TargetBody {
+    name: Target,
+    weight: u32,
+    zone: Option<String>,
+    labels: BTreeMap<String, String>,
+}
+
+async fn list_targets(State(ring): State<Arc<SharedRing>>) -> Json<Vec<TargetBody>> {
+    let targets = ring
+        .snapshot()
+        .snapshot()
+        .targets
+        .into_iter()
+        .map(|(name, info)| TargetBody {
+            name,
+            weight: info.weight,
+            zone: info.zone,
+            labels: info.labels,
+        })
+        .collect();
+    return Json(targets);
+}
+
+#[derive(Debug, Deserialize)]
+struct NewTarget {
+    name: Target,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_weight() -> u32 {
+    return 1;
+}
+
+async fn add_target(
+    State(ring): State<Arc<SharedRing>>,
+    Json(target): Json<NewTarget>,
+) -> Result<StatusCode, ApiError> {
+    ring.update(|fh| {
+        fh.transaction(|tx| {
+            tx.add(target.name, target.weight);
+        })
+        .map(|_| ())
+    })
+    .map_err(error_response)?;
+    return Ok(StatusCode::CREATED);
+}
+
+async fn remove_target(
+    State(ring): State<Arc<SharedRing>>,
+    Path(name): Path<Target>,
+) -> Result<StatusCode, ApiError> {
+    ring.update(|fh| {
+        fh.transaction(|tx| {
+            tx.remove(name);
+        })
+        .map(|_| ())
+    })
+    .map_err(error_response)?;
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupQuery {
+    key: String,
+    count: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct LookupBody {
+    key: String,
+    targets: Vec<Target>,
+}
+
+async fn lookup(
+    State(ring): State<Arc<SharedRing>>,
+    Query(query): Query<LookupQuery>,
+) -> Result<Json<LookupBody>, ApiError> {
+    let count = query.count.unwrap_or(1);
+    if count == 0 {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Need to request at least 1 resource",
+        ));
+    }
+    let targets = ring.snapshot().lookup_list(&query.key, count);
+    if targets.is_empty() {
+        return Err(api_error(StatusCode::SERVICE_UNAVAILABLE, "No targets set"));
+    }
+    return Ok(Json(LookupBody {
+        key: query.key,
+        targets,
+    }));
+}
+
+#[derive(Debug, Serialize)]
+struct StatsBody {
+    targets: usize,
+    positions: usize,
+    groups: usize,
+    replicas: u32,
+    hasher: String,
+}
+
+async fn stats(State(ring): State<Arc<SharedRing>>) -> Json<StatsBody> {
+    let fh = ring.snapshot();
+    return Json(StatsBody {
+        targets: fh.targets().count(),
+        positions: fh.position_count(),
+        groups: fh.get_all_groups().len(),
+        replicas: fh.replicas(),
+        hasher: fh.hasher().redacted(),
+    });
+}
+
+#[cfg(test)]
+mod test_admin {
+    use super::*;
+    use crate::Flexihash;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn call(app: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        return (status, String::from_utf8(body.to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn manage_targets() {
+        let ring = Arc::new(SharedRing::new(Flexihash::new()));
+        let app = router(ring.clone());
+
+        let (status, body) = call(&app, "GET", "/lookup?key=foo", "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, r#"{"error":"No targets set"}"#);
+
+        let (status, _) = call(&app, "POST", "/targets", r#"{"name": "t-a", "weight": 2}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = call(&app, "POST", "/targets", r#"{"name": "t-b"}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call(&app, "POST", "/targets", r#"{"name": "t-b"}"#).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body, r#"{"error":"Target t-b already exists"}"#);
+
+        let (status, body) = call(&app, "GET", "/targets", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"[{"name":"t-a","weight":2,"zone":null,"labels":{}},{"name":"t-b","weight":1,"zone":null,"labels":{}}]"#
+        );
+
+        let (status, body) = call(&app, "GET", "/stats", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"{"targets":2,"positions":192,"groups":0,"replicas":64,"hasher":"crc32"}"#
+        );
+
+        let expected = ring.snapshot().lookup("foo");
+        let (_, body) = call(&app, "GET", "/lookup?key=foo", "").await;
+        assert_eq!(
+            body,
+            format!(r#"{{"key":"foo","targets":["{}"]}}"#, expected)
+        );
+        let (status, _) = call(&app, "GET", "/lookup?key=foo&count=0", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = call(&app, "DELETE", "/targets/t-a", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(&app, "DELETE", "/targets/t-a", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            r#"{"error":"Target 't-a' does not exist; did you mean 't-b'?"}"#
+        );
+        assert_eq!(ring.snapshot().get_all_targets(), ["t-b"]);
+    }
+}
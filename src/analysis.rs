@@ -0,0 +1,582 @@
+use crate::{Flexihash, Position, ResourceKey, Target};
+use std::collections::{HashMap, HashSet};
+
+/*
+ * Tools for checking how well a ring spreads things out, before trusting
+ * it with real traffic
+ */
+impl Flexihash {
+    pub fn simulate_distribution<K: ResourceKey>(
+        &self,
+        n_keys: u64,
+        key_gen: impl Fn(u64) -> K,
+    ) -> HashMap<Target, u64> {
+        let mut counts: HashMap<Target, u64> = self
+            .target_to_positions
+            .keys()
+            .map(|t| (t.clone(), 0))
+            .collect();
+        if counts.is_empty() {
+            return counts;
+        }
+        for i in 0..n_keys {
+            // not lookup, which would count these as real traffic
+            if let Some(target) = self.find_targets(key_gen(i), "", 1).pop() {
+                *counts.entry(target).or_insert(0) += 1;
+            }
+        }
+        return counts;
+    }
+}
+
+/*
+ * The consistent hashing promise, checked: between two versions of a
+ * ring, a key may only move if it's leaving a target which was removed or
+ * reweighted, or joining one which was added or reweighted. A key moving
+ * between two untouched targets is a violation.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct DisruptionReport {
+    pub sampled: u64,
+    pub moved: u64,
+    pub violations: u64,
+    // The first few violations, as (key, before, after)
+    pub examples: Vec<(String, Target, Target)>,
+}
+
+impl DisruptionReport {
+    pub fn is_minimal(&self) -> bool {
+        return self.violations == 0;
+    }
+}
+
+const DISRUPTION_SAMPLE: u64 = 10000;
+const DISRUPTION_EXAMPLES: usize = 10;
+
+pub fn verify_minimal_disruption(before: &Flexihash, after: &Flexihash) -> DisruptionReport {
+    return verify_minimal_disruption_with(before, after, DISRUPTION_SAMPLE, |n| n);
+}
+
+pub fn verify_minimal_disruption_with<K: ResourceKey>(
+    before: &Flexihash,
+    after: &Flexihash,
+    n_keys: u64,
+    key_gen: impl Fn(u64) -> K,
+) -> DisruptionReport {
+    // targets which are allowed to lose or gain keys
+    let mut touched: HashSet<&Target> = HashSet::new();
+    for target in before.targets() {
+        if before.get_target_info(target).map(|i| i.weight)
+            != after.get_target_info(target).map(|i| i.weight)
+        {
+            touched.insert(target);
+        }
+    }
+    for target in after.targets() {
+        if before.get_target_info(target).is_none() {
+            touched.insert(target);
+        }
+    }
+
+    let mut report = DisruptionReport {
+        sampled: n_keys,
+        ..Default::default()
+    };
+    for i in 0..n_keys {
+        let key = key_gen(i);
+        // not lookup_list, which would count these as real traffic
+        let old = before.find_targets(&key, "", 1).pop();
+        let new = after.find_targets(&key, "", 1).pop();
+        let (old, new) = match (old, new) {
+            (Some(old), Some(new)) => (old, new),
+            // every key is new, or every key is lost
+            (None, _) | (_, None) => continue,
+        };
+        if old == new {
+            continue;
+        }
+        report.moved += 1;
+        if !touched.contains(&old) && !touched.contains(&new) {
+            report.violations += 1;
+            if report.examples.len() < DISRUPTION_EXAMPLES {
+                let key = String::from_utf8_lossy(key.ring_key().as_ref()).into_owned();
+                report.examples.push((key, old, new));
+            }
+        }
+    }
+    return report;
+}
+
+/*
+ * Clumping of a target's own replicas. With a good hash a target's
+ * positions are scattered like random points, so its longest gap is about
+ * H(n) times the even spacing; some hashers (and some hostname patterns)
+ * bunch them up instead, leaving the target with an uneven, lumpy share.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusteringFix {
+    // too few positions for any hash to spread evenly
+    MoreReplicas,
+    // enough positions, so the hash itself is clumping this name
+    Resalt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusteringReport {
+    pub target: Target,
+    pub positions: usize,
+    pub longest_gap: Position,
+    pub shortest_gap: Position,
+    pub gap_ratio: f64,
+    // The longest gap over what random placement would give; around 1 is
+    // normal
+    pub clustering: f64,
+    pub suggestion: Option<ClusteringFix>,
+}
+
+const CLUSTERING_LIMIT: f64 = 2.0;
+const MIN_SPREAD_POSITIONS: usize = 16;
+
+impl Flexihash {
+    pub fn replica_clustering(&self) -> Vec<ClusteringReport> {
+        let max = self.widest_position();
+        let space = max as f64 + 1.0;
+        let mut reports = Vec::new();
+        for (target, positions) in self.target_to_positions.iter() {
+            let mut positions = positions.clone();
+            positions.sort_unstable();
+            positions.dedup();
+            let n = positions.len();
+            if n < 2 {
+                reports.push(ClusteringReport {
+                    target: target.clone(),
+                    positions: n,
+                    longest_gap: max,
+                    shortest_gap: max,
+                    gap_ratio: 1.0,
+                    clustering: 1.0,
+                    suggestion: Some(ClusteringFix::MoreReplicas),
+                });
+                continue;
+            }
+
+            let mut gaps: Vec<Position> = positions.windows(2).map(|w| w[1] - w[0]).collect();
+            // from the last position round to the first
+            gaps.push(max - positions[n - 1] + positions[0] + 1);
+            let longest_gap = *gaps.iter().max().unwrap_or(&0);
+            let shortest_gap = *gaps.iter().min().unwrap_or(&0);
+            let harmonic: f64 = (1..=n).map(|k| 1.0 / k as f64).sum();
+            let clustering = longest_gap as f64 / (space / n as f64 * harmonic);
+            let suggestion = if n < MIN_SPREAD_POSITIONS {
+                Some(ClusteringFix::MoreReplicas)
+            } else if clustering > CLUSTERING_LIMIT {
+                Some(ClusteringFix::Resalt)
+            } else {
+                None
+            };
+            reports.push(ClusteringReport {
+                target: target.clone(),
+                positions: n,
+                longest_gap,
+                shortest_gap,
+                gap_ratio: longest_gap as f64 / shortest_gap as f64,
+                clustering,
+                suggestion,
+            });
+        }
+        return reports;
+    }
+
+    // The hasher's range, or further if the ring still holds positions
+    // from a wider hasher than the one it has now
+    fn widest_position(&self) -> Position {
+        let last = self.sorted_position_to_target.last().map_or(0, |(p, _)| *p);
+        return self.hasher.max_position().max(last);
+    }
+}
+
+/*
+ * Why did this key land there? Where a target comes in a key's candidate
+ * list, and which of the target's replicas got it there.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AffinityReport {
+    pub resource_position: Position,
+    // 0 for the target a lookup would return; None if every one of the
+    // target's positions has been clobbered by other targets, or if the
+    // key matches a rule which doesn't list it
+    pub rank: Option<usize>,
+    // The first of the target's positions clockwise from the key, which
+    // replica it is, and how far round the ring from the key it sits
+    pub position: Option<Position>,
+    pub replica: Option<usize>,
+    pub distance: Option<Position>,
+}
+
+impl Flexihash {
+    pub fn affinity<K: ResourceKey, S: AsRef<str>>(
+        &self,
+        resource: K,
+        target: S,
+    ) -> AffinityReport {
+        let target = self.normalize_str(target.as_ref());
+        let target = target.as_ref();
+        let positions = match self.target_to_positions.get(target) {
+            Some(positions) => positions,
+            None => self.missing_target(target),
+        };
+        let resource_position = self.resource_position(&resource);
+        let max = self.widest_position();
+        let distance_to = |p: Position| {
+            if p >= resource_position {
+                p - resource_position
+            } else {
+                max - resource_position + p + 1
+            }
+        };
+
+        let nearest = positions
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| self.position_to_target.get(p).map(|t| t.as_str()) == Some(target))
+            .min_by_key(|(_, p)| distance_to(**p));
+        return AffinityReport {
+            resource_position,
+            rank: self.lookup_candidates(&resource).position(|t| t == target),
+            position: nearest.map(|(_, p)| *p),
+            replica: nearest.map(|(i, _)| i),
+            distance: nearest.map(|(_, p)| distance_to(*p)),
+        };
+    }
+}
+
+/*
+ * Every step of a lookup, for when two services disagree about where a
+ * key goes: compare the positions first (a different hasher, prefix or
+ * normalization), then the walks (different targets or weights).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupTrace {
+    pub resource_position: Position,
+    // Index into the sorted ring where the walk started; the walk wraps
+    // round to 0 from here, including when it's past the last position
+    pub offset: usize,
+    // Each position looked at, in order, and who owned it
+    pub examined: Vec<(Position, Target)>,
+    // Positions passed over because their target was already chosen
+    pub duplicates_skipped: usize,
+    // Index into rules() of the rule the key matched, in which case the
+    // targets are that rule's and nothing was examined
+    pub rule: Option<usize>,
+}
+
+impl Flexihash {
+    pub fn lookup_traced<K: ResourceKey>(&self, resource: K) -> (Target, LookupTrace) {
+        let (targets, trace) = self.lookup_list_traced(resource, 1);
+        match targets.into_iter().next() {
+            Some(target) => return (target, trace),
+            None => panic!("No targets set"),
+        }
+    }
+
+    pub fn lookup_list_traced<K: ResourceKey>(
+        &self,
+        resource: K,
+        requested_count: u32,
+    ) -> (Vec<Target>, LookupTrace) {
+        if requested_count == 0 {
+            panic!("Need to request at least 1 resource");
+        }
+        let resource_position = self.resource_position(&resource);
+        let offset = self.search(resource_position);
+        let mut trace = LookupTrace {
+            resource_position,
+            offset,
+            examined: Vec::new(),
+            duplicates_skipped: 0,
+            rule: None,
+        };
+        let key = resource.ring_key();
+        trace.rule = self
+            .rules
+            .iter()
+            .position(|rule| rule.matches(key.as_ref()));
+        if let Some(index) = trace.rule {
+            let targets = self.rules[index].targets();
+            let targets = targets
+                .iter()
+                .take(requested_count as usize)
+                .cloned()
+                .collect();
+            return (targets, trace);
+        }
+        let wanted = (requested_count as usize).min(self.target_to_positions.len());
+        let mut targets: Vec<Target> = Vec::new();
+        let ring = &self.sorted_position_to_target;
+        for (position, target) in ring[offset..].iter().chain(ring[..offset].iter()) {
+            if targets.len() == wanted {
+                break;
+            }
+            trace.examined.push((*position, target.clone()));
+            if targets.contains(target) {
+                trace.duplicates_skipped += 1;
+            } else {
+                targets.push(target.clone());
+            }
+        }
+        return (targets, trace);
+    }
+}
+
+#[cfg(test)]
+mod test_analysis {
+    use super::*;
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn simulate_distribution_matches_original() {
+        // the same setup as test_compat
+        let mut fh = Flexihash::new();
+        for n in ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"].iter() {
+            fh.add_target(format!("{:032x}", md5::compute(n)), 1);
+        }
+        let counts =
+            fh.simulate_distribution(1000, |n| format!("{:032x}", md5::compute(n.to_string())));
+
+        assert_eq!(counts.len(), 10);
+        assert_eq!(counts.values().sum::<u64>(), 1000);
+        assert_eq!(counts["0cc175b9c0f1b6a831c399e269772661"], 105);
+        assert_eq!(counts["2510c39011c5be704182423e3a695e91"], 54);
+    }
+
+    #[test]
+    fn simulate_distribution_includes_idle_targets() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 1);
+        let counts = fh.simulate_distribution(0, |n| n);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["t-a"], 0);
+    }
+
+    #[test]
+    fn simulations_are_not_lookups() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        fh.enable_lookup_counts();
+        let counts = fh.simulate_distribution(100, |n| n);
+        assert_eq!(counts.values().sum::<u64>(), 100);
+        assert!(fh.lookup_counts().is_empty());
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn disruption_of_ordinary_changes_is_minimal() {
+        let mut before = Flexihash::new();
+        before.add_targets(vec!["t-a", "t-b", "t-c", "t-d"]);
+
+        let mut after = before.clone();
+        after.remove_target("t-b");
+        after.add_target("t-e", 1);
+        after.update_target_weight("t-c", 3);
+        let report = verify_minimal_disruption(&before, &after);
+        assert_eq!(report.sampled, 10000);
+        assert!(report.moved > 0);
+        assert!(report.is_minimal(), "{:?}", report);
+
+        let report = verify_minimal_disruption(&before, &before.clone());
+        assert_eq!(report.moved, 0);
+    }
+
+    #[test]
+    fn verifying_is_not_looking_up() {
+        let mut before = Flexihash::new();
+        before.add_targets(vec!["t-a", "t-b"]);
+        before.enable_lookup_counts();
+        let mut after = before.clone();
+        after.add_target("t-c", 1);
+        verify_minimal_disruption_with(&before, &after, 100, |n| n);
+        assert!(before.lookup_counts().is_empty());
+        assert!(after.lookup_counts().is_empty());
+    }
+
+    #[cfg(all(feature = "md5", feature = "crc"))]
+    #[test]
+    fn disruption_catches_reshuffles() {
+        let mut before = Flexihash::new();
+        before.add_targets(vec!["t-a", "t-b", "t-c"]);
+        // same targets, different hasher: keys move between untouched targets
+        let mut after = Flexihash::new();
+        after.set_hasher(crate::Hasher::Md5);
+        after.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let report = verify_minimal_disruption_with(&before, &after, 100, |n| format!("key{}", n));
+        assert!(!report.is_minimal());
+        assert_eq!(report.violations, report.moved);
+        assert_eq!(report.examples.len(), 10);
+        assert!(report.examples[0].0.starts_with("key"));
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn clustering_of_a_good_hash() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(crate::Hasher::Md5);
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let reports = fh.replica_clustering();
+        assert_eq!(reports.len(), 3);
+        for report in reports {
+            assert_eq!(report.positions, 64);
+            assert!(report.longest_gap > report.shortest_gap);
+            assert!(report.gap_ratio > 1.0);
+            assert_eq!(report.suggestion, None, "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn clustering_of_a_clumpy_hash() {
+        // adler32 of short keys only reaches a sliver of the ring
+        let mut fh = Flexihash::new();
+        fh.set_hasher(crate::Hasher::Adler32);
+        fh.add_target("t-a", 1);
+        let report = &fh.replica_clustering()[0];
+        assert!(report.clustering > 10.0, "{:?}", report);
+        assert_eq!(report.suggestion, Some(ClusteringFix::Resalt));
+
+        fh.set_replicas(4);
+        fh.add_target("t-b", 1);
+        let report = &fh.replica_clustering()[1];
+        assert_eq!(report.positions, 4);
+        assert_eq!(report.suggestion, Some(ClusteringFix::MoreReplicas));
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn clustering_after_narrowing_the_hasher() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(crate::Hasher::Md5);
+        fh.add_targets(vec!["t-a", "t-b"]);
+        // the positions are still md5's until the targets are re-added
+        fh.set_hasher(crate::Hasher::Adler32);
+        for report in fh.replica_clustering() {
+            assert_eq!(report.positions, 64);
+            assert!(report.longest_gap > report.shortest_gap);
+            assert_eq!(report.suggestion, None, "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn simulate_distribution_empty_ring() {
+        let fh = Flexihash::new();
+        assert_eq!(fh.simulate_distribution(100, |n| n).len(), 0);
+    }
+
+    #[test]
+    fn affinity_explains_placement() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (i, p) in [10, 20, 30].iter().enumerate() {
+            fh.set_hasher(crate::Hasher::Mock(*p));
+            fh.add_target(format!("t{}", i + 1), 1);
+        }
+        fh.set_hasher(crate::Hasher::Mock(15));
+
+        let report = fh.affinity("resource", "t2");
+        assert_eq!(report.resource_position, 15);
+        assert_eq!(report.rank, Some(0));
+        assert_eq!(report.position, Some(20));
+        assert_eq!(report.replica, Some(0));
+        assert_eq!(report.distance, Some(5));
+
+        // round the end of the ring and back to the start
+        let report = fh.affinity("resource", "t1");
+        assert_eq!(report.rank, Some(2));
+        assert_eq!(report.distance, Some(Position::MAX - 15 + 10 + 1));
+    }
+
+    #[test]
+    fn affinity_matches_lookups() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        fh.add_target("t-d", 3);
+        for key in 0..50 {
+            let owner = fh.lookup(key);
+            let report = fh.affinity(key, &owner);
+            assert_eq!(report.rank, Some(0));
+            let replica = report.replica.unwrap();
+            assert_eq!(
+                report.position.unwrap(),
+                crate::hash(fh.hasher(), format!("{}{}", owner, replica))
+            );
+            for other in fh.targets().filter(|t| **t != owner) {
+                assert!(fh.affinity(key, other).distance > report.distance);
+            }
+        }
+    }
+
+    #[test]
+    fn affinity_of_clobbered_target() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(crate::Hasher::Mock(1));
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let lost = if fh.lookup("resource") == "t-a" {
+            "t-b"
+        } else {
+            "t-a"
+        };
+        let report = fh.affinity("resource", lost);
+        assert_eq!(report.rank, None);
+        assert_eq!(report.replica, None);
+    }
+
+    #[test]
+    fn affinity_after_narrowing_the_hasher() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        fh.set_hasher(crate::Hasher::Mock(10));
+        fh.add_target("t-a", 1);
+        fh.set_hasher(crate::Hasher::Mock(1 << 40));
+        fh.add_target("t-b", 1);
+        // past t-a, short of t-b
+        fh.set_hasher(crate::Hasher::Adler32);
+        let near = fh.affinity("resource", "t-b");
+        let far = fh.affinity("resource", "t-a");
+        assert_eq!((near.rank, far.rank), (Some(0), Some(1)));
+        assert_eq!(
+            far.distance,
+            Some((1 << 40) - far.resource_position + 10 + 1)
+        );
+        assert!(near.distance < far.distance);
+    }
+
+    #[test]
+    fn traces_match_lookups() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(4);
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let mut skipped = 0;
+        for i in 0..200 {
+            let key = format!("r-{}", i);
+            let (target, trace) = fh.lookup_traced(&key);
+            assert_eq!(target, fh.lookup(&key));
+            assert_eq!(trace.examined.len(), 1);
+            assert_eq!(trace.duplicates_skipped, 0);
+            assert_eq!(trace.examined[0].1, target);
+            assert_eq!(
+                trace.examined[0].0,
+                fh.sorted_position_to_target[trace.offset % 12].0
+            );
+
+            let (targets, trace) = fh.lookup_list_traced(&key, 3);
+            assert_eq!(targets, fh.lookup_list(&key, 3));
+            assert_eq!(trace.examined.len(), 3 + trace.duplicates_skipped);
+            skipped += trace.duplicates_skipped;
+        }
+        assert!(skipped > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No targets set")]
+    fn trace_of_empty_ring() {
+        Flexihash::new().lookup_traced("resource");
+    }
+}
@@ -0,0 +1,419 @@
+use crate::mapped::MapError;
+use crate::scaling;
+use crate::snapshot::{Snapshot, SnapshotError};
+use crate::{key_position, Flexihash, Hasher, KeyNormalization, ResourceKey, Target, TargetInfo};
+use rkyv::rancor;
+use rkyv::util::AlignedVec;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/*
+ * A snapshot in rkyv's archive format, which is laid out so that it can be
+ * read where it sits: an ArchivedRing checks a buffer over once (bounds,
+ * UTF-8, sorted positions, owners in range) and then answers lookups
+ * straight from the positions array and target table in it, with nothing
+ * deserialized or rebuilt. Over an mmap'd file, a ring of any size is
+ * ready to use as soon as it's been checked.
+ *
+ * The buffer must be 16-byte aligned, for the positions - an mmap is, and
+ * so is the AlignedVec from to_archived_bytes; a plain Vec<u8> may not be.
+ *
+ * Unlike mapped.rs's format, this one keeps the weights, zones, tiers,
+ * labels, replicas, replica strategy and salt too, so it can be turned back
+ * into a full Flexihash. Rules can't be archived, being code.
+ */
+#[derive(Debug, Clone, PartialEq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct RingArchive {
+    pub hasher: String,
+    pub replicas: u32,
+    // as scaling::from_name has it; empty for a Custom strategy
+    pub replica_strategy: String,
+    pub salt: String,
+    pub key_prefix: String,
+    pub hash_tags: bool,
+    // 0 = as given, 1 = nfc, 2 = nfkc
+    pub key_normalization: u8,
+    pub generation: u64,
+    pub positions: Vec<u128>,
+    // index into targets
+    pub owners: Vec<u32>,
+    pub targets: Vec<String>,
+    pub weights: Vec<u32>,
+    pub zones: Vec<Option<String>>,
+    pub tiers: Vec<u32>,
+    pub labels: Vec<BTreeMap<String, String>>,
+}
+
+impl Flexihash {
+    pub fn to_archive(&self) -> RingArchive {
+        let targets = self.get_all_targets();
+        let info = |t: &Target| self.target_info.get(t).cloned().unwrap_or_default();
+        return RingArchive {
+            hasher: self.hasher.to_string(),
+            replicas: self.replicas,
+            replica_strategy: self.replica_strategy.name().unwrap_or_default(),
+            salt: self.salt.clone(),
+            key_prefix: self.key_prefix.clone(),
+            hash_tags: self.hash_tags,
+            key_normalization: match self.key_normalization {
+                KeyNormalization::AsGiven => 0,
+                #[cfg(feature = "unicode")]
+                KeyNormalization::Nfc => 1,
+                #[cfg(feature = "unicode")]
+                KeyNormalization::Nfkc => 2,
+            },
+            generation: self.generation,
+            positions: self.sorted_position_to_target.iter().map(|p| p.0).collect(),
+            owners: self
+                .sorted_position_to_target
+                .iter()
+                .map(|(_, target)| targets.binary_search(target).unwrap_or_default() as u32)
+                .collect(),
+            weights: targets.iter().map(|t| info(t).weight).collect(),
+            zones: targets.iter().map(|t| info(t).zone).collect(),
+            tiers: targets.iter().map(|t| info(t).tier).collect(),
+            labels: targets.iter().map(|t| info(t).labels).collect(),
+            targets,
+        };
+    }
+
+    pub fn to_archived_bytes(&self) -> AlignedVec {
+        return rkyv::to_bytes::<rancor::Error>(&self.to_archive())
+            .expect("archiving a ring can't fail");
+    }
+
+    pub fn save_archived<P: AsRef<Path>>(&self, path: P) -> Result<(), MapError> {
+        crate::snapshot::write_atomically(path.as_ref(), &self.to_archived_bytes())?;
+        return Ok(());
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchivedRing<B: AsRef<[u8]>> {
+    data: B,
+    // parsed once up front, being tiny
+    hasher: Hasher,
+    key_normalization: KeyNormalization,
+}
+
+#[cfg(feature = "mmap")]
+impl ArchivedRing<memmap2::Mmap> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ArchivedRing<memmap2::Mmap>, MapError> {
+        let file = std::fs::File::open(path)?;
+        // save_archived replaces files rather than writing into them
+        let data = unsafe { memmap2::Mmap::map(&file)? };
+        return ArchivedRing::new(data);
+    }
+}
+
+impl<B: AsRef<[u8]>> ArchivedRing<B> {
+    pub fn new(data: B) -> Result<ArchivedRing<B>, MapError> {
+        let bytes = data.as_ref();
+        if !(bytes.as_ptr() as usize).is_multiple_of(16) {
+            return Err(MapError::Invalid(
+                "buffer is not 16-byte aligned".to_string(),
+            ));
+        }
+        let archive = rkyv::access::<ArchivedRingArchive, rancor::Error>(bytes)
+            .map_err(|e| MapError::Invalid(e.to_string()))?;
+        let invalid = |message: &str| Err(MapError::Invalid(message.to_string()));
+
+        let hasher = match archive.hasher.as_str().parse() {
+            Ok(hasher) => hasher,
+            Err(_) => return invalid("unknown hasher"),
+        };
+        let key_normalization = match archive.key_normalization {
+            0 => KeyNormalization::AsGiven,
+            #[cfg(feature = "unicode")]
+            1 => KeyNormalization::Nfc,
+            #[cfg(feature = "unicode")]
+            2 => KeyNormalization::Nfkc,
+            _ => return invalid("unknown key normalization"),
+        };
+        if archive.owners.len() != archive.positions.len() {
+            return invalid("positions and owners don't match up");
+        }
+        let n_targets = archive.targets.len();
+        if archive.weights.len() != n_targets
+            || archive.zones.len() != n_targets
+            || archive.tiers.len() != n_targets
+            || archive.labels.len() != n_targets
+        {
+            return invalid("targets and their info don't match up");
+        }
+        let strategy = archive.replica_strategy.as_str();
+        if !strategy.is_empty() && scaling::from_name(strategy).is_none() {
+            return invalid("unknown replica strategy");
+        }
+        if archive
+            .positions
+            .windows(2)
+            .any(|w| w[0].to_native() >= w[1].to_native())
+        {
+            return invalid("positions out of order");
+        }
+        if archive
+            .owners
+            .iter()
+            .any(|o| o.to_native() as usize >= n_targets)
+        {
+            return invalid("owner out of range");
+        }
+        return Ok(ArchivedRing {
+            data,
+            hasher,
+            key_normalization,
+        });
+    }
+
+    fn archive(&self) -> &ArchivedRingArchive {
+        // checked over in new()
+        return unsafe { rkyv::access_unchecked::<ArchivedRingArchive>(self.data.as_ref()) };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.archive().positions.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.archive().positions.is_empty();
+    }
+
+    pub fn generation(&self) -> u64 {
+        return self.archive().generation.to_native();
+    }
+
+    pub fn get_all_targets(&self) -> Vec<&str> {
+        return self.archive().targets.iter().map(|t| t.as_str()).collect();
+    }
+
+    pub fn lookup<K: ResourceKey>(&self, resource: K) -> &str {
+        return match self.lookup_list(resource, 1).first() {
+            Some(target) => target,
+            None => panic!("No targets set"),
+        };
+    }
+
+    // Same results as Flexihash::lookup_list on the ring this was made
+    // from, unless that had rules
+    pub fn lookup_list<K: ResourceKey>(&self, resource: K, requested_count: u32) -> Vec<&str> {
+        if requested_count == 0 {
+            panic!("Need to request at least 1 resource");
+        }
+        let archive = self.archive();
+        let targets = &archive.targets;
+        if targets.is_empty() {
+            return Vec::new();
+        }
+        if targets.len() == 1 {
+            return vec![targets[0].as_str()];
+        }
+
+        let position = key_position(
+            &self.hasher,
+            archive.key_prefix.as_str(),
+            archive.hash_tags,
+            self.key_normalization,
+            resource.ring_key().as_ref(),
+        );
+        let positions = &archive.positions;
+        let start = positions.partition_point(|p| p.to_native() < position);
+
+        let mut results: Vec<&str> = Vec::new();
+        for i in (start..positions.len()).chain(0..start) {
+            let target = targets[archive.owners[i].to_native() as usize].as_str();
+            if !results.contains(&target) {
+                results.push(target);
+                if results.len() == requested_count as usize || results.len() == targets.len() {
+                    break;
+                }
+            }
+        }
+        return results;
+    }
+
+    // Back to a full ring, eg to make changes to it (without any rules);
+    // fails if the weights call for more positions than a ring is allowed
+    // by default, or the ring had a Custom replica strategy
+    pub fn to_flexihash(&self) -> Result<Flexihash, SnapshotError> {
+        let archive = self.archive();
+        let replica_strategy = scaling::from_name(archive.replica_strategy.as_str())
+            .ok_or(SnapshotError::UnnamedStrategy)?;
+        let snapshot = Snapshot {
+            hasher: self.hasher.clone(),
+            replicas: archive.replicas.to_native(),
+            replica_strategy,
+            salt: archive.salt.to_string(),
+            key_prefix: archive.key_prefix.to_string(),
+            hash_tags: archive.hash_tags,
+            key_normalization: self.key_normalization,
+            targets: (0..archive.targets.len())
+                .map(|i| {
+                    let info = TargetInfo {
+                        weight: archive.weights[i].to_native(),
+                        zone: archive.zones[i].as_ref().map(|z| z.to_string()),
+                        tier: archive.tiers[i].to_native(),
+                        labels: archive.labels[i]
+                            .iter()
+                            .map(|(k, v)| (k.to_string(), v.to_string()))
+                            .collect(),
+                    };
+                    (Target::from(archive.targets[i].as_str()), info)
+                })
+                .collect(),
+            generation: archive.generation.to_native(),
+            causal_token: None,
+        };
+        return Flexihash::try_from_snapshot(&snapshot);
+    }
+}
+
+#[cfg(test)]
+mod test_archived {
+    use super::*;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(16);
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 2);
+        fh.add_target("t-c", 1);
+        fh.set_key_prefix("app:");
+        return fh;
+    }
+
+    #[test]
+    fn lookups_match() {
+        let fh = ring();
+        let data = fh.to_archived_bytes();
+        let archived = ArchivedRing::new(&data[..]).unwrap();
+        assert_eq!(archived.len(), fh.sorted_position_to_target.len());
+        assert_eq!(archived.get_all_targets(), ["t-a", "t-b", "t-c"]);
+        for i in 0..200 {
+            assert_eq!(archived.lookup(i), fh.lookup(i));
+            assert_eq!(archived.lookup_list(i, 2), fh.lookup_list(i, 2));
+        }
+    }
+
+    #[test]
+    fn back_to_a_ring() {
+        let fh = ring();
+        let data = fh.to_archived_bytes();
+        let restored = ArchivedRing::new(&data[..])
+            .unwrap()
+            .to_flexihash()
+            .unwrap();
+        assert_eq!(
+            restored.sorted_position_to_target,
+            fh.sorted_position_to_target
+        );
+        assert_eq!(restored.get_target_info("t-b").unwrap().weight, 2);
+        assert_eq!(restored.generation(), fh.generation());
+        assert_eq!(restored.lookup("user:1"), fh.lookup("user:1"));
+    }
+
+    #[test]
+    fn keeps_target_info_and_strategy() {
+        struct Node;
+        impl crate::RingTarget for Node {
+            fn name(&self) -> Target {
+                return "t-d".to_string();
+            }
+            fn weight(&self) -> u32 {
+                return 4;
+            }
+            fn zone(&self) -> Option<String> {
+                return Some("eu-1".to_string());
+            }
+            fn tier(&self) -> u32 {
+                return 1;
+            }
+            fn labels(&self) -> BTreeMap<String, String> {
+                return BTreeMap::from([("rack".to_string(), "r1".to_string())]);
+            }
+        }
+
+        let mut fh = Flexihash::new();
+        fh.set_replica_strategy(std::sync::Arc::new(scaling::Logarithmic));
+        fh.add_target("t-a", 1);
+        fh.add_ring_target(&Node);
+        let data = fh.to_archived_bytes();
+        let restored = ArchivedRing::new(&data[..])
+            .unwrap()
+            .to_flexihash()
+            .unwrap();
+        assert_eq!(
+            restored.sorted_position_to_target,
+            fh.sorted_position_to_target
+        );
+        assert_eq!(restored.get_target_info("t-d"), fh.get_target_info("t-d"));
+        assert_eq!(restored.fingerprint(), fh.fingerprint());
+
+        // a Custom strategy can still be looked up in, but not restored
+        fh.set_replica_strategy(std::sync::Arc::new(scaling::Custom(|r, _| r as u64)));
+        let data = fh.to_archived_bytes();
+        let archived = ArchivedRing::new(&data[..]).unwrap();
+        assert_eq!(archived.lookup("user:1"), fh.lookup("user:1"));
+        assert!(matches!(
+            archived.to_flexihash(),
+            Err(SnapshotError::UnnamedStrategy)
+        ));
+    }
+
+    #[test]
+    fn small_rings() {
+        let empty = Flexihash::new().to_archived_bytes();
+        let archived = ArchivedRing::new(&empty[..]).unwrap();
+        assert!(archived.is_empty());
+        assert!(archived.lookup_list("resource", 1).is_empty());
+
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        let one = fh.to_archived_bytes();
+        assert_eq!(
+            ArchivedRing::new(&one[..]).unwrap().lookup("resource"),
+            "t-a"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_buffers() {
+        let mut archive = ring().to_archive();
+        archive.positions.swap(0, 1);
+        let data = rkyv::to_bytes::<rancor::Error>(&archive).unwrap();
+        assert!(ArchivedRing::new(&data[..]).is_err());
+
+        let mut archive = ring().to_archive();
+        archive.owners[0] = 3;
+        let data = rkyv::to_bytes::<rancor::Error>(&archive).unwrap();
+        assert!(ArchivedRing::new(&data[..]).is_err());
+
+        let mut archive = ring().to_archive();
+        archive.tiers.pop();
+        let data = rkyv::to_bytes::<rancor::Error>(&archive).unwrap();
+        assert!(ArchivedRing::new(&data[..]).is_err());
+
+        let mut archive = ring().to_archive();
+        archive.replica_strategy = "quadratic".to_string();
+        let data = rkyv::to_bytes::<rancor::Error>(&archive).unwrap();
+        assert!(ArchivedRing::new(&data[..]).is_err());
+
+        let data = ring().to_archived_bytes();
+        assert!(ArchivedRing::new(&data[..data.len() - 8]).is_err());
+        let mut misaligned = AlignedVec::<16>::new();
+        misaligned.push(0);
+        misaligned.extend_from_slice(&data);
+        assert!(ArchivedRing::new(&misaligned[1..]).is_err());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn from_a_file() {
+        let fh = ring();
+        let path = std::env::temp_dir().join(format!("flexihash-archived-{}", std::process::id()));
+        fh.save_archived(&path).unwrap();
+        let archived = ArchivedRing::open(&path).unwrap();
+        assert_eq!(archived.lookup("user:1"), fh.lookup("user:1"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+use crate::metrics::LookupCounts;
+use crate::{Flexihash, ResourceKey, Target};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/*
+ * Where the load-aware lookups below get each target's current load from.
+ * Any Fn(&str) -> f64 will do; LookupCounts is a ready-made counter - call
+ * record() for each target picked, and take() every so often so that old
+ * traffic stops counting. Each lookup takes a closure, and has a _by
+ * version which takes a reporter.
+ */
+pub trait LoadReporter {
+    fn load(&self, target: &str) -> f64;
+}
+
+impl<F: Fn(&str) -> f64> LoadReporter for F {
+    fn load(&self, target: &str) -> f64 {
+        return self(target);
+    }
+}
+
+impl LoadReporter for LookupCounts {
+    fn load(&self, target: &str) -> f64 {
+        return self.get(target) as f64;
+    }
+}
+
+const CHOICE_SALTS: [&str; 2] = ["choice-1", "choice-2"];
+
+/*
+ * Lookups which bend consistent placement a little to even out load: each
+ * only ever picks from a resource's first few candidates, so placement
+ * stays mostly stable while the hottest targets get some relief.
+ */
+impl Flexihash {
+    pub fn lookup_least_loaded<K: ResourceKey>(
+        &self,
+        resource: K,
+        k: u32,
+        load_fn: impl Fn(&str) -> f64,
+    ) -> Target {
+        return self.lookup_least_loaded_by(resource, k, &load_fn);
+    }
+
+    pub fn lookup_least_loaded_by<K: ResourceKey>(
+        &self,
+        resource: K,
+        k: u32,
+        load: &dyn LoadReporter,
+    ) -> Target {
+        let candidates = self.find_targets(resource, "", k);
+        if let Some((_, target)) = candidates
+            .into_iter()
+            .map(|t| (load.load(&t), t))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+        {
+            self.record_lookup(&target);
+            return target;
+        } else {
+            panic!("No targets set");
+        }
+    }
+
+    // Power of two choices: hash the resource two independent ways (with
+    // two salts, as lookup_salted would), and go with whichever of the two
+    // owners is less loaded
+    pub fn lookup_two_choices<K: ResourceKey>(
+        &self,
+        resource: K,
+        load_fn: impl Fn(&str) -> f64,
+    ) -> Target {
+        return self.lookup_two_choices_by(resource, &load_fn);
+    }
+
+    pub fn lookup_two_choices_by<K: ResourceKey>(
+        &self,
+        resource: K,
+        load: &dyn LoadReporter,
+    ) -> Target {
+        let owner = |salt| match self.find_targets(&resource, salt, 1).pop() {
+            Some(target) => target,
+            None => panic!("No targets set"),
+        };
+        let (first, second) = (owner(CHOICE_SALTS[0]), owner(CHOICE_SALTS[1]));
+        let target = if first != second && load.load(&second) < load.load(&first) {
+            second
+        } else {
+            first
+        };
+        self.record_lookup(&target);
+        return target;
+    }
+
+    // Consistent hashing with bounded loads: the first candidate, in ring
+    // order, with room for one more under factor x its fair share (by
+    // weight) of the total load. A factor of 1.25 means nobody takes more
+    // than 25% over their share; bigger factors move fewer keys.
+    pub fn lookup_bounded<K: ResourceKey>(
+        &self,
+        resource: K,
+        factor: f64,
+        load_fn: impl Fn(&str) -> f64,
+    ) -> Target {
+        return self.lookup_bounded_by(resource, factor, &load_fn);
+    }
+
+    pub fn lookup_bounded_by<K: ResourceKey>(
+        &self,
+        resource: K,
+        factor: f64,
+        load: &dyn LoadReporter,
+    ) -> Target {
+        if factor.is_nan() || factor < 1.0 {
+            panic!("Bounded load factor must be at least 1, got {}", factor);
+        }
+        let weight = |t: &str| self.get_target_info(t).map_or(1, |info| info.weight) as f64;
+        let mut total_load = 1.0;
+        let mut total_weight = 0.0;
+        for target in self.target_to_positions.keys() {
+            total_load += load.load(target);
+            total_weight += weight(target);
+        }
+        let mut first = None;
+        for target in self.lookup_candidates(resource) {
+            let bound = factor * total_load * weight(&target) / total_weight;
+            if load.load(&target) + 1.0 <= bound {
+                return target;
+            }
+            first.get_or_insert(target);
+        }
+        // only when the loads are changing under us
+        return first.unwrap_or_else(|| panic!("No targets set"));
+    }
+
+    // Pick one of the first k candidates at random, in proportion to their
+    // weights, so that one hot key is spread over k targets instead of
+    // hammering the first
+    pub fn lookup_weighted_random<K: ResourceKey>(
+        &self,
+        resource: K,
+        k: u32,
+        rng: &mut Rng,
+    ) -> Target {
+        let candidates = self.find_targets(resource, "", k);
+        let weight = |t: &Target| self.get_target_info(t).map_or(1, |info| info.weight) as u64;
+        let total: u64 = candidates.iter().map(weight).sum();
+        if total == 0 {
+            panic!("No targets set");
+        }
+        let mut pick = rng.next_u64() % total;
+        for target in candidates {
+            let w = weight(&target);
+            if pick < w {
+                self.record_lookup(&target);
+                return target;
+            }
+            pick -= w;
+        }
+        unreachable!();
+    }
+}
+
+/*
+ * A small fast RNG (splitmix64) for lookup_weighted_random; not for
+ * anything that needs to be unpredictable. Seed it to get the same
+ * choices every run, eg in tests.
+ */
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new() -> Rng {
+        // std seeds every RandomState from the OS, which is all we need
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        return Rng::seeded(hasher.finish());
+    }
+
+    pub fn seeded(seed: u64) -> Rng {
+        return Rng { state: seed };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        return mix(self.state);
+    }
+}
+
+// splitmix64's finalizer, for spreading out hashes which don't mix well
+// on their own (eg FNV, in the last few bytes)
+pub(crate) fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+impl Default for Rng {
+    fn default() -> Rng {
+        return Rng::new();
+    }
+}
+
+#[cfg(test)]
+mod test_balance {
+    use super::*;
+    use crate::Hasher;
+    use std::collections::HashMap;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (i, p) in [10, 20, 30].iter().enumerate() {
+            fh.set_hasher(Hasher::Mock(*p));
+            fh.add_target(format!("t{}", i + 1), 1);
+        }
+        fh.set_hasher(Hasher::Mock(15));
+        return fh;
+    }
+
+    #[test]
+    fn least_loaded_picks_from_first_k() {
+        let fh = ring();
+        // candidates in order are t2, t3, t1
+        let load = |t: &str| match t {
+            "t1" => 0.0,
+            "t2" => 5.0,
+            _ => 1.0,
+        };
+        assert_eq!(fh.lookup_least_loaded("resource", 1, load), "t2");
+        assert_eq!(fh.lookup_least_loaded("resource", 2, load), "t3");
+        assert_eq!(fh.lookup_least_loaded("resource", 3, load), "t1");
+    }
+
+    #[test]
+    fn least_loaded_counts_the_target_picked() {
+        let mut fh = ring();
+        fh.enable_lookup_counts();
+        let load = |t: &str| if t == "t2" { 5.0 } else { 1.0 };
+        assert_eq!(fh.lookup_least_loaded("resource", 2, load), "t3");
+        assert_eq!(fh.lookup_counts(), HashMap::from([("t3".to_string(), 1)]));
+    }
+
+    #[test]
+    fn least_loaded_prefers_ring_order_on_ties() {
+        let fh = ring();
+        assert_eq!(fh.lookup_least_loaded("resource", 3, |_| 1.0), "t2");
+    }
+
+    // adler32 puts short keys too close together for the salts to part them
+    #[cfg(feature = "crc")]
+    #[test]
+    fn two_choices_picks_less_loaded() {
+        let mut fh = Flexihash::new();
+        for i in 0..10 {
+            fh.add_target(format!("t{}", i), 1);
+        }
+        // find a key whose two choices differ
+        let [a, b] = CHOICE_SALTS;
+        let key = (0..)
+            .find(|i| fh.lookup_salted(i, a) != fh.lookup_salted(i, b))
+            .unwrap();
+        let first = fh.lookup_salted(key, a);
+        let second = fh.lookup_salted(key, b);
+
+        assert_eq!(fh.lookup_two_choices(key, |_| 0.0), first);
+        assert_eq!(
+            fh.lookup_two_choices(key, |t| if t == first { 2.0 } else { 1.0 }),
+            second
+        );
+        assert_eq!(
+            fh.lookup_two_choices(key, |t| if t == second { 2.0 } else { 1.0 }),
+            first
+        );
+
+        // one lookup, counted against the target it went to
+        fh.enable_lookup_counts();
+        fh.lookup_two_choices(key, |t| if t == first { 2.0 } else { 1.0 });
+        assert_eq!(fh.lookup_counts(), HashMap::from([(second, 1)]));
+    }
+
+    #[test]
+    fn two_choices_count_one_lookup() {
+        let mut fh = ring();
+        fh.enable_lookup_counts();
+        assert_eq!(fh.lookup_two_choices("resource", |_| 0.0), "t2");
+        assert_eq!(fh.lookup_counts(), HashMap::from([("t2".to_string(), 1)]));
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn two_choices_evens_out_skew() {
+        let mut fh = Flexihash::new();
+        fh.add_target("big", 4);
+        fh.add_target("small", 1);
+        let mut counts = std::collections::HashMap::new();
+        for i in 0..1000 {
+            let target = fh.lookup_two_choices(i, |t| *counts.get(t).unwrap_or(&0) as f64);
+            *counts.entry(target).or_insert(0) += 1;
+        }
+        let plain = fh.simulate_distribution(1000, |i| i);
+        assert!(counts["small"] > plain["small"]);
+    }
+
+    #[test]
+    fn weighted_random_follows_weights() {
+        let mut fh = ring();
+        fh.set_hasher(Hasher::Mock(30));
+        fh.update_target_weight("t3", 3);
+        fh.set_hasher(Hasher::Mock(15));
+        // candidates in order are t2 (weight 1) and t3 (weight 3)
+        let mut rng = Rng::seeded(42);
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..4000 {
+            let target = fh.lookup_weighted_random("resource", 2, &mut rng);
+            *counts.entry(target).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 2);
+        assert!((2800..3200).contains(&counts["t3"]), "{:?}", counts);
+
+        assert_eq!(fh.lookup_weighted_random("resource", 1, &mut rng), "t2");
+
+        // counted against whichever was picked, not the first candidate
+        fh.enable_lookup_counts();
+        for _ in 0..100 {
+            fh.lookup_weighted_random("resource", 2, &mut rng);
+        }
+        let counts = fh.lookup_counts();
+        assert_eq!(counts.values().sum::<u64>(), 100);
+        assert!(counts["t3"] > counts["t2"], "{:?}", counts);
+    }
+
+    #[test]
+    fn weighted_random_is_repeatable_when_seeded() {
+        let fh = ring();
+        let picks = |seed| {
+            let mut rng = Rng::seeded(seed);
+            (0..20)
+                .map(|_| fh.lookup_weighted_random("resource", 3, &mut rng))
+                .collect::<Vec<Target>>()
+        };
+        assert_eq!(picks(7), picks(7));
+        assert_ne!(picks(7), picks(8));
+    }
+
+    #[test]
+    fn bounded_load() {
+        let fh = ring();
+        // candidates in order are t2, t3, t1
+        assert_eq!(fh.lookup_bounded("resource", 1.25, |_| 0.0), "t2");
+        // 10 in total, 11 with this one; t2 is over 1.25 x 11 / 3
+        let load = |t: &str| if t == "t2" { 6.0 } else { 2.0 };
+        assert_eq!(fh.lookup_bounded("resource", 1.25, load), "t3");
+        assert_eq!(fh.lookup_bounded("resource", 2.0, load), "t2");
+    }
+
+    #[test]
+    fn bounded_load_evens_out() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c", "t-d"]);
+        let counts = LookupCounts::default();
+        for i in 0..1000 {
+            counts.record(&fh.lookup_bounded_by(i, 1.1, &counts));
+        }
+        let counts = counts.take();
+        assert_eq!(counts.values().sum::<u64>(), 1000);
+        assert!(counts.values().all(|c| *c <= 275), "{:?}", counts);
+        // and the reporter works for the other modes too
+        let idle = LookupCounts::default();
+        assert_eq!(
+            fh.lookup_least_loaded_by("resource", 1, &idle),
+            fh.lookup("resource")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Bounded load factor must be at least 1, got 0.5")]
+    fn bounded_load_factor() {
+        ring().lookup_bounded("resource", 0.5, |_| 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No targets set")]
+    fn least_loaded_on_empty() {
+        Flexihash::new().lookup_least_loaded("resource", 2, |_| 0.0);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{hash, hash_tag, normalize_key, Flexihash, Position, ResourceKey, Search};
+
+/*
+ * Lookups in bulk, for rebalancers and the like which route millions of
+ * keys at a go and spend more time on each lookup's overheads than on
+ * the search itself. Keys are taken a batch at a time: all of a batch's
+ * prefixed keys go into one reused buffer (rather than an allocation
+ * each), are hashed in one pass, and then searched for together, with
+ * the Eytzinger searches stepping down the tree in lockstep so that the
+ * memory loads for different keys overlap instead of waiting on each
+ * other. Results borrow the ring's own names rather than copying them.
+ *
+ * Which target each key gets is exactly what lookup would say, metrics
+ * and lookup counts included.
+ */
+const BATCH: usize = 64;
+
+impl Flexihash {
+    pub fn lookup_many<K: ResourceKey, I: IntoIterator<Item = K>>(
+        &self,
+        resources: I,
+    ) -> Vec<&str> {
+        let resources = resources.into_iter();
+        let mut results: Vec<&str> = Vec::with_capacity(resources.size_hint().0);
+        let mut batch = Batch::default();
+        for resource in resources {
+            self.add_to_batch(&mut batch, resource.ring_key().as_ref(), &mut results);
+            if batch.keys.len() == BATCH {
+                self.finish_batch(&mut batch, &mut results);
+            }
+        }
+        self.finish_batch(&mut batch, &mut results);
+
+        if let Some(metrics) = &self.metrics {
+            for target in results.iter() {
+                metrics.looked_up(target);
+            }
+        }
+        if let Some(counts) = &self.lookup_counts {
+            for target in results.iter() {
+                counts.record(target);
+            }
+        }
+        return results;
+    }
+
+    fn add_to_batch<'a>(&'a self, batch: &mut Batch, key: &[u8], results: &mut Vec<&'a str>) {
+        if !self.rules.is_empty() {
+            if let Some(targets) = self.route(key) {
+                // rules always have a target
+                results.push(&targets[0]);
+                return;
+            }
+        }
+        match self.target_to_positions.len() {
+            0 => panic!("No targets set"),
+            1 => {
+                if let Some(target) = self.target_to_positions.keys().next() {
+                    results.push(target);
+                }
+                return;
+            }
+            // everything weighted 0
+            _ if self.sorted_position_to_target.is_empty() => panic!("No targets set"),
+            _ => {}
+        }
+        let key = normalize_key(self.key_normalization, key);
+        let key = if self.hash_tags { hash_tag(&key) } else { &key };
+        let start = batch.buffer.len();
+        batch.buffer.extend_from_slice(self.key_prefix.as_bytes());
+        batch.buffer.extend_from_slice(key);
+        batch.keys.push((start, batch.buffer.len()));
+        // filled in by finish_batch
+        results.push("");
+        batch.slots.push(results.len() - 1);
+    }
+
+    fn finish_batch<'a>(&'a self, batch: &mut Batch, results: &mut [&'a str]) {
+        let n = batch.keys.len();
+        let mut positions = [0; BATCH];
+        for (position, (start, end)) in positions.iter_mut().zip(batch.keys.iter()) {
+            *position = hash(&self.hasher, &batch.buffer[*start..*end]);
+        }
+        let mut offsets = [0; BATCH];
+        match self.search {
+            Search::Eytzinger => self.eytzinger_search_batch(&positions[..n], &mut offsets[..n]),
+            Search::Interpolation => {
+                for (offset, position) in offsets.iter_mut().zip(positions[..n].iter()) {
+                    *offset = self.interpolation_search(*position);
+                }
+            }
+        }
+        let ring = &self.sorted_position_to_target;
+        for (slot, offset) in batch.slots.iter().zip(offsets.iter()) {
+            // past the last position wraps round to the first
+            results[*slot] = &ring[if *offset == ring.len() { 0 } else { *offset }].1;
+        }
+        batch.buffer.clear();
+        batch.keys.clear();
+        batch.slots.clear();
+    }
+
+    // eytzinger_search for each position, a level of the tree at a time
+    fn eytzinger_search_batch(&self, positions: &[Position], offsets: &mut [usize]) {
+        let n = self.eytzinger.len();
+        let mut ks = [1; BATCH];
+        let ks = &mut ks[..positions.len()];
+        // the deepest any search goes
+        for _ in 0..usize::BITS - n.leading_zeros() {
+            for (k, position) in ks.iter_mut().zip(positions.iter()) {
+                if *k <= n {
+                    *k = 2 * *k + (self.eytzinger[*k - 1].0 < *position) as usize;
+                }
+            }
+        }
+        for (k, offset) in ks.iter_mut().zip(offsets.iter_mut()) {
+            *k >>= k.trailing_ones() + 1;
+            *offset = if *k == 0 { n } else { self.eytzinger[*k - 1].1 };
+        }
+    }
+}
+
+#[derive(Default)]
+struct Batch {
+    // the prefixed keys, end to end
+    buffer: Vec<u8>,
+    keys: Vec<(usize, usize)>,
+    // where each key's result goes
+    slots: Vec<usize>,
+}
+
+#[cfg(test)]
+mod test_batch {
+    use super::*;
+    use crate::rules::Rule;
+    use crate::Target;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c", "t-d", "t-e"]);
+        fh.add_target("t-f", 3);
+        return fh;
+    }
+
+    fn check(fh: &Flexihash) {
+        let keys: Vec<String> = (0..1000)
+            .map(|i| format!("{{user{}}}:{}", i % 300, i))
+            .collect();
+        let many = fh.lookup_many(&keys);
+        assert_eq!(many.len(), keys.len());
+        for (key, target) in keys.iter().zip(many) {
+            assert_eq!(target, fh.lookup(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn same_as_lookup() {
+        let mut fh = ring();
+        check(&fh);
+        fh.set_key_prefix("app:");
+        fh.set_hash_tags(true);
+        check(&fh);
+        fh.set_search(Search::Interpolation);
+        check(&fh);
+        fh.add_rule(Rule::prefix("{user1", vec!["t-special"]));
+        check(&fh);
+
+        let mut one = Flexihash::new();
+        one.add_target("t-a", 1);
+        check(&one);
+        assert!(Flexihash::new()
+            .lookup_many(Vec::<String>::new())
+            .is_empty());
+    }
+
+    #[test]
+    fn counted_like_lookups() {
+        let mut fh = ring();
+        fh.enable_lookup_counts();
+        let targets = fh.lookup_many(0..100);
+        let mut expected = std::collections::HashMap::<Target, u64>::new();
+        for target in targets {
+            *expected.entry(target.to_string()).or_default() += 1;
+        }
+        assert_eq!(fh.lookup_counts(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "No targets set")]
+    fn empty_ring() {
+        Flexihash::new().lookup_many(["resource"]);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{Flexihash, ResourceKey, Target};
+
+/*
+ * Rolling out new targets to a slice of the keyspace: keys whose position
+ * (in the main ring's hash space) falls in the bottom `percent` of it are
+ * sent to the canary ring, everything else to the main ring. Which keys
+ * those are only depends on the main ring's hasher and key settings, so
+ * turning the percentage up only ever moves more keys across, never a
+ * different set.
+ *
+ * While the canary ring is empty, everything stays on the main ring.
+ */
+#[derive(Debug, Clone)]
+pub struct CanaryRing {
+    main: Flexihash,
+    canary: Flexihash,
+    percent: f64,
+}
+
+fn check_percent(percent: f64) {
+    if !(0.0..=100.0).contains(&percent) {
+        panic!("Canary percentage must be in [0, 100], got {}", percent);
+    }
+}
+
+impl CanaryRing {
+    pub fn new(main: Flexihash, canary: Flexihash, percent: f64) -> CanaryRing {
+        check_percent(percent);
+        return CanaryRing {
+            main,
+            canary,
+            percent,
+        };
+    }
+
+    pub fn set_percent(&mut self, percent: f64) {
+        check_percent(percent);
+        self.percent = percent;
+    }
+
+    pub fn percent(&self) -> f64 {
+        return self.percent;
+    }
+
+    pub fn main_ring(&self) -> &Flexihash {
+        return &self.main;
+    }
+
+    pub fn canary_ring(&self) -> &Flexihash {
+        return &self.canary;
+    }
+
+    pub fn main_ring_mut(&mut self) -> &mut Flexihash {
+        return &mut self.main;
+    }
+
+    pub fn canary_ring_mut(&mut self) -> &mut Flexihash {
+        return &mut self.canary;
+    }
+
+    pub fn is_canary<K: ResourceKey>(&self, resource: &K) -> bool {
+        if self.canary.target_to_positions.is_empty() {
+            return false;
+        }
+        // the top of a 128 bit space doesn't survive being made a float
+        if self.percent >= 100.0 {
+            return true;
+        }
+        let space = self.main.hasher.max_position() as f64 + 1.0;
+        let position = self.main.resource_position(resource) as f64;
+        return position < space * self.percent / 100.0;
+    }
+
+    pub fn lookup<K: ResourceKey>(&self, resource: K) -> Target {
+        if self.is_canary(&resource) {
+            return self.canary.lookup(resource);
+        }
+        return self.main.lookup(resource);
+    }
+
+    // All from the one ring; a canary key doesn't fall back to the main
+    // ring's targets
+    pub fn lookup_list<K: ResourceKey>(&self, resource: K, requested_count: u32) -> Vec<Target> {
+        if self.is_canary(&resource) {
+            return self.canary.lookup_list(resource, requested_count);
+        }
+        return self.main.lookup_list(resource, requested_count);
+    }
+}
+
+#[cfg(test)]
+mod test_canary {
+    use super::*;
+    use crate::{Hasher, Position};
+
+    fn ring(percent: f64, position: Position) -> CanaryRing {
+        let mut main = Flexihash::new();
+        main.add_targets(vec!["t-a", "t-b"]);
+        main.set_hasher(Hasher::Mock(position));
+        let mut canary = Flexihash::new();
+        canary.add_target("t-new", 1);
+        return CanaryRing::new(main, canary, percent);
+    }
+
+    #[test]
+    fn routes_by_position() {
+        let low = ring(10.0, Position::MAX / 20);
+        assert!(low.is_canary(&"resource"));
+        assert_eq!(low.lookup("resource"), "t-new");
+        assert_eq!(low.lookup_list("resource", 2), ["t-new"]);
+
+        let high = ring(10.0, Position::MAX / 5);
+        assert!(!high.is_canary(&"resource"));
+        assert_ne!(high.lookup("resource"), "t-new");
+
+        let mut none = ring(0.0, 0);
+        assert!(!none.is_canary(&"resource"));
+        none.set_percent(100.0);
+        assert!(none.is_canary(&"resource"));
+        assert!(ring(100.0, Position::MAX).is_canary(&"resource"));
+    }
+
+    #[test]
+    fn empty_canary() {
+        let mut ring = ring(100.0, 0);
+        ring.canary_ring_mut().remove_target("t-new");
+        assert!(!ring.is_canary(&"resource"));
+        assert_ne!(ring.lookup("resource"), "t-new");
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn fraction_of_keys() {
+        let mut main = Flexihash::new();
+        main.add_targets(vec!["t-a", "t-b"]);
+        let mut canary = Flexihash::new();
+        canary.add_target("t-new", 1);
+        let mut ring = CanaryRing::new(main, canary, 10.0);
+
+        let ten: Vec<i32> = (0..10000).filter(|i| ring.is_canary(i)).collect();
+        assert!((800..1200).contains(&ten.len()), "{}", ten.len());
+
+        // turning it up keeps the keys already moved
+        ring.set_percent(20.0);
+        let twenty: Vec<i32> = (0..10000).filter(|i| ring.is_canary(i)).collect();
+        assert!(twenty.len() > ten.len());
+        assert!(ten.iter().all(|i| twenty.contains(i)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Canary percentage must be in [0, 100], got 101")]
+    fn bad_percent() {
+        ring(101.0, 0);
+    }
+}
@@ -0,0 +1,537 @@
+use crate::{Flexihash, Target};
+use serde::ser::{self, Serialize};
+use std::fmt;
+
+/*
+ * Keys made of structured values - (tenant_id, object_id), or a struct -
+ * encoded the same way everywhere before hashing, rather than each service
+ * formatting its own string. The encoding is meant to stay put, since
+ * changing it would move every key:
+ *
+ *   bool              'b', then 0 or 1
+ *   integer >= 0      'u', then the value as a 16 byte big-endian u128
+ *   integer < 0       'i', then the value as a 16 byte big-endian i128
+ *   float             'f', then the f64 bits, big-endian
+ *   string, char      's', then the length as 8 bytes big-endian, then utf-8
+ *   bytes             'y', then the length as 8 bytes big-endian, then bytes
+ *   None, (), unit    'n'
+ *   Some(x)           'o', then x
+ *   sequence, tuple   '[', each element, ']'
+ *   map, struct       '{', each key then value, '}'
+ *   enum variant      'e', then its name as a string, then its value (if any)
+ *
+ * Integers are encoded by value, not type, so that eg switching an id from
+ * i32 to u64 doesn't move anything. Map entries are sorted by their encoded key, and struct
+ * fields are written as a map of field name to value, so neither a
+ * HashMap's order nor a struct's field order matter. Newtypes are
+ * transparent, and struct and enum names aren't included.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct EncodeError(String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Can't encode key: {}", self.0)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl ser::Error for EncodeError {
+    fn custom<T: fmt::Display>(msg: T) -> EncodeError {
+        return EncodeError(msg.to_string());
+    }
+}
+
+pub fn canonical_key<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    let mut out = Vec::new();
+    value.serialize(Encoder { out: &mut out })?;
+    return Ok(out);
+}
+
+struct Encoder<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn unsigned(self, v: u128) -> Result<(), EncodeError> {
+        self.out.push(b'u');
+        self.out.extend_from_slice(&v.to_be_bytes());
+        return Ok(());
+    }
+
+    fn signed(self, v: i128) -> Result<(), EncodeError> {
+        if v >= 0 {
+            return self.unsigned(v as u128);
+        }
+        self.out.push(b'i');
+        self.out.extend_from_slice(&v.to_be_bytes());
+        return Ok(());
+    }
+
+    fn sized(self, tag: u8, v: &[u8]) -> Result<(), EncodeError> {
+        self.out.push(tag);
+        self.out.extend_from_slice(&(v.len() as u64).to_be_bytes());
+        self.out.extend_from_slice(v);
+        return Ok(());
+    }
+
+    fn variant(&mut self, name: &str) {
+        self.out.push(b'e');
+        self.out.push(b's');
+        self.out
+            .extend_from_slice(&(name.len() as u64).to_be_bytes());
+        self.out.extend_from_slice(name.as_bytes());
+    }
+}
+
+impl<'a> ser::Serializer for Encoder<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+    type SerializeSeq = SeqEncoder<'a>;
+    type SerializeTuple = SeqEncoder<'a>;
+    type SerializeTupleStruct = SeqEncoder<'a>;
+    type SerializeTupleVariant = SeqEncoder<'a>;
+    type SerializeMap = MapEncoder<'a>;
+    type SerializeStruct = MapEncoder<'a>;
+    type SerializeStructVariant = MapEncoder<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), EncodeError> {
+        self.out.extend_from_slice(&[b'b', v as u8]);
+        return Ok(());
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), EncodeError> {
+        return self.signed(v as i128);
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), EncodeError> {
+        return self.signed(v as i128);
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), EncodeError> {
+        return self.signed(v as i128);
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), EncodeError> {
+        return self.signed(v as i128);
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), EncodeError> {
+        return self.signed(v);
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), EncodeError> {
+        return self.unsigned(v as u128);
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), EncodeError> {
+        return self.unsigned(v as u128);
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), EncodeError> {
+        return self.unsigned(v as u128);
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), EncodeError> {
+        return self.unsigned(v as u128);
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), EncodeError> {
+        return self.unsigned(v);
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), EncodeError> {
+        return self.serialize_f64(v as f64);
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), EncodeError> {
+        self.out.push(b'f');
+        self.out.extend_from_slice(&v.to_bits().to_be_bytes());
+        return Ok(());
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), EncodeError> {
+        return self.sized(b's', v.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), EncodeError> {
+        return self.sized(b's', v.as_bytes());
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), EncodeError> {
+        return self.sized(b'y', v);
+    }
+
+    fn serialize_none(self) -> Result<(), EncodeError> {
+        self.out.push(b'n');
+        return Ok(());
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), EncodeError> {
+        self.out.push(b'o');
+        return value.serialize(self);
+    }
+
+    fn serialize_unit(self) -> Result<(), EncodeError> {
+        return self.serialize_none();
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), EncodeError> {
+        return self.serialize_none();
+    }
+
+    fn serialize_unit_variant(
+        mut self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), EncodeError> {
+        self.variant(variant);
+        return Ok(());
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        return value.serialize(self);
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        mut self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        self.variant(variant);
+        return value.serialize(self);
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqEncoder<'a>, EncodeError> {
+        self.out.push(b'[');
+        return Ok(SeqEncoder { out: self.out });
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqEncoder<'a>, EncodeError> {
+        return self.serialize_seq(Some(len));
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqEncoder<'a>, EncodeError> {
+        return self.serialize_seq(Some(len));
+    }
+
+    fn serialize_tuple_variant(
+        mut self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqEncoder<'a>, EncodeError> {
+        self.variant(variant);
+        return self.serialize_seq(Some(len));
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapEncoder<'a>, EncodeError> {
+        return Ok(MapEncoder {
+            out: self.out,
+            entries: Vec::new(),
+            key: None,
+        });
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<MapEncoder<'a>, EncodeError> {
+        return self.serialize_map(Some(len));
+    }
+
+    fn serialize_struct_variant(
+        mut self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<MapEncoder<'a>, EncodeError> {
+        self.variant(variant);
+        return self.serialize_map(Some(len));
+    }
+}
+
+struct SeqEncoder<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl SeqEncoder<'_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        return value.serialize(Encoder { out: self.out });
+    }
+
+    fn finish(self) -> Result<(), EncodeError> {
+        self.out.push(b']');
+        return Ok(());
+    }
+}
+
+impl ser::SerializeSeq for SeqEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        return self.element(value);
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeTuple for SeqEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        return self.element(value);
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        return self.element(value);
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        return self.element(value);
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+// Entries are held back until the end so that they can be sorted
+struct MapEncoder<'a> {
+    out: &'a mut Vec<u8>,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+}
+
+impl MapEncoder<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), EncodeError> {
+        self.entries
+            .push((canonical_key(key)?, canonical_key(value)?));
+        return Ok(());
+    }
+
+    fn finish(mut self) -> Result<(), EncodeError> {
+        self.entries.sort();
+        self.out.push(b'{');
+        for (key, value) in self.entries {
+            self.out.extend_from_slice(&key);
+            self.out.extend_from_slice(&value);
+        }
+        self.out.push(b'}');
+        return Ok(());
+    }
+}
+
+impl ser::SerializeMap for MapEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), EncodeError> {
+        self.key = Some(canonical_key(key)?);
+        return Ok(());
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| EncodeError("Map value without a key".to_string()))?;
+        self.entries.push((key, canonical_key(value)?));
+        return Ok(());
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeStruct for MapEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        return self.field(key, value);
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeStructVariant for MapEncoder<'_> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        return self.field(key, value);
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        return self.finish();
+    }
+}
+
+impl Flexihash {
+    // Panics if the value can't be serialized, as well as when there are
+    // no targets
+    pub fn lookup_serde<T: Serialize + ?Sized>(&self, key: &T) -> Target {
+        return self.lookup(canonical_key(key).unwrap_or_else(|e| panic!("{}", e)));
+    }
+
+    pub fn lookup_list_serde<T: Serialize + ?Sized>(
+        &self,
+        key: &T,
+        requested_count: u32,
+    ) -> Vec<Target> {
+        let key = canonical_key(key).unwrap_or_else(|e| panic!("{}", e));
+        return self.lookup_list(key, requested_count);
+    }
+}
+
+#[cfg(test)]
+mod test_canonical {
+    use super::*;
+    use serde::Serialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Serialize)]
+    struct Key {
+        tenant: u32,
+        object: String,
+    }
+
+    #[derive(Serialize)]
+    struct Reordered {
+        object: &'static str,
+        tenant: u64,
+    }
+
+    #[derive(Serialize)]
+    struct Wrapped(Key);
+
+    #[derive(Serialize)]
+    enum Kind {
+        Plain,
+        Tagged(u8),
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(canonical_key(&true).unwrap(), b"b\x01");
+        let mut one = vec![b'u'];
+        one.extend_from_slice(&1u128.to_be_bytes());
+        assert_eq!(canonical_key(&1u8).unwrap(), one);
+        assert_eq!(canonical_key(&1u64).unwrap(), one);
+        assert_eq!(canonical_key(&1i8).unwrap(), one);
+        assert_eq!(
+            canonical_key(&-1i8).unwrap(),
+            canonical_key(&-1i64).unwrap()
+        );
+        assert_ne!(
+            canonical_key(&-1i8).unwrap(),
+            canonical_key(&u128::MAX).unwrap()
+        );
+        assert_eq!(
+            canonical_key("ab").unwrap(),
+            b"s\x00\x00\x00\x00\x00\x00\x00\x02ab"
+        );
+        assert_eq!(canonical_key(&'a').unwrap(), canonical_key("a").unwrap());
+        assert_eq!(canonical_key(&None::<u8>).unwrap(), b"n");
+        assert_eq!(canonical_key(&Some(())).unwrap(), b"on");
+        assert_eq!(canonical_key(&Vec::<u8>::new()).unwrap(), b"[]");
+        assert_eq!(
+            canonical_key(&Kind::Plain).unwrap(),
+            b"es\x00\x00\x00\x00\x00\x00\x00\x05Plain"
+        );
+        assert_ne!(
+            canonical_key(&Kind::Tagged(1)).unwrap(),
+            canonical_key(&Kind::Plain).unwrap()
+        );
+    }
+
+    #[test]
+    fn stable_across_layouts() {
+        let key = Key {
+            tenant: 7,
+            object: "photo-1".to_string(),
+        };
+        let reordered = Reordered {
+            object: "photo-1",
+            tenant: 7,
+        };
+        let mut map = HashMap::new();
+        map.insert("tenant", 7u16);
+        let mut tree = BTreeMap::new();
+        tree.insert("tenant", 7u16);
+
+        let encoded = canonical_key(&key).unwrap();
+        assert_eq!(canonical_key(&reordered).unwrap(), encoded);
+        assert_eq!(canonical_key(&Wrapped(key)).unwrap(), encoded);
+        assert_eq!(canonical_key(&map).unwrap(), canonical_key(&tree).unwrap());
+
+        // tuples aren't structs
+        assert_ne!(canonical_key(&(7, "photo-1")).unwrap(), encoded);
+    }
+
+    #[test]
+    fn lookups() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let key = Key {
+            tenant: 7,
+            object: "photo-1".to_string(),
+        };
+        let encoded = canonical_key(&key).unwrap();
+        assert_eq!(fh.lookup_serde(&key), fh.lookup(&encoded));
+        assert_eq!(fh.lookup_list_serde(&key, 2), fh.lookup_list(&encoded, 2));
+        assert_eq!(
+            fh.lookup_serde(&(7, "photo-1")),
+            fh.lookup_serde(&(7u64, "photo-1"))
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::snapshot::Snapshot;
+use crate::{Flexihash, Target};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/*
+ * Every change to a ring's targets, with when it happened and the
+ * generation it made, so that the ring as it was at any moment can be
+ * put back together afterwards with Flexihash::replay.
+ *
+ * The log starts from a snapshot of the ring taken when it was turned on,
+ * and is shared between clones (so it keeps going through a SharedRing's
+ * updates). Like a snapshot, it assumes the hasher, replicas and salt
+ * stay as they were; and zones and labels added later aren't recorded,
+ * though tiers are.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    Add(Target, u32),
+    Remove(Target),
+    // part-way through a drain, positions is less than the weight gives
+    Reweight {
+        target: Target,
+        weight: u32,
+        positions: u64,
+    },
+    Tier(Target, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub at: SystemTime,
+    pub generation: u64,
+    pub mutation: Mutation,
+}
+
+#[derive(Debug)]
+pub struct Changelog {
+    base: Snapshot,
+    entries: Mutex<Vec<LogEntry>>,
+}
+
+impl Changelog {
+    pub fn base(&self) -> &Snapshot {
+        return &self.base;
+    }
+
+    pub fn entries(&self) -> Vec<LogEntry> {
+        return self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+    }
+
+    pub(crate) fn record(&self, generation: u64, mutation: Mutation) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(LogEntry {
+                at: SystemTime::now(),
+                generation,
+                mutation,
+            });
+    }
+}
+
+impl Flexihash {
+    // Start logging changes from here; returns the log, which can also be
+    // had later from changelog()
+    pub fn enable_changelog(&mut self) -> Arc<Changelog> {
+        let log = Arc::new(Changelog {
+            base: self.snapshot(),
+            entries: Mutex::new(Vec::new()),
+        });
+        self.changelog = Some(log.clone());
+        return log;
+    }
+
+    pub fn changelog(&self) -> Option<Arc<Changelog>> {
+        return self.changelog.clone();
+    }
+
+    // The ring as it was at the given time; changes made together (eg by
+    // a transaction) are replayed together
+    pub fn replay(log: &Changelog, at: SystemTime) -> Flexihash {
+        let generation = log
+            .entries()
+            .iter()
+            .rev()
+            .find(|e| e.at <= at)
+            .map_or(log.base.generation, |e| e.generation);
+        return Flexihash::replay_to_generation(log, generation);
+    }
+
+    pub fn replay_to_generation(log: &Changelog, generation: u64) -> Flexihash {
+        let mut fh = Flexihash::from_snapshot(&log.base);
+        if generation <= log.base.generation {
+            return fh;
+        }
+        for entry in log
+            .entries()
+            .into_iter()
+            .filter(|e| e.generation <= generation)
+        {
+            match entry.mutation {
+                Mutation::Add(target, weight) => fh.place_target(target, weight),
+                Mutation::Remove(target) => fh.forget_target(&target),
+                Mutation::Reweight {
+                    target,
+                    weight,
+                    positions,
+                } => fh.reweight_target(target, weight, positions),
+                Mutation::Tier(target, tier) => fh.retier_target(target, tier),
+            }
+        }
+        fh.rebuild();
+        fh.generation = generation;
+        return fh;
+    }
+}
+
+#[cfg(test)]
+mod test_changelog {
+    use super::*;
+    use crate::shared::SharedRing;
+    use std::time::Duration;
+
+    #[test]
+    fn records_mutations() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        let log = fh.enable_changelog();
+        fh.add_target("t-b", 2);
+        fh.transaction(|tx| {
+            tx.remove("t-a").set_weight("t-b", 1);
+        })
+        .unwrap();
+
+        let entries = log.entries();
+        let mutations: Vec<(u64, Mutation)> = entries
+            .iter()
+            .map(|e| (e.generation, e.mutation.clone()))
+            .collect();
+        assert_eq!(
+            mutations,
+            [
+                (2, Mutation::Add("t-b".to_string(), 2)),
+                (3, Mutation::Remove("t-a".to_string())),
+                (
+                    3,
+                    Mutation::Reweight {
+                        target: "t-b".to_string(),
+                        weight: 1,
+                        positions: 64
+                    }
+                ),
+            ]
+        );
+        assert_eq!(log.base().targets.len(), 1);
+        assert!(fh.clone().changelog().is_some());
+    }
+
+    #[test]
+    fn replay() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(8);
+        fh.add_target("t-a", 1);
+        let log = fh.enable_changelog();
+        let mut rings = vec![fh.clone()];
+        fh.add_target("t-b", 1);
+        rings.push(fh.clone());
+        fh.remove_target("t-a");
+        rings.push(fh.clone());
+        fh.reweight_target("t-b".to_string(), 1, 3);
+        fh.rebuild();
+        rings.push(fh.clone());
+
+        for ring in rings.iter() {
+            let replayed = Flexihash::replay_to_generation(&log, ring.generation());
+            assert_eq!(
+                replayed.sorted_position_to_target,
+                ring.sorted_position_to_target
+            );
+            assert_eq!(replayed.generation(), ring.generation());
+        }
+
+        let entries = log.entries();
+        let before = entries[0].at - Duration::from_secs(1);
+        assert_eq!(Flexihash::replay(&log, before).generation(), 1);
+        let now = SystemTime::now();
+        assert_eq!(
+            Flexihash::replay(&log, now).sorted_position_to_target,
+            fh.sorted_position_to_target
+        );
+    }
+
+    #[test]
+    fn through_a_shared_ring() {
+        let mut fh = Flexihash::new();
+        let log = fh.enable_changelog();
+        let shared = Arc::new(SharedRing::new(fh));
+        shared.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        shared.drain("t-a", Duration::from_millis(4), |_| {}).wait();
+        let entries = log.entries();
+        assert_eq!(entries.len(), 17);
+        let replayed = Flexihash::replay_to_generation(&log, entries[8].generation);
+        assert_eq!(replayed.sorted_position_to_target.len(), 64 - 8 * 4);
+    }
+}
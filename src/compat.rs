@@ -0,0 +1,13 @@
+/*
+ * Adapters mirroring the APIs of other consistent hashing libraries, so that
+ * call sites can switch over without being rewritten, and rings which place
+ * keys exactly the way various proxies and load balancers do
+ */
+pub mod haproxy;
+pub mod hashring;
+// ketama points are md5 digests
+#[cfg(feature = "md5")]
+pub mod twemproxy;
+
+#[cfg(feature = "envoy")]
+pub mod envoy;
@@ -0,0 +1,225 @@
+use crate::{ResourceKey, Target};
+use xxhash_rust::xxh64::xxh64;
+
+/*
+ * Reproduces the placement of Envoy's RING_HASH load balancer, so that a
+ * client can pick the same host that an Envoy in front of the same cluster
+ * would have picked.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvoyHashFunction {
+    XxHash,
+    MurmurHash2,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvoyConfig {
+    pub hash_function: EnvoyHashFunction,
+    pub minimum_ring_size: u64,
+    pub maximum_ring_size: u64,
+}
+
+impl Default for EnvoyConfig {
+    fn default() -> EnvoyConfig {
+        return EnvoyConfig {
+            hash_function: EnvoyHashFunction::XxHash,
+            minimum_ring_size: 1024,
+            maximum_ring_size: 8 * 1024 * 1024,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvoyRing {
+    hosts: Vec<Target>,
+    ring: Vec<(u64, usize)>,
+}
+
+// Envoy's MurmurHash::murmurHash2, which is libstdc++'s std::_Hash_bytes
+pub fn murmur_hash2(key: &[u8], seed: u64) -> u64 {
+    const MUL: u64 = (0xc6a4a793 << 32) + 0x5bd1e995;
+    let shift_mix = |v: u64| v ^ (v >> 47);
+
+    let mut hash = seed ^ (key.len() as u64).wrapping_mul(MUL);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(chunk);
+        let data = shift_mix(u64::from_le_bytes(bytes).wrapping_mul(MUL)).wrapping_mul(MUL);
+        hash ^= data;
+        hash = hash.wrapping_mul(MUL);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        let mut data = 0u64;
+        for b in rest.iter().rev() {
+            data = (data << 8) + *b as u64;
+        }
+        hash ^= data;
+        hash = hash.wrapping_mul(MUL);
+    }
+    hash = shift_mix(hash).wrapping_mul(MUL);
+    return shift_mix(hash);
+}
+
+pub const STD_HASH_SEED: u64 = 0xc70f6907;
+
+impl EnvoyRing {
+    // Hosts are (address, weight) in the order Envoy sees them, where the
+    // address is what Envoy hashes: "ip:port", or the hostname when
+    // use_hostname_for_hashing is on
+    pub fn new<S: AsRef<str>>(hosts: &[(S, u32)], config: &EnvoyConfig) -> EnvoyRing {
+        let total: f64 = hosts.iter().map(|(_, w)| *w as f64).sum();
+        let weights: Vec<f64> = hosts.iter().map(|(_, w)| *w as f64 / total).collect();
+        let min_weight = weights.iter().cloned().fold(1.0, f64::min);
+
+        let scale = f64::min(
+            (min_weight * config.minimum_ring_size as f64).ceil() / min_weight,
+            config.maximum_ring_size as f64,
+        );
+
+        let mut ring = Vec::with_capacity(scale.ceil() as usize);
+        let mut current_hashes = 0.0;
+        let mut target_hashes = 0.0;
+        for (index, ((host, _), weight)) in hosts.iter().zip(weights.iter()).enumerate() {
+            target_hashes += scale * weight;
+            let mut i: u64 = 0;
+            while current_hashes < target_hashes {
+                let key = format!("{}_{}", host.as_ref(), i);
+                let hash = match config.hash_function {
+                    EnvoyHashFunction::XxHash => xxh64(key.as_bytes(), 0),
+                    EnvoyHashFunction::MurmurHash2 => murmur_hash2(key.as_bytes(), STD_HASH_SEED),
+                };
+                ring.push((hash, index));
+                i += 1;
+                current_hashes += 1.0;
+            }
+        }
+        ring.sort_by_key(|entry| entry.0);
+
+        return EnvoyRing {
+            hosts: hosts.iter().map(|(h, _)| h.as_ref().to_string()).collect(),
+            ring,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.ring.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ring.is_empty();
+    }
+
+    // Envoy's hash policies (header, cookie, source IP...) hash their input
+    // with xxHash, whichever function built the ring
+    pub fn lookup<K: ResourceKey>(&self, key: K) -> Option<&Target> {
+        return self.lookup_hash(xxh64(key.ring_key().as_ref(), 0));
+    }
+
+    // Envoy's own search, ported from libketama, including its quirks
+    pub fn lookup_hash(&self, hash: u64) -> Option<&Target> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let first = Some(&self.hosts[self.ring[0].1]);
+        let mut lowp: i64 = 0;
+        let mut highp: i64 = self.ring.len() as i64;
+        loop {
+            let midp = (lowp + highp) / 2;
+            if midp as usize == self.ring.len() {
+                return first;
+            }
+            let midval = self.ring[midp as usize].0;
+            let midval1 = if midp == 0 {
+                0
+            } else {
+                self.ring[midp as usize - 1].0
+            };
+            if hash <= midval && hash > midval1 {
+                return Some(&self.hosts[self.ring[midp as usize].1]);
+            }
+            if midval < hash {
+                lowp = midp + 1;
+            } else {
+                highp = midp - 1;
+            }
+            if lowp > highp {
+                return first;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_envoy {
+    use super::*;
+
+    #[test]
+    fn murmur_hash2_matches_libstdcxx() {
+        assert_eq!(murmur_hash2(b"", STD_HASH_SEED), 6142509188972423790);
+        assert_eq!(murmur_hash2(b"a", STD_HASH_SEED), 4993892634952068459);
+        assert_eq!(murmur_hash2(b"hello", STD_HASH_SEED), 2762169579135187400);
+        assert_eq!(
+            murmur_hash2(b"10.0.0.1:80_0", STD_HASH_SEED),
+            13659246566588789388
+        );
+        assert_eq!(
+            murmur_hash2(b"abcdefghijklmnop", STD_HASH_SEED),
+            9002761040096737846
+        );
+    }
+
+    #[test]
+    fn ring_sizes() {
+        let config = EnvoyConfig::default();
+        let ring = EnvoyRing::new(&[("10.0.0.1:80", 1), ("10.0.0.2:80", 1)], &config);
+        assert_eq!(ring.len(), 1024);
+
+        let ring = EnvoyRing::new(&[("10.0.0.1:80", 1), ("10.0.0.2:80", 3)], &config);
+        assert_eq!(ring.len(), 1024);
+        assert_eq!(ring.ring.iter().filter(|e| e.1 == 0).count(), 256);
+
+        // scaled up so that the lightest host still gets a whole number
+        let ring = EnvoyRing::new(&[("10.0.0.1:80", 1), ("10.0.0.2:80", 1000)], &config);
+        assert_eq!(ring.ring.iter().filter(|e| e.1 == 0).count(), 2);
+
+        let config = EnvoyConfig {
+            maximum_ring_size: 100,
+            ..EnvoyConfig::default()
+        };
+        let ring = EnvoyRing::new(&[("10.0.0.1:80", 1), ("10.0.0.2:80", 3)], &config);
+        assert_eq!(ring.len(), 100);
+    }
+
+    #[test]
+    fn lookup_is_first_entry_at_or_after_hash() {
+        let config = EnvoyConfig {
+            hash_function: EnvoyHashFunction::MurmurHash2,
+            minimum_ring_size: 16,
+            ..EnvoyConfig::default()
+        };
+        let ring = EnvoyRing::new(&[("a", 1), ("b", 1), ("c", 1)], &config);
+        for (i, (hash, index)) in ring.ring.iter().enumerate() {
+            assert_eq!(ring.lookup_hash(*hash), Some(&ring.hosts[*index]));
+            if i > 0 && ring.ring[i - 1].0 < hash - 1 {
+                assert_eq!(ring.lookup_hash(hash - 1), Some(&ring.hosts[*index]));
+            }
+        }
+        let last = ring.ring.last().unwrap().0;
+        assert_eq!(
+            ring.lookup_hash(last + 1),
+            Some(&ring.hosts[ring.ring[0].1])
+        );
+        assert_eq!(ring.lookup_hash(0), Some(&ring.hosts[ring.ring[0].1]));
+    }
+
+    #[test]
+    fn lookup_keys() {
+        let ring = EnvoyRing::new(&[("a", 1), ("b", 1)], &EnvoyConfig::default());
+        assert_eq!(ring.lookup("user-1"), ring.lookup_hash(xxh64(b"user-1", 0)));
+        assert!(EnvoyRing::new::<&str>(&[], &EnvoyConfig::default())
+            .lookup("user-1")
+            .is_none());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{ResourceKey, Target};
+#[cfg(feature = "crc")]
+use crc::crc32;
+
+/*
+ * Reproduces HAProxy's "hash-type consistent" server selection, so that
+ * application code can agree with an HAProxy backend about where each key
+ * lives (eg while migrating traffic from one to the other).
+ *
+ * Servers are identified by their numeric id ("puid"), which HAProxy
+ * assigns from 1 in declaration order unless set with "id". The address
+ * based "hash-key" options of newer versions aren't covered.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaproxyHashFunction {
+    Sdbm,
+    Djb2,
+    #[cfg(feature = "crc")]
+    Crc32,
+}
+
+#[derive(Debug, Clone)]
+pub struct HaproxyConfig {
+    pub hash_function: HaproxyHashFunction,
+    pub avalanche: bool,
+}
+
+impl Default for HaproxyConfig {
+    fn default() -> HaproxyConfig {
+        return HaproxyConfig {
+            hash_function: HaproxyHashFunction::Sdbm,
+            avalanche: false,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HaproxyRing {
+    config: HaproxyConfig,
+    servers: Vec<Target>,
+    nodes: Vec<(u32, usize)>,
+}
+
+// BE_WEIGHT_SCALE and SRV_EWGHT_RANGE
+const WEIGHT_SCALE: u32 = 16;
+const EWGHT_RANGE: u32 = 256 * WEIGHT_SCALE;
+
+// Bob Jenkins' full avalanche hash, as used by HAProxy
+pub fn full_hash(a: u32) -> u32 {
+    let mut a = a;
+    a = a.wrapping_add(0x7ed55d16).wrapping_add(a << 12);
+    a = (a ^ 0xc761c23c) ^ (a >> 19);
+    a = a.wrapping_add(0x165667b1).wrapping_add(a << 5);
+    a = a.wrapping_add(0xd3a2646c) ^ (a << 9);
+    a = a.wrapping_add(0xfd7046c5).wrapping_add(a << 3);
+    a = (a ^ 0xb55a4f09) ^ (a >> 16);
+    return a.wrapping_mul(3221225473);
+}
+
+pub fn hash_sdbm(key: &[u8]) -> u32 {
+    let mut hash: u32 = 0;
+    for b in key {
+        hash = (*b as u32)
+            .wrapping_add(hash << 6)
+            .wrapping_add(hash << 16)
+            .wrapping_sub(hash);
+    }
+    return hash;
+}
+
+pub fn hash_djb2(key: &[u8]) -> u32 {
+    let mut hash: u32 = 5381;
+    for b in key {
+        hash = (hash << 5).wrapping_add(hash).wrapping_add(*b as u32);
+    }
+    return hash;
+}
+
+impl HaproxyRing {
+    // (name, weight), with ids assigned from 1 in order
+    pub fn new<S: AsRef<str>>(servers: &[(S, u32)], config: &HaproxyConfig) -> HaproxyRing {
+        let servers: Vec<(&str, u32, u32)> = servers
+            .iter()
+            .enumerate()
+            .map(|(i, (name, weight))| (name.as_ref(), i as u32 + 1, *weight))
+            .collect();
+        return HaproxyRing::with_ids(&servers, config);
+    }
+
+    // (name, id, weight)
+    pub fn with_ids<S: AsRef<str>>(
+        servers: &[(S, u32, u32)],
+        config: &HaproxyConfig,
+    ) -> HaproxyRing {
+        let mut nodes = Vec::new();
+        for (index, (_, id, weight)) in servers.iter().enumerate() {
+            let key = id.wrapping_mul(EWGHT_RANGE);
+            for node in 0..weight * WEIGHT_SCALE {
+                nodes.push((full_hash(key.wrapping_add(node)), index));
+            }
+        }
+        // a stable sort keeps equal keys in insertion order, like ebtrees
+        nodes.sort_by_key(|node| node.0);
+        return HaproxyRing {
+            config: config.clone(),
+            servers: servers.iter().map(|s| s.0.as_ref().to_string()).collect(),
+            nodes,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.nodes.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.nodes.is_empty();
+    }
+
+    pub fn hash_key<K: ResourceKey>(&self, key: K) -> u32 {
+        let key = key.ring_key();
+        let hash = match self.config.hash_function {
+            HaproxyHashFunction::Sdbm => hash_sdbm(key.as_ref()),
+            HaproxyHashFunction::Djb2 => hash_djb2(key.as_ref()),
+            #[cfg(feature = "crc")]
+            HaproxyHashFunction::Crc32 => crc32::checksum_ieee(key.as_ref()),
+        };
+        if self.config.avalanche {
+            return full_hash(hash);
+        }
+        return hash;
+    }
+
+    pub fn lookup<K: ResourceKey>(&self, key: K) -> Option<&Target> {
+        return self.lookup_hash(self.hash_key(key));
+    }
+
+    // Unlike a plain ring, HAProxy picks whichever node is closest to the
+    // hash, looking both forwards and backwards
+    pub fn lookup_hash(&self, hash: u32) -> Option<&Target> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut next = self.nodes.partition_point(|n| n.0 < hash);
+        if next == self.nodes.len() {
+            next = 0;
+        }
+        let prev = if next == 0 {
+            self.nodes.len() - 1
+        } else {
+            next - 1
+        };
+        let dp = hash.wrapping_sub(self.nodes[prev].0);
+        let dn = self.nodes[next].0.wrapping_sub(hash);
+        let chosen = if dp <= dn { prev } else { next };
+        return Some(&self.servers[self.nodes[chosen].1]);
+    }
+}
+
+#[cfg(test)]
+mod test_haproxy {
+    use super::*;
+
+    #[test]
+    fn key_hashes() {
+        assert_eq!(hash_sdbm(b""), 0);
+        assert_eq!(hash_sdbm(b"a"), 97);
+        assert_eq!(hash_sdbm(b"ab"), 97 * 65599 + 98);
+        assert_eq!(hash_djb2(b""), 5381);
+        assert_eq!(hash_djb2(b"a"), 5381 * 33 + 97);
+        // reference values from HAProxy's own full_hash()
+        assert_eq!(full_hash(0), 2874071335);
+        assert_eq!(full_hash(1), 881230262);
+        assert_eq!(full_hash(4096), 1237450891);
+    }
+
+    #[test]
+    fn nodes_per_server() {
+        let ring = HaproxyRing::new(
+            &[("s1", 1), ("s2", 2), ("s3", 0)],
+            &HaproxyConfig::default(),
+        );
+        assert_eq!(ring.len(), 48);
+        assert_eq!(ring.nodes.iter().filter(|n| n.1 == 1).count(), 32);
+        assert_eq!(
+            ring.nodes[0].0,
+            ring.nodes.iter().map(|n| n.0).min().unwrap()
+        );
+    }
+
+    #[test]
+    fn ids_not_names_decide_placement() {
+        let config = HaproxyConfig::default();
+        let a = HaproxyRing::with_ids(&[("s1", 7, 1), ("s2", 9, 1)], &config);
+        let b = HaproxyRing::with_ids(&[("x", 9, 1), ("y", 7, 1)], &config);
+        for i in 0..100 {
+            let expected = match a.lookup(i).unwrap().as_str() {
+                "s1" => "y",
+                _ => "x",
+            };
+            assert_eq!(b.lookup(i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn lookup_picks_closest_node() {
+        let ring = HaproxyRing::new(&[("s1", 1), ("s2", 1)], &HaproxyConfig::default());
+        for pair in ring.nodes.windows(2) {
+            let (lo, hi) = (pair[0], pair[1]);
+            if hi.0 - lo.0 > 2 {
+                assert_eq!(ring.lookup_hash(lo.0 + 1), Some(&ring.servers[lo.1]));
+                assert_eq!(ring.lookup_hash(hi.0 - 1), Some(&ring.servers[hi.1]));
+            }
+        }
+        // wrapping around in both directions
+        let first = ring.nodes[0];
+        let last = *ring.nodes.last().unwrap();
+        assert_eq!(
+            ring.lookup_hash(last.0.wrapping_add(1)),
+            Some(&ring.servers[last.1])
+        );
+        assert_eq!(
+            ring.lookup_hash(first.0.wrapping_sub(1)),
+            Some(&ring.servers[first.1])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "crc")]
+    fn lookup_keys() {
+        let config = HaproxyConfig {
+            hash_function: HaproxyHashFunction::Crc32,
+            avalanche: true,
+        };
+        let ring = HaproxyRing::new(&[("s1", 1), ("s2", 1)], &config);
+        assert_eq!(
+            ring.lookup("/index.html"),
+            ring.lookup_hash(full_hash(crc32::checksum_ieee(b"/index.html")))
+        );
+        assert!(HaproxyRing::new::<&str>(&[], &config).lookup("x").is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{DuplicatePolicy, Flexihash, ResourceKey, Target};
+use std::collections::HashMap;
+
+/*
+ * Mirrors the `hashring` crate's HashRing: nodes are identified by their
+ * string form, and keys are ResourceKeys, rather than by std::hash::Hash.
+ */
+#[derive(Debug, Clone)]
+pub struct HashRing<T> {
+    ring: Flexihash,
+    nodes: HashMap<Target, T>,
+}
+
+impl<T: ToString> HashRing<T> {
+    pub fn new() -> HashRing<T> {
+        let mut ring = Flexihash::new();
+        ring.set_duplicate_policy(DuplicatePolicy::Replace);
+        return HashRing {
+            ring,
+            nodes: HashMap::new(),
+        };
+    }
+
+    pub fn with_replicas(replicas: u32) -> HashRing<T> {
+        let mut hr = HashRing::new();
+        hr.ring.set_replicas(replicas);
+        return hr;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.nodes.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.nodes.is_empty();
+    }
+
+    pub fn add(&mut self, node: T) {
+        self.add_weighted(node, 1);
+    }
+
+    pub fn add_weighted(&mut self, node: T, weight: u32) {
+        let name = node.to_string();
+        self.ring.add_target(name.clone(), weight);
+        self.nodes.insert(name, node);
+    }
+
+    pub fn batch_add(&mut self, nodes: Vec<T>) {
+        for node in nodes {
+            self.add(node);
+        }
+    }
+
+    pub fn remove(&mut self, node: &T) -> Option<T> {
+        let name = node.to_string();
+        let removed = self.nodes.remove(&name);
+        if removed.is_some() {
+            self.ring.remove_target(name);
+        }
+        return removed;
+    }
+
+    pub fn batch_remove(&mut self, nodes: &[T]) {
+        for node in nodes {
+            self.remove(node);
+        }
+    }
+
+    pub fn get<U: ResourceKey + ?Sized>(&self, key: &U) -> Option<&T> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        return self.nodes.get(&self.ring.lookup(key));
+    }
+
+    pub fn clear(&mut self) {
+        for name in self.ring.get_all_targets() {
+            self.ring.remove_target(name);
+        }
+        self.nodes.clear();
+    }
+}
+
+impl<T: ToString> Default for HashRing<T> {
+    fn default() -> HashRing<T> {
+        return HashRing::new();
+    }
+}
+
+#[cfg(test)]
+mod test_hashring {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn add_get_remove() {
+        let mut ring = HashRing::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.get("key"), None);
+
+        ring.add("cache-1");
+        ring.batch_add(vec!["cache-2", "cache-3"]);
+        assert_eq!(ring.len(), 3);
+
+        let owner = *ring.get("key").unwrap();
+        assert_eq!(ring.remove(&owner), Some(owner));
+        assert_eq!(ring.remove(&owner), None);
+        assert_ne!(ring.get("key"), Some(&owner));
+        assert_eq!(ring.len(), 2);
+    }
+
+    #[test]
+    fn matches_flexihash() {
+        let mut ring = HashRing::new();
+        let mut fh = Flexihash::new();
+        for i in 0..5 {
+            ring.add(format!("cache-{}", i));
+            fh.add_target(format!("cache-{}", i), 1);
+        }
+        for i in 0..100 {
+            assert_eq!(*ring.get(&i).unwrap(), fh.lookup(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn arbitrary_nodes() {
+        let mut ring = HashRing::with_replicas(16);
+        let node = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 11211);
+        ring.add_weighted(node, 2);
+        assert_eq!(ring.get("key"), Some(&node));
+
+        ring.clear();
+        assert!(ring.is_empty());
+        assert_eq!(ring.get("key"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{ResourceKey, Target};
+#[cfg(feature = "crc")]
+use crc::crc32;
+
+/*
+ * Reproduces twemproxy's (nutcracker's) "distribution: ketama" server
+ * selection along with its "hash:" options, so that clients can talk to
+ * the backing servers directly and still agree with the proxy.
+ *
+ * Server names are whatever twemproxy hashes - the explicit name if the
+ * pool config gives one, otherwise "host:port".
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwemproxyHashFunction {
+    OneAtATime,
+    Md5,
+    #[cfg(feature = "crc")]
+    Crc32,
+    #[cfg(feature = "crc")]
+    Crc32a,
+    Fnv1_64,
+    Fnv1a64,
+    Fnv1_32,
+    Fnv1a32,
+    Murmur,
+}
+
+impl Default for TwemproxyHashFunction {
+    fn default() -> TwemproxyHashFunction {
+        return TwemproxyHashFunction::Fnv1a64;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TwemproxyRing {
+    hash_function: TwemproxyHashFunction,
+    servers: Vec<Target>,
+    continuum: Vec<(u32, usize)>,
+}
+
+const POINTS_PER_SERVER: f32 = 160.0;
+const POINTS_PER_HASH: u32 = 4;
+
+const FNV_64_INIT: u64 = 0xcbf29ce484222325;
+const FNV_64_PRIME: u64 = 0x100000001b3;
+const FNV_32_INIT: u32 = 2166136261;
+const FNV_32_PRIME: u32 = 16777619;
+
+// Each md5 of a point name gives four little-endian points
+fn ketama_hash(key: &[u8], alignment: usize) -> u32 {
+    let digest = md5::compute(key);
+    let b = &digest[alignment * 4..alignment * 4 + 4];
+    return u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+}
+
+fn hash_one_at_a_time(key: &[u8]) -> u32 {
+    let mut value: u32 = 0;
+    for b in key {
+        value = value.wrapping_add(*b as u32);
+        value = value.wrapping_add(value << 10);
+        value ^= value >> 6;
+    }
+    value = value.wrapping_add(value << 3);
+    value ^= value >> 11;
+    return value.wrapping_add(value << 15);
+}
+
+fn hash_murmur(key: &[u8]) -> u32 {
+    let m: u32 = 0x5bd1e995;
+    let length = key.len() as u32;
+    let mut h = 0xdeadbeef_u32.wrapping_mul(length) ^ length;
+    let mut chunks = key.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(m);
+        k ^= k >> 24;
+        k = k.wrapping_mul(m);
+        h = h.wrapping_mul(m) ^ k;
+    }
+    let tail = chunks.remainder();
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(m);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(m);
+    return h ^ (h >> 15);
+}
+
+pub fn hash(function: TwemproxyHashFunction, key: &[u8]) -> u32 {
+    return match function {
+        TwemproxyHashFunction::OneAtATime => hash_one_at_a_time(key),
+        TwemproxyHashFunction::Md5 => ketama_hash(key, 0),
+        #[cfg(feature = "crc")]
+        TwemproxyHashFunction::Crc32 => (crc32::checksum_ieee(key) >> 16) & 0x7fff,
+        #[cfg(feature = "crc")]
+        TwemproxyHashFunction::Crc32a => crc32::checksum_ieee(key),
+        TwemproxyHashFunction::Fnv1_64 => {
+            let mut hash = FNV_64_INIT;
+            for b in key {
+                hash = hash.wrapping_mul(FNV_64_PRIME);
+                hash ^= *b as u64;
+            }
+            hash as u32
+        }
+        TwemproxyHashFunction::Fnv1a64 => {
+            let mut hash = FNV_64_INIT;
+            for b in key {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(FNV_64_PRIME);
+            }
+            hash as u32
+        }
+        TwemproxyHashFunction::Fnv1_32 => {
+            let mut hash = FNV_32_INIT;
+            for b in key {
+                hash = hash.wrapping_mul(FNV_32_PRIME);
+                hash ^= *b as u32;
+            }
+            hash
+        }
+        TwemproxyHashFunction::Fnv1a32 => {
+            let mut hash = FNV_32_INIT;
+            for b in key {
+                hash ^= *b as u32;
+                hash = hash.wrapping_mul(FNV_32_PRIME);
+            }
+            hash
+        }
+        TwemproxyHashFunction::Murmur => hash_murmur(key),
+    };
+}
+
+impl TwemproxyRing {
+    // (name, weight) for every live server in the pool
+    pub fn new<S: AsRef<str>>(
+        servers: &[(S, u32)],
+        hash_function: TwemproxyHashFunction,
+    ) -> TwemproxyRing {
+        let total_weight: u32 = servers.iter().map(|s| s.1).sum();
+        let live = servers.len() as f32;
+        let mut continuum = Vec::new();
+        for (index, (name, weight)) in servers.iter().enumerate() {
+            // twemproxy does this sum in single precision, and the result
+            // shifts by a few points if it's done in double
+            let pct = *weight as f32 / total_weight as f32;
+            let points = (pct * POINTS_PER_SERVER / 4.0 * live) as f64 + 0.0000000001;
+            let points = (points as f32).floor() as u32 * 4;
+            for i in 0..points / POINTS_PER_HASH {
+                let host = format!("{}-{}", name.as_ref(), i);
+                for x in 0..POINTS_PER_HASH as usize {
+                    continuum.push((ketama_hash(host.as_bytes(), x), index));
+                }
+            }
+        }
+        continuum.sort_by_key(|point| point.0);
+        return TwemproxyRing {
+            hash_function,
+            servers: servers.iter().map(|s| s.0.as_ref().to_string()).collect(),
+            continuum,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.continuum.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.continuum.is_empty();
+    }
+
+    pub fn hash_key<K: ResourceKey>(&self, key: K) -> u32 {
+        return hash(self.hash_function, key.ring_key().as_ref());
+    }
+
+    pub fn lookup<K: ResourceKey>(&self, key: K) -> Option<&Target> {
+        return self.lookup_hash(self.hash_key(key));
+    }
+
+    pub fn lookup_hash(&self, hash: u32) -> Option<&Target> {
+        if self.continuum.is_empty() {
+            return None;
+        }
+        let mut index = self.continuum.partition_point(|p| p.0 < hash);
+        if index == self.continuum.len() {
+            index = 0;
+        }
+        return Some(&self.servers[self.continuum[index].1]);
+    }
+}
+
+#[cfg(test)]
+mod test_twemproxy {
+    use super::*;
+
+    #[test]
+    fn key_hashes() {
+        use TwemproxyHashFunction::*;
+        assert_eq!(hash(Fnv1a64, b"a"), 0x8601ec8c);
+        assert_eq!(hash(Fnv1_64, b"a"), 0x8601b7be);
+        assert_eq!(hash(Fnv1a32, b"a"), 0xe40c292c);
+        assert_eq!(hash(Fnv1_32, b"a"), 0x050c5d7e);
+        // reference values from twemproxy's hashkit
+        assert_eq!(hash(OneAtATime, b"a"), 3392050242);
+        assert_eq!(hash(OneAtATime, b"hello"), 3372029979);
+        assert_eq!(hash(Murmur, b"a"), 1262581116);
+        assert_eq!(hash(Murmur, b"hello"), 1377504255);
+        assert_eq!(hash(Murmur, b"foo:bar:1234"), 1455771570);
+    }
+
+    #[test]
+    #[cfg(feature = "crc")]
+    fn crc_key_hashes() {
+        use TwemproxyHashFunction::*;
+        assert_eq!(hash(Crc32a, b"a"), 0xe8b7be43);
+        assert_eq!(hash(Crc32, b"a"), 0x68b7);
+    }
+
+    #[test]
+    fn points_per_server() {
+        let ring = TwemproxyRing::new(&[("a", 1), ("b", 1), ("c", 1)], Default::default());
+        assert_eq!(ring.len(), 480);
+        // 1:3:3 out of 7, computed as twemproxy's floats do
+        let ring = TwemproxyRing::new(&[("a", 1), ("b", 3), ("c", 3)], Default::default());
+        assert_eq!(ring.continuum.iter().filter(|p| p.1 == 0).count(), 68);
+        assert_eq!(ring.continuum.iter().filter(|p| p.1 == 1).count(), 204);
+    }
+
+    #[test]
+    fn points_come_from_md5_of_name() {
+        let ring = TwemproxyRing::new(&[("127.0.0.1:11211", 1)], Default::default());
+        let digest = md5::compute("127.0.0.1:11211-0");
+        let first = u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        assert!(ring.continuum.iter().any(|p| p.0 == first));
+    }
+
+    #[test]
+    fn lookup_wraps() {
+        let ring = TwemproxyRing::new(&[("a", 1), ("b", 1)], TwemproxyHashFunction::Md5);
+        let first = ring.continuum[0];
+        let last = *ring.continuum.last().unwrap();
+        assert_eq!(ring.lookup_hash(first.0), Some(&ring.servers[first.1]));
+        assert_eq!(ring.lookup_hash(last.0 + 1), Some(&ring.servers[first.1]));
+        assert_eq!(ring.lookup("foo"), ring.lookup_hash(ketama_hash(b"foo", 0)));
+        assert!(TwemproxyRing::new::<&str>(&[], Default::default())
+            .lookup("x")
+            .is_none());
+    }
+}
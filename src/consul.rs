@@ -0,0 +1,442 @@
+use crate::shared::SharedRing;
+use crate::validate::RingWarning;
+use crate::{Error, RingTarget, Target, TargetInfo};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+ * Keeps a SharedRing's membership in step with the healthy instances of a
+ * Consul service. Each instance becomes a target named "address:port",
+ * weighted by its passing weight, zoned by a "zone=..." tag (the prefix is
+ * configurable), and labelled with its service meta.
+ *
+ * Only plain HTTP is supported out of the box - enable one of ureq's TLS
+ * features alongside this one to talk to Consul over HTTPS.
+ */
+#[derive(Debug, Clone)]
+pub struct ConsulConfig {
+    pub address: String,
+    pub service: String,
+    pub zone_tag_prefix: String,
+    pub token: Option<String>,
+    pub wait: Duration,
+}
+
+impl ConsulConfig {
+    pub fn new<S: Into<String>>(service: S) -> ConsulConfig {
+        return ConsulConfig {
+            address: "http://127.0.0.1:8500".to_string(),
+            service: service.into(),
+            zone_tag_prefix: "zone=".to_string(),
+            token: None,
+            wait: Duration::from_secs(60),
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum ConsulError {
+    Http(ureq::Error),
+    Parse(String),
+    // the services don't fit in the ring, eg a weight over its limits
+    Ring(Error),
+}
+
+impl fmt::Display for ConsulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsulError::Http(e) => write!(f, "Consul request failed: {}", e),
+            ConsulError::Parse(e) => write!(f, "Unexpected response from Consul: {}", e),
+            ConsulError::Ring(e) => write!(f, "Services don't fit in the ring: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConsulError {}
+
+impl From<ureq::Error> for ConsulError {
+    fn from(e: ureq::Error) -> ConsulError {
+        return ConsulError::Http(e);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsulService {
+    pub address: String,
+    pub port: u16,
+    pub weight: u32,
+    pub zone: Option<String>,
+    pub meta: BTreeMap<String, String>,
+}
+
+impl RingTarget for ConsulService {
+    fn name(&self) -> Target {
+        return format!("{}:{}", self.address, self.port);
+    }
+    fn weight(&self) -> u32 {
+        return self.weight;
+    }
+    fn zone(&self) -> Option<String> {
+        return self.zone.clone();
+    }
+    // from the "tier" meta key, if it's a number
+    fn tier(&self) -> u32 {
+        return self
+            .meta
+            .get("tier")
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(0);
+    }
+    fn labels(&self) -> BTreeMap<String, String> {
+        return self.meta.clone();
+    }
+}
+
+#[derive(Debug)]
+pub enum ConsulEvent {
+    Synced {
+        added: Vec<Target>,
+        removed: Vec<Target>,
+        changed: Vec<Target>,
+        // anything risky about the ring as it now is
+        warnings: Vec<RingWarning>,
+    },
+    Failed(ConsulError),
+}
+
+// Parse the body of /v1/health/service/<name>
+pub fn parse_services(
+    body: &str,
+    config: &ConsulConfig,
+) -> Result<Vec<ConsulService>, ConsulError> {
+    let entries: serde_json::Value =
+        serde_json::from_str(body).map_err(|e| ConsulError::Parse(e.to_string()))?;
+    let entries = entries
+        .as_array()
+        .ok_or_else(|| ConsulError::Parse("expected a list of services".to_string()))?;
+    let mut services = Vec::new();
+    for entry in entries {
+        let service = &entry["Service"];
+        // an instance with no address of its own is reached via its node
+        let address = match service["Address"].as_str() {
+            Some(address) if !address.is_empty() => address,
+            _ => entry["Node"]["Address"]
+                .as_str()
+                .ok_or_else(|| ConsulError::Parse("service without an address".to_string()))?,
+        };
+        let port = service["Port"]
+            .as_u64()
+            .ok_or_else(|| ConsulError::Parse("service without a port".to_string()))?;
+        let weight = service["Weights"]["Passing"].as_u64().unwrap_or(1);
+        let zone = service["Tags"].as_array().and_then(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_str()?.strip_prefix(config.zone_tag_prefix.as_str()))
+                .map(|zone| zone.to_string())
+                .next()
+        });
+        let meta = service["Meta"]
+            .as_object()
+            .map(|meta| {
+                meta.iter()
+                    .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let port = u16::try_from(port)
+            .map_err(|_| ConsulError::Parse(format!("port {} out of range", port)))?;
+        let weight = u32::try_from(weight)
+            .map_err(|_| ConsulError::Parse(format!("weight {} out of range", weight)))?;
+        services.push(ConsulService {
+            address: address.to_string(),
+            port,
+            weight,
+            zone,
+            meta,
+        });
+    }
+    return Ok(services);
+}
+
+// Fetch the passing instances of the service. Given the index from a
+// previous call, this is a blocking query which only returns once something
+// changes (or config.wait runs out).
+pub fn fetch(
+    config: &ConsulConfig,
+    index: Option<u64>,
+) -> Result<(u64, Vec<ConsulService>), ConsulError> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(config.wait + Duration::from_secs(10)))
+        .build()
+        .into();
+    let url = format!(
+        "{}/v1/health/service/{}",
+        config.address.trim_end_matches('/'),
+        config.service
+    );
+    let mut request = agent.get(&url).query("passing", "true");
+    if let Some(index) = index {
+        request = request
+            .query("index", index.to_string())
+            .query("wait", format!("{}s", config.wait.as_secs()));
+    }
+    if let Some(token) = &config.token {
+        request = request.header("X-Consul-Token", token);
+    }
+    let mut response = request.call()?;
+    let new_index = response
+        .headers()
+        .get("X-Consul-Index")
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    let body = response.body_mut().read_to_string()?;
+    return Ok((new_index, parse_services(&body, config)?));
+}
+
+// Make the ring's membership match the given services, returning what
+// changed (or None if it already matched)
+pub fn apply(services: &[ConsulService], ring: &SharedRing) -> Option<ConsulEvent> {
+    let current = ring.snapshot();
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for service in services {
+        let name = service.name();
+        let info = TargetInfo {
+            weight: service.weight(),
+            zone: service.zone(),
+            tier: service.tier(),
+            labels: service.labels(),
+        };
+        match current.get_target_info(&name) {
+            None => added.push(name),
+            Some(existing) if *existing != info => changed.push(name),
+            Some(_) => {}
+        }
+    }
+    let wanted: Vec<Target> = services.iter().map(|s| s.name()).collect();
+    let removed: Vec<Target> = current
+        .get_all_targets()
+        .into_iter()
+        .filter(|t| !wanted.contains(t))
+        .collect();
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        return None;
+    }
+
+    let put: Vec<&dyn RingTarget> = services
+        .iter()
+        .filter(|s| {
+            let name = s.name();
+            added.contains(&name) || changed.contains(&name)
+        })
+        .map(|s| s as &dyn RingTarget)
+        .collect();
+    let result = ring.update(|fh| {
+        fh.try_sync_ring_targets(&removed, &put)?;
+        return Ok(fh.validate());
+    });
+    let warnings = match result {
+        Ok(warnings) => warnings,
+        Err(e) => return Some(ConsulEvent::Failed(ConsulError::Ring(e))),
+    };
+    added.sort();
+    changed.sort();
+    return Some(ConsulEvent::Synced {
+        added,
+        removed,
+        changed,
+        warnings,
+    });
+}
+
+// Watches the service from a background thread until dropped
+pub struct ConsulWatcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl ConsulWatcher {
+    pub fn new<F: Fn(ConsulEvent) + Send + 'static>(
+        config: ConsulConfig,
+        ring: Arc<SharedRing>,
+        on_event: F,
+    ) -> ConsulWatcher {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        std::thread::spawn(move || {
+            let mut index = None;
+            while !stopped.load(Ordering::Relaxed) {
+                match fetch(&config, index) {
+                    Ok((new_index, services)) => {
+                        // Consul's advice: if the index goes backwards, start over
+                        index = match index {
+                            Some(old) if new_index < old => None,
+                            _ => Some(new_index),
+                        };
+                        if stopped.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        if let Some(event) = apply(&services, &ring) {
+                            on_event(event);
+                        }
+                    }
+                    Err(e) => {
+                        on_event(ConsulEvent::Failed(e));
+                        index = None;
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            }
+        });
+        return ConsulWatcher { stop };
+    }
+}
+
+impl Drop for ConsulWatcher {
+    fn drop(&mut self) {
+        // the thread notices once its current query returns
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test_consul {
+    use super::*;
+    use crate::Flexihash;
+    use std::io::{Read, Write};
+
+    const BODY: &str = r#"[
+        {
+            "Node": {"Node": "node-1", "Address": "10.0.0.1"},
+            "Service": {
+                "ID": "web-1", "Service": "web", "Address": "",
+                "Port": 8080, "Tags": ["primary", "zone=eu-1a"],
+                "Meta": {"version": "2"}, "Weights": {"Passing": 3, "Warning": 1}
+            }
+        },
+        {
+            "Node": {"Node": "node-2", "Address": "10.0.0.2"},
+            "Service": {
+                "ID": "web-2", "Service": "web", "Address": "10.0.1.2",
+                "Port": 8080, "Tags": [], "Meta": {}, "Weights": {"Passing": 1, "Warning": 1}
+            }
+        }
+    ]"#;
+
+    #[test]
+    fn parse() {
+        let services = parse_services(BODY, &ConsulConfig::new("web")).unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].name(), "10.0.0.1:8080");
+        assert_eq!(services[0].weight, 3);
+        assert_eq!(services[0].zone.as_deref(), Some("eu-1a"));
+        assert_eq!(
+            services[0].meta.get("version").map(|v| v.as_str()),
+            Some("2")
+        );
+        assert_eq!(services[1].name(), "10.0.1.2:8080");
+        assert_eq!(services[1].zone, None);
+
+        assert!(matches!(
+            parse_services("{}", &ConsulConfig::new("web")),
+            Err(ConsulError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn apply_reports_changes() {
+        let shared = SharedRing::new(Flexihash::new());
+        shared.update(|fh| {
+            fh.add_targets(vec!["10.0.0.1:8080", "10.0.0.9:8080"]);
+        });
+        let services = parse_services(BODY, &ConsulConfig::new("web")).unwrap();
+        match apply(&services, &shared) {
+            Some(ConsulEvent::Synced {
+                added,
+                removed,
+                changed,
+                ..
+            }) => {
+                assert_eq!(added, ["10.0.1.2:8080"]);
+                assert_eq!(removed, ["10.0.0.9:8080"]);
+                assert_eq!(changed, ["10.0.0.1:8080"]);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        let ring = shared.snapshot();
+        let info = ring.get_target_info("10.0.0.1:8080").unwrap();
+        assert_eq!(info.weight, 3);
+        assert_eq!(info.zone.as_deref(), Some("eu-1a"));
+        assert!(apply(&services, &shared).is_none());
+    }
+
+    #[test]
+    fn out_of_range_numbers() {
+        let config = ConsulConfig::new("web");
+        for service in [
+            r#"{"Address": "10.0.0.1", "Port": 65536}"#,
+            r#"{"Address": "10.0.0.1", "Port": 80, "Weights": {"Passing": 4294967296}}"#,
+        ] {
+            let body = format!(r#"[{{"Service": {}}}]"#, service);
+            assert!(matches!(
+                parse_services(&body, &config),
+                Err(ConsulError::Parse(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn apply_fails_rather_than_panicking() {
+        let shared = SharedRing::new(Flexihash::new());
+        shared.update(|fh| {
+            fh.add_target("10.0.0.9:8080", 1);
+        });
+        let mut services = parse_services(BODY, &ConsulConfig::new("web")).unwrap();
+        services[1].weight = u32::MAX;
+        assert!(matches!(
+            apply(&services, &shared),
+            Some(ConsulEvent::Failed(ConsulError::Ring(
+                Error::TooManyPositions(..)
+            )))
+        ));
+        // left as it was
+        assert_eq!(shared.snapshot().get_all_targets(), ["10.0.0.9:8080"]);
+    }
+
+    #[test]
+    fn fetch_from_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nX-Consul-Index: 42\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                BODY.len(),
+                BODY
+            )
+            .unwrap();
+            return String::from_utf8(request).unwrap();
+        });
+
+        let mut config = ConsulConfig::new("web");
+        config.address = address;
+        config.token = Some("secret".to_string());
+        config.wait = Duration::from_secs(5);
+        let (index, services) = fetch(&config, Some(7)).unwrap();
+        assert_eq!(index, 42);
+        assert_eq!(services.len(), 2);
+
+        let request = server.join().unwrap().to_lowercase();
+        assert!(request.starts_with("get /v1/health/service/web?passing=true&index=7&wait=5s "));
+        assert!(request.contains("x-consul-token: secret"));
+    }
+}
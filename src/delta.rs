@@ -0,0 +1,576 @@
+use crate::snapshot::{check_name, checksum};
+use crate::{Error, Flexihash, Target, TargetInfo};
+use std::fmt;
+
+/*
+ * The changes between two generations of a ring, for controllers to hand
+ * out instead of whole snapshots. Changes apply in the order removals,
+ * additions, weight changes, the same as the gRPC Delta, and then the
+ * zones, tiers and labels of targets which gained or changed them.
+ *
+ * A delta only makes sense against the generation it was taken from;
+ * applied to anything else it would silently undo whatever changed in
+ * between, so check_base (and apply_delta) turn that into an error. The
+ * fingerprint, when there is one, also catches a ring which has the right
+ * generation number but came from somewhere else. A delta also has to move
+ * the ring forward, or applying it would wind the generation back.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RingDelta {
+    pub base_generation: u64,
+    pub generation: u64,
+    pub causal_token: Option<String>,
+    pub expected_fingerprint: Option<u64>,
+    pub remove: Vec<Target>,
+    pub add: Vec<(Target, u32)>,
+    pub set_weight: Vec<(Target, u32)>,
+    // The whole of each target's zone, tier and labels; the weights here
+    // are ignored, add and set_weight carry those
+    pub set_info: Vec<(Target, TargetInfo)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    Parse(usize, String),
+    Checksum,
+    InvalidName(String),
+    // the ring's generation, the delta's base generation
+    Stale(u64, u64),
+    // the ring's fingerprint, the one the delta expected
+    Fingerprint(u64, u64),
+    // the delta's base generation, its generation
+    Backwards(u64, u64),
+    Ring(Error),
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::Parse(line, msg) => write!(f, "Line {}: {}", line, msg),
+            DeltaError::Checksum => write!(f, "Delta checksum does not match"),
+            DeltaError::InvalidName(name) => write!(f, "Can't store name {:?}", name),
+            DeltaError::Stale(ring, base) => write!(
+                f,
+                "Delta is against generation {}, but the ring is at generation {}",
+                base, ring
+            ),
+            DeltaError::Fingerprint(ring, expected) => write!(
+                f,
+                "Delta expects a ring with fingerprint {:016x}, not {:016x}",
+                expected, ring
+            ),
+            DeltaError::Backwards(base, generation) => write!(
+                f,
+                "Delta goes from generation {} to {}, which isn't forward",
+                base, generation
+            ),
+            DeltaError::Ring(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+impl From<Error> for DeltaError {
+    fn from(e: Error) -> DeltaError {
+        return DeltaError::Ring(e);
+    }
+}
+
+const MAGIC: &str = "flexihash delta 1";
+
+fn check(name: &str) -> Result<(), DeltaError> {
+    return check_name(name).map_err(|_| DeltaError::InvalidName(name.to_string()));
+}
+
+impl RingDelta {
+    pub fn is_empty(&self) -> bool {
+        return self.remove.is_empty()
+            && self.add.is_empty()
+            && self.set_weight.is_empty()
+            && self.set_info.is_empty();
+    }
+
+    pub fn check_base(&self, fh: &Flexihash) -> Result<(), DeltaError> {
+        if fh.generation() != self.base_generation {
+            return Err(DeltaError::Stale(fh.generation(), self.base_generation));
+        }
+        if let Some(expected) = self.expected_fingerprint {
+            if fh.fingerprint() != expected {
+                return Err(DeltaError::Fingerprint(fh.fingerprint(), expected));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DeltaError> {
+        let mut out = String::new();
+        out.push_str(MAGIC);
+        out.push('\n');
+        out.push_str(&format!("base {}\n", self.base_generation));
+        out.push_str(&format!("generation {}\n", self.generation));
+        if let Some(token) = &self.causal_token {
+            check(token)?;
+            out.push_str(&format!("token {}\n", token));
+        }
+        if let Some(fingerprint) = self.expected_fingerprint {
+            out.push_str(&format!("fingerprint {:016x}\n", fingerprint));
+        }
+        for target in self.remove.iter() {
+            check(target)?;
+            out.push_str(&format!("remove {}\n", target));
+        }
+        for (target, weight) in self.add.iter() {
+            check(target)?;
+            out.push_str(&format!("add {} {}\n", weight, target));
+        }
+        for (target, weight) in self.set_weight.iter() {
+            check(target)?;
+            out.push_str(&format!("weight {} {}\n", weight, target));
+        }
+        for (target, info) in self.set_info.iter() {
+            check(target)?;
+            out.push_str(&format!("info {}\n", target));
+            if let Some(zone) = &info.zone {
+                check(zone)?;
+                out.push_str(&format!("zone {}\n", zone));
+            }
+            if info.tier != 0 {
+                out.push_str(&format!("tier {}\n", info.tier));
+            }
+            for (key, value) in info.labels.iter() {
+                check(key)?;
+                check(value)?;
+                if key.contains('=') {
+                    return Err(DeltaError::InvalidName(key.clone()));
+                }
+                out.push_str(&format!("label {}={}\n", key, value));
+            }
+        }
+        out.push_str(&format!("checksum {:016x}\n", checksum(out.as_bytes())));
+        return Ok(out.into_bytes());
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<RingDelta, DeltaError> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| DeltaError::Parse(0, "Not valid UTF-8".to_string()))?;
+        let body_len = text
+            .trim_end_matches('\n')
+            .rfind('\n')
+            .map(|i| i + 1)
+            .ok_or(DeltaError::Checksum)?;
+        let (body, trailer) = text.split_at(body_len);
+        match trailer.trim_end().strip_prefix("checksum ") {
+            Some(sum) if u64::from_str_radix(sum, 16).ok() == Some(checksum(body.as_bytes())) => {}
+            _ => return Err(DeltaError::Checksum),
+        }
+
+        let mut delta = RingDelta::default();
+        for (n, line) in body.lines().enumerate() {
+            let bad = |msg: &str| DeltaError::Parse(n + 1, msg.to_string());
+            if n == 0 {
+                if line != MAGIC {
+                    return Err(bad("Not a flexihash delta"));
+                }
+                continue;
+            }
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad("Missing value"))?;
+            match key {
+                "base" => {
+                    delta.base_generation = value.parse().map_err(|_| bad("Invalid generation"))?;
+                }
+                "generation" => {
+                    delta.generation = value.parse().map_err(|_| bad("Invalid generation"))?;
+                }
+                "token" => delta.causal_token = Some(value.to_string()),
+                "fingerprint" => {
+                    let fingerprint =
+                        u64::from_str_radix(value, 16).map_err(|_| bad("Invalid fingerprint"))?;
+                    delta.expected_fingerprint = Some(fingerprint);
+                }
+                "remove" => delta.remove.push(value.to_string()),
+                "add" | "weight" => {
+                    let (weight, name) =
+                        value.split_once(' ').ok_or_else(|| bad("Missing name"))?;
+                    let weight = weight.parse().map_err(|_| bad("Invalid weight"))?;
+                    if key == "add" {
+                        delta.add.push((name.to_string(), weight));
+                    } else {
+                        delta.set_weight.push((name.to_string(), weight));
+                    }
+                }
+                "info" => delta
+                    .set_info
+                    .push((value.to_string(), TargetInfo::default())),
+                "zone" | "tier" | "label" => {
+                    let (_, info) = delta.set_info.last_mut().ok_or_else(|| bad("No target"))?;
+                    if key == "zone" {
+                        info.zone = Some(value.to_string());
+                    } else if key == "tier" {
+                        info.tier = value.parse().map_err(|_| bad("Invalid tier"))?;
+                    } else {
+                        let (k, v) = value.split_once('=').ok_or_else(|| bad("Invalid label"))?;
+                        info.labels.insert(k.to_string(), v.to_string());
+                    }
+                }
+                _ => return Err(bad("Unknown field")),
+            }
+        }
+        return Ok(delta);
+    }
+}
+
+impl Flexihash {
+    // Covers everything which decides where keys go, but not the generation
+    pub fn fingerprint(&self) -> u64 {
+        let mut state = format!(
+            "{}\n{}\n{:?}\n{}\n{}\n{}\n{:?}\n",
+            self.hasher,
+            self.replicas,
+            self.replica_strategy,
+            self.salt,
+            self.key_prefix,
+            self.hash_tags,
+            self.key_normalization
+        );
+        let mut targets: Vec<_> = self.target_info.iter().collect();
+        targets.sort_by(|a, b| a.0.cmp(b.0));
+        for (target, info) in targets {
+            state.push_str(&format!("{} {}\n", info.weight, target));
+        }
+        return checksum(state.as_bytes());
+    }
+
+    // What it takes to turn this ring into `newer`, target-wise (zones,
+    // tiers and labels included); the hasher, replicas and salt are left
+    // alone
+    pub fn delta_to(&self, newer: &Flexihash) -> RingDelta {
+        let mut delta = RingDelta {
+            base_generation: self.generation(),
+            generation: newer.generation(),
+            causal_token: newer.causal_token().map(|t| t.to_string()),
+            expected_fingerprint: Some(self.fingerprint()),
+            ..RingDelta::default()
+        };
+        for target in self.target_info.keys() {
+            if !newer.target_info.contains_key(target) {
+                delta.remove.push(target.clone());
+            }
+        }
+        let plain = TargetInfo::default();
+        for (target, info) in newer.target_info.iter() {
+            let old = self.target_info.get(target);
+            match old {
+                None => delta.add.push((target.clone(), info.weight)),
+                Some(old) if old.weight != info.weight => {
+                    delta.set_weight.push((target.clone(), info.weight));
+                }
+                Some(_) => {}
+            }
+            let old = old.unwrap_or(&plain);
+            if old.zone != info.zone || old.tier != info.tier || old.labels != info.labels {
+                delta.set_info.push((target.clone(), info.clone()));
+            }
+        }
+        delta.remove.sort();
+        delta.add.sort();
+        delta.set_weight.sort();
+        delta.set_info.sort_by(|a, b| a.0.cmp(&b.0));
+        return delta;
+    }
+
+    // All or nothing: if anything about the delta doesn't fit this ring,
+    // the ring is left as it was. Afterwards the ring takes on the delta's
+    // generation and causal token, ready for the next one.
+    pub fn apply_delta(&mut self, delta: &RingDelta) -> Result<(), DeltaError> {
+        if delta.generation <= delta.base_generation {
+            return Err(DeltaError::Backwards(
+                delta.base_generation,
+                delta.generation,
+            ));
+        }
+        delta.check_base(self)?;
+        // the ring isn't touched until all of it is known to fit
+        for (target, _) in delta.set_info.iter() {
+            let target = self.normalize(target.clone());
+            let kept = self.target_info.contains_key(&target)
+                && !delta
+                    .remove
+                    .iter()
+                    .any(|t| self.normalize(t.clone()) == target);
+            let added = delta
+                .add
+                .iter()
+                .any(|(t, _)| self.normalize(t.clone()) == target);
+            if !kept && !added {
+                return Err(DeltaError::Ring(self.target_missing(&target)));
+            }
+        }
+        self.transaction(|tx| {
+            for target in delta.remove.iter() {
+                tx.remove(target.as_str());
+            }
+            for (target, weight) in delta.add.iter() {
+                tx.add(target.as_str(), *weight);
+            }
+            for (target, weight) in delta.set_weight.iter() {
+                tx.set_weight(target.as_str(), *weight);
+            }
+        })?;
+        for (target, info) in delta.set_info.iter() {
+            let target = self.normalize(target.clone());
+            if self.target_info[&target].tier != info.tier {
+                self.retier_target(target.clone(), info.tier);
+            }
+            if let Some(old) = std::sync::Arc::make_mut(&mut self.target_info).get_mut(&target) {
+                old.zone = info.zone.clone();
+                old.labels = info.labels.clone();
+            }
+        }
+        if !delta.set_info.is_empty() {
+            // they carry a copy of the tiers
+            self.sub_rings = Default::default();
+        }
+        self.generation = delta.generation;
+        self.causal_token = delta.causal_token.clone();
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test_delta {
+    use super::*;
+
+    fn rings() -> (Flexihash, Flexihash) {
+        let mut old = Flexihash::new();
+        old.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let mut new = old.clone();
+        new.remove_target("t-a");
+        new.add_target("t-d", 2);
+        new.update_target_weight("t-b", 3);
+        new.set_causal_token("controller-1");
+        return (old, new);
+    }
+
+    #[test]
+    fn delta_to() {
+        let (old, new) = rings();
+        let delta = old.delta_to(&new);
+        assert_eq!(delta.base_generation, old.generation());
+        assert_eq!(delta.generation, new.generation());
+        assert_eq!(delta.causal_token.as_deref(), Some("controller-1"));
+        assert_eq!(delta.remove, ["t-a"]);
+        assert_eq!(delta.add, [("t-d".to_string(), 2)]);
+        assert_eq!(delta.set_weight, [("t-b".to_string(), 3)]);
+        assert!(new.delta_to(&new).is_empty());
+    }
+
+    #[test]
+    fn generations() {
+        let mut fh = Flexihash::new();
+        assert_eq!(fh.generation(), 0);
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        assert_eq!(fh.generation(), 3);
+        // one for the whole transaction
+        fh.transaction(|tx| {
+            tx.remove("t-a").set_weight("t-b", 2);
+        })
+        .unwrap();
+        assert_eq!(fh.generation(), 4);
+    }
+
+    #[test]
+    fn stale() {
+        let (old, new) = rings();
+        let delta = old.delta_to(&new);
+        assert_eq!(delta.check_base(&old), Ok(()));
+
+        let mut moved_on = old.clone();
+        moved_on.add_target("t-e", 1);
+        let err = delta.check_base(&moved_on).unwrap_err();
+        assert_eq!(
+            err,
+            DeltaError::Stale(old.generation() + 1, old.generation())
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Delta is against generation {}, but the ring is at generation {}",
+                old.generation(),
+                old.generation() + 1
+            )
+        );
+    }
+
+    #[test]
+    fn apply_delta() {
+        let (old, new) = rings();
+        let delta = old.delta_to(&new);
+        let mut fh = old.clone();
+        fh.apply_delta(&delta).unwrap();
+        assert_eq!(fh.sorted_position_to_target, new.sorted_position_to_target);
+        assert_eq!(fh.generation(), new.generation());
+        assert_eq!(fh.causal_token(), Some("controller-1"));
+        assert_eq!(fh.fingerprint(), new.fingerprint());
+
+        // a second time, it's stale
+        assert_eq!(
+            fh.apply_delta(&delta),
+            Err(DeltaError::Stale(new.generation(), old.generation()))
+        );
+    }
+
+    #[test]
+    fn apply_delta_preconditions() {
+        let (old, new) = rings();
+        let delta = old.delta_to(&new);
+
+        // right generation, wrong ring
+        let mut other = Flexihash::new();
+        other.add_targets(vec!["t-a", "t-b", "t-x"]);
+        assert_eq!(other.generation(), old.generation());
+        let before = other.sorted_position_to_target.clone();
+        assert_eq!(
+            other.apply_delta(&delta),
+            Err(DeltaError::Fingerprint(
+                other.fingerprint(),
+                old.fingerprint()
+            ))
+        );
+        assert_eq!(other.sorted_position_to_target, before);
+
+        // changes which don't fit are an error, and nothing is applied
+        let mut fh = old.clone();
+        let bad = RingDelta {
+            base_generation: old.generation(),
+            generation: old.generation() + 1,
+            remove: vec!["t-a".to_string()],
+            set_weight: vec![("t-z".to_string(), 2)],
+            ..RingDelta::default()
+        };
+        assert_eq!(
+            fh.apply_delta(&bad),
+            Err(DeltaError::Ring(Error::TargetMissing(
+                "t-z".to_string(),
+                vec!["t-b".to_string(), "t-c".to_string()]
+            )))
+        );
+        assert_eq!(fh.get_all_targets(), old.get_all_targets());
+        assert_eq!(fh.generation(), old.generation());
+
+        // nor can it wind the generation back
+        for generation in [old.generation(), old.generation() - 1] {
+            let backwards = RingDelta {
+                generation,
+                ..delta.clone()
+            };
+            assert_eq!(
+                fh.apply_delta(&backwards),
+                Err(DeltaError::Backwards(old.generation(), generation))
+            );
+        }
+        assert_eq!(fh.get_all_targets(), old.get_all_targets());
+        assert_eq!(fh.generation(), old.generation());
+    }
+
+    #[test]
+    fn round_trip() {
+        let (old, new) = rings();
+        let delta = old.delta_to(&new);
+        let data = delta.to_bytes().unwrap();
+        assert_eq!(RingDelta::from_bytes(&data), Ok(delta));
+
+        let mut data = data;
+        data[20] ^= 1;
+        assert_eq!(RingDelta::from_bytes(&data), Err(DeltaError::Checksum));
+    }
+
+    struct Node;
+
+    impl crate::RingTarget for Node {
+        fn name(&self) -> Target {
+            return "t-e".to_string();
+        }
+
+        fn zone(&self) -> Option<String> {
+            return Some("rack-7".to_string());
+        }
+
+        fn labels(&self) -> std::collections::BTreeMap<String, String> {
+            let mut labels = std::collections::BTreeMap::new();
+            labels.insert("disk".to_string(), "ssd".to_string());
+            return labels;
+        }
+    }
+
+    #[test]
+    fn zones_tiers_and_labels() {
+        let (old, _) = rings();
+        let mut new = old.clone();
+        new.set_target_tier("t-b", 1);
+        let delta = old.delta_to(&new);
+        // nothing moves, but it's not nothing
+        assert!(!delta.is_empty());
+        assert_eq!(delta.set_info.len(), 1);
+        new.add_ring_target(&Node);
+
+        let delta = old.delta_to(&new);
+        assert_eq!(delta.add, [("t-e".to_string(), 1)]);
+        let names: Vec<&str> = delta.set_info.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(names, ["t-b", "t-e"]);
+        let delta = RingDelta::from_bytes(&delta.to_bytes().unwrap()).unwrap();
+        let mut fh = old.clone();
+        fh.apply_delta(&delta).unwrap();
+        for target in new.get_all_targets() {
+            assert_eq!(fh.get_target_info(&target), new.get_target_info(&target));
+        }
+        assert_eq!(
+            fh.lookup_tiered("key", |_| false),
+            new.lookup_tiered("key", |_| false)
+        );
+
+        // and taken away again
+        let mut newer = fh.clone();
+        newer.remove_target("t-e");
+        newer.set_target_tier("t-b", 0);
+        let delta = fh.delta_to(&newer);
+        assert_eq!(
+            delta.set_info,
+            [(
+                "t-b".to_string(),
+                TargetInfo {
+                    weight: 1,
+                    ..TargetInfo::default()
+                }
+            )]
+        );
+        fh.apply_delta(&delta).unwrap();
+        assert_eq!(fh.get_target_info("t-b").unwrap().tier, 0);
+
+        // only for targets which will be there
+        let bad = RingDelta {
+            base_generation: fh.generation(),
+            generation: fh.generation() + 1,
+            remove: vec!["t-a".to_string()],
+            set_info: vec![("t-a".to_string(), TargetInfo::default())],
+            ..RingDelta::default()
+        };
+        assert!(matches!(
+            fh.apply_delta(&bad),
+            Err(DeltaError::Ring(Error::TargetMissing(..)))
+        ));
+        assert_eq!(fh.get_all_targets(), newer.get_all_targets());
+    }
+
+    #[test]
+    fn unstorable_names() {
+        let delta = RingDelta {
+            remove: vec!["bad\nname".to_string()],
+            ..RingDelta::default()
+        };
+        assert_eq!(
+            delta.to_bytes(),
+            Err(DeltaError::InvalidName("bad\nname".to_string()))
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::shared::SharedRing;
+use crate::Target;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/*
+ * Steps a target's weight down to zero over a while, rather than all at
+ * once, so that its neighbours pick up its keys a few at a time. Each step
+ * drops some of the target's replica positions; the ones kept are the same
+ * ones it had before, so keys only ever move off the draining target.
+ *
+ * Once drained the target is left in the ring with weight 0, to be removed
+ * once it's actually gone. Dropping the Drain part-way stops it where it
+ * is; update_target_weight() with the original weight puts it back.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DrainEvent {
+    Step {
+        target: Target,
+        // replica positions the target has left
+        positions: u64,
+        step: u32,
+        steps: u32,
+    },
+    Finished(Target),
+    // removed by something else part-way through
+    Gone(Target),
+}
+
+const MAX_STEPS: u64 = 16;
+
+#[derive(Debug)]
+pub struct Drain {
+    // dropping this wakes the thread up and stops it
+    _stop: mpsc::Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drain {
+    pub fn new<S: Into<String>, F: Fn(DrainEvent) + Send + 'static>(
+        ring: Arc<SharedRing>,
+        target: S,
+        duration: Duration,
+        on_event: F,
+    ) -> Drain {
+        let (target, start) = ring.update(|fh| {
+            let target = fh.normalize(target.into());
+            if fh.get_target_info(&target).is_none() {
+                fh.missing_target(&target);
+            }
+            let start = fh.target_positions(&target);
+            (target, start)
+        });
+        let steps = start.clamp(1, MAX_STEPS);
+        let interval = duration / steps as u32;
+        let (stop, stopped) = mpsc::channel();
+
+        let handle = std::thread::spawn(move || {
+            for step in 1..=steps {
+                match stopped.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                let positions = start - start * step / steps;
+                let present = ring.update(|fh| {
+                    let weight = match fh.get_target_info(&target) {
+                        Some(info) => info.weight,
+                        None => return false,
+                    };
+                    // weight stays as it was until the end, so that an
+                    // interrupted drain can be undone
+                    let weight = if positions == 0 { 0 } else { weight };
+                    fh.reweight_target(target.clone(), weight, positions);
+                    fh.rebuild();
+                    return true;
+                });
+                if !present {
+                    on_event(DrainEvent::Gone(target));
+                    return;
+                }
+                on_event(DrainEvent::Step {
+                    target: target.clone(),
+                    positions,
+                    step: step as u32,
+                    steps: steps as u32,
+                });
+            }
+            on_event(DrainEvent::Finished(target));
+        });
+        return Drain {
+            _stop: stop,
+            handle: Some(handle),
+        };
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.handle.as_ref().is_none_or(|h| h.is_finished());
+    }
+
+    // Block until the drain has run its course
+    pub fn wait(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl SharedRing {
+    pub fn drain<S: Into<String>, F: Fn(DrainEvent) + Send + 'static>(
+        self: &Arc<Self>,
+        target: S,
+        duration: Duration,
+        on_event: F,
+    ) -> Drain {
+        return Drain::new(self.clone(), target, duration, on_event);
+    }
+}
+
+#[cfg(test)]
+mod test_drain {
+    use super::*;
+    use crate::Flexihash;
+    use std::sync::Mutex;
+
+    fn shared() -> Arc<SharedRing> {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(8);
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        return Arc::new(SharedRing::new(fh));
+    }
+
+    fn recorder() -> (Arc<Mutex<Vec<DrainEvent>>>, impl Fn(DrainEvent) + Send) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        return (events, move |e| sink.lock().unwrap().push(e));
+    }
+
+    #[test]
+    fn drains_in_steps() {
+        let shared = shared();
+        let before = shared.snapshot();
+        let (events, on_event) = recorder();
+        shared
+            .drain("t-b", Duration::from_millis(40), on_event)
+            .wait();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 9);
+        let positions: Vec<u64> = events[..8]
+            .iter()
+            .map(|e| match e {
+                DrainEvent::Step { positions, .. } => *positions,
+                e => panic!("Unexpected {:?}", e),
+            })
+            .collect();
+        assert_eq!(positions, [7, 6, 5, 4, 3, 2, 1, 0]);
+        assert_eq!(events[8], DrainEvent::Finished("t-b".to_string()));
+
+        let after = shared.snapshot();
+        assert_eq!(after.get_target_info("t-b").unwrap().weight, 0);
+        assert_eq!(after.get_all_targets(), ["t-a", "t-b", "t-c"]);
+        // only t-b's keys moved
+        for i in 0..100 {
+            let key = format!("r-{}", i);
+            if before.lookup(&key) != "t-b" {
+                assert_eq!(after.lookup(&key), before.lookup(&key));
+            } else {
+                assert_ne!(after.lookup(&key), "t-b");
+            }
+        }
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        let shared = shared();
+        let before = shared.snapshot();
+        let (events, on_event) = recorder();
+        drop(shared.drain("t-b", Duration::from_secs(60), on_event));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(
+            shared.snapshot().sorted_position_to_target,
+            before.sorted_position_to_target
+        );
+    }
+
+    #[test]
+    fn target_removed_part_way() {
+        let shared = shared();
+        let (events, on_event) = recorder();
+        let drain = shared.drain("t-a", Duration::from_millis(80), on_event);
+        shared.update(|fh| {
+            fh.remove_target("t-a");
+        });
+        drain.wait();
+        assert_eq!(
+            events.lock().unwrap().last(),
+            Some(&DrainEvent::Gone("t-a".to_string()))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 't-x' does not exist")]
+    fn drain_missing_target() {
+        shared().drain("t-x", Duration::from_secs(1), |_| {});
+    }
+}
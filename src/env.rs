@@ -0,0 +1,206 @@
+use crate::validate::RingWarning;
+use crate::{Error, Flexihash, HashError};
+use std::fmt;
+
+/*
+ * A ring configured entirely from environment variables:
+ *
+ *   FLEXIHASH_TARGETS   required; "a=2,b=1,c" - name=weight, weight 1 if
+ *                       left out
+ *   FLEXIHASH_HASHER    optional; as in a snapshot, eg "md5"
+ *   FLEXIHASH_REPLICAS  optional
+ *   FLEXIHASH_SALT      optional
+ *
+ * Target names can't contain ',' or '=' this way. A ring with no targets
+ * in it is refused; validate() has other warnings worth checking too.
+ */
+#[derive(Debug)]
+pub enum EnvError {
+    Missing(String),
+    // the variable, and what was wrong with it
+    Invalid(String, String),
+    Hasher(HashError),
+    Ring(Error),
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvError::Missing(var) => write!(f, "{} is not set", var),
+            EnvError::Invalid(var, msg) => write!(f, "{}: {}", var, msg),
+            EnvError::Hasher(e) => write!(f, "{}: {}", HASHER, e),
+            EnvError::Ring(e) => write!(f, "{}: {}", TARGETS, e),
+        }
+    }
+}
+
+impl std::error::Error for EnvError {}
+
+impl From<HashError> for EnvError {
+    fn from(e: HashError) -> EnvError {
+        return EnvError::Hasher(e);
+    }
+}
+
+impl From<Error> for EnvError {
+    fn from(e: Error) -> EnvError {
+        return EnvError::Ring(e);
+    }
+}
+
+const TARGETS: &str = "FLEXIHASH_TARGETS";
+const HASHER: &str = "FLEXIHASH_HASHER";
+const REPLICAS: &str = "FLEXIHASH_REPLICAS";
+const SALT: &str = "FLEXIHASH_SALT";
+
+impl Flexihash {
+    pub fn from_env() -> Result<Flexihash, EnvError> {
+        return Flexihash::from_vars(|var| std::env::var_os(var));
+    }
+
+    // from_env, but with the variables coming from anywhere
+    pub fn from_vars<V: Into<std::ffi::OsString>>(
+        get: impl Fn(&str) -> Option<V>,
+    ) -> Result<Flexihash, EnvError> {
+        let var = |name: &str| -> Result<Option<String>, EnvError> {
+            return match get(name) {
+                None => Ok(None),
+                Some(value) => match value.into().into_string() {
+                    Ok(value) => Ok(Some(value)),
+                    Err(_) => Err(EnvError::Invalid(
+                        name.to_string(),
+                        "Not valid UTF-8".to_string(),
+                    )),
+                },
+            };
+        };
+
+        let mut fh = Flexihash::new();
+        if let Some(hasher) = var(HASHER)? {
+            fh.set_hasher(hasher.parse()?);
+        }
+        if let Some(replicas) = var(REPLICAS)? {
+            let replicas = replicas.parse().map_err(|_| {
+                EnvError::Invalid(
+                    REPLICAS.to_string(),
+                    format!("Invalid replicas {:?}", replicas),
+                )
+            })?;
+            fh.set_replicas(replicas);
+        }
+        if let Some(salt) = var(SALT)? {
+            fh.set_salt(salt);
+        }
+
+        let targets = var(TARGETS)?.ok_or_else(|| EnvError::Missing(TARGETS.to_string()))?;
+        for entry in targets
+            .split(',')
+            .map(|e| e.trim())
+            .filter(|e| !e.is_empty())
+        {
+            let (name, weight) = match entry.split_once('=') {
+                Some((name, weight)) => {
+                    let weight = weight.trim().parse().map_err(|_| {
+                        EnvError::Invalid(
+                            TARGETS.to_string(),
+                            format!("Invalid weight {:?}", entry),
+                        )
+                    })?;
+                    (name.trim(), weight)
+                }
+                None => (entry, 1),
+            };
+            if name.is_empty() {
+                return Err(EnvError::Invalid(
+                    TARGETS.to_string(),
+                    format!("Missing name {:?}", entry),
+                ));
+            }
+            fh.try_add_target(name, weight)?;
+        }
+        if fh.validate().contains(&RingWarning::NoTargets) {
+            return Err(EnvError::Invalid(
+                TARGETS.to_string(),
+                RingWarning::NoTargets.to_string(),
+            ));
+        }
+        return Ok(fh);
+    }
+}
+
+#[cfg(test)]
+mod test_env {
+    use super::*;
+    use crate::Hasher;
+    use std::collections::HashMap;
+
+    fn from(vars: &[(&str, &str)]) -> Result<Flexihash, EnvError> {
+        let vars: HashMap<&str, &str> = vars.iter().cloned().collect();
+        return Flexihash::from_vars(|var| vars.get(var).copied());
+    }
+
+    #[test]
+    fn reads_everything() {
+        let fh = from(&[
+            ("FLEXIHASH_TARGETS", "a=2, b=1,c"),
+            ("FLEXIHASH_HASHER", "adler32"),
+            ("FLEXIHASH_REPLICAS", "16"),
+            ("FLEXIHASH_SALT", "prod:"),
+        ])
+        .unwrap();
+        assert_eq!(fh.get_all_targets(), ["a", "b", "c"]);
+        assert_eq!(fh.get_target_info("a").unwrap().weight, 2);
+        assert_eq!(fh.get_target_info("c").unwrap().weight, 1);
+        assert!(matches!(fh.hasher(), Hasher::Adler32));
+        assert_eq!(fh.replicas(), 16);
+        assert_eq!(fh.salt(), "prod:");
+    }
+
+    #[test]
+    fn defaults() {
+        let fh = from(&[("FLEXIHASH_TARGETS", "a")]).unwrap();
+        assert_eq!(fh.hasher().to_string(), Hasher::default().to_string());
+        assert_eq!(fh.replicas(), 64);
+        assert_eq!(fh.get_all_targets(), ["a"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            from(&[]).unwrap_err().to_string(),
+            "FLEXIHASH_TARGETS is not set"
+        );
+        assert_eq!(
+            from(&[("FLEXIHASH_TARGETS", "a=x")])
+                .unwrap_err()
+                .to_string(),
+            "FLEXIHASH_TARGETS: Invalid weight \"a=x\""
+        );
+        assert_eq!(
+            from(&[("FLEXIHASH_TARGETS", "=2")])
+                .unwrap_err()
+                .to_string(),
+            "FLEXIHASH_TARGETS: Missing name \"=2\""
+        );
+        assert_eq!(
+            from(&[("FLEXIHASH_TARGETS", "a,a")])
+                .unwrap_err()
+                .to_string(),
+            "FLEXIHASH_TARGETS: Target a already exists"
+        );
+        assert_eq!(
+            from(&[("FLEXIHASH_TARGETS", " , ")])
+                .unwrap_err()
+                .to_string(),
+            "FLEXIHASH_TARGETS: No targets set"
+        );
+        assert!(matches!(
+            from(&[("FLEXIHASH_TARGETS", "a"), ("FLEXIHASH_HASHER", "sha1")]),
+            Err(EnvError::Hasher(HashError::UnknownHasher(_)))
+        ));
+        assert!(matches!(
+            from(&[("FLEXIHASH_TARGETS", "a"), ("FLEXIHASH_REPLICAS", "-1")]),
+            Err(EnvError::Invalid(..))
+        ));
+    }
+}
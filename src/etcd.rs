@@ -0,0 +1,608 @@
+use crate::shared::SharedRing;
+use crate::validate::RingWarning;
+use crate::{Error, RingTarget, Target, TargetInfo};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+ * Stores ring membership in etcd, one key per target under a prefix, so
+ * that every process watching the prefix sees the same ring. Talks to
+ * etcd's JSON gateway (/v3/kv/..., /v3/watch) rather than gRPC.
+ *
+ * Values are JSON: {"weight": 2, "zone": "eu-1a", "tier": 0, "labels": {...}}
+ *
+ * Hasher and replica settings stay local to each process, so they need to
+ * agree through configuration as usual.
+ */
+#[derive(Debug, Clone)]
+pub struct EtcdConfig {
+    pub endpoint: String,
+    pub prefix: String,
+    pub timeout: Duration,
+    // how long a watch is left open with nothing happening before it's
+    // started again; also how long a dropped watcher can take to stop
+    pub watch_timeout: Duration,
+}
+
+impl EtcdConfig {
+    pub fn new<S: Into<String>>(prefix: S) -> EtcdConfig {
+        return EtcdConfig {
+            endpoint: "http://127.0.0.1:2379".to_string(),
+            prefix: prefix.into(),
+            timeout: Duration::from_secs(10),
+            watch_timeout: Duration::from_secs(60),
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum EtcdError {
+    Http(ureq::Error),
+    Io(std::io::Error),
+    Parse(String),
+    // the stored targets don't fit in the ring, eg a weight over its limits
+    Ring(Error),
+}
+
+impl fmt::Display for EtcdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EtcdError::Http(e) => write!(f, "etcd request failed: {}", e),
+            EtcdError::Io(e) => write!(f, "etcd watch failed: {}", e),
+            EtcdError::Parse(e) => write!(f, "Unexpected response from etcd: {}", e),
+            EtcdError::Ring(e) => write!(f, "Stored targets don't fit in the ring: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EtcdError {}
+
+impl From<ureq::Error> for EtcdError {
+    fn from(e: ureq::Error) -> EtcdError {
+        return EtcdError::Http(e);
+    }
+}
+
+impl From<std::io::Error> for EtcdError {
+    fn from(e: std::io::Error) -> EtcdError {
+        return EtcdError::Io(e);
+    }
+}
+
+#[derive(Debug)]
+pub enum EtcdEvent {
+    Applied {
+        added: Vec<Target>,
+        removed: Vec<Target>,
+        changed: Vec<Target>,
+        // anything risky about the ring as it now is
+        warnings: Vec<RingWarning>,
+    },
+    Failed(EtcdError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Put(Target, TargetInfo),
+    Delete(Target),
+}
+
+// Lets a stored TargetInfo go through add_ring_target
+struct StoredTarget<'a>(&'a Target, &'a TargetInfo);
+
+impl RingTarget for StoredTarget<'_> {
+    fn name(&self) -> Target {
+        return self.0.clone();
+    }
+    fn weight(&self) -> u32 {
+        return self.1.weight;
+    }
+    fn zone(&self) -> Option<String> {
+        return self.1.zone.clone();
+    }
+    fn tier(&self) -> u32 {
+        return self.1.tier;
+    }
+    fn labels(&self) -> BTreeMap<String, String> {
+        return self.1.labels.clone();
+    }
+}
+
+fn encode_info(info: &TargetInfo) -> String {
+    return json!({"weight": info.weight, "zone": info.zone, "tier": info.tier, "labels": info.labels}).to_string();
+}
+
+fn decode_info(value: &[u8]) -> Result<TargetInfo, EtcdError> {
+    let value: Value =
+        serde_json::from_slice(value).map_err(|e| EtcdError::Parse(e.to_string()))?;
+    let labels = value["labels"]
+        .as_object()
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    let number = |key: &str, default: u32| match &value[key] {
+        Value::Null => Ok(default),
+        v => v
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| EtcdError::Parse(format!("Invalid {}: {}", key, v))),
+    };
+    return Ok(TargetInfo {
+        weight: number("weight", 1)?,
+        zone: value["zone"].as_str().map(|z| z.to_string()),
+        tier: number("tier", 0)?,
+        labels,
+    });
+}
+
+// The gateway sends int64s as strings
+fn parse_i64(value: &Value) -> i64 {
+    return match value {
+        Value::String(s) => s.parse().unwrap_or(0),
+        v => v.as_i64().unwrap_or(0),
+    };
+}
+
+fn decode_bytes(value: &Value) -> Result<Vec<u8>, EtcdError> {
+    return BASE64
+        .decode(value.as_str().unwrap_or(""))
+        .map_err(|e| EtcdError::Parse(e.to_string()));
+}
+
+// The end of the key range covering everything starting with the prefix
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // all 0xff (or empty) means "everything", which etcd spells as \0
+    return vec![0];
+}
+
+pub struct EtcdTopology {
+    config: EtcdConfig,
+    agent: ureq::Agent,
+}
+
+impl EtcdTopology {
+    pub fn new(config: EtcdConfig) -> EtcdTopology {
+        let agent = ureq::Agent::config_builder()
+            .timeout_connect(Some(config.timeout))
+            .build()
+            .into();
+        return EtcdTopology { config, agent };
+    }
+
+    fn key(&self, target: &str) -> String {
+        return format!("{}{}", self.config.prefix, target);
+    }
+
+    fn call(&self, path: &str, body: Value) -> Result<Value, EtcdError> {
+        let url = format!("{}{}", self.config.endpoint.trim_end_matches('/'), path);
+        let mut response = self
+            .agent
+            .post(&url)
+            .config()
+            .timeout_global(Some(self.config.timeout))
+            .build()
+            .header("content-type", "application/json")
+            .send(body.to_string())?;
+        let body = response.body_mut().read_to_string()?;
+        return serde_json::from_str(&body).map_err(|e| EtcdError::Parse(e.to_string()));
+    }
+
+    pub fn publish<S: AsRef<str>>(&self, target: S, info: &TargetInfo) -> Result<(), EtcdError> {
+        self.call(
+            "/v3/kv/put",
+            json!({
+                "key": BASE64.encode(self.key(target.as_ref())),
+                "value": BASE64.encode(encode_info(info)),
+            }),
+        )?;
+        return Ok(());
+    }
+
+    pub fn unpublish<S: AsRef<str>>(&self, target: S) -> Result<(), EtcdError> {
+        self.call(
+            "/v3/kv/deleterange",
+            json!({"key": BASE64.encode(self.key(target.as_ref()))}),
+        )?;
+        return Ok(());
+    }
+
+    // Every target currently stored, and the revision they were read at
+    pub fn load(&self) -> Result<(i64, Vec<(Target, TargetInfo)>), EtcdError> {
+        let prefix = self.config.prefix.as_bytes();
+        let response = self.call(
+            "/v3/kv/range",
+            json!({
+                "key": BASE64.encode(prefix),
+                "range_end": BASE64.encode(prefix_end(prefix)),
+            }),
+        )?;
+        return parse_range(&response, &self.config.prefix);
+    }
+
+    // Stream changes made after the given revision, until the connection
+    // drops, watch_timeout passes or on_change returns false
+    pub fn watch<F: FnMut(Vec<Change>) -> bool>(
+        &self,
+        after: i64,
+        mut on_change: F,
+    ) -> Result<(), EtcdError> {
+        let prefix = self.config.prefix.as_bytes();
+        let url = format!("{}/v3/watch", self.config.endpoint.trim_end_matches('/'));
+        let body = json!({
+            "create_request": {
+                "key": BASE64.encode(prefix),
+                "range_end": BASE64.encode(prefix_end(prefix)),
+                "start_revision": (after + 1).to_string(),
+            }
+        });
+        let response = self
+            .agent
+            .post(&url)
+            .config()
+            .timeout_global(Some(self.config.watch_timeout))
+            .build()
+            .header("content-type", "application/json")
+            .send(body.to_string())?;
+        let reader = std::io::BufReader::new(response.into_body().into_reader());
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                // a quiet prefix, rather than a failure
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message: Value =
+                serde_json::from_str(&line).map_err(|e| EtcdError::Parse(e.to_string()))?;
+            let changes = parse_watch(&message, &self.config.prefix)?;
+            if !changes.is_empty() && !on_change(changes) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    return e
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<ureq::Error>())
+        .is_some_and(|e| matches!(e, ureq::Error::Timeout(_)));
+}
+
+pub fn parse_range(
+    response: &Value,
+    prefix: &str,
+) -> Result<(i64, Vec<(Target, TargetInfo)>), EtcdError> {
+    let revision = parse_i64(&response["header"]["revision"]);
+    let mut targets = Vec::new();
+    for kv in response["kvs"].as_array().into_iter().flatten() {
+        let key = decode_bytes(&kv["key"])?;
+        let key = String::from_utf8_lossy(&key);
+        if let Some(name) = key.strip_prefix(prefix) {
+            targets.push((name.to_string(), decode_info(&decode_bytes(&kv["value"])?)?));
+        }
+    }
+    return Ok((revision, targets));
+}
+
+pub fn parse_watch(message: &Value, prefix: &str) -> Result<Vec<Change>, EtcdError> {
+    let result = &message["result"];
+    if let Some(reason) = result["cancel_reason"].as_str() {
+        return Err(EtcdError::Parse(format!("watch cancelled: {}", reason)));
+    }
+    let mut changes = Vec::new();
+    for event in result["events"].as_array().into_iter().flatten() {
+        let key = decode_bytes(&event["kv"]["key"])?;
+        let key = String::from_utf8_lossy(&key);
+        let name = match key.strip_prefix(prefix) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // PUT is the default, so the gateway leaves it out
+        if event["type"].as_str() == Some("DELETE") {
+            changes.push(Change::Delete(name));
+        } else {
+            changes.push(Change::Put(
+                name,
+                decode_info(&decode_bytes(&event["kv"]["value"])?)?,
+            ));
+        }
+    }
+    return Ok(changes);
+}
+
+// Apply a batch of changes to the ring in one update, returning what
+// actually changed; if the ring can't take them, none are applied
+pub fn apply(changes: &[Change], ring: &SharedRing) -> Option<EtcdEvent> {
+    // the last word on each target
+    let mut latest: Vec<(&Target, Option<&TargetInfo>)> = Vec::new();
+    for change in changes {
+        let (target, info) = match change {
+            Change::Put(target, info) => (target, Some(info)),
+            Change::Delete(target) => (target, None),
+        };
+        latest.retain(|(t, _)| *t != target);
+        latest.push((target, info));
+    }
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    let result = ring.update(|fh| {
+        let mut put = Vec::new();
+        for (target, info) in latest {
+            match (fh.get_target_info(target), info) {
+                (Some(_), None) => removed.push(target.clone()),
+                (None, None) => {}
+                (Some(existing), Some(info)) if existing == info => {}
+                (existing, Some(info)) => {
+                    if existing.is_some() {
+                        changed.push(target.clone());
+                    } else {
+                        added.push(target.clone());
+                    }
+                    put.push(StoredTarget(target, info));
+                }
+            }
+        }
+        let put: Vec<&dyn RingTarget> = put.iter().map(|t| t as &dyn RingTarget).collect();
+        fh.try_sync_ring_targets(&removed, &put)?;
+        return Ok(fh.validate());
+    });
+    let warnings = match result {
+        Ok(warnings) => warnings,
+        Err(e) => return Some(EtcdEvent::Failed(EtcdError::Ring(e))),
+    };
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        return None;
+    }
+    return Some(EtcdEvent::Applied {
+        added,
+        removed,
+        changed,
+        warnings,
+    });
+}
+
+// Replace the ring's membership wholesale with what's stored
+fn resync(targets: &[(Target, TargetInfo)], ring: &SharedRing) -> Option<EtcdEvent> {
+    let wanted: Vec<&Target> = targets.iter().map(|t| &t.0).collect();
+    let mut changes: Vec<Change> = ring
+        .snapshot()
+        .get_all_targets()
+        .into_iter()
+        .filter(|t| !wanted.contains(&t))
+        .map(Change::Delete)
+        .collect();
+    for (target, info) in targets {
+        changes.push(Change::Put(target.clone(), info.clone()));
+    }
+    return apply(&changes, ring);
+}
+
+// Keeps a SharedRing in step with the stored targets from a background
+// thread until dropped
+pub struct EtcdWatcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl EtcdWatcher {
+    pub fn new<F: Fn(EtcdEvent) + Send + 'static>(
+        config: EtcdConfig,
+        ring: Arc<SharedRing>,
+        on_event: F,
+    ) -> EtcdWatcher {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        std::thread::spawn(move || {
+            let topology = EtcdTopology::new(config);
+            while !stopped.load(Ordering::Relaxed) {
+                // (re)start from a full load, so that nothing missed while
+                // disconnected is lost
+                let result = topology.load().and_then(|(revision, targets)| {
+                    if let Some(event) = resync(&targets, &ring) {
+                        on_event(event);
+                    }
+                    topology.watch(revision, |changes| {
+                        if let Some(event) = apply(&changes, &ring) {
+                            on_event(event);
+                        }
+                        return !stopped.load(Ordering::Relaxed);
+                    })
+                });
+                if let Err(e) = result {
+                    on_event(EtcdEvent::Failed(e));
+                }
+                if !stopped.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        });
+        return EtcdWatcher { stop };
+    }
+}
+
+impl Drop for EtcdWatcher {
+    fn drop(&mut self) {
+        // the thread notices at the next change it sees, or when its
+        // watch times out
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test_etcd {
+    use super::*;
+    use crate::Flexihash;
+    use std::io::{Read, Write};
+
+    fn kv(key: &str, value: &str) -> Value {
+        return json!({"key": BASE64.encode(key), "value": BASE64.encode(value)});
+    }
+
+    #[test]
+    fn prefix_ranges() {
+        assert_eq!(prefix_end(b"ring/"), b"ring0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+        assert_eq!(prefix_end(b""), b"\0");
+    }
+
+    #[test]
+    fn info_round_trip() {
+        let mut info = TargetInfo {
+            weight: 3,
+            zone: Some("eu-1a".to_string()),
+            tier: 1,
+            labels: BTreeMap::new(),
+        };
+        info.labels.insert("rack".to_string(), "r1".to_string());
+        assert_eq!(decode_info(encode_info(&info).as_bytes()).unwrap(), info);
+        assert!(decode_info(b"nope").is_err());
+        assert_eq!(decode_info(b"{}").unwrap().weight, 1);
+        for bad in [
+            r#"{"weight": 4294967296}"#,
+            r#"{"weight": -1}"#,
+            r#"{"tier": 4294967296}"#,
+        ] {
+            assert!(matches!(
+                decode_info(bad.as_bytes()),
+                Err(EtcdError::Parse(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_responses() {
+        let response = json!({
+            "header": {"revision": "17"},
+            "kvs": [kv("ring/t-a", r#"{"weight": 2}"#), kv("other/t-x", "{}")],
+        });
+        let (revision, targets) = parse_range(&response, "ring/").unwrap();
+        assert_eq!(revision, 17);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].0, "t-a");
+        assert_eq!(targets[0].1.weight, 2);
+
+        let message = json!({"result": {"events": [
+            {"kv": kv("ring/t-b", r#"{"weight": 1, "zone": "z"}"#)},
+            {"type": "DELETE", "kv": {"key": BASE64.encode("ring/t-a")}},
+        ]}});
+        let changes = parse_watch(&message, "ring/").unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(
+            matches!(&changes[0], Change::Put(t, info) if t == "t-b" && info.zone.as_deref() == Some("z"))
+        );
+        assert_eq!(changes[1], Change::Delete("t-a".to_string()));
+
+        let created = json!({"result": {"header": {}, "created": true}});
+        assert!(parse_watch(&created, "ring/").unwrap().is_empty());
+        let cancelled = json!({"result": {"canceled": true, "cancel_reason": "compacted"}});
+        assert!(parse_watch(&cancelled, "ring/").is_err());
+    }
+
+    #[test]
+    fn apply_changes() {
+        let shared = SharedRing::new(Flexihash::new());
+        shared.update(|fh| {
+            fh.add_targets(vec!["t-a", "t-b"]);
+        });
+        let info = |weight| TargetInfo {
+            weight,
+            ..Default::default()
+        };
+        let changes = vec![
+            Change::Put("t-a".to_string(), info(1)),
+            Change::Put("t-b".to_string(), info(4)),
+            Change::Put("t-c".to_string(), info(1)),
+            Change::Delete("t-a".to_string()),
+            Change::Delete("t-z".to_string()),
+        ];
+        match apply(&changes, &shared) {
+            Some(EtcdEvent::Applied {
+                added,
+                removed,
+                changed,
+                ..
+            }) => {
+                assert_eq!(added, ["t-c"]);
+                assert_eq!(removed, ["t-a"]);
+                assert_eq!(changed, ["t-b"]);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(shared.snapshot().get_target_info("t-b").unwrap().weight, 4);
+        assert!(apply(&changes[1..3], &shared).is_none());
+
+        let event = resync(&[("t-d".to_string(), info(1))], &shared);
+        assert!(matches!(event, Some(EtcdEvent::Applied { .. })));
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-d"]);
+    }
+
+    #[test]
+    fn apply_fails_rather_than_panicking() {
+        let shared = SharedRing::new(Flexihash::new());
+        shared.update(|fh| {
+            fh.add_targets(vec!["t-a"]);
+        });
+        let huge = TargetInfo {
+            weight: u32::MAX,
+            ..Default::default()
+        };
+        let changes = vec![
+            Change::Delete("t-a".to_string()),
+            Change::Put("t-b".to_string(), huge),
+        ];
+        assert!(matches!(
+            apply(&changes, &shared),
+            Some(EtcdEvent::Failed(EtcdError::Ring(Error::TooManyPositions(
+                ..
+            ))))
+        ));
+        // none of it applied
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-a"]);
+    }
+
+    #[test]
+    fn quiet_watches_time_out() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = EtcdConfig::new("ring/");
+        config.endpoint = format!("http://{}", listener.local_addr().unwrap());
+        config.watch_timeout = Duration::from_millis(200);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap();
+            // and then nothing, for longer than the watch waits
+            std::thread::sleep(Duration::from_secs(2));
+        });
+        let started = std::time::Instant::now();
+        let result = EtcdTopology::new(config).watch(0, |_| true);
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{Flexihash, Hasher, Search};
+
+/*
+ * Golden placement vectors: for each configuration, where a handful of
+ * keys land. Anything which changes these (hashing, replica naming, search)
+ * silently moves keys for every existing user, so it should never happen
+ * by accident. If it ever has to happen on purpose, bump GOLDEN_VERSION
+ * and regenerate with:
+ *
+ *   cargo test golden::regenerate -- --ignored --nocapture
+ */
+const GOLDEN_VERSION: u32 = 1;
+
+type Targets = &'static [(&'static str, u32)];
+
+struct Case {
+    hasher: &'static str,
+    replicas: u32,
+    targets: Targets,
+    expected: &'static [(&'static str, [&'static str; 2])],
+}
+
+const KEYS: &[&str] = &[
+    "",
+    "a",
+    "foo",
+    "object-a",
+    "user:1234",
+    "resource-42",
+    "ünïcode",
+    "{tag}:x",
+];
+
+const CONFIGS: &[(&str, u32, Targets)] = &[
+    ("crc32", 64, &[("a", 1), ("b", 1), ("c", 1)]),
+    (
+        "crc32",
+        1,
+        &[("t1", 1), ("t2", 1), ("t3", 1), ("t4", 1), ("t5", 1)],
+    ),
+    (
+        "crc32",
+        64,
+        &[
+            ("node-0", 1),
+            ("node-1", 1),
+            ("node-2", 1),
+            ("node-3", 1),
+            ("node-4", 1),
+            ("node-5", 1),
+            ("node-6", 1),
+            ("node-7", 1),
+            ("node-8", 1),
+            ("node-9", 1),
+        ],
+    ),
+    (
+        "md5",
+        64,
+        &[
+            ("cache-1:11211", 1),
+            ("cache-2:11211", 1),
+            ("cache-3:11211", 1),
+        ],
+    ),
+    ("md5", 16, &[("big", 3), ("small", 1)]),
+];
+
+const CASES: &[Case] = &[
+    Case {
+        hasher: "crc32",
+        replicas: 64,
+        targets: &[("a", 1), ("b", 1), ("c", 1)],
+        expected: &[
+            ("", ["a", "b"]),
+            ("a", ["a", "c"]),
+            ("foo", ["a", "c"]),
+            ("object-a", ["a", "c"]),
+            ("user:1234", ["c", "b"]),
+            ("resource-42", ["b", "c"]),
+            ("ünïcode", ["b", "c"]),
+            ("{tag}:x", ["c", "b"]),
+        ],
+    },
+    Case {
+        hasher: "crc32",
+        replicas: 1,
+        targets: &[("t1", 1), ("t2", 1), ("t3", 1), ("t4", 1), ("t5", 1)],
+        expected: &[
+            ("", ["t5", "t4"]),
+            ("a", ["t5", "t4"]),
+            ("foo", ["t5", "t4"]),
+            ("object-a", ["t5", "t4"]),
+            ("user:1234", ["t5", "t4"]),
+            ("resource-42", ["t2", "t3"]),
+            ("ünïcode", ["t5", "t4"]),
+            ("{tag}:x", ["t5", "t4"]),
+        ],
+    },
+    Case {
+        hasher: "crc32",
+        replicas: 64,
+        targets: &[
+            ("node-0", 1),
+            ("node-1", 1),
+            ("node-2", 1),
+            ("node-3", 1),
+            ("node-4", 1),
+            ("node-5", 1),
+            ("node-6", 1),
+            ("node-7", 1),
+            ("node-8", 1),
+            ("node-9", 1),
+        ],
+        expected: &[
+            ("", ["node-6", "node-2"]),
+            ("a", ["node-3", "node-2"]),
+            ("foo", ["node-3", "node-1"]),
+            ("object-a", ["node-7", "node-3"]),
+            ("user:1234", ["node-9", "node-6"]),
+            ("resource-42", ["node-5", "node-1"]),
+            ("ünïcode", ["node-1", "node-0"]),
+            ("{tag}:x", ["node-6", "node-0"]),
+        ],
+    },
+    Case {
+        hasher: "md5",
+        replicas: 64,
+        targets: &[
+            ("cache-1:11211", 1),
+            ("cache-2:11211", 1),
+            ("cache-3:11211", 1),
+        ],
+        expected: &[
+            ("", ["cache-3:11211", "cache-2:11211"]),
+            ("a", ["cache-3:11211", "cache-1:11211"]),
+            ("foo", ["cache-2:11211", "cache-3:11211"]),
+            ("object-a", ["cache-3:11211", "cache-1:11211"]),
+            ("user:1234", ["cache-2:11211", "cache-3:11211"]),
+            ("resource-42", ["cache-1:11211", "cache-2:11211"]),
+            ("ünïcode", ["cache-3:11211", "cache-1:11211"]),
+            ("{tag}:x", ["cache-1:11211", "cache-3:11211"]),
+        ],
+    },
+    Case {
+        hasher: "md5",
+        replicas: 16,
+        targets: &[("big", 3), ("small", 1)],
+        expected: &[
+            ("", ["big", "small"]),
+            ("a", ["big", "small"]),
+            ("foo", ["big", "small"]),
+            ("object-a", ["small", "big"]),
+            ("user:1234", ["big", "small"]),
+            ("resource-42", ["big", "small"]),
+            ("ünïcode", ["small", "big"]),
+            ("{tag}:x", ["small", "big"]),
+        ],
+    },
+];
+
+fn build(hasher: &str, replicas: u32, targets: &[(&str, u32)], search: Search) -> Flexihash {
+    let mut fh = Flexihash::new();
+    fh.set_hasher(match hasher {
+        "crc32" => Hasher::Crc32,
+        _ => Hasher::Md5,
+    });
+    fh.set_replicas(replicas);
+    fh.set_search(search);
+    for (target, weight) in targets {
+        fh.add_target(*target, *weight);
+    }
+    return fh;
+}
+
+#[test]
+fn placement_matches_golden_vectors() {
+    assert_eq!(CASES.len(), CONFIGS.len());
+    for case in CASES {
+        for search in [Search::Eytzinger, Search::Interpolation] {
+            let fh = build(case.hasher, case.replicas, case.targets, search);
+            for (key, expected) in case.expected {
+                assert_eq!(
+                    fh.lookup_list(*key, 2),
+                    expected,
+                    "v{} {} x{} {:?}: {:?}",
+                    GOLDEN_VERSION,
+                    case.hasher,
+                    case.replicas,
+                    search,
+                    key
+                );
+            }
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn regenerate() {
+    println!("const GOLDEN_VERSION: u32 = {};", GOLDEN_VERSION);
+    println!("const CASES: &[Case] = &[");
+    for (hasher, replicas, targets) in CONFIGS {
+        let fh = build(hasher, *replicas, targets, Search::Eytzinger);
+        println!("    Case {{");
+        println!("        hasher: {:?},", hasher);
+        println!("        replicas: {},", replicas);
+        println!("        targets: &{:?},", targets);
+        println!("        expected: &[");
+        for key in KEYS {
+            let list = fh.lookup_list(*key, 2);
+            println!("            ({:?}, [{:?}, {:?}]),", key, list[0], list[1]);
+        }
+        println!("        ],");
+        println!("    }},");
+    }
+    println!("];");
+}
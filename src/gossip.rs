@@ -0,0 +1,931 @@
+use crate::shared::SharedRing;
+use crate::snapshot::check_name;
+use crate::Target;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/*
+ * SWIM-style gossip membership: processes find each other through any one
+ * seed, probe each other for failures, and spread joins, leaves and deaths
+ * by piggybacking them on the probes, so that every process's ring keeps
+ * itself up to date without a central discovery service.
+ *
+ * Gossip itself does no I/O: hand it the messages which arrive (handle)
+ * and call tick every so often, and send on whatever they return, over
+ * any transport. start_udp does that over a UDP socket, in which case
+ * member names are the members' "host:port" addresses.
+ *
+ * Alive and suspected members are in the ring; dead ones (and ones which
+ * have left) are not. A suspected member which is actually fine hears
+ * about it and refutes it by bumping its incarnation number. So does one
+ * which was declared dead (eg while partitioned off, or before a restart)
+ * and turns up again: whoever hears from it tells it so.
+ *
+ * Messages aren't authenticated, so anything which can send to the socket
+ * can add targets to the ring; only run it on a network you trust.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: Target,
+    pub weight: u32,
+    pub incarnation: u64,
+    pub state: MemberState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Ping,
+    // ask the receiver to ping the target for us
+    PingReq(Target),
+    Ack,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub kind: Kind,
+    pub seq: u64,
+    pub from: Member,
+    pub updates: Vec<Member>,
+}
+
+#[derive(Debug)]
+pub enum GossipError {
+    Io(std::io::Error),
+    Parse(usize, String),
+    InvalidName(String),
+}
+
+impl fmt::Display for GossipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GossipError::Io(e) => write!(f, "Gossip failed: {}", e),
+            GossipError::Parse(line, msg) => write!(f, "Line {}: {}", line, msg),
+            GossipError::InvalidName(name) => write!(f, "Can't send name {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for GossipError {}
+
+impl From<std::io::Error> for GossipError {
+    fn from(e: std::io::Error) -> GossipError {
+        return GossipError::Io(e);
+    }
+}
+
+const MAGIC: &str = "flexihash gossip 1";
+
+fn check(name: &str) -> Result<(), GossipError> {
+    return check_name(name).map_err(|_| GossipError::InvalidName(name.to_string()));
+}
+
+impl MemberState {
+    fn as_str(&self) -> &'static str {
+        return match self {
+            MemberState::Alive => "alive",
+            MemberState::Suspect => "suspect",
+            MemberState::Dead => "dead",
+        };
+    }
+}
+
+impl Member {
+    fn to_line(&self) -> Result<String, GossipError> {
+        check(&self.name)?;
+        return Ok(format!(
+            "{} {} {} {}",
+            self.state.as_str(),
+            self.incarnation,
+            self.weight,
+            self.name
+        ));
+    }
+
+    fn from_line(line: &str) -> Option<Member> {
+        let mut parts = line.splitn(4, ' ');
+        let state = match parts.next()? {
+            "alive" => MemberState::Alive,
+            "suspect" => MemberState::Suspect,
+            "dead" => MemberState::Dead,
+            _ => return None,
+        };
+        let incarnation = parts.next()?.parse().ok()?;
+        let weight = parts.next()?.parse().ok()?;
+        let name = parts.next()?.to_string();
+        check(&name).ok()?;
+        return Some(Member {
+            name,
+            weight,
+            incarnation,
+            state,
+        });
+    }
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, GossipError> {
+        let mut out = String::new();
+        out.push_str(MAGIC);
+        out.push('\n');
+        match &self.kind {
+            Kind::Ping => out.push_str(&format!("ping {}\n", self.seq)),
+            Kind::Ack => out.push_str(&format!("ack {}\n", self.seq)),
+            Kind::PingReq(target) => {
+                check(target)?;
+                out.push_str(&format!("ping-req {}\ntarget {}\n", self.seq, target));
+            }
+        }
+        out.push_str(&format!("from {}\n", self.from.to_line()?));
+        for member in self.updates.iter() {
+            out.push_str(&format!("member {}\n", member.to_line()?));
+        }
+        return Ok(out.into_bytes());
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Message, GossipError> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| GossipError::Parse(0, "Not valid UTF-8".to_string()))?;
+        let mut kind = None;
+        let mut seq = 0;
+        let mut from = None;
+        let mut updates = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let bad = |msg: &str| GossipError::Parse(n + 1, msg.to_string());
+            if n == 0 {
+                if line != MAGIC {
+                    return Err(bad("Not a flexihash gossip message"));
+                }
+                continue;
+            }
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad("Missing value"))?;
+            match key {
+                "ping" | "ack" | "ping-req" => {
+                    seq = value.parse().map_err(|_| bad("Invalid sequence number"))?;
+                    kind = Some(match key {
+                        "ping" => Kind::Ping,
+                        "ack" => Kind::Ack,
+                        // filled in by the target line
+                        _ => Kind::PingReq(String::new()),
+                    });
+                }
+                "target" => {
+                    check(value).map_err(|_| bad("Invalid target"))?;
+                    kind = Some(Kind::PingReq(value.to_string()));
+                }
+                "from" => {
+                    from = Some(Member::from_line(value).ok_or_else(|| bad("Invalid member"))?)
+                }
+                "member" => {
+                    updates.push(Member::from_line(value).ok_or_else(|| bad("Invalid member"))?)
+                }
+                _ => return Err(bad("Unknown field")),
+            }
+        }
+        let missing = |what: &str| GossipError::Parse(0, format!("Missing {}", what));
+        let kind = match kind {
+            Some(Kind::PingReq(target)) if target.is_empty() => return Err(missing("target")),
+            Some(kind) => kind,
+            None => return Err(missing("message type")),
+        };
+        return Ok(Message {
+            kind,
+            seq,
+            from: from.ok_or_else(|| missing("sender"))?,
+            updates,
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    // how often each member probes another
+    pub probe_interval: Duration,
+    // how long to wait for an ack before asking others to probe too
+    pub probe_timeout: Duration,
+    // how long a suspected member has to refute it before it's dead
+    pub suspect_timeout: Duration,
+    pub indirect_probes: usize,
+    // updates piggybacked on each message
+    pub max_updates: usize,
+}
+
+impl GossipConfig {
+    pub fn new() -> GossipConfig {
+        return GossipConfig {
+            probe_interval: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(300),
+            suspect_timeout: Duration::from_secs(5),
+            indirect_probes: 3,
+            max_updates: 8,
+        };
+    }
+}
+
+impl Default for GossipConfig {
+    fn default() -> GossipConfig {
+        return GossipConfig::new();
+    }
+}
+
+#[derive(Debug)]
+struct Probe {
+    target: Target,
+    seq: u64,
+    sent: Instant,
+    indirect: bool,
+}
+
+#[derive(Debug)]
+pub struct Gossip {
+    me: Member,
+    config: GossipConfig,
+    ring: Arc<SharedRing>,
+    // everyone else, and when their state last changed
+    members: BTreeMap<Target, (Member, Instant)>,
+    // updates still to be passed on, and how many more times
+    updates: Vec<(Member, usize)>,
+    seq: u64,
+    probe: Option<Probe>,
+    next_probe: Option<Instant>,
+    last_probed: Option<Target>,
+    // pings sent for someone else's ping-req: our seq -> their name, seq
+    relays: HashMap<u64, (Target, u64, Instant)>,
+}
+
+type Outgoing = Vec<(Target, Message)>;
+
+impl Gossip {
+    // Puts this member in the ring straight away
+    pub fn new<S: Into<String>>(
+        name: S,
+        weight: u32,
+        ring: Arc<SharedRing>,
+        config: GossipConfig,
+    ) -> Gossip {
+        let mut gossip = Gossip {
+            me: Member {
+                name: name.into(),
+                weight,
+                incarnation: 0,
+                state: MemberState::Alive,
+            },
+            config,
+            ring,
+            members: BTreeMap::new(),
+            updates: Vec::new(),
+            seq: 0,
+            probe: None,
+            next_probe: None,
+            last_probed: None,
+            relays: HashMap::new(),
+        };
+        gossip.sync_ring();
+        return gossip;
+    }
+
+    pub fn me(&self) -> &Member {
+        return &self.me;
+    }
+
+    pub fn ring(&self) -> &Arc<SharedRing> {
+        return &self.ring;
+    }
+
+    // Everyone we know of, including ourselves, by name
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.values().map(|m| m.0.clone()).collect();
+        let index = members.partition_point(|m| m.name < self.me.name);
+        members.insert(index, self.me.clone());
+        return members;
+    }
+
+    // Say hello to any one member already in the cluster; it replies with
+    // everyone it knows, and tells the rest about us
+    pub fn join<S: Into<String>>(&mut self, seed: S) -> Outgoing {
+        let seq = self.next_seq();
+        return vec![(seed.into(), self.message(Kind::Ping, seq))];
+    }
+
+    // Tell everyone we're going; after this, nothing is sent or received
+    pub fn leave(&mut self) -> Outgoing {
+        self.me.incarnation += 1;
+        self.me.state = MemberState::Dead;
+        self.sync_ring();
+        let names: Vec<Target> = self.live_members().cloned().collect();
+        let seq = self.next_seq();
+        return names
+            .into_iter()
+            .map(|name| (name, self.message(Kind::Ping, seq)))
+            .collect();
+    }
+
+    pub fn set_weight(&mut self, weight: u32) {
+        self.me.weight = weight;
+        self.me.incarnation += 1;
+        self.queue(self.me.clone());
+        self.sync_ring();
+    }
+
+    pub fn handle(&mut self, message: Message, now: Instant) -> Outgoing {
+        let mut out = Vec::new();
+        if self.me.state == MemberState::Dead {
+            return out;
+        }
+        let sender = message.from.name.clone();
+        let leaving = message.from.state == MemberState::Dead;
+        let pinged = message.kind == Kind::Ping;
+        let known = self
+            .members
+            .get(&sender)
+            .is_some_and(|(m, _)| m.state != MemberState::Dead);
+        let mut changed = self.merge(message.from, now);
+        for update in message.updates {
+            changed |= self.merge(update, now);
+        }
+        // before replying, so that anyone the ring turned away isn't in it
+        if changed {
+            self.sync_ring();
+        }
+        // Still dead as far as we're concerned, but evidently not; nobody
+        // probes the dead, so unless we say, it'll never know to refute it.
+        // A ping's ack carries it anyway, with everyone else.
+        let dead = match self.members.get(&sender) {
+            Some((m, _)) if m.state == MemberState::Dead && !leaving => Some(m.clone()),
+            _ => None,
+        }
+        .filter(|_| !pinged);
+        match message.kind {
+            Kind::Ping => {
+                let mut ack = self.message(Kind::Ack, message.seq);
+                if !known {
+                    ack.updates.extend(self.members());
+                }
+                out.push((sender.clone(), ack));
+            }
+            Kind::PingReq(target) => {
+                let seq = self.next_seq();
+                self.relays.insert(seq, (sender.clone(), message.seq, now));
+                out.push((target, self.message(Kind::Ping, seq)));
+            }
+            Kind::Ack => {
+                if self.probe.as_ref().map(|p| p.seq) == Some(message.seq) {
+                    self.probe = None;
+                }
+                if let Some((requester, seq, _)) = self.relays.remove(&message.seq) {
+                    out.push((requester, self.message(Kind::Ack, seq)));
+                }
+            }
+        }
+        if let Some(dead) = dead {
+            // seq 0 is never a probe's, so this is only news
+            let mut news = self.message(Kind::Ack, 0);
+            news.updates.push(dead);
+            out.push((sender, news));
+        }
+        return out;
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Outgoing {
+        let mut out = Vec::new();
+        if self.me.state == MemberState::Dead {
+            return out;
+        }
+        let mut changed = false;
+        let interval = self.config.probe_interval;
+        self.relays.retain(|_, relay| now < relay.2 + interval);
+
+        if let Some(mut probe) = self.probe.take() {
+            if now >= probe.sent + interval {
+                // nothing back all round: suspect it
+                if let Some((member, _)) = self.members.get(&probe.target) {
+                    if member.state == MemberState::Alive {
+                        let mut suspect = member.clone();
+                        suspect.state = MemberState::Suspect;
+                        changed |= self.merge(suspect, now);
+                    }
+                }
+            } else {
+                if !probe.indirect && now >= probe.sent + self.config.probe_timeout {
+                    probe.indirect = true;
+                    for helper in self.helpers(&probe.target) {
+                        let request = self.message(Kind::PingReq(probe.target.clone()), probe.seq);
+                        out.push((helper, request));
+                    }
+                }
+                self.probe = Some(probe);
+            }
+        }
+
+        let timeout = self.config.suspect_timeout;
+        let expired: Vec<Member> = self
+            .members
+            .values()
+            .filter(|(m, since)| m.state == MemberState::Suspect && now >= *since + timeout)
+            .map(|(m, _)| Member {
+                state: MemberState::Dead,
+                ..m.clone()
+            })
+            .collect();
+        for member in expired {
+            changed |= self.merge(member, now);
+        }
+
+        if self.probe.is_none() && self.next_probe.is_none_or(|t| now >= t) {
+            if let Some(target) = self.next_target() {
+                let seq = self.next_seq();
+                out.push((target.clone(), self.message(Kind::Ping, seq)));
+                self.probe = Some(Probe {
+                    target,
+                    seq,
+                    sent: now,
+                    indirect: false,
+                });
+            }
+            self.next_probe = Some(now + interval);
+        }
+
+        if changed {
+            self.sync_ring();
+        }
+        return out;
+    }
+
+    // Take in news of a member; true if it changed anything
+    fn merge(&mut self, update: Member, now: Instant) -> bool {
+        if update.name == self.me.name {
+            if update.state != MemberState::Alive && update.incarnation >= self.me.incarnation {
+                self.me.incarnation = update.incarnation + 1;
+                self.queue(self.me.clone());
+            }
+            return false;
+        }
+        let newer = match self.members.get(&update.name) {
+            None => update.state != MemberState::Dead,
+            Some((known, _)) => match update.state {
+                MemberState::Alive => update.incarnation > known.incarnation,
+                MemberState::Suspect => {
+                    update.incarnation > known.incarnation
+                        || (update.incarnation == known.incarnation
+                            && known.state == MemberState::Alive)
+                }
+                MemberState::Dead => {
+                    update.incarnation > known.incarnation
+                        || (update.incarnation == known.incarnation
+                            && known.state != MemberState::Dead)
+                }
+            },
+        };
+        if newer {
+            self.queue(update.clone());
+            self.members.insert(update.name.clone(), (update, now));
+        }
+        return newer;
+    }
+
+    fn queue(&mut self, member: Member) {
+        // enough times over that everyone very probably hears
+        let n = self.members.len() + 2;
+        let times = 3 * (usize::BITS - n.leading_zeros()) as usize;
+        self.updates.retain(|(m, _)| m.name != member.name);
+        self.updates.push((member, times));
+    }
+
+    fn message(&mut self, kind: Kind, seq: u64) -> Message {
+        let n = self.config.max_updates.min(self.updates.len());
+        let mut updates = Vec::with_capacity(n);
+        for (member, times) in self.updates.iter_mut().take(n) {
+            updates.push(member.clone());
+            *times -= 1;
+        }
+        self.updates.retain(|(_, times)| *times > 0);
+        // the least passed on go first next time
+        self.updates
+            .sort_by_key(|(_, times)| std::cmp::Reverse(*times));
+        return Message {
+            kind,
+            seq,
+            from: self.me.clone(),
+            updates,
+        };
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        return self.seq;
+    }
+
+    fn live_members(&self) -> impl Iterator<Item = &Target> {
+        return self
+            .members
+            .values()
+            .filter(|(m, _)| m.state != MemberState::Dead)
+            .map(|(m, _)| &m.name);
+    }
+
+    // Round robin, in name order
+    fn next_target(&mut self) -> Option<Target> {
+        let last = self.last_probed.clone();
+        let next = self
+            .live_members()
+            .find(|name| last.as_ref().is_none_or(|l| *name > l))
+            .or_else(|| self.live_members().next())
+            .cloned();
+        self.last_probed = next.clone();
+        return next;
+    }
+
+    fn helpers(&self, target: &str) -> Vec<Target> {
+        return self
+            .members
+            .values()
+            .filter(|(m, _)| m.state == MemberState::Alive && m.name != target)
+            .map(|(m, _)| m.name.clone())
+            .take(self.config.indirect_probes)
+            .collect();
+    }
+
+    // Members whose weights the ring can't take (eg a peer advertising
+    // a weight which would blow through max_total_positions) are left out
+    // of it, and forgotten rather than passed on
+    fn sync_ring(&mut self) {
+        let mut rejected: Vec<Target> = Vec::new();
+        self.ring.update(|fh| {
+            for member in self.members.values().map(|m| &m.0).chain([&self.me]) {
+                let present = fh.get_target_info(&member.name).map(|info| info.weight);
+                let result = match (member.state, present) {
+                    (MemberState::Dead, Some(_)) => {
+                        fh.remove_target(&member.name);
+                        continue;
+                    }
+                    (MemberState::Dead, None) => continue,
+                    (_, None) => fh
+                        .try_add_target(member.name.as_str(), member.weight)
+                        .map(|_| ()),
+                    (_, Some(weight)) if weight != member.weight => fh
+                        .try_update_target_weight(&member.name, member.weight)
+                        .map(|_| ()),
+                    _ => continue,
+                };
+                if result.is_err() {
+                    rejected.push(member.name.clone());
+                }
+            }
+        });
+        for name in rejected {
+            self.members.remove(&name);
+            self.updates.retain(|(m, _)| m.name != name);
+        }
+    }
+}
+
+/*
+ * Runs a Gossip over UDP on a thread of its own, until the returned driver
+ * is dropped - at which point it leaves the cluster. Messages which fail
+ * to send or to parse are dropped, the same as lost packets.
+ */
+pub fn start_udp(
+    mut gossip: Gossip,
+    socket: UdpSocket,
+    seeds: Vec<String>,
+) -> Result<GossipDriver, GossipError> {
+    let poll = gossip.config.probe_timeout / 4;
+    socket.set_read_timeout(Some(poll.max(Duration::from_millis(1))))?;
+    let (stop, stopped) = mpsc::channel::<()>();
+    let handle = std::thread::spawn(move || {
+        let send = |out: Outgoing| {
+            for (to, message) in out {
+                if let Ok(data) = message.to_bytes() {
+                    let _ = socket.send_to(&data, to.as_str());
+                }
+            }
+        };
+        for seed in seeds {
+            send(gossip.join(seed));
+        }
+        let mut buf = vec![0u8; 65536];
+        loop {
+            if !matches!(stopped.try_recv(), Err(mpsc::TryRecvError::Empty)) {
+                send(gossip.leave());
+                return;
+            }
+            if let Ok((len, _)) = socket.recv_from(&mut buf) {
+                if let Ok(message) = Message::from_bytes(&buf[..len]) {
+                    send(gossip.handle(message, Instant::now()));
+                }
+            }
+            send(gossip.tick(Instant::now()));
+        }
+    });
+    return Ok(GossipDriver {
+        stop,
+        handle: Some(handle),
+    });
+}
+
+#[derive(Debug)]
+pub struct GossipDriver {
+    stop: mpsc::Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for GossipDriver {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_gossip {
+    use super::*;
+    use crate::Flexihash;
+    use std::collections::HashSet;
+
+    // A pretend network, with a clock we can move along by hand
+    struct Net {
+        nodes: BTreeMap<String, Gossip>,
+        down: HashSet<String>,
+        now: Instant,
+    }
+
+    impl Net {
+        fn new(names: &[&str]) -> Net {
+            let nodes = names
+                .iter()
+                .map(|name| {
+                    let ring = Arc::new(SharedRing::new(Flexihash::new()));
+                    (
+                        name.to_string(),
+                        Gossip::new(*name, 1, ring, GossipConfig::new()),
+                    )
+                })
+                .collect();
+            return Net {
+                nodes,
+                down: HashSet::new(),
+                now: Instant::now(),
+            };
+        }
+
+        fn deliver(&mut self, mut queue: Outgoing) {
+            while let Some((to, message)) = queue.pop() {
+                if self.down.contains(&to) || self.down.contains(&message.from.name) {
+                    continue;
+                }
+                let message = Message::from_bytes(&message.to_bytes().unwrap()).unwrap();
+                if let Some(node) = self.nodes.get_mut(&to) {
+                    queue.extend(node.handle(message, self.now));
+                }
+            }
+        }
+
+        fn run(&mut self, steps: u32) {
+            for _ in 0..steps {
+                self.now += Duration::from_millis(100);
+                let names: Vec<String> = self.nodes.keys().cloned().collect();
+                for name in names {
+                    if !self.down.contains(&name) {
+                        let out = self.nodes.get_mut(&name).unwrap().tick(self.now);
+                        self.deliver(out);
+                    }
+                }
+            }
+        }
+
+        fn join(&mut self, name: &str, seed: &str) {
+            let out = self.nodes.get_mut(name).unwrap().join(seed);
+            self.deliver(out);
+        }
+
+        fn targets(&self, name: &str) -> Vec<Target> {
+            let mut targets = self.nodes[name].ring().snapshot().get_all_targets();
+            targets.sort();
+            return targets;
+        }
+    }
+
+    fn cluster() -> Net {
+        let mut net = Net::new(&["n-a", "n-b", "n-c", "n-d"]);
+        net.join("n-b", "n-a");
+        net.join("n-c", "n-a");
+        net.join("n-d", "n-c");
+        net.run(30);
+        return net;
+    }
+
+    #[test]
+    fn joins_spread() {
+        let net = cluster();
+        for name in ["n-a", "n-b", "n-c", "n-d"] {
+            assert_eq!(net.targets(name), ["n-a", "n-b", "n-c", "n-d"], "{}", name);
+        }
+        let members = net.nodes["n-a"].members();
+        assert_eq!(members.len(), 4);
+        assert!(members.iter().all(|m| m.state == MemberState::Alive));
+    }
+
+    #[test]
+    fn failures_are_detected() {
+        let mut net = cluster();
+        net.down.insert("n-c".to_string());
+        let suspected = |net: &Net| {
+            net.nodes["n-a"]
+                .members()
+                .iter()
+                .any(|m| m.name == "n-c" && m.state == MemberState::Suspect)
+        };
+        for _ in 0..50 {
+            if suspected(&net) {
+                break;
+            }
+            net.run(1);
+        }
+        // suspected members stay in the ring until they time out
+        assert!(suspected(&net));
+        assert_eq!(net.targets("n-a"), ["n-a", "n-b", "n-c", "n-d"]);
+        net.run(100);
+        for name in ["n-a", "n-b", "n-d"] {
+            assert_eq!(net.targets(name), ["n-a", "n-b", "n-d"], "{}", name);
+        }
+    }
+
+    #[test]
+    fn suspicion_is_refuted() {
+        let mut net = cluster();
+        let rumour = Member {
+            name: "n-c".to_string(),
+            weight: 1,
+            incarnation: 0,
+            state: MemberState::Suspect,
+        };
+        let now = net.now;
+        let b = net.nodes.get_mut("n-b").unwrap();
+        assert!(b.merge(rumour.clone(), now));
+        assert!(!b.merge(rumour, now));
+        net.run(30);
+        for name in ["n-a", "n-b", "n-d"] {
+            let c = net.nodes[name]
+                .members()
+                .into_iter()
+                .find(|m| m.name == "n-c")
+                .unwrap();
+            assert_eq!(
+                (c.state, c.incarnation),
+                (MemberState::Alive, 1),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn the_dead_come_back() {
+        let mut net = cluster();
+        net.down.insert("n-c".to_string());
+        net.run(300);
+        for name in ["n-a", "n-b", "n-d"] {
+            assert_eq!(net.targets(name), ["n-a", "n-b", "n-d"], "{}", name);
+            // by now the news has stopped going round
+            assert!(net.nodes[name].updates.is_empty(), "{}", name);
+        }
+
+        net.down.clear();
+        net.run(30);
+        for name in ["n-a", "n-b", "n-c", "n-d"] {
+            assert_eq!(net.targets(name), ["n-a", "n-b", "n-c", "n-d"], "{}", name);
+        }
+        assert!(net.nodes["n-c"].me().incarnation > 0);
+    }
+
+    #[test]
+    fn restarts_rejoin() {
+        let mut net = cluster();
+        net.down.insert("n-c".to_string());
+        net.run(100);
+        net.down.clear();
+        // a fresh start, at incarnation 0 again
+        let ring = Arc::new(SharedRing::new(Flexihash::new()));
+        let restarted = Gossip::new("n-c", 1, ring, GossipConfig::new());
+        net.nodes.insert("n-c".to_string(), restarted);
+        net.join("n-c", "n-a");
+        net.run(30);
+        for name in ["n-a", "n-b", "n-c", "n-d"] {
+            assert_eq!(net.targets(name), ["n-a", "n-b", "n-c", "n-d"], "{}", name);
+        }
+    }
+
+    #[test]
+    fn leaving() {
+        let mut net = cluster();
+        let out = net.nodes.get_mut("n-d").unwrap().leave();
+        net.deliver(out);
+        assert!(net.targets("n-d").iter().all(|t| t != "n-d"));
+        for name in ["n-a", "n-b", "n-c"] {
+            assert_eq!(net.targets(name), ["n-a", "n-b", "n-c"], "{}", name);
+        }
+        assert!(net.nodes.get_mut("n-d").unwrap().tick(net.now).is_empty());
+    }
+
+    #[test]
+    fn weight_changes_spread() {
+        let mut net = cluster();
+        net.nodes.get_mut("n-b").unwrap().set_weight(3);
+        net.run(30);
+        let ring = net.nodes["n-d"].ring().snapshot();
+        assert_eq!(ring.get_target_info("n-b").unwrap().weight, 3);
+    }
+
+    #[test]
+    fn oversized_weights_are_dropped() {
+        let mut net = cluster();
+        let now = net.now;
+        let a = net.nodes.get_mut("n-a").unwrap();
+        let data = b"flexihash gossip 1\nping 1\nfrom alive 0 4294967295 n-evil\n";
+        let out = a.handle(Message::from_bytes(data).unwrap(), now);
+        assert!(a.ring().snapshot().get_target_info("n-evil").is_none());
+        assert!(a.members().iter().all(|m| m.name != "n-evil"));
+        // and not passed on to anyone else
+        for (_, message) in out {
+            assert!(message.updates.iter().all(|m| m.name != "n-evil"));
+        }
+        net.run(30);
+        assert_eq!(net.targets("n-b"), ["n-a", "n-b", "n-c", "n-d"]);
+    }
+
+    #[test]
+    fn encoding() {
+        let message = Message {
+            kind: Kind::PingReq("n b".to_string()),
+            seq: 7,
+            from: Member {
+                name: "n-a".to_string(),
+                weight: 2,
+                incarnation: 3,
+                state: MemberState::Alive,
+            },
+            updates: vec![Member {
+                name: "n-c".to_string(),
+                weight: 1,
+                incarnation: 0,
+                state: MemberState::Dead,
+            }],
+        };
+        let data = message.to_bytes().unwrap();
+        assert_eq!(Message::from_bytes(&data).unwrap(), message);
+        assert!(matches!(
+            Message::from_bytes(b"flexihash gossip 1\nping 1\n"),
+            Err(GossipError::Parse(0, _))
+        ));
+        assert!(matches!(
+            Message::from_bytes(b"flexihash gossip 1\nping 1\nfrom alive x 1 n-a\n"),
+            Err(GossipError::Parse(3, _))
+        ));
+        assert!(matches!(
+            Message::from_bytes(b"flexihash gossip 1\nping 1\nfrom alive 0 1 \n"),
+            Err(GossipError::Parse(3, _))
+        ));
+    }
+
+    #[test]
+    fn over_udp() {
+        let config = GossipConfig {
+            probe_interval: Duration::from_millis(20),
+            probe_timeout: Duration::from_millis(8),
+            ..GossipConfig::new()
+        };
+        let start = |seeds: Vec<String>| {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let name = socket.local_addr().unwrap().to_string();
+            let ring = Arc::new(SharedRing::new(Flexihash::new()));
+            let gossip = Gossip::new(name.clone(), 1, ring.clone(), config.clone());
+            (name, ring, start_udp(gossip, socket, seeds).unwrap())
+        };
+        let wait_for = |ring: &SharedRing, n: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while ring.snapshot().get_all_targets().len() != n && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            ring.snapshot().get_all_targets().len()
+        };
+        let (seed, ring_a, _a) = start(vec![]);
+        let (_, ring_b, b) = start(vec![seed]);
+        assert_eq!(wait_for(&ring_a, 2), 2);
+        assert_eq!(wait_for(&ring_b, 2), 2);
+        drop(b);
+        assert_eq!(wait_for(&ring_a, 1), 1);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::shared::SharedRing;
+use crate::Error;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("flexihash");
+}
+
+use proto::ring_server::{Ring, RingServer};
+use proto::*;
+
+/*
+ * Serves lookups from one authoritative ring, so that clients in other
+ * languages don't need their own implementation (and can't disagree with
+ * each other about where keys live). See proto/flexihash.proto.
+ */
+#[derive(Debug, Clone)]
+pub struct RingService {
+    ring: Arc<SharedRing>,
+}
+
+impl RingService {
+    pub fn new(ring: Arc<SharedRing>) -> RingService {
+        return RingService { ring };
+    }
+
+    pub fn into_server(self) -> RingServer<RingService> {
+        return RingServer::new(self);
+    }
+
+    fn topology(&self) -> Topology {
+        let snapshot = self.ring.snapshot().snapshot();
+        return Topology {
+            hasher: snapshot.hasher.redacted(),
+            replicas: snapshot.replicas,
+            targets: snapshot
+                .targets
+                .into_iter()
+                .map(|(name, info)| proto::TargetInfo {
+                    name,
+                    weight: info.weight,
+                    zone: info.zone,
+                    labels: info.labels.into_iter().collect(),
+                })
+                .collect(),
+        };
+    }
+}
+
+fn error_status(error: Error) -> Status {
+    return match error {
+        Error::TargetExists(_) => Status::already_exists(error.to_string()),
+        Error::TargetMissing(..) => Status::not_found(error.to_string()),
+        Error::TooManyPositions(..) => Status::resource_exhausted(error.to_string()),
+    };
+}
+
+#[tonic::async_trait]
+impl Ring for RingService {
+    async fn lookup(
+        &self,
+        request: Request<LookupRequest>,
+    ) -> Result<Response<LookupResponse>, Status> {
+        let key = request.into_inner().key;
+        return match self.ring.snapshot().lookup_list(key, 1).pop() {
+            Some(target) => Ok(Response::new(LookupResponse { target })),
+            None => Err(Status::failed_precondition("No targets set")),
+        };
+    }
+
+    async fn lookup_list(
+        &self,
+        request: Request<LookupListRequest>,
+    ) -> Result<Response<LookupListResponse>, Status> {
+        let request = request.into_inner();
+        if request.count == 0 {
+            return Err(Status::invalid_argument(
+                "Need to request at least 1 resource",
+            ));
+        }
+        let targets = self.ring.snapshot().lookup_list(request.key, request.count);
+        return Ok(Response::new(LookupListResponse { targets }));
+    }
+
+    async fn get_topology(
+        &self,
+        _request: Request<GetTopologyRequest>,
+    ) -> Result<Response<Topology>, Status> {
+        return Ok(Response::new(self.topology()));
+    }
+
+    async fn apply_delta(&self, request: Request<Delta>) -> Result<Response<Topology>, Status> {
+        let delta = request.into_inner();
+        let remove = delta.remove;
+        let mut add: Vec<(String, u32)> = delta.add.into_iter().collect();
+        add.sort();
+        let mut set_weight: Vec<(String, u32)> = delta.set_weight.into_iter().collect();
+        set_weight.sort();
+
+        self.ring
+            .update(|fh| {
+                fh.transaction(|tx| {
+                    for target in remove {
+                        tx.remove(target);
+                    }
+                    for (target, weight) in add {
+                        tx.add(target, weight);
+                    }
+                    for (target, weight) in set_weight {
+                        tx.set_weight(target, weight);
+                    }
+                })
+                .map(|_| ())
+            })
+            .map_err(error_status)?;
+        return Ok(Response::new(self.topology()));
+    }
+}
+
+#[cfg(test)]
+mod test_grpc {
+    use super::*;
+    use crate::Flexihash;
+
+    fn service() -> RingService {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 2);
+        return RingService::new(Arc::new(SharedRing::new(fh)));
+    }
+
+    #[tokio::test]
+    async fn lookups_match_ring() {
+        let service = service();
+        let expected = service.ring.snapshot().lookup("resource");
+        let response = service
+            .lookup(Request::new(LookupRequest {
+                key: b"resource".to_vec(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().target, expected);
+
+        let response = service
+            .lookup_list(Request::new(LookupListRequest {
+                key: b"resource".to_vec(),
+                count: 5,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().targets.len(), 2);
+
+        let error = service
+            .lookup_list(Request::new(LookupListRequest {
+                key: b"resource".to_vec(),
+                count: 0,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn apply_delta_is_all_or_nothing() {
+        let service = service();
+        let topology = service
+            .apply_delta(Request::new(Delta {
+                remove: vec!["t-a".to_string()],
+                add: vec![("t-c".to_string(), 3)].into_iter().collect(),
+                set_weight: vec![("t-b".to_string(), 1)].into_iter().collect(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let targets: Vec<(&str, u32)> = topology
+            .targets
+            .iter()
+            .map(|t| (t.name.as_str(), t.weight))
+            .collect();
+        assert_eq!(targets, [("t-b", 1), ("t-c", 3)]);
+        assert_eq!(topology.hasher, "crc32");
+
+        let error = service
+            .apply_delta(Request::new(Delta {
+                remove: vec!["t-b".to_string(), "t-missing".to_string()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::NotFound);
+        assert_eq!(service.ring.snapshot().get_all_targets(), ["t-b", "t-c"]);
+
+        service
+            .apply_delta(Request::new(Delta {
+                remove: vec!["t-b".to_string(), "t-c".to_string()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let error = service
+            .lookup(Request::new(LookupRequest { key: vec![] }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+}
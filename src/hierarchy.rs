@@ -0,0 +1,437 @@
+use crate::balance::mix;
+use crate::snapshot::checksum;
+use crate::{Flexihash, ResourceKey, Target};
+
+/*
+ * Hierarchical placement, after Ceph's CRUSH: targets live at the bottom of
+ * a tree of failure domains (eg datacenter -> rack -> host -> target), and
+ * replicas are picked by walking down the tree, so that a rule can say
+ * "2 copies in dc-a on different racks, 1 in dc-b" - which a flat ring
+ * order can't.
+ *
+ * Each branch is picked from its siblings straw2-style: every child draws
+ * ln(hash) / weight and the longest straw wins, so a child gets its share
+ * by weight, and changing one child's weight only moves keys to or from
+ * that child. A bucket weighs as much as the targets under it.
+ *
+ * This is separate from the ring and has its own hashing, so it gives the
+ * same answers whatever the ring's hasher or which hash features are on.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hierarchy {
+    levels: Vec<String>,
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node {
+    name: String,
+    weight: u64,
+    // buckets above the bottom level, targets (with no children) below it
+    children: Vec<Node>,
+}
+
+// How many times a pick can land somewhere already used before giving up
+// on the rest of a placement
+const RETRIES: u32 = 50;
+
+/*
+ * One step of a placement: count targets from under the given branch (an
+ * empty path being the whole tree), each in a different failure domain of
+ * the given level. Placement::any only keeps the targets themselves apart.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    under: Vec<String>,
+    count: usize,
+    distinct: Option<String>,
+}
+
+impl Placement {
+    pub fn new<S: AsRef<str>>(under: &[S], count: usize, distinct: &str) -> Placement {
+        return Placement {
+            under: under.iter().map(|s| s.as_ref().to_string()).collect(),
+            count,
+            distinct: Some(distinct.to_string()),
+        };
+    }
+
+    pub fn any<S: AsRef<str>>(under: &[S], count: usize) -> Placement {
+        return Placement {
+            under: under.iter().map(|s| s.as_ref().to_string()).collect(),
+            count,
+            distinct: None,
+        };
+    }
+}
+
+impl Hierarchy {
+    // Levels from the top down, eg ["datacenter", "rack", "host"]
+    pub fn new<S: Into<String>>(levels: Vec<S>) -> Hierarchy {
+        return Hierarchy {
+            levels: levels.into_iter().map(|l| l.into()).collect(),
+            root: Node {
+                name: String::new(),
+                weight: 0,
+                children: Vec::new(),
+            },
+        };
+    }
+
+    // Build from a ring's targets, using the label of each level's name
+    // (or the zone, for a level called "zone") as the target's path.
+    // Targets missing any of them are left out.
+    pub fn from_ring<S: Into<String>>(fh: &Flexihash, levels: Vec<S>) -> Hierarchy {
+        let mut hierarchy = Hierarchy::new(levels);
+        let mut targets: Vec<_> = fh.target_info.iter().collect();
+        targets.sort_by(|a, b| a.0.cmp(b.0));
+        for (target, info) in targets {
+            let path: Option<Vec<&str>> = hierarchy
+                .levels
+                .iter()
+                .map(|level| match level.as_str() {
+                    "zone" => info.zone.as_deref(),
+                    _ => info.labels.get(level).map(|l| l.as_str()),
+                })
+                .collect();
+            if let Some(path) = path {
+                hierarchy.add_target(&path, target.as_str(), info.weight);
+            }
+        }
+        return hierarchy;
+    }
+
+    pub fn levels(&self) -> &[String] {
+        return &self.levels;
+    }
+
+    // Adding a target which is already somewhere in the tree moves it
+    pub fn add_target<S: AsRef<str>>(&mut self, path: &[S], target: &str, weight: u32) {
+        if path.len() != self.levels.len() {
+            panic!(
+                "A target's path needs {} levels, got {}",
+                self.levels.len(),
+                path.len()
+            );
+        }
+        if self.contains(target) {
+            self.remove_target(target);
+        }
+        let mut node = &mut self.root;
+        for name in path.iter().map(|s| s.as_ref()) {
+            node.weight += weight as u64;
+            let index = match node.children.iter().position(|c| c.name == name) {
+                Some(index) => index,
+                None => {
+                    node.children.push(Node {
+                        name: name.to_string(),
+                        weight: 0,
+                        children: Vec::new(),
+                    });
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[index];
+        }
+        node.weight += weight as u64;
+        node.children.push(Node {
+            name: target.to_string(),
+            weight: weight as u64,
+            children: Vec::new(),
+        });
+    }
+
+    // Failure domains left empty are removed along with the target
+    pub fn remove_target(&mut self, target: &str) {
+        if !Hierarchy::remove_from(&mut self.root, target, self.levels.len()) {
+            panic!("Target '{}' does not exist", target);
+        }
+    }
+
+    fn remove_from(node: &mut Node, target: &str, depth: usize) -> bool {
+        if depth == 0 {
+            if let Some(index) = node.children.iter().position(|c| c.name == target) {
+                node.weight -= node.children.remove(index).weight;
+                return true;
+            }
+            return false;
+        }
+        for index in 0..node.children.len() {
+            let before = node.children[index].weight;
+            if Hierarchy::remove_from(&mut node.children[index], target, depth - 1) {
+                node.weight -= before - node.children[index].weight;
+                if node.children[index].children.is_empty() {
+                    node.children.remove(index);
+                }
+                return true;
+            }
+        }
+        return false;
+    }
+
+    pub fn contains(&self, target: &str) -> bool {
+        return self.targets().iter().any(|t| t == target);
+    }
+
+    pub fn targets(&self) -> Vec<Target> {
+        let mut nodes = vec![&self.root];
+        for _ in 0..=self.levels.len() {
+            nodes = nodes.iter().flat_map(|n| n.children.iter()).collect();
+        }
+        return nodes.into_iter().map(|n| n.name.clone()).collect();
+    }
+
+    // The targets for a resource, placement by placement. A target is
+    // never picked twice, but failure domains are only kept apart within
+    // each placement. When a branch runs out of domains (or targets) to
+    // spread over, its placement comes up short rather than doubling up.
+    pub fn place<K: ResourceKey>(&self, resource: K, placements: &[Placement]) -> Vec<Target> {
+        let key = resource.ring_key();
+        let key = key.as_ref();
+        let mut chosen: Vec<Target> = Vec::new();
+        for placement in placements {
+            let start = self.find(&placement.under);
+            let depth = match &placement.distinct {
+                Some(level) => match self.levels.iter().position(|l| l == level) {
+                    Some(index) if index >= placement.under.len() => index + 1,
+                    Some(_) => panic!(
+                        "Can't keep {}s apart under {}",
+                        level,
+                        placement.under.join("/")
+                    ),
+                    None => panic!("Failure domain level '{}' does not exist", level),
+                },
+                None => self.levels.len() + 1,
+            };
+            let mut domains: Vec<&Node> = Vec::new();
+            let mut r = 0;
+            let mut failures = 0;
+            while domains.len() < placement.count && failures < RETRIES {
+                let domain = match descend(start, depth - placement.under.len(), key, r) {
+                    Some(domain) => domain,
+                    None => break,
+                };
+                let target = descend(domain, self.levels.len() + 1 - depth, key, r);
+                r += 1;
+                match target {
+                    Some(target)
+                        if !domains.iter().any(|d| std::ptr::eq(*d, domain))
+                            && !chosen.contains(&target.name) =>
+                    {
+                        domains.push(domain);
+                        chosen.push(target.name.clone());
+                    }
+                    _ => failures += 1,
+                }
+            }
+        }
+        return chosen;
+    }
+
+    fn find(&self, path: &[String]) -> &Node {
+        if path.len() > self.levels.len() {
+            panic!("Failure domain '{}' does not exist", path.join("/"));
+        }
+        let mut node = &self.root;
+        for name in path {
+            node = match node.children.iter().find(|c| &c.name == name) {
+                Some(child) => child,
+                None => panic!("Failure domain '{}' does not exist", path.join("/")),
+            };
+        }
+        return node;
+    }
+}
+
+// Pick one branch per level, `levels` levels down
+fn descend<'a>(mut node: &'a Node, levels: usize, key: &[u8], r: u32) -> Option<&'a Node> {
+    for _ in 0..levels {
+        node = straw2(node, key, r)?;
+    }
+    return Some(node);
+}
+
+fn straw2<'a>(node: &'a Node, key: &[u8], r: u32) -> Option<&'a Node> {
+    let mut best: Option<(f64, &Node)> = None;
+    for child in node.children.iter().filter(|c| c.weight > 0) {
+        let straw = draw(key, &child.name, r).ln() / child.weight as f64;
+        if best.is_none_or(|(b, _)| straw > b) {
+            best = Some((straw, child));
+        }
+    }
+    return best.map(|(_, child)| child);
+}
+
+// A hash of (key, name, r), spread over (0, 1]
+fn draw(key: &[u8], name: &str, r: u32) -> f64 {
+    let mut data = Vec::with_capacity(key.len() + name.len() + 6);
+    data.extend_from_slice(key);
+    data.push(0xff);
+    data.extend_from_slice(name.as_bytes());
+    data.push(0xff);
+    data.extend_from_slice(&r.to_le_bytes());
+    let z = mix(checksum(&data));
+    return ((z >> 11) + 1) as f64 / (1u64 << 53) as f64;
+}
+
+#[cfg(test)]
+mod test_hierarchy {
+    use super::*;
+    use crate::RingTarget;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn tree() -> Hierarchy {
+        let mut tree = Hierarchy::new(vec!["datacenter", "rack"]);
+        for dc in ["dc-a", "dc-b", "dc-c"] {
+            for rack in ["rack-1", "rack-2", "rack-3"] {
+                for host in ["h1", "h2"] {
+                    let target = format!("{}-{}-{}", dc, rack, host);
+                    tree.add_target(&[dc, rack], &target, 1);
+                }
+            }
+        }
+        return tree;
+    }
+
+    #[test]
+    fn spreads_over_domains() {
+        let tree = tree();
+        let rule = [Placement::new::<&str>(&[], 3, "datacenter")];
+        for i in 0..200 {
+            let targets = tree.place(i, &rule);
+            assert_eq!(targets.len(), 3);
+            let mut dcs: Vec<&str> = targets.iter().map(|t| &t[..4]).collect();
+            dcs.sort();
+            assert_eq!(dcs, ["dc-a", "dc-b", "dc-c"]);
+        }
+        assert_eq!(tree.place("resource", &rule), tree.place("resource", &rule));
+    }
+
+    #[test]
+    fn placements_within_branches() {
+        let tree = tree();
+        let rule = [
+            Placement::new(&["dc-a"], 2, "rack"),
+            Placement::new(&["dc-b"], 1, "rack"),
+        ];
+        for i in 0..200 {
+            let targets = tree.place(i, &rule);
+            assert_eq!(targets.len(), 3);
+            assert!(targets[0].starts_with("dc-a") && targets[1].starts_with("dc-a"));
+            assert_ne!(targets[0][..11], targets[1][..11]);
+            assert!(targets[2].starts_with("dc-b"));
+        }
+        // only three racks to go round
+        let rule = [Placement::new(&["dc-a"], 4, "rack")];
+        assert_eq!(tree.place("resource", &rule).len(), 3);
+        let rule = [Placement::any(&["dc-a", "rack-1"], 3)];
+        assert_eq!(tree.place("resource", &rule).len(), 2);
+    }
+
+    #[test]
+    fn follows_weights() {
+        let mut tree = Hierarchy::new(vec!["rack"]);
+        tree.add_target(&["rack-1"], "t-a", 1);
+        tree.add_target(&["rack-2"], "t-b", 3);
+        let rule = [Placement::any::<&str>(&[], 1)];
+        let mut counts = HashMap::new();
+        for i in 0..4000 {
+            *counts.entry(tree.place(i, &rule).remove(0)).or_insert(0) += 1;
+        }
+        assert!((2800..3200).contains(&counts["t-b"]), "{:?}", counts);
+    }
+
+    #[test]
+    fn removing_a_target_only_moves_its_own_domain() {
+        let before = tree();
+        let mut after = before.clone();
+        after.remove_target("dc-b-rack-2-h1");
+        let rule = [Placement::any::<&str>(&[], 1)];
+        let mut moved = 0;
+        for i in 0..2000 {
+            let old = before.place(i, &rule);
+            let new = after.place(i, &rule);
+            if old != new {
+                assert!(old[0].starts_with("dc-b"), "{} -> {}", old[0], new[0]);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 250, "{}", moved);
+        assert_eq!(after.targets().len(), 17);
+        assert!(!after.contains("dc-b-rack-2-h1"));
+    }
+
+    #[test]
+    fn moving_and_removing() {
+        let mut tree = Hierarchy::new(vec!["datacenter", "rack"]);
+        tree.add_target(&["dc-a", "rack-1"], "t-a", 1);
+        tree.add_target(&["dc-a", "rack-2"], "t-b", 2);
+        tree.add_target(&["dc-b", "rack-1"], "t-a", 3);
+        assert_eq!(tree.targets(), ["t-b", "t-a"]);
+        assert_eq!(tree.root.weight, 5);
+        assert_eq!(tree.root.children[0].weight, 2);
+        tree.remove_target("t-b");
+        assert_eq!(tree.root.children.len(), 1);
+        assert_eq!(tree.root.weight, 3);
+        assert_eq!(
+            tree.place("resource", &[Placement::any::<&str>(&[], 2)]),
+            ["t-a"]
+        );
+    }
+
+    struct Server(&'static str, &'static str, &'static str);
+
+    impl RingTarget for Server {
+        fn name(&self) -> Target {
+            return self.0.to_string();
+        }
+        fn zone(&self) -> Option<String> {
+            return Some(self.1.to_string());
+        }
+        fn labels(&self) -> BTreeMap<String, String> {
+            let mut labels = BTreeMap::new();
+            if !self.2.is_empty() {
+                labels.insert("rack".to_string(), self.2.to_string());
+            }
+            return labels;
+        }
+    }
+
+    #[test]
+    fn from_ring() {
+        let mut fh = Flexihash::new();
+        fh.add_ring_target(&Server("t-a", "us", "r1"));
+        fh.add_ring_target(&Server("t-b", "us", "r2"));
+        fh.add_ring_target(&Server("t-c", "eu", "r1"));
+        fh.add_ring_target(&Server("t-d", "eu", ""));
+        let tree = Hierarchy::from_ring(&fh, vec!["zone", "rack"]);
+        assert_eq!(tree.levels(), ["zone", "rack"]);
+        assert_eq!(tree.targets(), ["t-a", "t-b", "t-c"]);
+        let targets = tree.place("resource", &[Placement::new(&["us"], 2, "rack")]);
+        assert_eq!(targets.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Failure domain 'dc-x' does not exist")]
+    fn unknown_branch() {
+        tree().place("resource", &[Placement::new(&["dc-x"], 1, "rack")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Failure domain level 'row' does not exist")]
+    fn unknown_level() {
+        tree().place("resource", &[Placement::new::<&str>(&[], 1, "row")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "A target's path needs 2 levels, got 1")]
+    fn short_path() {
+        tree().add_target(&["dc-a"], "t-a", 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 't-x' does not exist")]
+    fn remove_missing() {
+        tree().remove_target("t-x");
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ * HighwayHash-64 (https://github.com/google/highwayhash), a portable port
+ * of the reference C implementation. It's keyed, so without the key nobody
+ * can pick resource names that all land on one target; and unlike SipHash
+ * it chews through 32 bytes per round, so lookups stay cheap.
+ *
+ * The key is four u64 lanes, as in the reference API.
+ */
+const INIT_MUL0: [u64; 4] = [
+    0xdbe6d5d5fe4cce2f,
+    0xa4093822299f31d0,
+    0x13198a2e03707344,
+    0x243f6a8885a308d3,
+];
+const INIT_MUL1: [u64; 4] = [
+    0x3bd39e10cb0ef593,
+    0xc0acf169b5f18a8c,
+    0xbe5466cf34e90c6c,
+    0x452821e638d01377,
+];
+
+#[derive(Debug, Clone)]
+struct State {
+    v0: [u64; 4],
+    v1: [u64; 4],
+    mul0: [u64; 4],
+    mul1: [u64; 4],
+}
+
+impl State {
+    fn new(key: &[u64; 4]) -> State {
+        let mut state = State {
+            v0: [0; 4],
+            v1: [0; 4],
+            mul0: INIT_MUL0,
+            mul1: INIT_MUL1,
+        };
+        for i in 0..4 {
+            state.v0[i] = INIT_MUL0[i] ^ key[i];
+            state.v1[i] = INIT_MUL1[i] ^ key[i].rotate_left(32);
+        }
+        return state;
+    }
+
+    fn update(&mut self, lanes: [u64; 4]) {
+        for (i, lane) in lanes.iter().enumerate() {
+            self.v1[i] = self.v1[i].wrapping_add(self.mul0[i].wrapping_add(*lane));
+            self.mul0[i] ^= (self.v1[i] & 0xffffffff).wrapping_mul(self.v0[i] >> 32);
+            self.v0[i] = self.v0[i].wrapping_add(self.mul1[i]);
+            self.mul1[i] ^= (self.v0[i] & 0xffffffff).wrapping_mul(self.v1[i] >> 32);
+        }
+        let (v0, v1) = (&mut self.v0, &mut self.v1);
+        zipper_merge_and_add(v1[1], v1[0], v0, 1, 0);
+        zipper_merge_and_add(v1[3], v1[2], v0, 3, 2);
+        zipper_merge_and_add(v0[1], v0[0], v1, 1, 0);
+        zipper_merge_and_add(v0[3], v0[2], v1, 3, 2);
+    }
+
+    fn update_packet(&mut self, packet: &[u8]) {
+        let lane = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&packet[i * 8..i * 8 + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        self.update([lane(0), lane(1), lane(2), lane(3)]);
+    }
+
+    // The last 1..31 bytes; the length is folded in here
+    fn update_remainder(&mut self, bytes: &[u8]) {
+        let size = bytes.len() as u64;
+        let size_mod4 = bytes.len() & 3;
+        let remainder = &bytes[bytes.len() & !3..];
+        for i in 0..4 {
+            self.v0[i] = self.v0[i].wrapping_add((size << 32).wrapping_add(size));
+            let (low, high) = (self.v1[i] as u32, (self.v1[i] >> 32) as u32);
+            self.v1[i] =
+                low.rotate_left(size as u32) as u64 | (high.rotate_left(size as u32) as u64) << 32;
+        }
+        let mut packet = [0u8; 32];
+        packet[..bytes.len() & !3].copy_from_slice(&bytes[..bytes.len() & !3]);
+        if size & 16 != 0 {
+            // the last four bytes, which may overlap the ones copied above
+            packet[28..32].copy_from_slice(&bytes[bytes.len() - 4..]);
+        } else if size_mod4 != 0 {
+            packet[16] = remainder[0];
+            packet[17] = remainder[size_mod4 >> 1];
+            packet[18] = remainder[size_mod4 - 1];
+        }
+        self.update_packet(&packet);
+    }
+
+    fn finish64(mut self) -> u64 {
+        for _ in 0..4 {
+            let v0 = self.v0;
+            self.update([
+                v0[2].rotate_left(32),
+                v0[3].rotate_left(32),
+                v0[0].rotate_left(32),
+                v0[1].rotate_left(32),
+            ]);
+        }
+        return self.v0[0]
+            .wrapping_add(self.v1[0])
+            .wrapping_add(self.mul0[0])
+            .wrapping_add(self.mul1[0]);
+    }
+}
+
+fn zipper_merge_and_add(v1: u64, v0: u64, out: &mut [u64; 4], add1: usize, add0: usize) {
+    out[add0] = out[add0].wrapping_add(
+        (((v0 & 0xff000000) | (v1 & 0xff00000000)) >> 24)
+            | (((v0 & 0xff0000000000) | (v1 & 0xff000000000000)) >> 16)
+            | (v0 & 0xff0000)
+            | ((v0 & 0xff00) << 32)
+            | ((v1 & 0xff00000000000000) >> 8)
+            | (v0 << 56),
+    );
+    out[add1] = out[add1].wrapping_add(
+        (((v1 & 0xff000000) | (v0 & 0xff00000000)) >> 24)
+            | (v1 & 0xff0000)
+            | ((v1 & 0xff0000000000) >> 16)
+            | ((v1 & 0xff00) << 24)
+            | ((v0 & 0xff000000000000) >> 8)
+            | ((v1 & 0xff) << 48)
+            | (v0 & 0xff00000000000000),
+    );
+}
+
+// Incremental hashing, so that the ring can hash a target's name once and
+// carry on from there for each replica
+#[derive(Debug, Clone)]
+pub(crate) struct Highway {
+    state: State,
+    packet: [u8; 32],
+    len: usize,
+}
+
+impl Highway {
+    pub(crate) fn new(key: &[u64; 4]) -> Highway {
+        return Highway {
+            state: State::new(key),
+            packet: [0; 32],
+            len: 0,
+        };
+    }
+
+    pub(crate) fn append(&mut self, mut bytes: &[u8]) {
+        if self.len > 0 {
+            let take = bytes.len().min(32 - self.len);
+            self.packet[self.len..self.len + take].copy_from_slice(&bytes[..take]);
+            self.len += take;
+            bytes = &bytes[take..];
+            if self.len < 32 {
+                return;
+            }
+            self.state.update_packet(&self.packet);
+            self.len = 0;
+        }
+        let mut packets = bytes.chunks_exact(32);
+        for packet in &mut packets {
+            self.state.update_packet(packet);
+        }
+        let rest = packets.remainder();
+        self.packet[..rest.len()].copy_from_slice(rest);
+        self.len = rest.len();
+    }
+
+    pub(crate) fn finish64(&self) -> u64 {
+        let mut state = self.state.clone();
+        if self.len > 0 {
+            state.update_remainder(&self.packet[..self.len]);
+        }
+        return state.finish64();
+    }
+}
+
+pub(crate) fn highway64(key: &[u64; 4], bytes: &[u8]) -> u64 {
+    let mut hasher = Highway::new(key);
+    hasher.append(bytes);
+    return hasher.finish64();
+}
+
+#[cfg(test)]
+mod test_highway {
+    use super::*;
+
+    // The reference test key, and the data for case n is the bytes 0..n
+    const KEY: [u64; 4] = [
+        0x0706050403020100,
+        0x0F0E0D0C0B0A0908,
+        0x1716151413121110,
+        0x1F1E1D1C1B1A1918,
+    ];
+
+    #[test]
+    fn reference_vectors() {
+        let data: Vec<u8> = (0..64).collect();
+        let expected: [(usize, u64); 6] = [
+            (0, 0x907A56DE22C26E53),
+            (1, 0x7EAB43AAC7CDDD78),
+            (3, 0x5C6BEFAB8A463D80),
+            (17, 0x19FE67D2C8C5C0E2),
+            (32, 0xA0C964D9ECD580FC),
+            (63, 0xAB8EEBE9BF2139A0),
+        ];
+        for (n, want) in expected.iter() {
+            assert_eq!(highway64(&KEY, &data[..*n]), *want, "{} bytes", n);
+        }
+    }
+
+    #[test]
+    fn incremental_matches_one_shot() {
+        let data: Vec<u8> = (0..100).collect();
+        for split in 0..data.len() {
+            let mut hasher = Highway::new(&KEY);
+            hasher.append(&data[..split]);
+            hasher.append(&data[split..]);
+            assert_eq!(
+                hasher.finish64(),
+                highway64(&KEY, &data),
+                "split at {}",
+                split
+            );
+        }
+    }
+}
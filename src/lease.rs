@@ -0,0 +1,223 @@
+use crate::partition::Partition;
+use crate::{Exhausted, Flexihash, ResourceKey, Target};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+ * Time-limited leases on targets' arcs of the ring, to build fencing on:
+ * a target only acts as an arc's owner while its lease is current, and
+ * stamps what it writes with the lease's token. Tokens only ever go up,
+ * and a lease which lapses gets a new one when it's next acquired, so
+ * storage can turn away writes from an owner which paused (GC, a network
+ * partition, ...) past its lease and then woke up.
+ *
+ * A lease covers all of a target's arcs. Renewing a current lease keeps
+ * its token; the table doesn't know about the ring, so leases for targets
+ * which have left just lapse.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub target: Target,
+    pub token: u64,
+    pub expires: Instant,
+}
+
+impl Lease {
+    pub fn is_current(&self, now: Instant) -> bool {
+        return now < self.expires;
+    }
+}
+
+#[derive(Debug)]
+pub struct LeaseTable {
+    duration: Duration,
+    // the leases, and the last token handed out
+    leases: Mutex<(HashMap<Target, Lease>, u64)>,
+}
+
+impl LeaseTable {
+    pub fn new(duration: Duration) -> LeaseTable {
+        return LeaseTable {
+            duration,
+            leases: Mutex::new((HashMap::new(), 0)),
+        };
+    }
+
+    pub fn duration(&self) -> Duration {
+        return self.duration;
+    }
+
+    // Take out a lease, or extend one which is still current
+    pub fn acquire<S: Into<String>>(&self, target: S, now: Instant) -> Lease {
+        let target = target.into();
+        let mut guard = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        let (leases, last_token) = &mut *guard;
+        let token = match leases.get(&target) {
+            Some(lease) if lease.is_current(now) => lease.token,
+            _ => {
+                *last_token += 1;
+                *last_token
+            }
+        };
+        let lease = Lease {
+            target: target.clone(),
+            token,
+            expires: now + self.duration,
+        };
+        leases.insert(target, lease.clone());
+        return lease;
+    }
+
+    // Give a lease up early, eg when shutting down cleanly
+    pub fn release(&self, target: &str) {
+        let mut guard = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        guard.0.remove(target);
+    }
+
+    // The target's lease, current or not
+    pub fn get(&self, target: &str) -> Option<Lease> {
+        let guard = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        return guard.0.get(target).cloned();
+    }
+
+    pub fn current(&self, target: &str, now: Instant) -> Option<Lease> {
+        return self.get(target).filter(|lease| lease.is_current(now));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeasedLookup {
+    pub target: Target,
+    // the owner's lease, if it has ever had one
+    pub lease: Option<Lease>,
+    pub current: bool,
+}
+
+impl Flexihash {
+    // The usual owner, and whether it holds a current lease
+    pub fn lookup_leased<K: ResourceKey>(
+        &self,
+        resource: K,
+        leases: &LeaseTable,
+        now: Instant,
+    ) -> LeasedLookup {
+        let target = self.lookup(resource);
+        let lease = leases.get(&target);
+        let current = lease.as_ref().is_some_and(|l| l.is_current(now));
+        return LeasedLookup {
+            target,
+            lease,
+            current,
+        };
+    }
+
+    // The first target in ring order with a current lease, so that an arc
+    // whose owner has lapsed is looked after by its next neighbour until
+    // the owner is back; None if nobody's lease is current
+    pub fn lookup_leased_fallback<K: ResourceKey>(
+        &self,
+        resource: K,
+        leases: &LeaseTable,
+        now: Instant,
+    ) -> Option<Lease> {
+        return self
+            .cycle_candidates(resource, Exhausted::Stop)
+            .find_map(|target| leases.current(&target, now));
+    }
+
+    // The stretches of the ring whose owners don't hold a current lease
+    pub fn expired_partitions(&self, leases: &LeaseTable, now: Instant) -> Vec<Partition> {
+        return self
+            .partitions()
+            .into_iter()
+            .filter(|p| leases.current(&p.target, now).is_none())
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test_lease {
+    use super::*;
+    use crate::Hasher;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (i, p) in [10, 20, 30].iter().enumerate() {
+            fh.set_hasher(Hasher::Mock(*p));
+            fh.add_target(format!("t{}", i + 1), 1);
+        }
+        fh.set_hasher(Hasher::Mock(15));
+        return fh;
+    }
+
+    #[test]
+    fn tokens_only_go_up() {
+        let leases = LeaseTable::new(Duration::from_secs(10));
+        let now = Instant::now();
+        let a = leases.acquire("t1", now);
+        let b = leases.acquire("t2", now);
+        assert_eq!((a.token, b.token), (1, 2));
+        // renewing keeps the token
+        let renewed = leases.acquire("t1", now + Duration::from_secs(5));
+        assert_eq!(renewed.token, 1);
+        assert_eq!(renewed.expires, now + Duration::from_secs(15));
+        // lapsing doesn't
+        let later = now + Duration::from_secs(20);
+        assert!(leases.current("t1", later).is_none());
+        assert_eq!(leases.acquire("t1", later).token, 3);
+        leases.release("t2");
+        assert_eq!(leases.get("t2"), None);
+    }
+
+    #[test]
+    fn lookups() {
+        let fh = ring();
+        let leases = LeaseTable::new(Duration::from_secs(10));
+        let now = Instant::now();
+        // candidates in order are t2, t3, t1
+        assert_eq!(
+            fh.lookup_leased("resource", &leases, now),
+            LeasedLookup {
+                target: "t2".to_string(),
+                lease: None,
+                current: false
+            }
+        );
+        assert_eq!(fh.lookup_leased_fallback("resource", &leases, now), None);
+
+        let t2 = leases.acquire("t2", now);
+        leases.acquire("t1", now + Duration::from_secs(5));
+        let found = fh.lookup_leased("resource", &leases, now);
+        assert!(found.current);
+        assert_eq!(found.lease, Some(t2.clone()));
+        assert_eq!(
+            fh.lookup_leased_fallback("resource", &leases, now),
+            Some(t2)
+        );
+
+        // t2 lapses first, and t1 covers for it
+        let later = now + Duration::from_secs(12);
+        assert!(!fh.lookup_leased("resource", &leases, later).current);
+        let covering = fh
+            .lookup_leased_fallback("resource", &leases, later)
+            .unwrap();
+        assert_eq!(covering.target, "t1");
+    }
+
+    #[test]
+    fn expired_partitions() {
+        let fh = ring();
+        let leases = LeaseTable::new(Duration::from_secs(10));
+        let now = Instant::now();
+        leases.acquire("t1", now);
+        leases.acquire("t3", now);
+        let expired = fh.expired_partitions(&leases, now);
+        assert_eq!(expired.len(), 1);
+        assert_eq!((expired[0].start, expired[0].end), (11, 20));
+        // everything, once all the leases run out
+        let later = now + Duration::from_secs(10);
+        assert_eq!(fh.expired_partitions(&leases, later), fh.partitions());
+    }
+}
@@ -1,31 +1,622 @@
+#![allow(clippy::needless_return)]
+
+#[cfg(feature = "crc")]
 use crc::crc32;
-use md5;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod analysis;
+#[cfg(feature = "rkyv")]
+pub mod archived;
+pub mod balance;
+pub mod batch;
+pub mod canary;
+#[cfg(feature = "serde")]
+pub mod canonical;
+pub mod changelog;
+pub mod compat;
+#[cfg(feature = "consul")]
+pub mod consul;
+pub mod delta;
+pub mod drain;
+pub mod env;
+#[cfg(feature = "etcd")]
+pub mod etcd;
+#[cfg(all(test, feature = "md5", feature = "crc"))]
+mod golden;
+#[cfg(feature = "gossip")]
+pub mod gossip;
+pub mod hierarchy;
+#[cfg(feature = "highway")]
+mod highway;
+pub mod lease;
+pub mod locality;
+pub mod mapped;
+pub mod metrics;
+pub mod migration;
+pub mod observer;
+pub mod partition;
+pub mod perfect;
+pub mod planner;
+pub mod probe;
+pub mod quorum;
+#[cfg(feature = "raft")]
+pub mod raft;
+pub mod resolver;
+pub mod retry;
+pub mod rules;
+pub mod scaling;
+pub mod schedule;
+pub mod shards;
+pub mod shared;
+pub mod snapshot;
+#[cfg(feature = "spooky")]
+mod spooky;
+#[cfg(feature = "t1ha")]
+mod t1ha;
+pub mod tenant;
+pub mod tier;
+pub mod validate;
+pub mod workload;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "notify")]
+pub mod reload;
+#[cfg(feature = "tokio")]
+pub mod watch;
 
 pub type Position = u128;
 pub type Target = String;
 pub type Resource = String;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Hasher {
+    #[cfg(feature = "crc")]
     Crc32,
+    #[cfg(feature = "md5")]
     Md5,
-    Mock(String),
+    Adler32,
+    // HighwayHash-64 with the given key; keep the key secret and resource
+    // names can't be chosen to pile up on one target
+    #[cfg(feature = "highway")]
+    Highway([u64; 4]),
+    // SpookyHash V2, all 128 bits of it: hash1 is the high half
+    #[cfg(feature = "spooky")]
+    Spooky,
+    // t1ha2_atonce with the given seed, 0 if the rest of the stack is unseeded
+    #[cfg(feature = "t1ha")]
+    T1ha(u64),
+    // Only the top n bits of another hasher's output, eg for systems which
+    // placed things by the first 4 bytes of an MD5 digest. Digests count as
+    // big-endian numbers, so "top" means the first bytes of the digest.
+    // Build with Hasher::truncated, which checks the width.
+    Truncated(Box<Hasher>, u32),
+    Mock(Position),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HashError {
+    InvalidMock(String),
+    InvalidWidth(u32),
+    UnknownHasher(String),
+    InvalidOption(String),
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashError::InvalidMock(val) => write!(f, "Invalid mock position '{}'", val),
+            HashError::InvalidWidth(bits) => {
+                write!(f, "Invalid digest width {}, expected 32, 64 or 128", bits)
+            }
+            HashError::UnknownHasher(name) => write!(f, "Unknown hasher '{}'", name),
+            HashError::InvalidOption(option) => write!(f, "Invalid hasher option '{}'", option),
+        }
+    }
+}
+
+impl std::error::Error for HashError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    TargetExists(Target),
+    // the target, and the closest names to it which do exist
+    TargetMissing(Target, Vec<Target>),
+    // the target, the positions the ring would have, and the limit
+    TooManyPositions(Target, u64, u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TargetExists(target) => write!(f, "Target {} already exists", target),
+            Error::TargetMissing(target, similar) => {
+                write!(f, "Target '{}' does not exist", target)?;
+                let similar: Vec<String> = similar.iter().map(|t| format!("'{}'", t)).collect();
+                return match similar.split_last() {
+                    None => Ok(()),
+                    Some((last, [])) => write!(f, "; did you mean {}?", last),
+                    Some((last, rest)) => {
+                        write!(f, "; did you mean {} or {}?", rest.join(", "), last)
+                    }
+                };
+            }
+            Error::TooManyPositions(target, total, limit) => write!(
+                f,
+                "Target {} would take the ring to {} positions, over the limit of {}",
+                target, total, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// crc32, like flexihash-php and flexihash-py; adler32 (which is always
+// there) when built without the crc feature
+impl Default for Hasher {
+    #[cfg(feature = "crc")]
+    fn default() -> Hasher {
+        return Hasher::Crc32;
+    }
+
+    #[cfg(not(feature = "crc"))]
+    fn default() -> Hasher {
+        return Hasher::Adler32;
+    }
+}
+
+impl Hasher {
+    pub fn mock<S: AsRef<str>>(value: S) -> Result<Hasher, HashError> {
+        let value = value.as_ref();
+        return match value.parse() {
+            Ok(position) => Ok(Hasher::Mock(position)),
+            Err(_) => Err(HashError::InvalidMock(value.to_string())),
+        };
+    }
+
+    // As Display, but safe to show anyone: secret keys are hidden
+    pub fn redacted(&self) -> String {
+        let name = self.to_string();
+        return match name.split_once("key=") {
+            Some((before, key)) => {
+                format!("{}key=<secret>{}", before, key.get(64..).unwrap_or(""))
+            }
+            None => name,
+        };
+    }
+
+    pub fn truncated(inner: Hasher, bits: u32) -> Result<Hasher, HashError> {
+        if ![32, 64, 128].contains(&bits) {
+            return Err(HashError::InvalidWidth(bits));
+        }
+        return Ok(Hasher::Truncated(Box::new(inner), bits));
+    }
+
+    // How far to shift the inner hasher's output to keep only its top bits
+    fn truncation_shift(inner: &Hasher, bits: u32) -> u32 {
+        let width = Position::BITS - inner.max_position().leading_zeros();
+        return width.saturating_sub(bits);
+    }
+
+    pub fn max_position(&self) -> Position {
+        return match self {
+            #[cfg(feature = "crc")]
+            Hasher::Crc32 => u32::MAX as Position,
+            #[cfg(feature = "md5")]
+            Hasher::Md5 => Position::MAX,
+            Hasher::Adler32 => u32::MAX as Position,
+            #[cfg(feature = "highway")]
+            Hasher::Highway(_) => u64::MAX as Position,
+            #[cfg(feature = "spooky")]
+            Hasher::Spooky => Position::MAX,
+            #[cfg(feature = "t1ha")]
+            Hasher::T1ha(_) => u64::MAX as Position,
+            Hasher::Truncated(inner, bits) => {
+                inner.max_position() >> Hasher::truncation_shift(inner, *bits)
+            }
+            Hasher::Mock(_) => Position::MAX,
+        };
+    }
+}
+
+/*
+ * Hashers as strings, for config files and command lines: a name, then
+ * any options after a colon, eg "crc32", "t1ha:seed=42", "md5:bits=32".
+ * Any hasher can take "bits" to truncate it. Mock positions are written
+ * bare, "mock:42".
+ */
+impl fmt::Display for Hasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            #[cfg(feature = "crc")]
+            Hasher::Crc32 => write!(f, "crc32"),
+            #[cfg(feature = "md5")]
+            Hasher::Md5 => write!(f, "md5"),
+            Hasher::Adler32 => write!(f, "adler32"),
+            #[cfg(feature = "highway")]
+            Hasher::Highway(key) => write!(
+                f,
+                "highway:key={:016x}{:016x}{:016x}{:016x}",
+                key[0], key[1], key[2], key[3]
+            ),
+            #[cfg(feature = "spooky")]
+            Hasher::Spooky => write!(f, "spooky"),
+            #[cfg(feature = "t1ha")]
+            Hasher::T1ha(seed) => write!(f, "t1ha:seed={}", seed),
+            Hasher::Truncated(inner, bits) => {
+                let inner = inner.to_string();
+                let separator = if inner.contains(':') { ',' } else { ':' };
+                write!(f, "{}{}bits={}", inner, separator, bits)
+            }
+            Hasher::Mock(position) => write!(f, "mock:{}", position),
+        };
+    }
+}
+
+impl std::str::FromStr for Hasher {
+    type Err = HashError;
+
+    fn from_str(value: &str) -> Result<Hasher, HashError> {
+        let (name, options) = match value.split_once(':') {
+            Some((name, options)) => (name, options.split(',').collect()),
+            None => (value, Vec::new()),
+        };
+        // bare values have an empty key
+        let mut options: Vec<(&str, &str)> = options
+            .into_iter()
+            .map(|option: &str| option.split_once('=').unwrap_or(("", option)))
+            .collect();
+        let mut take = |key: &str| {
+            let i = options.iter().position(|(k, _)| *k == key)?;
+            Some(options.remove(i).1)
+        };
+        let invalid =
+            |key: &str, value: &str| HashError::InvalidOption(format!("{}={}", key, value));
+
+        let mut hasher = match name {
+            #[cfg(feature = "crc")]
+            "crc32" => Hasher::Crc32,
+            #[cfg(feature = "md5")]
+            "md5" => Hasher::Md5,
+            "adler32" => Hasher::Adler32,
+            #[cfg(feature = "highway")]
+            "highway" => {
+                let key = take("key").ok_or_else(|| HashError::InvalidOption("key".to_string()))?;
+                Hasher::Highway(parse_highway_key(key).ok_or_else(|| invalid("key", key))?)
+            }
+            #[cfg(feature = "spooky")]
+            "spooky" => Hasher::Spooky,
+            #[cfg(feature = "t1ha")]
+            "t1ha" => match take("seed") {
+                Some(seed) => Hasher::T1ha(seed.parse().map_err(|_| invalid("seed", seed))?),
+                None => Hasher::T1ha(0),
+            },
+            "mock" => Hasher::mock(take("").unwrap_or_default())?,
+            _ => return Err(HashError::UnknownHasher(name.to_string())),
+        };
+        while let Some(bits) = take("bits") {
+            hasher = Hasher::truncated(hasher, bits.parse().map_err(|_| invalid("bits", bits))?)?;
+        }
+        if let Some((key, value)) = options.first() {
+            return Err(invalid(key, value));
+        }
+        return Ok(hasher);
+    }
+}
+
+#[cfg(feature = "highway")]
+fn parse_highway_key(hex: &str) -> Option<[u64; 4]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u64; 4];
+    for (i, lane) in key.iter_mut().enumerate() {
+        *lane = u64::from_str_radix(&hex[i * 16..i * 16 + 16], 16).ok()?;
+    }
+    return Some(key);
 }
 
-pub fn hash<S: Into<String>>(hasher: &Hasher, value: S) -> Position {
-    let value = value.into();
+pub fn hash<B: AsRef<[u8]>>(hasher: &Hasher, value: B) -> Position {
+    let value = value.as_ref();
     return match hasher {
-        Hasher::Crc32 => crc32::checksum_ieee(value.as_bytes()) as u128,
+        #[cfg(feature = "crc")]
+        Hasher::Crc32 => crc32::checksum_ieee(value) as u128,
+        #[cfg(feature = "md5")]
         Hasher::Md5 => u128::from_be_bytes(md5::compute(value).0),
-        Hasher::Mock(val) => u128::from_str_radix(val, 10).unwrap(),
+        Hasher::Adler32 => adler32_finish(adler32_update((1, 0), value)) as u128,
+        #[cfg(feature = "highway")]
+        Hasher::Highway(key) => highway::highway64(key, value) as u128,
+        #[cfg(feature = "spooky")]
+        Hasher::Spooky => {
+            let (hash1, hash2) = spooky::spooky128(value, (0, 0));
+            ((hash1 as u128) << 64) | hash2 as u128
+        }
+        #[cfg(feature = "t1ha")]
+        Hasher::T1ha(seed) => t1ha::t1ha2_atonce(value, *seed) as u128,
+        Hasher::Truncated(inner, bits) => {
+            hash(inner, value) >> Hasher::truncation_shift(inner, *bits)
+        }
+        Hasher::Mock(val) => *val,
+    };
+}
+
+// Adler-32 (RFC 1950), kept as its two running sums so that it can be
+// carried on from a prefix
+const ADLER32_MOD: u32 = 65521;
+
+fn adler32_update(state: (u32, u32), bytes: &[u8]) -> (u32, u32) {
+    let (mut a, mut b) = state;
+    // 5552 is the most bytes that can be summed before b could overflow
+    for chunk in bytes.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= ADLER32_MOD;
+        b %= ADLER32_MOD;
+    }
+    return (a, b);
+}
+
+fn adler32_finish(state: (u32, u32)) -> u32 {
+    return (state.1 << 16) | state.0;
+}
+
+// Positions for a target's replicas are the hashes of "{target}{i}"; hash
+// the target part once and carry on from there for each i, rather than
+// formatting a new string per replica
+enum ReplicaHasher {
+    #[cfg(feature = "crc")]
+    Crc32(u32),
+    #[cfg(feature = "md5")]
+    Md5(md5::Context),
+    Adler32((u32, u32)),
+    #[cfg(feature = "highway")]
+    Highway(highway::Highway),
+    // Hashers which mix the length in first have nothing to carry on from,
+    // so these keep the target and hash the whole key each time
+    #[cfg(any(feature = "spooky", feature = "t1ha"))]
+    Whole(Hasher, Vec<u8>),
+    Truncated(Box<ReplicaHasher>, u32),
+    Mock(Position),
+}
+
+impl ReplicaHasher {
+    fn new(hasher: &Hasher, target: &str) -> ReplicaHasher {
+        return match hasher {
+            #[cfg(feature = "crc")]
+            Hasher::Crc32 => ReplicaHasher::Crc32(crc32::checksum_ieee(target.as_bytes())),
+            #[cfg(feature = "md5")]
+            Hasher::Md5 => {
+                let mut context = md5::Context::new();
+                context.consume(target);
+                ReplicaHasher::Md5(context)
+            }
+            Hasher::Adler32 => ReplicaHasher::Adler32(adler32_update((1, 0), target.as_bytes())),
+            #[cfg(feature = "highway")]
+            Hasher::Highway(key) => {
+                let mut state = highway::Highway::new(key);
+                state.append(target.as_bytes());
+                ReplicaHasher::Highway(state)
+            }
+            #[cfg(feature = "spooky")]
+            Hasher::Spooky => ReplicaHasher::Whole(hasher.clone(), target.as_bytes().to_vec()),
+            #[cfg(feature = "t1ha")]
+            Hasher::T1ha(_) => ReplicaHasher::Whole(hasher.clone(), target.as_bytes().to_vec()),
+            Hasher::Truncated(inner, bits) => ReplicaHasher::Truncated(
+                Box::new(ReplicaHasher::new(inner, target)),
+                Hasher::truncation_shift(inner, *bits),
+            ),
+            Hasher::Mock(val) => ReplicaHasher::Mock(*val),
+        };
+    }
+
+    fn position(&self, i: u64) -> Position {
+        let mut buf = [0u8; 20];
+        let mut start = buf.len();
+        let mut n = i;
+        loop {
+            start -= 1;
+            buf[start] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        let digits = &buf[start..];
+        return match self {
+            #[cfg(feature = "crc")]
+            ReplicaHasher::Crc32(crc) => crc32::update(*crc, &crc32::IEEE_TABLE, digits) as u128,
+            #[cfg(feature = "md5")]
+            ReplicaHasher::Md5(context) => {
+                let mut context = context.clone();
+                context.consume(digits);
+                u128::from_be_bytes(context.compute().0)
+            }
+            ReplicaHasher::Adler32(state) => adler32_finish(adler32_update(*state, digits)) as u128,
+            #[cfg(feature = "highway")]
+            ReplicaHasher::Highway(state) => {
+                let mut state = state.clone();
+                state.append(digits);
+                state.finish64() as u128
+            }
+            #[cfg(any(feature = "spooky", feature = "t1ha"))]
+            ReplicaHasher::Whole(hasher, target) => {
+                let mut key = target.clone();
+                key.extend_from_slice(digits);
+                hash(hasher, key)
+            }
+            ReplicaHasher::Truncated(inner, shift) => inner.position(i) >> shift,
+            ReplicaHasher::Mock(val) => *val,
+        };
+    }
+}
+
+/*
+ * Resource keys
+ *
+ * Anything that can be looked up. Numbers and addresses hash as their usual
+ * string form, so eg `lookup(42)` and `lookup("42")` agree with each other
+ * (and with flexihash-php / flexihash-py); tuples hash as their parts
+ * joined with ':'.
+ */
+pub trait ResourceKey {
+    fn ring_key(&self) -> impl AsRef<[u8]>;
+}
+
+impl<T: ResourceKey + ?Sized> ResourceKey for &T {
+    fn ring_key(&self) -> impl AsRef<[u8]> {
+        return (**self).ring_key();
+    }
+}
+
+impl ResourceKey for str {
+    fn ring_key(&self) -> impl AsRef<[u8]> {
+        return self.as_bytes();
+    }
+}
+
+impl ResourceKey for String {
+    fn ring_key(&self) -> impl AsRef<[u8]> {
+        return self.as_bytes();
+    }
+}
+
+impl ResourceKey for [u8] {
+    fn ring_key(&self) -> impl AsRef<[u8]> {
+        return self;
+    }
+}
+
+impl ResourceKey for Vec<u8> {
+    fn ring_key(&self) -> impl AsRef<[u8]> {
+        return self.as_slice();
+    }
+}
+
+macro_rules! resource_key_via_to_string {
+    ($($t:ty),*) => {
+        $(
+            impl ResourceKey for $t {
+                fn ring_key(&self) -> impl AsRef<[u8]> {
+                    return self.to_string();
+                }
+            }
+        )*
+    };
+}
+
+resource_key_via_to_string!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, char);
+resource_key_via_to_string!(
+    std::net::IpAddr,
+    std::net::Ipv4Addr,
+    std::net::Ipv6Addr,
+    std::net::SocketAddr
+);
+#[cfg(feature = "uuid")]
+resource_key_via_to_string!(uuid::Uuid);
+
+macro_rules! resource_key_for_tuple {
+    ($first:ident $(, $rest:ident)*) => {
+        impl<$first: ResourceKey, $($rest: ResourceKey),*> ResourceKey for ($first, $($rest),*) {
+            #[allow(non_snake_case)]
+            fn ring_key(&self) -> impl AsRef<[u8]> {
+                let ($first, $($rest),*) = self;
+                let mut key = $first.ring_key().as_ref().to_vec();
+                $(
+                    key.push(b':');
+                    key.extend_from_slice($rest.ring_key().as_ref());
+                )*
+                return key;
+            }
+        }
     };
 }
 
+resource_key_for_tuple!(A, B);
+resource_key_for_tuple!(A, B, C);
+resource_key_for_tuple!(A, B, C, D);
+
 #[cfg(test)]
 mod test_hashers {
     use super::*;
 
+    #[test]
+    fn replica_hasher_matches_formatted_keys() {
+        #[allow(unused_mut)]
+        let mut hashers = vec![Hasher::Adler32, Hasher::Mock(7)];
+        #[cfg(feature = "crc")]
+        hashers.push(Hasher::Crc32);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::Md5);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::truncated(Hasher::Md5, 32).unwrap());
+        for hasher in hashers {
+            for target in ["", "cache-1", "ünïcode"] {
+                let replicas = ReplicaHasher::new(&hasher, target);
+                for i in [0, 1, 9, 10, 99, 100, 12345, u64::MAX] {
+                    assert_eq!(
+                        replicas.position(i),
+                        hash(&hasher, format!("{}{}", target, i))
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "highway")]
+    #[test]
+    fn test_highway() {
+        let hasher = Hasher::Highway([1, 2, 3, 4]);
+        let target = "a-target-name-long-enough-to-fill-a-packet";
+        let replicas = ReplicaHasher::new(&hasher, target);
+        for i in [0, 9, 12345, u64::MAX] {
+            assert_eq!(
+                replicas.position(i),
+                hash(&hasher, format!("{}{}", target, i))
+            );
+        }
+        assert!(hash(&hasher, "test") <= u64::MAX as Position);
+        assert_ne!(
+            hash(&hasher, "test"),
+            hash(&Hasher::Highway([1, 2, 3, 5]), "test")
+        );
+    }
+
+    #[cfg(feature = "spooky")]
+    #[test]
+    fn test_spooky() {
+        let replicas = ReplicaHasher::new(&Hasher::Spooky, "cache-1");
+        for i in [0, 9, 12345, u64::MAX] {
+            assert_eq!(
+                replicas.position(i),
+                hash(&Hasher::Spooky, format!("cache-1{}", i))
+            );
+        }
+        assert_eq!(
+            hash(&Hasher::Spooky, ""),
+            0x232706FC6BF50919_8B72EE65B4E851C7
+        );
+    }
+
+    #[cfg(feature = "t1ha")]
+    #[test]
+    fn test_t1ha() {
+        let hasher = Hasher::T1ha(42);
+        let replicas = ReplicaHasher::new(&hasher, "cache-1");
+        for i in [0, 9, 12345, u64::MAX] {
+            assert_eq!(replicas.position(i), hash(&hasher, format!("cache-1{}", i)));
+        }
+        assert_eq!(hash(&Hasher::T1ha(0), ""), 0);
+        assert_ne!(hash(&hasher, "test"), hash(&Hasher::T1ha(0), "test"));
+    }
+
+    #[cfg(feature = "md5")]
     #[test]
     fn test_md5() {
         assert_eq!(
@@ -42,194 +633,1829 @@ mod test_hashers {
         );
     }
 
+    #[cfg(feature = "crc")]
     #[test]
     fn test_crc32() {
         assert_eq!(hash(&Hasher::Crc32, String::from("test")), 3632233996);
         assert_eq!(hash(&Hasher::Crc32, String::from("test")), 3632233996);
         assert_eq!(hash(&Hasher::Crc32, String::from("different")), 1812431075);
     }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(hash(&Hasher::Adler32, ""), 1);
+        assert_eq!(hash(&Hasher::Adler32, "a"), 6422626);
+        assert_eq!(hash(&Hasher::Adler32, "test"), 73204161);
+        assert_eq!(hash(&Hasher::Adler32, "different"), 306381752);
+        assert_eq!(hash(&Hasher::Adler32, "Wikipedia"), 0x11e60398);
+        // long enough for the sums to need reducing mid-way
+        assert_eq!(hash(&Hasher::Adler32, "x".repeat(6000)), 497351959);
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn resource_keys() {
+        let h = Hasher::Crc32;
+        assert_eq!(hash(&h, "test".ring_key()), 3632233996);
+        assert_eq!(hash(&h, String::from("test").ring_key()), 3632233996);
+        assert_eq!(hash(&h, b"test".to_vec().ring_key()), 3632233996);
+        assert_eq!(hash(&h, 42.ring_key()), hash(&h, "42"));
+        assert_eq!(hash(&h, (-7i64).ring_key()), hash(&h, "-7"));
+        assert_eq!(hash(&h, ("tenant", 42).ring_key()), hash(&h, "tenant:42"));
+        assert_eq!(hash(&h, ("a", "b", 'c').ring_key()), hash(&h, "a:b:c"));
+        let addr: std::net::SocketAddr = "10.0.0.1:80".parse().unwrap();
+        assert_eq!(hash(&h, addr.ring_key()), hash(&h, "10.0.0.1:80"));
+    }
+
+    #[cfg(all(feature = "uuid", feature = "crc"))]
+    #[test]
+    fn resource_key_uuid() {
+        let id = uuid::Uuid::nil();
+        assert_eq!(
+            hash(&Hasher::Crc32, id.ring_key()),
+            hash(&Hasher::Crc32, "00000000-0000-0000-0000-000000000000")
+        );
+    }
+
+    #[cfg(all(feature = "md5", feature = "crc"))]
+    #[test]
+    fn test_truncated() {
+        let md5 = |bits| Hasher::truncated(Hasher::Md5, bits).unwrap();
+        assert_eq!(hash(&md5(32), "test"), 0x098f6bcd);
+        assert_eq!(hash(&md5(64), "test"), 0x098f6bcd4621d373);
+        assert_eq!(hash(&md5(128), "test"), hash(&Hasher::Md5, "test"));
+        assert_eq!(md5(32).max_position(), u32::MAX as Position);
+        assert_eq!(md5(64).max_position(), u64::MAX as Position);
+
+        // already narrow enough
+        let crc32 = Hasher::truncated(Hasher::Crc32, 64).unwrap();
+        assert_eq!(hash(&crc32, "test"), 3632233996);
+        assert_eq!(crc32.max_position(), u32::MAX as Position);
+
+        assert_eq!(
+            Hasher::truncated(Hasher::Md5, 48).unwrap_err(),
+            HashError::InvalidWidth(48)
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        #[allow(unused_mut)]
+        let mut hashers = vec![Hasher::Adler32, Hasher::Mock(42)];
+        #[cfg(feature = "crc")]
+        hashers.push(Hasher::Crc32);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::Md5);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::truncated(Hasher::Md5, 32).unwrap());
+        #[cfg(feature = "highway")]
+        hashers.push(Hasher::Highway([1, 2, 3, u64::MAX]));
+        #[cfg(feature = "spooky")]
+        hashers.push(Hasher::Spooky);
+        #[cfg(feature = "t1ha")]
+        hashers.push(Hasher::truncated(Hasher::T1ha(42), 32).unwrap());
+        for hasher in hashers {
+            let name = hasher.to_string();
+            let parsed: Hasher = name.parse().unwrap();
+            assert_eq!(parsed.to_string(), name);
+            assert_eq!(hash(&parsed, "test"), hash(&hasher, "test"));
+        }
+
+        #[cfg(feature = "crc")]
+        assert_eq!(Hasher::Crc32.to_string(), "crc32");
+        #[cfg(feature = "md5")]
+        assert_eq!(
+            Hasher::truncated(Hasher::Md5, 32).unwrap().to_string(),
+            "md5:bits=32"
+        );
+        assert!(matches!(
+            "mock:7,bits=64".parse(),
+            Ok(Hasher::Truncated(_, 64))
+        ));
+        assert_eq!(
+            "sha1".parse::<Hasher>().unwrap_err(),
+            HashError::UnknownHasher("sha1".to_string())
+        );
+        assert_eq!(
+            "adler32:bits=12".parse::<Hasher>().unwrap_err(),
+            HashError::InvalidWidth(12)
+        );
+        assert_eq!(
+            "adler32:colour=red".parse::<Hasher>().unwrap_err(),
+            HashError::InvalidOption("colour=red".to_string())
+        );
+        assert_eq!(
+            "mock:x".parse::<Hasher>().unwrap_err(),
+            HashError::InvalidMock("x".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mock() {
+        assert_eq!(hash(&Hasher::Mock(42), "test"), 42);
+        assert_eq!(hash(&Hasher::mock("42").unwrap(), "different"), 42);
+        assert_eq!(
+            Hasher::mock("forty-two").unwrap_err(),
+            HashError::InvalidMock("forty-two".to_string())
+        );
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Search {
+    Eytzinger,
+    // Guess where the position should be, assuming the hasher spreads
+    // positions evenly; falls back to binary search if that doesn't
+    // narrow things down quickly.
+    Interpolation,
+}
+
+// Unicode normalization of resource keys before hashing, so that eg "é"
+// typed as one code point or as "e" plus an accent lands in one place.
+// Keys which aren't UTF-8 are hashed as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyNormalization {
+    AsGiven,
+    #[cfg(feature = "unicode")]
+    Nfc,
+    // also folds compatibility forms, eg "ﬁ" to "fi" and fullwidth to ascii
+    #[cfg(feature = "unicode")]
+    Nfkc,
+}
+
+// How target names are tidied up before use, so that config sources which
+// disagree on eg hostname case can't create two targets for one machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameNormalization {
+    AsGiven,
+    AsciiLowercase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    Error,
+    Ignore,
+    // Re-place the target, eg to update its weight
+    Replace,
+}
+
+/*
+ * Targets
+ *
+ * Plain names are enough to place a target, but applications can also hand
+ * over their own node types, whose zone, tier and labels are kept alongside.
+ */
+pub trait RingTarget {
+    fn name(&self) -> Target;
+
+    fn weight(&self) -> u32 {
+        return 1;
+    }
+
+    fn zone(&self) -> Option<String> {
+        return None;
+    }
+
+    // 0 for the main pool; see tier.rs
+    fn tier(&self) -> u32 {
+        return 0;
+    }
+
+    fn labels(&self) -> BTreeMap<String, String> {
+        return BTreeMap::new();
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TargetInfo {
+    pub weight: u32,
+    pub zone: Option<String>,
+    pub tier: u32,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Flexihash {
     replicas: u32,
+    replica_strategy: Arc<dyn scaling::ReplicaStrategy>,
     hasher: Hasher,
-    position_to_target: BTreeMap<Position, Target>,
-    sorted_position_to_target: Vec<(Position, Target)>,
-    target_to_positions: HashMap<Target, Vec<Position>>,
+    search: Search,
+    duplicate_policy: DuplicatePolicy,
+    name_normalization: NameNormalization,
+    key_prefix: String,
+    salt: String,
+    hash_tags: bool,
+    key_normalization: KeyNormalization,
+    max_total_positions: u64,
+    metrics: Option<std::sync::Arc<dyn metrics::MetricsSink>>,
+    lookup_counts: Option<Arc<metrics::LookupCounts>>,
+    observers: Vec<Arc<dyn observer::TopologyObserver>>,
+    rules: Vec<rules::Rule>,
+    changelog: Option<Arc<changelog::Changelog>>,
+    // derived per-tenant layouts, dropped whenever this ring changes
+    sub_rings: tenant::SubRings,
+    // The bulky parts are shared between clones until one of them changes,
+    // so that handing a copy of a big ring to each worker is cheap
+    position_to_target: Arc<BTreeMap<Position, Target>>,
+    sorted_position_to_target: Arc<Vec<(Position, Target)>>,
+    eytzinger: Arc<Vec<(Position, usize)>>,
+    target_to_positions: Arc<BTreeMap<Target, Vec<Position>>>,
+    groups: HashMap<String, Vec<Target>>,
+    target_info: Arc<HashMap<Target, TargetInfo>>,
+    // when each target was added, for targets_in_insertion_order
+    added: HashMap<Target, u64>,
+    next_added: u64,
+    // bumped by every change to the targets, so that whoever hands out
+    // deltas can tell when a ring has moved on from the one they expected
+    generation: u64,
+    // opaque to us; eg the id of the controller which made the last change
+    causal_token: Option<String>,
 }
 
+const DEFAULT_MAX_TOTAL_POSITIONS: u64 = 1 << 24;
+
 /*
  * Basic methods
  */
 impl Flexihash {
     pub fn new() -> Flexihash {
         return Flexihash {
-            hasher: Hasher::Crc32,
+            hasher: Hasher::default(),
             replicas: 64,
-            position_to_target: BTreeMap::new(),
-            sorted_position_to_target: Vec::new(),
-            target_to_positions: HashMap::new(),
+            replica_strategy: Arc::new(scaling::Linear),
+            search: Search::Eytzinger,
+            duplicate_policy: DuplicatePolicy::Error,
+            name_normalization: NameNormalization::AsGiven,
+            key_prefix: String::new(),
+            salt: String::new(),
+            hash_tags: false,
+            key_normalization: KeyNormalization::AsGiven,
+            max_total_positions: DEFAULT_MAX_TOTAL_POSITIONS,
+            metrics: None,
+            lookup_counts: None,
+            observers: Vec::new(),
+            rules: Vec::new(),
+            changelog: None,
+            sub_rings: tenant::SubRings::default(),
+            position_to_target: Arc::new(BTreeMap::new()),
+            sorted_position_to_target: Arc::new(Vec::new()),
+            eytzinger: Arc::new(Vec::new()),
+            target_to_positions: Arc::new(BTreeMap::new()),
+            groups: HashMap::new(),
+            target_info: Arc::new(HashMap::new()),
+            added: HashMap::new(),
+            next_added: 0,
+            generation: 0,
+            causal_token: None,
         };
     }
 
     pub fn set_hasher(&mut self, hasher: Hasher) {
         self.hasher = hasher;
+        self.sub_rings = tenant::SubRings::default();
     }
 
     pub fn set_replicas(&mut self, replicas: u32) {
         self.replicas = replicas;
     }
-}
 
-/*
- * Formatting
- */
-use std::fmt;
+    // Only affects targets added or reweighted afterwards
+    pub fn set_replica_strategy(&mut self, strategy: Arc<dyn scaling::ReplicaStrategy>) {
+        self.replica_strategy = strategy;
+    }
 
-impl fmt::Display for Flexihash {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut keys: Vec<&Target> = self.target_to_positions.keys().collect();
-        keys.sort();
-        write!(f, "Flexihash({:?})", keys)
+    pub fn set_search(&mut self, search: Search) {
+        self.search = search;
+        self.sub_rings = tenant::SubRings::default();
     }
-}
 
-#[cfg(test)]
-mod test_formatting {
-    use super::*;
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicate_policy = policy;
+    }
 
-    #[test]
-    fn to_string() {
-        let mut fh = Flexihash::new();
-        fh.add_target("foo", 2);
-        fh.add_target("bar", 4);
-        assert_eq!(fh.to_string(), "Flexihash([\"bar\", \"foo\"])");
+    // Applies to names passed in afterwards; targets already in the ring
+    // keep the names they were added with
+    pub fn set_name_normalization(&mut self, normalization: NameNormalization) {
+        self.name_normalization = normalization;
     }
 
-    #[test]
-    fn debug() {
-        let mut fh = Flexihash::new();
-        fh.add_target("foo", 2);
-        fh.add_target("bar", 4);
-        assert_eq!(format!("{:?}", fh).to_string().len() > 10, true);
+    // Prepended to every resource before hashing, so that several keyspaces
+    // can share one set of targets without all landing in the same places
+    pub fn set_key_prefix<S: Into<String>>(&mut self, prefix: S) {
+        self.key_prefix = prefix.into();
+        self.sub_rings = tenant::SubRings::default();
     }
-}
 
-/*
- * Add / remove targets
- */
-impl Flexihash {
-    pub fn add_target<S: Into<String>>(&mut self, target: S, weight: u32) -> &Flexihash {
-        let target = target.into();
-        if self.target_to_positions.contains_key(&target) {
-            panic!("Target {} already exists", target);
-        }
-        let mut positions = Vec::new();
-        for i in 0..self.replicas * weight {
-            let t = target.clone();
-            let sub_target = format!("{}{}", t, i);
-            let position = hash(&self.hasher, sub_target);
-            positions.push(position.clone());
-            self.position_to_target
-                .insert(position.clone(), target.clone());
-        }
-        self.sorted_position_to_target = Vec::with_capacity(self.position_to_target.len());
-        for (k, v) in self.position_to_target.iter() {
-            self.sorted_position_to_target.push((k.clone(), v.clone()));
-        }
-        self.target_to_positions.insert(target.clone(), positions);
-        return self;
+    // Prepended to each target's replica keys, so that two rings over the
+    // same targets can be laid out differently (eg staging and prod) while
+    // each stays reproducible. Only affects targets added afterwards.
+    pub fn set_salt<S: Into<String>>(&mut self, salt: S) {
+        self.salt = salt.into();
+        self.sub_rings = tenant::SubRings::default();
     }
 
-    pub fn add_targets<S: Into<String>>(&mut self, targets: Vec<S>) -> &Flexihash {
-        for target in targets {
-            self.add_target(target, 1);
-        }
-        return self;
+    // Redis-style "{tag}"s - if a resource contains one, only the tag is
+    // hashed, so eg "{user1}:name" and "{user1}:email" share a target
+    pub fn set_hash_tags(&mut self, enabled: bool) {
+        self.hash_tags = enabled;
+        self.sub_rings = tenant::SubRings::default();
     }
 
-    pub fn remove_target<S: Into<String>>(&mut self, target: S) -> &Flexihash {
-        let target = target.into();
-        if let Some(position_list) = self.target_to_positions.get(target.as_str()) {
-            for position in position_list {
-                self.position_to_target.remove(position);
-            }
-            self.sorted_position_to_target = Vec::new();
-            for (k, v) in self.position_to_target.iter() {
-                self.sorted_position_to_target.push((k.clone(), v.clone()));
-            }
-            self.target_to_positions.remove(target.as_str());
-        } else {
-            panic!("Target '{}' does not exist", target);
-        }
+    pub fn set_key_normalization(&mut self, normalization: KeyNormalization) {
+        self.key_normalization = normalization;
+        self.sub_rings = tenant::SubRings::default();
+    }
 
-        return self;
+    // A cap on replicas x weight summed over all targets, so that a typo'd
+    // weight fails cleanly instead of eating all the memory there is
+    pub fn set_max_total_positions(&mut self, max: u64) {
+        self.max_total_positions = max;
     }
 
-    pub fn get_all_targets(&self) -> Vec<Target> {
-        let mut targets = Vec::new();
-        for (k, _) in self.target_to_positions.iter() {
-            targets.push(k.clone());
+    pub fn set_metrics_sink(&mut self, sink: std::sync::Arc<dyn metrics::MetricsSink>) {
+        self.metrics = Some(sink);
+    }
+
+    // Count lookups per target in-process; see lookup_counts()
+    pub fn enable_lookup_counts(&mut self) {
+        if self.lookup_counts.is_none() {
+            self.lookup_counts = Some(Arc::new(metrics::LookupCounts::default()));
         }
-        targets.sort();
-        return targets;
     }
-}
 
-#[cfg(test)]
-mod test_add_remove {
+    // Empty unless enable_lookup_counts() has been called
+    pub fn lookup_counts(&self) -> HashMap<Target, u64> {
+        return self
+            .lookup_counts
+            .as_ref()
+            .map_or_else(HashMap::new, |c| c.counts());
+    }
+
+    pub fn reset_lookup_counts(&self) {
+        if let Some(counts) = &self.lookup_counts {
+            counts.take();
+        }
+    }
+
+    pub fn add_topology_observer(&mut self, observer: Arc<dyn observer::TopologyObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn replicas(&self) -> u32 {
+        return self.replicas;
+    }
+
+    pub fn name_normalization(&self) -> NameNormalization {
+        return self.name_normalization;
+    }
+
+    pub fn hasher(&self) -> &Hasher {
+        return &self.hasher;
+    }
+
+    pub fn key_prefix(&self) -> &str {
+        return &self.key_prefix;
+    }
+
+    pub fn salt(&self) -> &str {
+        return &self.salt;
+    }
+
+    pub fn hash_tags(&self) -> bool {
+        return self.hash_tags;
+    }
+
+    pub fn key_normalization(&self) -> KeyNormalization {
+        return self.key_normalization;
+    }
+
+    pub fn max_total_positions(&self) -> u64 {
+        return self.max_total_positions;
+    }
+
+    pub fn generation(&self) -> u64 {
+        return self.generation;
+    }
+
+    pub fn causal_token(&self) -> Option<&str> {
+        return self.causal_token.as_deref();
+    }
+
+    pub fn set_causal_token<S: Into<String>>(&mut self, token: S) {
+        self.causal_token = Some(token.into());
+    }
+
+    // Distinct positions on the ring, which is less than replicas x weight
+    // when some of them collide
+    pub fn position_count(&self) -> usize {
+        return self.sorted_position_to_target.len();
+    }
+}
+
+impl Default for Flexihash {
+    fn default() -> Flexihash {
+        return Flexihash::new();
+    }
+}
+
+/*
+ * A fixed ring, spelled out in one place:
+ *
+ *   static RING: OnceLock<Flexihash> = OnceLock::new();
+ *   let ring = RING.get_or_init(|| flexihash! {
+ *       hasher: Crc32,
+ *       replicas: 64,
+ *       targets: ["cache-1" => 2, "cache-2" => 1],
+ *   });
+ *
+ * hasher and replicas are optional. Hashing can't happen at compile time,
+ * so this still builds the ring when it runs - but in one go, with a
+ * single rebuild, and a mistake like a duplicated target panics before
+ * anything gets to use the ring.
+ */
+#[macro_export]
+macro_rules! flexihash {
+    (
+        $(hasher: $hasher:expr,)?
+        $(replicas: $replicas:expr,)?
+        targets: [$($target:expr => $weight:expr),* $(,)?] $(,)?
+    ) => {{
+        #[allow(unused_imports)]
+        use $crate::Hasher::*;
+        #[allow(unused_mut)]
+        let mut fh = $crate::Flexihash::new();
+        $(fh.set_hasher($hasher);)?
+        $(fh.set_replicas($replicas);)?
+        fh.transaction(|_tx| {
+            $(_tx.add($target, $weight);)*
+        })
+        .unwrap_or_else(|e| panic!("{}", e));
+        fh
+    }};
+}
+
+#[cfg(test)]
+mod test_macro {
+    use super::*;
+    use std::sync::OnceLock;
+
+    #[test]
+    fn flexihash_macro() {
+        let fh = flexihash! {
+            hasher: Adler32,
+            replicas: 8,
+            targets: ["t-a" => 2, "t-b" => 1],
+        };
+        assert!(matches!(fh.hasher(), Hasher::Adler32));
+        assert_eq!(fh.replicas(), 8);
+        assert_eq!(fh.get_target_info("t-a").unwrap().weight, 2);
+        assert_eq!(fh.generation(), 1);
+
+        let mut expected = Flexihash::new();
+        expected.set_hasher(Hasher::Adler32);
+        expected.set_replicas(8);
+        expected.add_target("t-a", 2);
+        expected.add_target("t-b", 1);
+        assert_eq!(
+            fh.sorted_position_to_target,
+            expected.sorted_position_to_target
+        );
+
+        let fh = flexihash! { targets: ["t-a" => 1] };
+        assert_eq!(fh.hasher().to_string(), Hasher::default().to_string());
+        assert_eq!(fh.get_all_targets(), ["t-a"]);
+
+        let fh = flexihash! { hasher: Mock(5), targets: [] };
+        assert!(matches!(fh.hasher(), Hasher::Mock(5)));
+    }
+
+    #[test]
+    fn flexihash_macro_static() {
+        static RING: OnceLock<Flexihash> = OnceLock::new();
+        let ring = RING.get_or_init(|| flexihash! { targets: ["t-a" => 1] });
+        assert_eq!(ring.lookup("resource"), "t-a");
+    }
+
+    #[test]
+    #[should_panic(expected = "Target t-a already exists")]
+    fn flexihash_macro_duplicate() {
+        flexihash! { targets: ["t-a" => 1, "t-a" => 2] };
+    }
+}
+
+#[cfg(test)]
+mod test_basic {
     use super::*;
 
     #[test]
-    fn get_all_targets_empty() {
-        let fh = Flexihash::new();
-        assert_eq!(fh.get_all_targets().len(), 0);
+    fn configuration_getters() {
+        let mut fh = Flexihash::new();
+        assert_eq!(fh.replicas(), 64);
+        #[cfg(feature = "crc")]
+        assert!(matches!(fh.hasher(), Hasher::Crc32));
+        #[cfg(not(feature = "crc"))]
+        assert!(matches!(fh.hasher(), Hasher::Adler32));
+        assert_eq!(fh.position_count(), 0);
+
+        fh.set_replicas(8);
+        fh.set_hasher(Hasher::Adler32);
+        fh.set_key_prefix("ns:");
+        fh.set_hash_tags(true);
+        fh.set_salt("staging");
+        fh.set_max_total_positions(1000);
+        fh.set_name_normalization(NameNormalization::AsciiLowercase);
+        fh.add_target("t-a", 2);
+        assert_eq!(fh.replicas(), 8);
+        assert_eq!(fh.salt(), "staging");
+        assert_eq!(fh.max_total_positions(), 1000);
+        assert_eq!(fh.name_normalization(), NameNormalization::AsciiLowercase);
+        assert!(matches!(fh.hasher(), Hasher::Adler32));
+        assert_eq!(fh.key_prefix(), "ns:");
+        assert!(fh.hash_tags());
+        assert_eq!(fh.position_count(), 16);
+
+        // collisions leave fewer positions than replicas
+        let mut fh = Flexihash::new();
+        fh.set_hasher(Hasher::Mock(1));
+        fh.add_target("t-a", 1);
+        assert_eq!(fh.position_count(), 1);
+    }
+}
+
+/*
+ * Formatting
+ */
+use std::fmt;
+
+impl fmt::Display for Flexihash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys: Vec<&Target> = self.targets().collect();
+        write!(f, "Flexihash({:?})", keys)
+    }
+}
+
+#[cfg(test)]
+mod test_formatting {
+    use super::*;
+
+    #[test]
+    fn to_string() {
+        let mut fh = Flexihash::new();
+        fh.add_target("foo", 2);
+        fh.add_target("bar", 4);
+        assert_eq!(fh.to_string(), "Flexihash([\"bar\", \"foo\"])");
+    }
+
+    #[test]
+    fn debug() {
+        let mut fh = Flexihash::new();
+        fh.add_target("foo", 2);
+        fh.add_target("bar", 4);
+        assert!(format!("{:?}", fh).len() > 10);
+    }
+}
+
+/*
+ * Add / remove targets
+ */
+impl Flexihash {
+    pub fn add_target<S: Into<String>>(&mut self, target: S, weight: u32) -> &Flexihash {
+        if let Err(e) = self.try_add_target(target, weight) {
+            panic!("{}", e);
+        }
+        return self;
+    }
+
+    pub fn try_add_target<S: Into<String>>(
+        &mut self,
+        target: S,
+        weight: u32,
+    ) -> Result<&Flexihash, Error> {
+        let target = self.normalize(target.into());
+        let existing = self.target_positions(&target);
+        if self.target_to_positions.contains_key(&target) {
+            match self.duplicate_policy {
+                DuplicatePolicy::Error => return Err(Error::TargetExists(target)),
+                DuplicatePolicy::Ignore => return Ok(self),
+                DuplicatePolicy::Replace => {}
+            }
+        }
+        let total = self.total_positions() - existing + self.positions_for(weight);
+        self.check_total_positions(&target, total)?;
+        if existing > 0 {
+            self.forget_target(&target);
+        }
+        self.place_target(target, weight);
+        self.rebuild();
+        return Ok(self);
+    }
+
+    pub fn add_targets<S: Into<String>>(&mut self, targets: Vec<S>) -> &Flexihash {
+        for target in targets {
+            self.add_target(target, 1);
+        }
+        return self;
+    }
+
+    pub fn remove_target<S: Into<String>>(&mut self, target: S) -> &Flexihash {
+        let target = self.normalize(target.into());
+        if self.target_to_positions.contains_key(&target) {
+            self.forget_target(&target);
+            self.rebuild();
+        } else {
+            self.missing_target(&target);
+        }
+
+        return self;
+    }
+
+    pub fn get_all_targets(&self) -> Vec<Target> {
+        return self.targets().cloned().collect();
+    }
+
+    // In name order, without copying anything
+    pub fn targets(&self) -> impl Iterator<Item = &Target> {
+        return self.target_to_positions.keys();
+    }
+
+    // In the order they were added; re-adding a target with
+    // DuplicatePolicy::Replace moves it to the end, changing its weight
+    // doesn't
+    pub fn targets_in_insertion_order(&self) -> Vec<Target> {
+        let mut targets: Vec<(&u64, &Target)> = self.added.iter().map(|(t, n)| (n, t)).collect();
+        targets.sort_unstable();
+        return targets.into_iter().map(|(_, t)| t.clone()).collect();
+    }
+
+    pub fn add_group<G: Into<String>, S: Into<String>>(
+        &mut self,
+        group: G,
+        targets: Vec<S>,
+    ) -> &Flexihash {
+        let group = group.into();
+        if self.groups.contains_key(&group) {
+            panic!("Group {} already exists", group);
+        }
+        let targets: Vec<Target> = targets
+            .into_iter()
+            .map(|t| self.normalize(t.into()))
+            .collect();
+        // check everything up front, so that a bad group changes nothing
+        for (i, target) in targets.iter().enumerate() {
+            if targets[..i].contains(target) {
+                panic!("Target {} appears in group {} twice", target, group);
+            }
+            if self.target_to_positions.contains_key(target)
+                && self.duplicate_policy == DuplicatePolicy::Error
+            {
+                panic!("Target {} already exists", target);
+            }
+        }
+        let mut total = self.total_positions();
+        for target in targets.iter() {
+            let exists = self.target_to_positions.contains_key(target);
+            let replaced = exists && self.duplicate_policy == DuplicatePolicy::Replace;
+            if replaced {
+                total -= self.target_positions(target);
+            }
+            if !exists || replaced {
+                total = total.saturating_add(self.positions_for(1));
+            }
+            if let Err(e) = self.check_total_positions(target, total) {
+                panic!("{}", e);
+            }
+        }
+        let mut members = Vec::new();
+        for target in targets {
+            if self.target_to_positions.contains_key(&target) {
+                match self.duplicate_policy {
+                    DuplicatePolicy::Error => unreachable!(),
+                    DuplicatePolicy::Ignore => continue,
+                    DuplicatePolicy::Replace => self.forget_target(&target),
+                }
+            }
+            self.place_target(target.clone(), 1);
+            members.push(target);
+        }
+        self.groups.insert(group, members);
+        self.rebuild();
+        return self;
+    }
+
+    pub fn remove_group<G: Into<String>>(&mut self, group: G) -> &Flexihash {
+        let group = group.into();
+        if let Some(members) = self.groups.remove(&group) {
+            for target in members {
+                self.forget_target(&target);
+            }
+            self.rebuild();
+        } else {
+            panic!("Group '{}' does not exist", group);
+        }
+        return self;
+    }
+
+    pub fn update_target_weight<S: Into<String>>(&mut self, target: S, weight: u32) -> &Flexihash {
+        let target = self.normalize(target.into());
+        if !self.target_to_positions.contains_key(&target) {
+            self.missing_target(&target);
+        }
+        if let Err(e) = self.try_update_target_weight(target, weight) {
+            panic!("{}", e);
+        }
+        return self;
+    }
+
+    pub fn try_update_target_weight<S: Into<String>>(
+        &mut self,
+        target: S,
+        weight: u32,
+    ) -> Result<&Flexihash, Error> {
+        let target = self.normalize(target.into());
+        if !self.target_to_positions.contains_key(&target) {
+            return Err(self.target_missing(&target));
+        }
+        let count = self.positions_for(weight);
+        let total = self.total_positions() - self.target_positions(&target) + count;
+        self.check_total_positions(&target, total)?;
+        self.reweight_target(target, weight, count);
+        self.rebuild();
+        return Ok(self);
+    }
+
+    pub fn add_ring_target<T: RingTarget + ?Sized>(&mut self, target: &T) -> &Flexihash {
+        let name = self.normalize(target.name());
+        let ignored = self.duplicate_policy == DuplicatePolicy::Ignore
+            && self.target_to_positions.contains_key(&name);
+        self.add_target(name.clone(), target.weight());
+        if !ignored {
+            if let Some(info) = Arc::make_mut(&mut self.target_info).get_mut(&name) {
+                info.zone = target.zone();
+                info.tier = target.tier();
+                info.labels = target.labels();
+            }
+        }
+        return self;
+    }
+
+    // For syncing with a registry: remove some targets and add_ring_target
+    // others (reweighting those already there), all or nothing
+    #[cfg(any(feature = "consul", feature = "etcd"))]
+    pub(crate) fn try_sync_ring_targets(
+        &mut self,
+        remove: &[Target],
+        put: &[&dyn RingTarget],
+    ) -> Result<(), Error> {
+        let remove: Vec<Target> = remove.iter().map(|t| self.normalize(t.clone())).collect();
+        let put: Vec<(Target, &dyn RingTarget, bool)> = put
+            .iter()
+            .map(|target| {
+                let name = self.normalize(target.name());
+                let present =
+                    self.target_to_positions.contains_key(&name) && !remove.contains(&name);
+                (name, *target, present)
+            })
+            .collect();
+        self.transaction(|tx| {
+            for target in remove.iter() {
+                tx.remove(target.clone());
+            }
+            for (name, target, present) in put.iter() {
+                if *present {
+                    tx.set_weight(name.clone(), target.weight());
+                } else {
+                    tx.add(name.clone(), target.weight());
+                }
+            }
+        })?;
+        let infos = Arc::make_mut(&mut self.target_info);
+        for (name, target, _) in put {
+            if let Some(info) = infos.get_mut(&name) {
+                info.zone = target.zone();
+                info.tier = target.tier();
+                info.labels = target.labels();
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn get_target_info<S: AsRef<str>>(&self, target: S) -> Option<&TargetInfo> {
+        return self
+            .target_info
+            .get(self.normalize_str(target.as_ref()).as_ref());
+    }
+
+    fn normalize(&self, target: String) -> Target {
+        return match self.normalize_str(&target) {
+            Cow::Borrowed(_) => target,
+            Cow::Owned(normalized) => normalized,
+        };
+    }
+
+    fn normalize_str<'a>(&self, target: &'a str) -> Cow<'a, str> {
+        return match self.name_normalization {
+            NameNormalization::AsciiLowercase if target.bytes().any(|b| b.is_ascii_uppercase()) => {
+                Cow::Owned(target.to_ascii_lowercase())
+            }
+            _ => Cow::Borrowed(target),
+        };
+    }
+
+    // The existing targets whose names are closest to one that doesn't
+    // exist, if any are close enough to be a typo of it: all of those at
+    // the smallest edit distance, up to three
+    pub fn similar_targets(&self, target: &str) -> Vec<Target> {
+        let target = self.normalize_str(target);
+        return similar_names(&target, self.target_to_positions.keys().map(|t| t.as_str()));
+    }
+
+    // Operators mistype hostnames far more often than they ask for
+    // targets which have really gone
+    fn missing_target(&self, target: &str) -> ! {
+        panic!("{}", self.target_missing(target));
+    }
+
+    pub(crate) fn target_missing(&self, target: &str) -> Error {
+        return Error::TargetMissing(target.to_string(), self.similar_targets(target));
+    }
+
+    pub fn get_all_groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self.groups.keys().cloned().collect();
+        groups.sort();
+        return groups;
+    }
+
+    // Replica positions asked for, before any collide
+    fn positions_for(&self, weight: u32) -> u64 {
+        return self.replica_strategy.positions(self.replicas, weight);
+    }
+
+    fn target_positions(&self, target: &str) -> u64 {
+        return self
+            .target_to_positions
+            .get(target)
+            .map_or(0, |p| p.len() as u64);
+    }
+
+    fn total_positions(&self) -> u64 {
+        return self
+            .target_to_positions
+            .values()
+            .map(|p| p.len() as u64)
+            .sum();
+    }
+
+    fn check_total_positions(&self, target: &str, total: u64) -> Result<(), Error> {
+        if total > self.max_total_positions {
+            return Err(Error::TooManyPositions(
+                target.to_string(),
+                total,
+                self.max_total_positions,
+            ));
+        }
+        return Ok(());
+    }
+
+    // Callers check positions_for() against the limit first
+    fn place_target(&mut self, target: Target, weight: u32) {
+        let count = self.positions_for(weight);
+        self.place_replicas(target.clone(), weight, count);
+        for observer in self.observers.iter() {
+            observer.on_target_added(&target, &self.target_to_positions[&target]);
+        }
+        if let Some(log) = &self.changelog {
+            // the generation the rebuild after this will make
+            log.record(
+                self.generation + 1,
+                changelog::Mutation::Add(target, weight),
+            );
+        }
+    }
+
+    // Callers check positions_for() (or count) against the limit first
+    fn reweight_target(&mut self, target: Target, weight: u32, count: u64) {
+        let old_weight = self.target_info.get(&target).map_or(0, |i| i.weight);
+        let old_positions = self.unplace_target(&target);
+        self.place_replicas(target.clone(), weight, count);
+        for observer in self.observers.iter() {
+            observer.on_weight_changed(
+                &target,
+                old_weight,
+                weight,
+                &old_positions,
+                &self.target_to_positions[&target],
+            );
+        }
+        if let Some(log) = &self.changelog {
+            let mutation = changelog::Mutation::Reweight {
+                target,
+                weight,
+                positions: count,
+            };
+            log.record(self.generation + 1, mutation);
+        }
+    }
+
+    // Only the first count replicas, which are a subset of the ones for
+    // any larger count - so shrinking count only moves keys off target
+    fn place_replicas(&mut self, target: Target, weight: u32, count: u64) {
+        let mut positions = Vec::with_capacity(count as usize);
+        let replicas = if self.salt.is_empty() {
+            ReplicaHasher::new(&self.hasher, &target)
+        } else {
+            ReplicaHasher::new(&self.hasher, &format!("{}{}", self.salt, target))
+        };
+        let position_to_target = Arc::make_mut(&mut self.position_to_target);
+        for i in 0..count {
+            let position = replicas.position(i);
+            positions.push(position);
+            position_to_target.insert(position, target.clone());
+        }
+        Arc::make_mut(&mut self.target_info)
+            .entry(target.clone())
+            .or_default()
+            .weight = weight;
+        if !self.added.contains_key(&target) {
+            self.added.insert(target.clone(), self.next_added);
+            self.next_added += 1;
+        }
+        Arc::make_mut(&mut self.target_to_positions).insert(target, positions);
+    }
+
+    fn unplace_target(&mut self, target: &str) -> Vec<Position> {
+        let positions = Arc::make_mut(&mut self.target_to_positions)
+            .remove(target)
+            .unwrap_or_default();
+        let position_to_target = Arc::make_mut(&mut self.position_to_target);
+        for position in positions.iter() {
+            position_to_target.remove(position);
+        }
+        return positions;
+    }
+
+    // Like unplace_target, but for good rather than to be placed again
+    fn forget_target(&mut self, target: &str) {
+        let positions = self.unplace_target(target);
+        for observer in self.observers.iter() {
+            observer.on_target_removed(target, &positions);
+        }
+        if let Some(log) = &self.changelog {
+            let mutation = changelog::Mutation::Remove(target.to_string());
+            log.record(self.generation + 1, mutation);
+        }
+        Arc::make_mut(&mut self.target_info).remove(target);
+        self.added.remove(target);
+        for members in self.groups.values_mut() {
+            members.retain(|t| t != target);
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let started = std::time::Instant::now();
+        self.generation += 1;
+        self.sub_rings = tenant::SubRings::default();
+        let mut sorted = Vec::with_capacity(self.position_to_target.len());
+        for (k, v) in self.position_to_target.iter() {
+            sorted.push((*k, v.clone()));
+        }
+
+        // Lay the same positions out in Eytzinger (BFS) order, so that the
+        // first few levels of the search tree share cache lines
+        let mut eytzinger = vec![(0, 0); sorted.len()];
+        let mut i = 0;
+        fill_eytzinger(&sorted, &mut eytzinger, &mut i, 1);
+        self.sorted_position_to_target = Arc::new(sorted);
+        self.eytzinger = Arc::new(eytzinger);
+
+        if let Some(metrics) = &self.metrics {
+            metrics.rebuilt(started.elapsed(), self.sorted_position_to_target.len());
+            metrics.topology_changed(self.target_to_positions.len());
+        }
+    }
+}
+
+fn fill_eytzinger(
+    sorted: &[(Position, Target)],
+    eytzinger: &mut [(Position, usize)],
+    i: &mut usize,
+    k: usize,
+) {
+    if k <= eytzinger.len() {
+        fill_eytzinger(sorted, eytzinger, i, 2 * k);
+        eytzinger[k - 1] = (sorted[*i].0, *i);
+        *i += 1;
+        fill_eytzinger(sorted, eytzinger, i, 2 * k + 1);
+    }
+}
+
+#[cfg(test)]
+mod test_add_remove {
+    use super::*;
+
+    #[test]
+    fn get_all_targets_empty() {
+        let fh = Flexihash::new();
+        assert_eq!(fh.get_all_targets().len(), 0);
+    }
+
+    #[test]
+    fn targets_are_borrowed_in_order() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-c", "t-a", "t-b"]);
+        fh.remove_target("t-b");
+        let targets: Vec<&Target> = fh.targets().collect();
+        assert_eq!(targets, ["t-a", "t-c"]);
+    }
+
+    #[test]
+    fn targets_in_insertion_order() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-c", "t-a", "t-b"]);
+        fh.remove_target("t-a");
+        fh.add_target("t-a", 1);
+        fh.update_target_weight("t-c", 2);
+        assert_eq!(fh.targets_in_insertion_order(), ["t-c", "t-b", "t-a"]);
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-b", "t-c"]);
+
+        fh.set_duplicate_policy(DuplicatePolicy::Replace);
+        fh.add_target("t-c", 1);
+        assert_eq!(fh.targets_in_insertion_order(), ["t-b", "t-a", "t-c"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_target_throws_exception_on_duplicate_target() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.add_target("t-a", 1);
+    }
+
+    #[test]
+    fn add_target_ignores_duplicate_target() {
+        let mut fh = Flexihash::new();
+        fh.set_duplicate_policy(DuplicatePolicy::Ignore);
+        fh.add_target("t-a", 1);
+        fh.add_target("t-a", 2);
+
+        assert_eq!(fh.get_all_targets(), ["t-a"]);
+        assert_eq!(fh.position_to_target.len(), 64);
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn add_target_replaces_duplicate_target() {
+        let mut fh = Flexihash::new();
+        fh.set_duplicate_policy(DuplicatePolicy::Replace);
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 1);
+        fh.add_target("t-a", 2);
+
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-b"]);
+        assert_eq!(fh.target_to_positions["t-a"].len(), 128);
+        assert_eq!(fh.position_to_target.len(), 192);
+    }
+
+    #[test]
+    fn add_target_and_get_all_targets() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 1);
+        fh.add_target("t-c", 1);
+
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-b", "t-c"]);
+    }
+
+    #[test]
+    fn add_targets_and_get_all_targets() {
+        let targets = vec!["t-a", "t-b", "t-c"];
+
+        let mut fh = Flexihash::new();
+        fh.add_targets(targets.clone());
+        assert_eq!(fh.get_all_targets(), targets);
+    }
+
+    #[test]
+    fn remove_target() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 1);
+        fh.add_target("t-c", 1);
+        fh.remove_target("t-b");
+
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-c"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 'not-there' does not exist")]
+    fn remove_target_fails_on_missing_target() {
+        let mut fh = Flexihash::new();
+        fh.remove_target("not-there");
+    }
+
+    #[test]
+    fn update_target_weight() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.update_target_weight("t-a", 3);
+        assert_eq!(fh.sorted_position_to_target.len(), 192);
     }
 
     #[test]
-    #[should_panic]
-    fn add_target_throws_exception_on_duplicate_target() {
+    #[should_panic(expected = "Target 'not-there' does not exist")]
+    fn update_target_weight_fails_on_missing_target() {
+        let mut fh = Flexihash::new();
+        fh.update_target_weight("not-there", 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 'cache-01' does not exist; did you mean 'cache-1'?")]
+    fn missing_target_suggests_typos() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["cache-1", "cache-2", "db-1"]);
+        fh.remove_target("cache-01");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Target 'web-9' does not exist; did you mean 'web-1', 'web-2' or 'web-3'?"
+    )]
+    fn missing_target_lists_the_closest() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["web-1", "web-2", "web-3", "web-4"]);
+        fh.update_target_weight("web-9", 2);
+    }
+
+    #[test]
+    fn missing_target_errors_carry_suggestions() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["cache-1", "cache-2", "db-1"]);
+        let error = fh.try_update_target_weight("cache-01", 2).unwrap_err();
+        assert_eq!(
+            error,
+            Error::TargetMissing("cache-01".to_string(), vec!["cache-1".to_string()])
+        );
+        assert_eq!(
+            error.to_string(),
+            "Target 'cache-01' does not exist; did you mean 'cache-1'?"
+        );
+
+        // against the targets as they'd be partway through
+        let error = fh
+            .transaction(|tx| {
+                tx.remove("cache-1").remove("cache-01");
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Target 'cache-01' does not exist; did you mean 'cache-2'?"
+        );
+        assert_eq!(
+            Error::TargetMissing("x".to_string(), vec![]).to_string(),
+            "Target 'x' does not exist"
+        );
+    }
+
+    #[test]
+    fn similar_targets() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec![
+            "web-1.eu", "web-2.eu", "web-3.eu", "web-4.eu", "db-1.eu",
+        ]);
+        assert_eq!(fh.similar_targets("web-1.us"), ["web-1.eu"]);
+        assert_eq!(
+            fh.similar_targets("web-9.eu"),
+            ["web-1.eu", "web-2.eu", "web-3.eu"]
+        );
+        assert!(fh.similar_targets("cache-1.eu").is_empty());
+        assert!(Flexihash::new().similar_targets("web-1.eu").is_empty());
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "ab"), 2);
+    }
+
+    struct Node {
+        host: String,
+        port: u16,
+        capacity: u32,
+        rack: String,
+    }
+
+    impl RingTarget for Node {
+        fn name(&self) -> Target {
+            return format!("{}:{}", self.host, self.port);
+        }
+
+        fn weight(&self) -> u32 {
+            return self.capacity;
+        }
+
+        fn zone(&self) -> Option<String> {
+            return Some(self.rack.clone());
+        }
+    }
+
+    #[test]
+    fn add_ring_target() {
+        let mut fh = Flexihash::new();
+        fh.add_ring_target(&Node {
+            host: "cache-1".to_string(),
+            port: 11211,
+            capacity: 2,
+            rack: "rack-7".to_string(),
+        });
+        fh.add_target("cache-2:11211", 1);
+
+        assert_eq!(fh.get_all_targets(), ["cache-1:11211", "cache-2:11211"]);
+        assert_eq!(fh.target_to_positions["cache-1:11211"].len(), 128);
+        let info = fh.get_target_info("cache-1:11211").unwrap();
+        assert_eq!(info.weight, 2);
+        assert_eq!(info.zone, Some("rack-7".to_string()));
+        assert_eq!(fh.get_target_info("cache-2:11211").unwrap().zone, None);
+        assert_eq!(fh.get_target_info("cache-3:11211"), None);
+    }
+
+    #[test]
+    fn target_info_survives_weight_changes() {
+        let mut fh = Flexihash::new();
+        fh.add_ring_target(&Node {
+            host: "cache-1".to_string(),
+            port: 11211,
+            capacity: 1,
+            rack: "rack-7".to_string(),
+        });
+        fh.update_target_weight("cache-1:11211", 3);
+        let info = fh.get_target_info("cache-1:11211").unwrap();
+        assert_eq!(info.weight, 3);
+        assert_eq!(info.zone, Some("rack-7".to_string()));
+
+        fh.remove_target("cache-1:11211");
+        assert_eq!(fh.get_target_info("cache-1:11211"), None);
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn add_and_remove_group() {
         let mut fh = Flexihash::new();
         fh.add_target("t-a", 1);
+        fh.add_group("rack-7", vec!["t-b", "t-c"]);
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-b", "t-c"]);
+        assert_eq!(fh.get_all_groups(), ["rack-7"]);
+
+        fh.remove_group("rack-7");
+        assert_eq!(fh.get_all_targets(), ["t-a"]);
+        assert_eq!(fh.get_all_groups().len(), 0);
+        assert_eq!(fh.position_to_target.len(), 64);
+    }
+
+    #[test]
+    fn add_group_is_atomic() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-b", 1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            fh.add_group("rack-7", vec!["t-a", "t-b"]);
+        }));
+        assert!(result.is_err());
+        assert_eq!(fh.get_all_targets(), ["t-b"]);
+        assert_eq!(fh.get_all_groups().len(), 0);
+    }
+
+    #[test]
+    fn remove_group_skips_removed_members() {
+        let mut fh = Flexihash::new();
+        fh.add_group("rack-7", vec!["t-a", "t-b"]);
+        fh.remove_target("t-a");
         fh.add_target("t-a", 1);
+        fh.remove_group("rack-7");
+        assert_eq!(fh.get_all_targets(), ["t-a"]);
     }
 
     #[test]
-    fn add_target_and_get_all_targets() {
+    #[should_panic(expected = "Group 'not-there' does not exist")]
+    fn remove_group_fails_on_missing_group() {
+        let mut fh = Flexihash::new();
+        fh.remove_group("not-there");
+    }
+
+    #[test]
+    fn huge_weights_fail_rather_than_wrapping() {
+        let mut fh = Flexihash::new();
+        // 64 * (2^26 + 1) wraps to 64 in u32 maths
+        assert!(matches!(
+            fh.try_add_target("t-a", (1 << 26) + 1),
+            Err(Error::TooManyPositions(_, total, _)) if total == 64 * ((1 << 26) + 1)
+        ));
+        assert!(matches!(
+            fh.try_add_target("t-a", u32::MAX),
+            Err(Error::TooManyPositions(..))
+        ));
+        assert_eq!(fh.position_count(), 0);
+    }
+
+    #[test]
+    fn max_total_positions_covers_the_whole_ring() {
         let mut fh = Flexihash::new();
+        fh.set_max_total_positions(200);
         fh.add_target("t-a", 1);
         fh.add_target("t-b", 1);
-        fh.add_target("t-c", 1);
+        assert!(fh.try_add_target("t-c", 2).is_err());
+        assert!(fh.try_add_target("t-c", 1).is_ok());
+
+        // replacing a target only counts the difference
+        fh.set_max_total_positions(256);
+        fh.set_duplicate_policy(DuplicatePolicy::Replace);
+        assert!(fh.try_add_target("t-c", 2).is_ok());
+        assert!(fh.try_add_target("t-c", 3).is_err());
+        assert_eq!(fh.target_to_positions["t-c"].len(), 128);
+    }
 
-        assert_eq!(fh.get_all_targets(), ["t-a", "t-b", "t-c"]);
+    #[test]
+    #[should_panic(
+        expected = "Target t-c would take the ring to 192 positions, over the limit of 150"
+    )]
+    fn add_group_counts_new_targets_when_ignoring_duplicates() {
+        let mut fh = Flexihash::new();
+        fh.set_max_total_positions(150);
+        fh.set_duplicate_policy(DuplicatePolicy::Ignore);
+        fh.add_target("t-a", 1);
+        // t-a is ignored, but t-b and t-c are new
+        fh.add_group("rack-1", vec!["t-a", "t-b", "t-c"]);
     }
 
     #[test]
-    fn add_targets_and_get_all_targets() {
-        let targets = vec!["t-a", "t-b", "t-c"];
+    fn try_update_target_weight() {
+        let mut fh = Flexihash::new();
+        fh.set_max_total_positions(200);
+        fh.add_targets(vec!["t-a", "t-b"]);
+        assert!(matches!(
+            fh.try_update_target_weight("t-a", u32::MAX),
+            Err(Error::TooManyPositions(..))
+        ));
+        assert_eq!(fh.get_target_info("t-a").unwrap().weight, 1);
+        assert!(matches!(
+            fh.try_update_target_weight("t-x", 1),
+            Err(Error::TargetMissing(..))
+        ));
+        assert!(fh.try_update_target_weight("t-a", 2).is_ok());
+        assert_eq!(fh.target_to_positions["t-a"].len(), 128);
+    }
 
+    #[test]
+    #[should_panic(
+        expected = "Target t-a would take the ring to 128 positions, over the limit of 100"
+    )]
+    fn update_target_weight_checks_the_limit() {
         let mut fh = Flexihash::new();
-        fh.add_targets(targets.clone());
-        assert_eq!(fh.get_all_targets(), targets);
+        fh.set_max_total_positions(100);
+        fh.add_target("t-a", 1);
+        fh.update_target_weight("t-a", 2);
     }
 
     #[test]
-    fn remove_target() {
+    fn names_can_be_lowercased() {
+        let mut fh = Flexihash::new();
+        fh.set_name_normalization(NameNormalization::AsciiLowercase);
+        fh.add_target("Cache01", 1);
+        assert_eq!(fh.get_all_targets(), ["cache01"]);
+        assert!(fh.try_add_target("cache01", 1).is_err());
+        assert_eq!(fh.get_target_info("CACHE01").unwrap().weight, 1);
+        fh.update_target_weight("CacHe01", 2);
+        assert_eq!(fh.get_target_info("cache01").unwrap().weight, 2);
+
+        fh.transaction(|tx| {
+            tx.add("Cache02", 1).set_weight("CACHE02", 3);
+        })
+        .unwrap();
+        assert_eq!(fh.get_target_info("cache02").unwrap().weight, 3);
+
+        fh.remove_target("CACHE01");
+        fh.remove_target("cache02");
+        assert_eq!(fh.get_all_targets().len(), 0);
+
+        // not beyond ascii
+        fh.add_target("Ünï", 1);
+        assert_eq!(fh.get_all_targets(), ["Ünï"]);
+    }
+
+    #[test]
+    fn salt_is_hashed_with_replica_keys() {
+        let mut fh = Flexihash::new();
+        fh.set_salt("staging:");
+        fh.add_targets(vec!["t-a", "t-b"]);
+
+        let mut plain = Flexihash::new();
+        plain.add_targets(vec!["staging:t-a", "t-b"]);
+        assert_eq!(
+            fh.target_to_positions["t-a"],
+            plain.target_to_positions["staging:t-a"]
+        );
+        assert_ne!(
+            fh.target_to_positions["t-b"],
+            plain.target_to_positions["t-b"]
+        );
+    }
+}
+
+/*
+ * Memory
+ */
+use std::mem::size_of;
+
+fn string_footprint(s: &str) -> usize {
+    return size_of::<String>() + s.len();
+}
+
+impl Flexihash {
+    // A rough estimate of the heap used by the ring, counting allocated
+    // capacity rather than just what's in use. Map overheads are guessed:
+    // BTreeMap nodes are assumed around two thirds full, and HashMaps carry
+    // a control byte per bucket.
+    pub fn memory_footprint(&self) -> usize {
+        let mut total = self.key_prefix.capacity() + self.salt.capacity();
+
+        total += self.position_to_target.len() * size_of::<(Position, Target)>() * 3 / 2;
+        total += self
+            .position_to_target
+            .values()
+            .map(|t| t.capacity())
+            .sum::<usize>();
+
+        total += self.sorted_position_to_target.capacity() * size_of::<(Position, Target)>();
+        total += self
+            .sorted_position_to_target
+            .iter()
+            .map(|(_, t)| t.capacity())
+            .sum::<usize>();
+
+        total += self.eytzinger.capacity() * size_of::<(Position, usize)>();
+
+        total += self.target_to_positions.len() * size_of::<(Target, Vec<Position>)>() * 3 / 2;
+        for (target, positions) in self.target_to_positions.iter() {
+            total += target.capacity() + positions.capacity() * size_of::<Position>();
+        }
+
+        total += self.groups.capacity() * (size_of::<(String, Vec<Target>)>() + 1);
+        for (group, members) in self.groups.iter() {
+            total += group.capacity() + members.capacity() * size_of::<Target>();
+            total += members.iter().map(|t| t.capacity()).sum::<usize>();
+        }
+
+        total += self.target_info.capacity() * (size_of::<(Target, TargetInfo)>() + 1);
+        for (target, info) in self.target_info.iter() {
+            total += target.capacity();
+            total += info.zone.as_ref().map_or(0, |z| z.capacity());
+            for (k, v) in info.labels.iter() {
+                total += string_footprint(k) + string_footprint(v);
+            }
+        }
+
+        total += self.added.capacity() * (size_of::<(Target, u64)>() + 1);
+        total += self.added.keys().map(|t| t.capacity()).sum::<usize>();
+
+        return total;
+    }
+
+    // Give back memory left over from removals (BTreeMaps free as they go,
+    // everything else holds on to its peak size). Anything still shared
+    // with a clone is left alone, rather than copied just to shrink it.
+    pub fn shrink_to_fit(&mut self) {
+        self.key_prefix.shrink_to_fit();
+        self.salt.shrink_to_fit();
+        if let Some(sorted) = Arc::get_mut(&mut self.sorted_position_to_target) {
+            sorted.shrink_to_fit();
+        }
+        if let Some(eytzinger) = Arc::get_mut(&mut self.eytzinger) {
+            eytzinger.shrink_to_fit();
+        }
+        if let Some(target_to_positions) = Arc::get_mut(&mut self.target_to_positions) {
+            for positions in target_to_positions.values_mut() {
+                positions.shrink_to_fit();
+            }
+        }
+        self.groups.shrink_to_fit();
+        for members in self.groups.values_mut() {
+            members.shrink_to_fit();
+        }
+        if let Some(target_info) = Arc::get_mut(&mut self.target_info) {
+            target_info.shrink_to_fit();
+        }
+        self.added.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod test_memory {
+    use super::*;
+
+    #[test]
+    fn footprint_follows_size() {
+        let mut fh = Flexihash::new();
+        let empty = fh.memory_footprint();
+        fh.add_target("t-a", 1);
+        let one = fh.memory_footprint();
+        assert!(one > empty + 64 * size_of::<(Position, Target)>());
+        fh.add_target("t-b", 2);
+        assert!(fh.memory_footprint() > one);
+    }
+
+    #[test]
+    fn shrink_after_removals() {
+        let mut fh = Flexihash::new();
+        for i in 0..200 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        for i in 1..200 {
+            fh.remove_target(format!("target{}", i));
+        }
+        let before = fh.memory_footprint();
+        let expected = fh.lookup("resource");
+        fh.shrink_to_fit();
+        assert!(fh.memory_footprint() < before);
+        assert_eq!(fh.lookup("resource"), expected);
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn clones_share_until_changed() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let mut copy = fh.clone();
+        assert!(Arc::ptr_eq(
+            &fh.sorted_position_to_target,
+            &copy.sorted_position_to_target
+        ));
+        assert!(Arc::ptr_eq(&fh.target_info, &copy.target_info));
+
+        copy.add_target("t-c", 1);
+        assert!(!Arc::ptr_eq(
+            &fh.sorted_position_to_target,
+            &copy.sorted_position_to_target
+        ));
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-b"]);
+        assert_eq!(fh.position_count(), 128);
+        assert_eq!(copy.get_all_targets(), ["t-a", "t-b", "t-c"]);
+    }
+}
+
+/*
+ * Transactions
+ */
+#[derive(Debug)]
+enum Operation {
+    Add(Target, u32),
+    Remove(Target),
+    SetWeight(Target, u32),
+}
+
+#[derive(Debug, Default)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    pub fn add<S: Into<String>>(&mut self, target: S, weight: u32) -> &mut Transaction {
+        self.operations.push(Operation::Add(target.into(), weight));
+        return self;
+    }
+
+    pub fn remove<S: Into<String>>(&mut self, target: S) -> &mut Transaction {
+        self.operations.push(Operation::Remove(target.into()));
+        return self;
+    }
+
+    pub fn set_weight<S: Into<String>>(&mut self, target: S, weight: u32) -> &mut Transaction {
+        self.operations
+            .push(Operation::SetWeight(target.into(), weight));
+        return self;
+    }
+}
+
+impl Flexihash {
+    pub fn transaction<F: FnOnce(&mut Transaction)>(&mut self, f: F) -> Result<&Flexihash, Error> {
+        let mut tx = Transaction::default();
+        f(&mut tx);
+        for op in tx.operations.iter_mut() {
+            let target = match op {
+                Operation::Add(target, _) => target,
+                Operation::Remove(target) => target,
+                Operation::SetWeight(target, _) => target,
+            };
+            *target = self.normalize(std::mem::take(target));
+        }
+
+        // Play the operations against the set of target names first, so
+        // that nothing is touched unless every operation is valid
+        // names, along with the positions each would end up with
+        let mut targets: HashMap<&str, u64> = self
+            .target_to_positions
+            .iter()
+            .map(|(t, p)| (t.as_str(), p.len() as u64))
+            .collect();
+        let mut total = self.total_positions();
+        for op in tx.operations.iter() {
+            match op {
+                Operation::Add(target, weight) => {
+                    let existing = targets.get(target.as_str()).copied();
+                    match (existing, self.duplicate_policy) {
+                        (Some(_), DuplicatePolicy::Error) => {
+                            return Err(Error::TargetExists(target.clone()));
+                        }
+                        (Some(_), DuplicatePolicy::Ignore) => continue,
+                        _ => {}
+                    }
+                    let count = self.positions_for(*weight);
+                    total = total - existing.unwrap_or(0) + count;
+                    self.check_total_positions(target, total)?;
+                    targets.insert(target, count);
+                }
+                Operation::Remove(target) => match targets.remove(target.as_str()) {
+                    Some(count) => total -= count,
+                    None => {
+                        let similar = similar_names(target, targets.keys().copied());
+                        return Err(Error::TargetMissing(target.clone(), similar));
+                    }
+                },
+                Operation::SetWeight(target, weight) => {
+                    let count = self.positions_for(*weight);
+                    let existing = match targets.insert(target, count) {
+                        Some(existing) => existing,
+                        None => {
+                            targets.remove(target.as_str());
+                            let similar = similar_names(target, targets.keys().copied());
+                            return Err(Error::TargetMissing(target.clone(), similar));
+                        }
+                    };
+                    total = total - existing + count;
+                    self.check_total_positions(target, total)?;
+                }
+            }
+        }
+
+        for op in tx.operations {
+            match op {
+                Operation::Add(target, weight) => {
+                    if self.target_to_positions.contains_key(&target) {
+                        if self.duplicate_policy == DuplicatePolicy::Ignore {
+                            continue;
+                        }
+                        self.forget_target(&target);
+                    }
+                    self.place_target(target, weight);
+                }
+                Operation::Remove(target) => {
+                    self.forget_target(&target);
+                }
+                Operation::SetWeight(target, weight) => {
+                    let count = self.positions_for(weight);
+                    self.reweight_target(target, weight, count);
+                }
+            }
+        }
+        self.rebuild();
+        return Ok(self);
+    }
+}
+
+#[cfg(test)]
+mod test_transactions {
+    use super::*;
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn transaction_applies_all_operations() {
         let mut fh = Flexihash::new();
         fh.add_target("t-a", 1);
         fh.add_target("t-b", 1);
-        fh.add_target("t-c", 1);
-        fh.remove_target("t-b");
+        fh.transaction(|tx| {
+            tx.add("t-c", 1).remove("t-a").set_weight("t-b", 2);
+        })
+        .unwrap();
+
+        assert_eq!(fh.get_all_targets(), ["t-b", "t-c"]);
+        assert_eq!(fh.target_to_positions["t-b"].len(), 128);
+        assert_eq!(fh.sorted_position_to_target.len(), 192);
+    }
 
-        assert_eq!(fh.get_all_targets(), ["t-a", "t-c"]);
+    #[test]
+    fn transaction_checks_the_position_limit() {
+        let mut fh = Flexihash::new();
+        fh.set_max_total_positions(256);
+        fh.add_target("t-a", 2);
+        let result = fh.transaction(|tx| {
+            tx.add("t-b", 1).set_weight("t-a", 4);
+        });
+        assert!(matches!(result, Err(Error::TooManyPositions(..))));
+        assert_eq!(fh.get_all_targets(), ["t-a"]);
+
+        // removals earlier in the transaction make room
+        fh.transaction(|tx| {
+            tx.remove("t-a").add("t-b", 4);
+        })
+        .unwrap();
+        assert_eq!(fh.get_all_targets(), ["t-b"]);
     }
 
     #[test]
-    #[should_panic(expected = "Target 'not-there' does not exist")]
-    fn remove_target_fails_on_missing_target() {
+    fn transaction_matches_individual_operations() {
+        let mut fh1 = Flexihash::new();
+        fh1.add_targets(vec!["t-a", "t-b", "t-c"]);
+        fh1.transaction(|tx| {
+            tx.remove("t-b").add("t-d", 2);
+        })
+        .unwrap();
+
+        let mut fh2 = Flexihash::new();
+        fh2.add_targets(vec!["t-a", "t-b", "t-c"]);
+        fh2.remove_target("t-b");
+        fh2.add_target("t-d", 2);
+
+        assert_eq!(fh1.sorted_position_to_target, fh2.sorted_position_to_target);
+    }
+
+    #[test]
+    fn transaction_rolls_back_on_error() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        let result = fh.transaction(|tx| {
+            tx.add("t-b", 1).remove("t-a").remove("t-a");
+        });
+
+        assert_eq!(
+            result.unwrap_err(),
+            Error::TargetMissing("t-a".to_string(), vec!["t-b".to_string()])
+        );
+        assert_eq!(fh.get_all_targets(), ["t-a"]);
+        assert_eq!(fh.sorted_position_to_target.len(), 64);
+    }
+
+    #[test]
+    fn transaction_rejects_duplicate_add() {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        let result = fh.transaction(|tx| {
+            tx.add("t-a", 1);
+        });
+
+        assert_eq!(result.unwrap_err(), Error::TargetExists("t-a".to_string()));
+    }
+}
+
+/*
+ * Freezing
+ */
+#[derive(Debug, Clone)]
+pub struct FrozenFlexihash {
+    inner: Flexihash,
+}
+
+impl Flexihash {
+    pub fn freeze(self) -> FrozenFlexihash {
+        return FrozenFlexihash { inner: self };
+    }
+}
+
+impl FrozenFlexihash {
+    pub fn unfreeze(self) -> Flexihash {
+        return self.inner;
+    }
+}
+
+impl std::ops::Deref for FrozenFlexihash {
+    type Target = Flexihash;
+
+    fn deref(&self) -> &Flexihash {
+        return &self.inner;
+    }
+}
+
+impl fmt::Display for FrozenFlexihash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test_freezing {
+    use super::*;
+
+    #[test]
+    fn frozen_lookups() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let expected = fh.lookup("resource");
+
+        let frozen = fh.freeze();
+        assert_eq!(frozen.lookup("resource"), expected);
+        assert_eq!(frozen.get_all_targets(), ["t-a", "t-b", "t-c"]);
+        assert_eq!(frozen.to_string(), "Flexihash([\"t-a\", \"t-b\", \"t-c\"])");
+    }
+
+    #[test]
+    fn unfreeze() {
         let mut fh = Flexihash::new();
-        fh.remove_target("not-there");
+        fh.add_target("t-a", 1);
+
+        let mut fh = fh.freeze().unfreeze();
+        fh.add_target("t-b", 1);
+        assert_eq!(fh.get_all_targets(), ["t-a", "t-b"]);
     }
 }
 
@@ -237,42 +2463,87 @@ mod test_add_remove {
  * Lookups
  */
 impl Flexihash {
-    pub fn lookup<S: Into<String>>(&self, resource: S) -> Target {
+    pub fn lookup<K: ResourceKey>(&self, resource: K) -> Target {
         let targets = self.lookup_list(resource, 1);
-        if let Some(target) = targets.get(0) {
+        if let Some(target) = targets.first() {
+            return target.clone();
+        } else {
+            panic!("No targets set");
+        }
+    }
+
+    pub fn lookup_list<K: ResourceKey>(&self, resource: K, requested_count: u32) -> Vec<Target> {
+        return self.lookup_list_salted(resource, "", requested_count);
+    }
+
+    // For experiments: the same ring, but with the salt mixed into the
+    // resource's hash, so that a cohort is moved somewhere else - always
+    // the same somewhere for the same salt. An empty salt is a plain lookup.
+    pub fn lookup_salted<K: ResourceKey, S: AsRef<str>>(&self, resource: K, salt: S) -> Target {
+        let targets = self.lookup_list_salted(resource, salt, 1);
+        if let Some(target) = targets.first() {
             return target.clone();
         } else {
             panic!("No targets set");
         }
     }
 
-    pub fn lookup_list<S: Into<String>>(&self, resource: S, requested_count: u32) -> Vec<Target> {
-        let resource = resource.into();
+    pub fn lookup_list_salted<K: ResourceKey, S: AsRef<str>>(
+        &self,
+        resource: K,
+        salt: S,
+        requested_count: u32,
+    ) -> Vec<Target> {
+        let results = self.find_targets(resource, salt.as_ref(), requested_count);
+        if let Some(target) = results.first() {
+            self.record_lookup(target);
+        }
+        return results;
+    }
+
+    // Once per lookup, for whichever target it settled on
+    fn record_lookup(&self, target: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.looked_up(target);
+        }
+        if let Some(counts) = &self.lookup_counts {
+            counts.record(target);
+        }
+    }
+
+    fn find_targets<K: ResourceKey>(
+        &self,
+        resource: K,
+        salt: &str,
+        requested_count: u32,
+    ) -> Vec<Target> {
         if requested_count == 0 {
             panic!("Need to request at least 1 resource");
         }
-        if self.target_to_positions.len() == 0 {
+        if !self.rules.is_empty() {
+            if let Some(targets) = self.route(resource.ring_key().as_ref()) {
+                return targets
+                    .iter()
+                    .take(requested_count as usize)
+                    .cloned()
+                    .collect();
+            }
+        }
+        if self.target_to_positions.is_empty() {
             return Vec::new();
         }
         if self.target_to_positions.len() == 1 {
             // if only one item, return first entry
-            for (k, _) in self.target_to_positions.iter() {
+            if let Some(k) = self.target_to_positions.keys().next() {
                 return vec![k.clone()];
             }
         }
 
-        let resource_position = hash(&self.hasher, resource);
+        let resource_position = self.salted_position(&resource, salt);
         let n_targets = self.target_to_positions.len();
 
         let mut results: Vec<Target> = Vec::new();
-        let s = String::new();
-        let offset = match self
-            .sorted_position_to_target
-            .binary_search(&(resource_position, s))
-        {
-            Ok(pos) => pos,
-            Err(pos) => pos,
-        };
+        let offset = self.search(resource_position);
         for i in (offset..self.sorted_position_to_target.len()).chain(0..offset) {
             if let Some((_, target)) = self.sorted_position_to_target.get(i) {
                 if !results.contains(target) {
@@ -285,16 +2556,208 @@ impl Flexihash {
         }
         return results;
     }
+
+    // Like lookup_list, but rather than a fixed number of targets, keep
+    // going until their weights add up to at least min_total_weight (or
+    // the ring runs out)
+    pub fn lookup_list_with_capacity<K: ResourceKey>(
+        &self,
+        resource: K,
+        min_total_weight: u32,
+    ) -> Vec<Target> {
+        if min_total_weight == 0 {
+            panic!("Need to request a capacity of at least 1");
+        }
+        let mut results: Vec<Target> = Vec::new();
+        if self.sorted_position_to_target.is_empty() {
+            return results;
+        }
+
+        let mut total: u64 = 0;
+        let offset = self.search(self.resource_position(&resource));
+        for i in (offset..self.sorted_position_to_target.len()).chain(0..offset) {
+            let target = &self.sorted_position_to_target[i].1;
+            if !results.contains(target) {
+                total += self.target_info.get(target).map_or(1, |info| info.weight) as u64;
+                results.push(target.clone());
+                if total >= min_total_weight as u64
+                    || results.len() == self.target_to_positions.len()
+                {
+                    break;
+                }
+            }
+        }
+        return results;
+    }
+
+    // Everyone who owns some of the keys from start to end inclusive,
+    // in ring order from start; if end < start, the range wraps round
+    pub fn targets_in_range(&self, start: Position, end: Position) -> Vec<Target> {
+        let ring = &self.sorted_position_to_target;
+        let mut results: Vec<Target> = Vec::new();
+        if ring.is_empty() {
+            return results;
+        }
+        let ranges = if start <= end {
+            vec![(start, end)]
+        } else {
+            vec![(start, Position::MAX), (0, end)]
+        };
+        for (start, end) in ranges {
+            // up to and including the owner of end, which may be back at
+            // the start of the ring
+            for i in self.search(start)..=self.search(end) {
+                let target = &ring[i % ring.len()].1;
+                if !results.contains(target) {
+                    results.push(target.clone());
+                }
+            }
+        }
+        return results;
+    }
+
+    // Walk the ring from the resource's position, for retry loops which
+    // don't know up front how many targets they'll get through
+    pub fn cycle_candidates<K: ResourceKey>(
+        &self,
+        resource: K,
+        exhausted: Exhausted,
+    ) -> Candidates<'_> {
+        let offset = if self.sorted_position_to_target.is_empty() {
+            0
+        } else {
+            self.search(self.resource_position(&resource))
+        };
+        return Candidates {
+            ring: self,
+            offset,
+            walked: 0,
+            seen: Vec::new(),
+            repeated: 0,
+            exhausted,
+        };
+    }
+
+    fn resource_position<K: ResourceKey>(&self, resource: &K) -> Position {
+        return self.salted_position(resource, "");
+    }
+
+    // The salt goes after the key prefix and before the resource (or its
+    // hash tag), so "exp-1" and "x" hash as "exp-1:x"
+    fn salted_position<K: ResourceKey>(&self, resource: &K, salt: &str) -> Position {
+        let prefix = if salt.is_empty() {
+            Cow::Borrowed(self.key_prefix.as_str())
+        } else {
+            Cow::Owned(format!("{}{}:", self.key_prefix, salt))
+        };
+        return key_position(
+            &self.hasher,
+            &prefix,
+            self.hash_tags,
+            self.key_normalization,
+            resource.ring_key().as_ref(),
+        );
+    }
+
+    // Index into sorted_position_to_target of the first position at or after
+    // the given one, or the length of the ring if there is none
+    fn search(&self, position: Position) -> usize {
+        return match self.search {
+            Search::Eytzinger => self.eytzinger_search(position),
+            Search::Interpolation => self.interpolation_search(position),
+        };
+    }
+
+    fn eytzinger_search(&self, position: Position) -> usize {
+        let n = self.eytzinger.len();
+        let mut k = 1;
+        while k <= n {
+            k = 2 * k + (self.eytzinger[k - 1].0 < position) as usize;
+        }
+        k >>= k.trailing_ones() + 1;
+        if k == 0 {
+            return n;
+        }
+        return self.eytzinger[k - 1].1;
+    }
+
+    fn interpolation_search(&self, position: Position) -> usize {
+        let ring = &self.sorted_position_to_target;
+        // everything before lo is < position, everything from hi is >= position
+        let mut lo = 0;
+        let mut hi = ring.len();
+        let max_probes = usize::BITS - ring.len().leading_zeros();
+        for _ in 0..max_probes {
+            if lo == hi {
+                return lo;
+            }
+            let low = ring[lo].0;
+            let high = ring[hi - 1].0;
+            if position <= low {
+                return lo;
+            }
+            if position > high {
+                return hi;
+            }
+            let fraction = (position - low) as f64 / (high - low) as f64;
+            let mid = lo + ((hi - 1 - lo) as f64 * fraction) as usize;
+            if ring[mid].0 < position {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        return lo + ring[lo..hi].partition_point(|p| p.0 < position);
+    }
+
+    pub fn sample_keys_for<S: Into<String>, K: ResourceKey + Clone>(
+        &self,
+        target: S,
+        n: usize,
+        key_space: impl Fn(u64) -> K,
+    ) -> Vec<K> {
+        let target = self.normalize(target.into());
+        if !self.target_to_positions.contains_key(&target) {
+            self.missing_target(&target);
+        }
+        let mut keys = Vec::new();
+        // all of this target's positions may have been clobbered by other
+        // targets, in which case no key will ever map to it
+        let owned = self
+            .position_to_target
+            .values()
+            .filter(|t| **t == target)
+            .count() as u64;
+        if n == 0 || owned == 0 {
+            return keys;
+        }
+        // Give up after a hundred times as many candidates as the target's
+        // share of positions says it should take, or a million for hashers
+        // like adler32 which bunch similar keys up (so this may find fewer
+        // than n, eg if key_space never reaches it)
+        let per_key = 100 * (self.position_to_target.len() as u64).div_ceil(owned);
+        let attempts = (n as u64).saturating_mul(per_key).max(1 << 20);
+        for i in 0..attempts {
+            let key = key_space(i);
+            // not lookup, which would count these in the metrics
+            if self.find_targets(&key, "", 1).first() == Some(&target) {
+                keys.push(key);
+                if keys.len() == n {
+                    break;
+                }
+            }
+        }
+        return keys;
+    }
 }
 
 /**
  * Ensure the Flexihash class gives the same results as the original code
  */
-#[cfg(test)]
+#[cfg(all(test, feature = "crc"))]
 mod test_compat {
     #[cfg(test)]
     use crate::Flexihash;
-    use md5;
     use std::collections::HashMap;
 
     #[test]
@@ -304,8 +2767,8 @@ mod test_compat {
         let mut fh = Flexihash::new();
         let mut results = HashMap::new();
 
-        for n in vec!["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"].iter() {
-            let target = format!("{:032x}", md5::compute(n.to_string()));
+        for n in ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"].iter() {
+            let target = format!("{:032x}", md5::compute(n));
             fh.add_target(target.clone(), 1);
             results.insert(target, 0);
         }
@@ -339,6 +2802,145 @@ mod test_compat {
     }
 }
 
+pub(crate) fn key_position(
+    hasher: &Hasher,
+    key_prefix: &str,
+    hash_tags: bool,
+    normalization: KeyNormalization,
+    key: &[u8],
+) -> Position {
+    let key = normalize_key(normalization, key);
+    let key = if hash_tags { hash_tag(&key) } else { &key };
+    if key_prefix.is_empty() {
+        return hash(hasher, key);
+    }
+    let mut prefixed = key_prefix.as_bytes().to_vec();
+    prefixed.extend_from_slice(key);
+    return hash(hasher, prefixed);
+}
+
+fn similar_names<'a>(target: &str, names: impl Iterator<Item = &'a str>) -> Vec<Target> {
+    let limit = (target.chars().count() / 3).max(1);
+    let mut best = limit + 1;
+    let mut similar = Vec::new();
+    // sorted, so that ties go the same way every time
+    let mut names: Vec<&str> = names.collect();
+    names.sort_unstable();
+    for name in names {
+        let distance = edit_distance(target, name);
+        if distance < best {
+            best = distance;
+            similar.clear();
+        }
+        if distance == best && similar.len() < 3 {
+            similar.push(name.to_string());
+        }
+    }
+    return similar;
+}
+
+// Levenshtein, over chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + (ca != *cb) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    return previous[b.len()];
+}
+
+fn normalize_key(normalization: KeyNormalization, key: &[u8]) -> Cow<'_, [u8]> {
+    #[cfg(feature = "unicode")]
+    {
+        use unicode_normalization::{
+            is_nfc_quick, is_nfkc_quick, IsNormalized, UnicodeNormalization,
+        };
+        // ascii is already in every normal form
+        let text = match std::str::from_utf8(key) {
+            Ok(text) if !text.is_ascii() => text,
+            _ => return Cow::Borrowed(key),
+        };
+        let normalized: String = match normalization {
+            KeyNormalization::AsGiven => return Cow::Borrowed(key),
+            KeyNormalization::Nfc if is_nfc_quick(text.chars()) == IsNormalized::Yes => {
+                return Cow::Borrowed(key);
+            }
+            KeyNormalization::Nfc => text.nfc().collect(),
+            KeyNormalization::Nfkc if is_nfkc_quick(text.chars()) == IsNormalized::Yes => {
+                return Cow::Borrowed(key);
+            }
+            KeyNormalization::Nfkc => text.nfkc().collect(),
+        };
+        return Cow::Owned(normalized.into_bytes());
+    }
+    #[cfg(not(feature = "unicode"))]
+    {
+        let KeyNormalization::AsGiven = normalization;
+        return Cow::Borrowed(key);
+    }
+}
+
+// Same rules as Redis Cluster: the first "{", then the first "}" after it,
+// and only if there's something in between
+fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(start) = key.iter().position(|b| *b == b'{') {
+        if let Some(len) = key[start + 1..].iter().position(|b| *b == b'}') {
+            if len > 0 {
+                return &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+    return key;
+}
+
+// What cycle_candidates does once it has been all the way round the ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exhausted {
+    Stop,
+    // Go through the same targets again in the same order, forever
+    Repeat,
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidates<'a> {
+    ring: &'a Flexihash,
+    offset: usize,
+    walked: usize,
+    seen: Vec<&'a Target>,
+    repeated: usize,
+    exhausted: Exhausted,
+}
+
+impl Iterator for Candidates<'_> {
+    type Item = Target;
+
+    fn next(&mut self) -> Option<Target> {
+        let ring = &self.ring.sorted_position_to_target;
+        // the first time round, positions are checked one by one; clobbered
+        // targets can leave fewer than target_to_positions.len() to find
+        while self.walked < ring.len() && self.seen.len() < self.ring.target_to_positions.len() {
+            let target = &ring[(self.offset + self.walked) % ring.len()].1;
+            self.walked += 1;
+            if !self.seen.contains(&target) {
+                self.seen.push(target);
+                return Some(target.clone());
+            }
+        }
+        if self.exhausted == Exhausted::Stop || self.seen.is_empty() {
+            return None;
+        }
+        let target = self.seen[self.repeated % self.seen.len()];
+        self.repeated += 1;
+        return Some(target.clone());
+    }
+}
+
 #[cfg(test)]
 mod test_lookups {
     use super::*;
@@ -357,6 +2959,7 @@ mod test_lookups {
         fh.lookup_list("test", 0);
     }
 
+    #[cfg(feature = "crc")]
     #[test]
     fn lookup_list_returns_with_short_list_if_all_targets_used() {
         let mut fh = Flexihash::new();
@@ -368,8 +2971,64 @@ mod test_lookups {
         fh.remove_target("14746907"); // remove the fourth value; with the third clobbered, only X and Y are left
         let result = fh.lookup_list("test", 3); // try to get 3 results, our target list is X, Y, 80726
         assert_eq!(result.len(), 2); // but 80726 isn't reachable since it was clobbered
-        assert_eq!(result.contains(&String::from("x")), true); // all that's left is x
-        assert_eq!(result.contains(&String::from("y")), true); // and y
+        assert!(result.contains(&String::from("x"))); // all that's left is x
+        assert!(result.contains(&String::from("y"))); // and y
+    }
+
+    #[test]
+    fn cycle_candidates() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let first_pass = fh.lookup_list("resource", 3);
+
+        let stopping: Vec<Target> = fh.cycle_candidates("resource", Exhausted::Stop).collect();
+        assert_eq!(stopping, first_pass);
+
+        let repeating: Vec<Target> = fh
+            .cycle_candidates("resource", Exhausted::Repeat)
+            .take(7)
+            .collect();
+        assert_eq!(repeating[..3], first_pass[..]);
+        assert_eq!(repeating[3..6], first_pass[..]);
+        assert_eq!(repeating[6], first_pass[0]);
+
+        let fh = Flexihash::new();
+        assert_eq!(
+            fh.cycle_candidates("resource", Exhausted::Repeat).next(),
+            None
+        );
+    }
+
+    #[test]
+    fn targets_in_range() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (target, position) in [("t1", 10), ("t2", 20), ("t3", 30)] {
+            fh.set_hasher(Hasher::Mock(position));
+            fh.add_target(target, 1);
+        }
+        assert_eq!(fh.targets_in_range(11, 20), ["t2"]);
+        assert_eq!(fh.targets_in_range(10, 21), ["t1", "t2", "t3"]);
+        assert_eq!(fh.targets_in_range(15, 15), ["t2"]);
+        // past the last position belongs to the first
+        assert_eq!(fh.targets_in_range(31, Position::MAX), ["t1"]);
+        assert_eq!(fh.targets_in_range(25, 5), ["t3", "t1"]);
+        assert_eq!(fh.targets_in_range(35, 15), ["t1", "t2"]);
+
+        assert_eq!(Flexihash::new().targets_in_range(0, 10).len(), 0);
+    }
+
+    #[test]
+    fn cycle_candidates_skips_clobbered_targets() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(Hasher::Mock(1));
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let candidates: Vec<Target> = fh
+            .cycle_candidates("resource", Exhausted::Repeat)
+            .take(3)
+            .collect();
+        assert_eq!(candidates.len(), 3);
+        assert!(candidates.iter().all(|t| *t == candidates[0]));
     }
 
     #[test]
@@ -392,7 +3051,7 @@ mod test_lookups {
         fh.add_targets(targets.clone());
 
         for i in 1..10 {
-            assert_eq!(targets.contains(&fh.lookup(format!("r{}", i))), true)
+            assert!(targets.contains(&fh.lookup(format!("r{}", i))))
         }
     }
 
@@ -441,6 +3100,19 @@ mod test_lookups {
         assert_eq!(results1, results2);
     }
 
+    #[test]
+    fn lookups_with_non_string_keys() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        for i in 0..100 {
+            assert_eq!(fh.lookup(i), fh.lookup(format!("{}", i)));
+            assert_eq!(
+                fh.lookup_list(("user", i), 2),
+                fh.lookup_list(format!("user:{}", i), 2)
+            );
+        }
+    }
+
     #[test]
     fn get_multiple_targets() {
         let mut fh = Flexihash::new();
@@ -481,22 +3153,22 @@ mod test_lookups {
         let mut fh = Flexihash::new();
         fh.set_replicas(1);
 
-        fh.set_hasher(Hasher::Mock("10".to_string()));
+        fh.set_hasher(Hasher::Mock(10));
         fh.add_target("t1", 1);
 
-        fh.set_hasher(Hasher::Mock("20".to_string()));
+        fh.set_hasher(Hasher::Mock(20));
         fh.add_target("t2", 1);
 
-        fh.set_hasher(Hasher::Mock("30".to_string()));
+        fh.set_hasher(Hasher::Mock(30));
         fh.add_target("t3", 1);
 
-        fh.set_hasher(Hasher::Mock("40".to_string()));
+        fh.set_hasher(Hasher::Mock(40));
         fh.add_target("t4", 1);
 
-        fh.set_hasher(Hasher::Mock("50".to_string()));
+        fh.set_hasher(Hasher::Mock(50));
         fh.add_target("t5", 1);
 
-        fh.set_hasher(Hasher::Mock("35".to_string()));
+        fh.set_hasher(Hasher::Mock(35));
         let targets = fh.lookup_list("resource", 4);
 
         assert_eq!(targets, ["t4", "t5", "t1", "t2"]);
@@ -507,16 +3179,16 @@ mod test_lookups {
         let mut fh = Flexihash::new();
         fh.set_replicas(1);
 
-        fh.set_hasher(Hasher::Mock("10".to_string()));
+        fh.set_hasher(Hasher::Mock(10));
         fh.add_target("t1", 1);
 
-        fh.set_hasher(Hasher::Mock("20".to_string()));
+        fh.set_hasher(Hasher::Mock(20));
         fh.add_target("t2", 1);
 
-        fh.set_hasher(Hasher::Mock("30".to_string()));
+        fh.set_hasher(Hasher::Mock(30));
         fh.add_target("t3", 1);
 
-        fh.set_hasher(Hasher::Mock("99".to_string()));
+        fh.set_hasher(Hasher::Mock(99));
         let targets = fh.lookup_list("resource", 2);
 
         assert_eq!(targets, ["t1", "t2"]);
@@ -527,16 +3199,16 @@ mod test_lookups {
         let mut fh = Flexihash::new();
         fh.set_replicas(1);
 
-        fh.set_hasher(Hasher::Mock("10".to_string()));
+        fh.set_hasher(Hasher::Mock(10));
         fh.add_target("t1", 1);
 
-        fh.set_hasher(Hasher::Mock("20".to_string()));
+        fh.set_hasher(Hasher::Mock(20));
         fh.add_target("t2", 1);
 
-        fh.set_hasher(Hasher::Mock("30".to_string()));
+        fh.set_hasher(Hasher::Mock(30));
         fh.add_target("t3", 1);
 
-        fh.set_hasher(Hasher::Mock("15".to_string()));
+        fh.set_hasher(Hasher::Mock(15));
         let targets = fh.lookup_list("resource", 2);
 
         assert_eq!(targets, ["t2", "t3"]);
@@ -547,16 +3219,16 @@ mod test_lookups {
         let mut fh = Flexihash::new();
         fh.set_replicas(1);
 
-        fh.set_hasher(Hasher::Mock("10".to_string()));
+        fh.set_hasher(Hasher::Mock(10));
         fh.add_target("t1", 1);
 
-        fh.set_hasher(Hasher::Mock("20".to_string()));
+        fh.set_hasher(Hasher::Mock(20));
         fh.add_target("t2", 1);
 
-        fh.set_hasher(Hasher::Mock("30".to_string()));
+        fh.set_hasher(Hasher::Mock(30));
         fh.add_target("t3", 1);
 
-        fh.set_hasher(Hasher::Mock("15".to_string()));
+        fh.set_hasher(Hasher::Mock(15));
 
         assert_eq!(fh.lookup("resource"), "t2");
         assert_eq!(fh.lookup_list("resource", 3), ["t2", "t3", "t1"]);
@@ -571,4 +3243,262 @@ mod test_lookups {
         assert_eq!(fh.lookup("resource"), "t1");
         assert_eq!(fh.lookup_list("resource", 3), ["t1"]);
     }
+
+    #[test]
+    fn sample_keys_for_target() {
+        let mut fh = Flexihash::new();
+        for i in 1..10 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        let keys = fh.sample_keys_for("target3", 5, |i| format!("key{}", i));
+
+        assert_eq!(keys.len(), 5);
+        for key in keys {
+            assert_eq!(fh.lookup(key), "target3");
+        }
+    }
+
+    #[test]
+    fn sample_keys_for_unreachable_target() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        fh.set_hasher(Hasher::Mock(10));
+        fh.add_target("t1", 1);
+        fh.add_target("t2", 1); // clobbers t1's only position
+
+        assert_eq!(fh.sample_keys_for("t1", 3, |i| i.to_string()).len(), 0);
+    }
+
+    #[test]
+    fn sample_keys_for_gives_up() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        fh.set_hasher(Hasher::Mock(10));
+        fh.add_target("t1", 1);
+        fh.set_hasher(Hasher::Mock(20));
+        fh.add_target("t2", 1);
+        fh.enable_lookup_counts();
+        // every key hashes to 20, which is t2's
+        assert_eq!(fh.sample_keys_for("t1", 3, |i| i.to_string()).len(), 0);
+        assert_eq!(fh.sample_keys_for("t2", 3, |i| i.to_string()).len(), 3);
+        assert!(fh.lookup_counts().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 'not-there' does not exist")]
+    fn sample_keys_for_missing_target() {
+        let fh = Flexihash::new();
+        fh.sample_keys_for("not-there", 1, |i| i.to_string());
+    }
+
+    #[test]
+    fn search_matches_binary_search() {
+        let mut fh = Flexihash::new();
+        for i in 0..37 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        let positions: Vec<Position> = fh.sorted_position_to_target.iter().map(|p| p.0).collect();
+        let mut probes = vec![0, u128::MAX];
+        for p in positions.iter() {
+            probes.extend_from_slice(&[*p - 1, *p, *p + 1]);
+        }
+        for probe in probes {
+            assert_eq!(fh.search(probe), positions.partition_point(|p| *p < probe));
+        }
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn interpolation_search_matches_eytzinger_search() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(Hasher::Md5);
+        for i in 0..37 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        let mut probes = vec![0, u128::MAX];
+        for (p, _) in fh.sorted_position_to_target.iter() {
+            probes.extend_from_slice(&[*p - 1, *p, *p + 1]);
+        }
+        for i in 0..1000 {
+            probes.push(hash(&Hasher::Md5, format!("r{}", i)));
+        }
+        for probe in probes {
+            assert_eq!(fh.interpolation_search(probe), fh.eytzinger_search(probe));
+        }
+    }
+
+    #[test]
+    fn interpolation_search_with_skewed_positions() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        fh.set_search(Search::Interpolation);
+        for p in [1, 2, 3, 4, 5, 1000000000] {
+            fh.set_hasher(Hasher::Mock(p));
+            fh.add_target(format!("t{}", p), 1);
+        }
+
+        fh.set_hasher(Hasher::Mock(4));
+        assert_eq!(fh.lookup_list("resource", 3), ["t4", "t5", "t1000000000"]);
+        fh.set_hasher(Hasher::Mock(6));
+        assert_eq!(fh.lookup("resource"), "t1000000000");
+        fh.set_hasher(Hasher::Mock(1000000001));
+        assert_eq!(fh.lookup("resource"), "t1");
+    }
+
+    #[test]
+    fn lookup_list_with_capacity_accumulates_weights() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (p, weight) in [(10, 1), (20, 3), (30, 2)] {
+            fh.set_hasher(Hasher::Mock(p));
+            fh.add_target(format!("t{}", p), weight);
+        }
+        fh.set_hasher(Hasher::Mock(15));
+        // (weight 3 means three replicas at the same mocked position, so one
+        // position each but the weight still counts)
+        assert_eq!(fh.lookup_list_with_capacity("r", 1), ["t20"]);
+        assert_eq!(fh.lookup_list_with_capacity("r", 3), ["t20"]);
+        assert_eq!(fh.lookup_list_with_capacity("r", 4), ["t20", "t30"]);
+        assert_eq!(fh.lookup_list_with_capacity("r", 6), ["t20", "t30", "t10"]);
+        assert_eq!(
+            fh.lookup_list_with_capacity("r", 100),
+            ["t20", "t30", "t10"]
+        );
+        assert!(Flexihash::new()
+            .lookup_list_with_capacity("r", 1)
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Need to request a capacity of at least 1")]
+    fn lookup_list_with_capacity_rejects_zero() {
+        Flexihash::new().lookup_list_with_capacity("r", 0);
+    }
+
+    #[test]
+    fn key_prefix_is_hashed_with_resource() {
+        let mut fh = Flexihash::new();
+        for i in 1..10 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        let plain = fh.clone();
+        fh.set_key_prefix("users:");
+        for i in 0..100 {
+            let key = format!("r{}", i);
+            assert_eq!(fh.lookup(&key), plain.lookup(format!("users:{}", key)));
+        }
+        let moved = (0..100).filter(|i| fh.lookup(i) != plain.lookup(i)).count();
+        assert!(moved > 0);
+
+        fh.set_key_prefix("");
+        assert_eq!(fh.lookup("r1"), plain.lookup("r1"));
+    }
+
+    #[test]
+    fn salted_lookups() {
+        let mut fh = Flexihash::new();
+        for i in 1..10 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        for i in 0..100 {
+            let key = format!("r{}", i);
+            assert_eq!(fh.lookup_salted(&key, ""), fh.lookup(&key));
+            assert_eq!(
+                fh.lookup_salted(&key, "exp-1"),
+                fh.lookup(format!("exp-1:{}", key))
+            );
+            assert_eq!(
+                fh.lookup_list_salted(&key, "exp-1", 3),
+                fh.lookup_list(format!("exp-1:{}", key), 3)
+            );
+        }
+
+        // after the prefix, and salting the hash tag rather than defeating it
+        fh.set_key_prefix("users:");
+        fh.set_hash_tags(true);
+        assert_eq!(
+            fh.salted_position(&"{u1}:name", "exp-1"),
+            hash(fh.hasher(), "users:exp-1:u1")
+        );
+    }
+
+    #[test]
+    fn hash_tag_extraction() {
+        assert_eq!(hash_tag(b"{user1}:name"), b"user1");
+        assert_eq!(hash_tag(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_tag(b"foo{{bar}}zap"), b"{bar");
+        assert_eq!(hash_tag(b"foo{bar"), b"foo{bar");
+        assert_eq!(hash_tag(b"plain"), b"plain");
+    }
+
+    #[test]
+    fn hash_tags_colocate_keys() {
+        let mut fh = Flexihash::new();
+        for i in 1..10 {
+            fh.add_target(format!("target{}", i), 1);
+        }
+        let plain = fh.clone();
+        fh.set_hash_tags(true);
+        for i in 0..50 {
+            let user = format!("user{}", i);
+            let expected = plain.lookup(&user);
+            assert_eq!(fh.lookup(format!("{{{}}}:name", user)), expected);
+            assert_eq!(fh.lookup(format!("{{{}}}:email", user)), expected);
+            assert_eq!(
+                fh.lookup_list(format!("x{{{}}}", user), 2),
+                plain.lookup_list(&user, 2)
+            );
+        }
+        assert_eq!(fh.lookup("untagged"), plain.lookup("untagged"));
+
+        fh.set_key_prefix("ns:");
+        assert_eq!(fh.lookup("{user1}:name"), plain.lookup("ns:user1"));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn key_normalization() {
+        let mut fh = Flexihash::new();
+        for i in 0..10 {
+            fh.add_target(format!("t{}", i), 1);
+        }
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        assert_ne!(
+            fh.resource_position(&composed),
+            fh.resource_position(&decomposed)
+        );
+
+        fh.set_key_normalization(KeyNormalization::Nfc);
+        assert_eq!(
+            fh.resource_position(&composed),
+            fh.resource_position(&decomposed)
+        );
+        assert_ne!(
+            fh.resource_position(&"\u{fb01}le"),
+            fh.resource_position(&"file")
+        );
+        // bytes which aren't utf-8 are left alone
+        let bytes: &[u8] = b"caf\xe9";
+        let mut plain = fh.clone();
+        plain.set_key_normalization(KeyNormalization::AsGiven);
+        assert_eq!(
+            fh.resource_position(&bytes),
+            plain.resource_position(&bytes)
+        );
+
+        fh.set_key_normalization(KeyNormalization::Nfkc);
+        assert_eq!(
+            fh.resource_position(&composed),
+            fh.resource_position(&decomposed)
+        );
+        assert_eq!(
+            fh.resource_position(&"\u{fb01}le"),
+            fh.resource_position(&"file")
+        );
+        // normalized before looking for hash tags
+        fh.set_hash_tags(true);
+        assert_eq!(fh.lookup("\u{ff5b}user1\u{ff5d}:x"), fh.lookup("user1"));
+    }
 }
@@ -0,0 +1,103 @@
+use crate::{Flexihash, ResourceKey, Target, TargetInfo};
+
+/*
+ * Lookups which keep consistent placement (the same set of targets as
+ * lookup_list) but hand them back nearest first, so that eg a CDN edge
+ * reads from a replica in its own region when there is one.
+ */
+impl Flexihash {
+    // distance() can use the target's zone, labels (eg a latency class) or
+    // name; equally distant targets stay in ring order
+    pub fn lookup_list_nearest<K: ResourceKey>(
+        &self,
+        resource: K,
+        requested_count: u32,
+        distance: impl Fn(&str, &TargetInfo) -> u32,
+    ) -> Vec<Target> {
+        let default = TargetInfo::default();
+        let mut candidates: Vec<(u32, Target)> = self
+            .lookup_list(resource, requested_count)
+            .into_iter()
+            .map(|t| {
+                let info = self.target_info.get(&t).unwrap_or(&default);
+                (distance(&t, info), t)
+            })
+            .collect();
+        candidates.sort_by_key(|c| c.0);
+        return candidates.into_iter().map(|c| c.1).collect();
+    }
+
+    pub fn lookup_list_prefer_zone<K: ResourceKey, S: AsRef<str>>(
+        &self,
+        resource: K,
+        requested_count: u32,
+        zone: S,
+    ) -> Vec<Target> {
+        let zone = zone.as_ref();
+        return self.lookup_list_nearest(resource, requested_count, |_, info| {
+            if info.zone.as_deref() == Some(zone) {
+                0
+            } else {
+                1
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_locality {
+    use super::*;
+    use crate::{Hasher, RingTarget};
+    use std::collections::BTreeMap;
+
+    struct Node(&'static str, &'static str, u32);
+
+    impl RingTarget for Node {
+        fn name(&self) -> Target {
+            return self.0.to_string();
+        }
+        fn zone(&self) -> Option<String> {
+            return Some(self.1.to_string());
+        }
+        fn labels(&self) -> BTreeMap<String, String> {
+            let mut labels = BTreeMap::new();
+            labels.insert("latency".to_string(), self.2.to_string());
+            return labels;
+        }
+    }
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (p, node) in [
+            (10, Node("t1", "us", 30)),
+            (20, Node("t2", "eu", 10)),
+            (30, Node("t3", "us", 20)),
+            (40, Node("t4", "eu", 40)),
+        ] {
+            fh.set_hasher(Hasher::Mock(p));
+            fh.add_ring_target(&node);
+        }
+        fh.set_hasher(Hasher::Mock(15));
+        return fh;
+    }
+
+    #[test]
+    fn same_candidates_reordered() {
+        let fh = ring();
+        assert_eq!(fh.lookup_list("r", 3), ["t2", "t3", "t4"]);
+        assert_eq!(fh.lookup_list_prefer_zone("r", 3, "us"), ["t3", "t2", "t4"]);
+        assert_eq!(fh.lookup_list_prefer_zone("r", 3, "eu"), ["t2", "t4", "t3"]);
+        assert_eq!(
+            fh.lookup_list_prefer_zone("r", 3, "mars"),
+            ["t2", "t3", "t4"]
+        );
+
+        let by_latency =
+            fh.lookup_list_nearest("r", 3, |_, info| info.labels["latency"].parse().unwrap());
+        assert_eq!(by_latency, ["t2", "t3", "t4"]);
+        let by_latency =
+            fh.lookup_list_nearest("r", 4, |_, info| info.labels["latency"].parse().unwrap());
+        assert_eq!(by_latency, ["t2", "t3", "t1", "t4"]);
+    }
+}
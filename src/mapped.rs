@@ -0,0 +1,648 @@
+use crate::{key_position, Flexihash, Hasher, KeyNormalization, Position, ResourceKey};
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::path::Path;
+
+/*
+ * A flat, read-only copy of a ring which lookups can use straight from
+ * the bytes (eg a memory-mapped file), with no parsing or rebuilding. All
+ * numbers are little-endian, and each array starts on a multiple of its
+ * element size:
+ *
+ *   0    magic           b"FHMAP\0\0\x01"
+ *   8    hasher          u8 (0 = crc32, 1 = md5, 2 = mock, 3 = adler32,
+ *                        4 = highway, 5 = t1ha, 6 = spooky)
+ *   9    hash tags       u8 (0 = off, 1 = on)
+ *   10   digest bits     u8 (0 = full width, else 32/64/128)
+ *   11   key normalization u8 (0 = as given, 1 = nfc, 2 = nfkc)
+ *   12   (reserved)      4 bytes
+ *   16   mock position   u128, or the t1ha seed
+ *   32   n_positions     u64
+ *   40   n_targets       u64
+ *   48   names_len       u64
+ *   56   prefix_len      u64
+ *   64   positions       [u128; n_positions], sorted
+ *        owners          [u32; n_positions], index into the target table
+ *        name offsets    [u32; n_targets + 1], into names
+ *        names           [u8; names_len], utf-8
+ *        key prefix      [u8; prefix_len], utf-8
+ *        highway key     [u64; 4], for highway rings only
+ */
+const MAGIC: &[u8; 8] = b"FHMAP\0\0\x01";
+const HEADER_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(std::io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Io(e) => write!(f, "Failed to access mapped ring: {}", e),
+            MapError::Invalid(e) => write!(f, "Invalid mapped ring: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl From<std::io::Error> for MapError {
+    fn from(e: std::io::Error) -> MapError {
+        return MapError::Io(e);
+    }
+}
+
+fn invalid<T>(message: &str) -> Result<T, MapError> {
+    return Err(MapError::Invalid(message.to_string()));
+}
+
+impl Flexihash {
+    pub fn to_mapped_bytes(&self) -> Vec<u8> {
+        let targets = self.get_all_targets();
+        let mut names = Vec::new();
+        let mut offsets = vec![0u32];
+        for target in targets.iter() {
+            names.extend_from_slice(target.as_bytes());
+            offsets.push(names.len() as u32);
+        }
+
+        // stacked truncations come to the narrowest of them
+        let mut hasher = &self.hasher;
+        let mut bits = 0;
+        while let Hasher::Truncated(inner, width) = hasher {
+            bits = if bits == 0 { *width } else { bits.min(*width) };
+            hasher = inner;
+        }
+        let (kind, mock) = match *hasher {
+            #[cfg(feature = "crc")]
+            Hasher::Crc32 => (0u8, 0),
+            #[cfg(feature = "md5")]
+            Hasher::Md5 => (1u8, 0),
+            Hasher::Mock(position) => (2u8, position),
+            Hasher::Adler32 => (3u8, 0),
+            #[cfg(feature = "highway")]
+            Hasher::Highway(_) => (4u8, 0),
+            #[cfg(feature = "t1ha")]
+            Hasher::T1ha(seed) => (5u8, seed as u128),
+            #[cfg(feature = "spooky")]
+            Hasher::Spooky => (6u8, 0),
+            // peeled off above
+            Hasher::Truncated(..) => unreachable!(),
+        };
+        let n = self.sorted_position_to_target.len();
+        let mut data = Vec::with_capacity(HEADER_LEN + n * 20 + offsets.len() * 4 + names.len());
+        data.extend_from_slice(MAGIC);
+        data.push(kind);
+        data.push(self.hash_tags as u8);
+        data.push(bits as u8);
+        data.push(match self.key_normalization {
+            KeyNormalization::AsGiven => 0,
+            #[cfg(feature = "unicode")]
+            KeyNormalization::Nfc => 1,
+            #[cfg(feature = "unicode")]
+            KeyNormalization::Nfkc => 2,
+        });
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&mock.to_le_bytes());
+        data.extend_from_slice(&(n as u64).to_le_bytes());
+        data.extend_from_slice(&(targets.len() as u64).to_le_bytes());
+        data.extend_from_slice(&(names.len() as u64).to_le_bytes());
+        data.extend_from_slice(&(self.key_prefix.len() as u64).to_le_bytes());
+        for (position, _) in self.sorted_position_to_target.iter() {
+            data.extend_from_slice(&position.to_le_bytes());
+        }
+        for (_, target) in self.sorted_position_to_target.iter() {
+            let owner = targets.binary_search(target).unwrap_or_default() as u32;
+            data.extend_from_slice(&owner.to_le_bytes());
+        }
+        for offset in offsets {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data.extend_from_slice(&names);
+        data.extend_from_slice(self.key_prefix.as_bytes());
+        #[cfg(feature = "highway")]
+        if let Hasher::Highway(key) = hasher {
+            for lane in key.iter() {
+                data.extend_from_slice(&lane.to_le_bytes());
+            }
+        }
+        return data;
+    }
+
+    pub fn save_mapped<P: AsRef<Path>>(&self, path: P) -> Result<(), MapError> {
+        crate::snapshot::write_atomically(path.as_ref(), &self.to_mapped_bytes())?;
+        return Ok(());
+    }
+
+    pub fn to_packed_bytes(&self) -> Vec<u8> {
+        return pack(&self.to_mapped_bytes());
+    }
+}
+
+/*
+ * The same thing squeezed for sending over the wire, where it can't be
+ * used in place anyway: the header as above but with its own magic, then
+ * each position as a LEB128 varint of the gap from the one before, then
+ * each owner as a varint, then everything after the owners as-is. Sorted
+ * positions are close together and most rings have few targets, so this
+ * comes to a handful of bytes per position instead of 20.
+ */
+const PACKED_MAGIC: &[u8; 8] = b"FHPACK\0\x01";
+
+fn pack(mapped: &[u8]) -> Vec<u8> {
+    let n = read_u64(mapped, 32) as usize;
+    let owners_at = HEADER_LEN + n * 16;
+    let rest_at = owners_at + n * 4;
+    let mut data = Vec::with_capacity(HEADER_LEN + n * 4 + mapped.len() - rest_at);
+    data.extend_from_slice(PACKED_MAGIC);
+    data.extend_from_slice(&mapped[8..HEADER_LEN]);
+    let mut previous = 0;
+    for i in 0..n {
+        let position = read_u128(mapped, HEADER_LEN + i * 16);
+        write_varint(&mut data, position - previous);
+        previous = position;
+    }
+    for i in 0..n {
+        write_varint(&mut data, read_u32(mapped, owners_at + i * 4) as u128);
+    }
+    data.extend_from_slice(&mapped[rest_at..]);
+    return data;
+}
+
+fn unpack(packed: &[u8]) -> Result<Vec<u8>, MapError> {
+    if packed.len() < HEADER_LEN || &packed[0..8] != PACKED_MAGIC {
+        return invalid("bad header");
+    }
+    // every varint takes at least a byte, which bounds n before we trust
+    // it with an allocation
+    let n = read_u64(packed, 32);
+    if n > (packed.len() - HEADER_LEN) as u64 / 2 {
+        return invalid("length does not match header");
+    }
+    let n = n as usize;
+    let mut data = Vec::with_capacity(packed.len() + n * 20);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&packed[8..HEADER_LEN]);
+    let mut at = HEADER_LEN;
+    let mut position: Position = 0;
+    for _ in 0..n {
+        let gap = read_varint(packed, &mut at)?;
+        position = match position.checked_add(gap) {
+            Some(position) => position,
+            None => return invalid("positions out of range"),
+        };
+        data.extend_from_slice(&position.to_le_bytes());
+    }
+    for _ in 0..n {
+        match u32::try_from(read_varint(packed, &mut at)?) {
+            Ok(owner) => data.extend_from_slice(&owner.to_le_bytes()),
+            Err(_) => return invalid("owner out of range"),
+        }
+    }
+    data.extend_from_slice(&packed[at..]);
+    return Ok(data);
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> Result<u128, MapError> {
+    let mut value: u128 = 0;
+    for shift in (0..Position::BITS).step_by(7) {
+        let byte = match bytes.get(*at) {
+            Some(byte) => *byte,
+            None => return invalid("truncated varint"),
+        };
+        *at += 1;
+        let bits = (byte & 0x7f) as u128;
+        if bits << shift >> shift != bits {
+            return invalid("varint too large");
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    return invalid("varint too large");
+}
+
+#[derive(Debug)]
+pub struct MappedRing<B: AsRef<[u8]>> {
+    data: B,
+    hasher: Hasher,
+    hash_tags: bool,
+    key_normalization: KeyNormalization,
+    n_positions: usize,
+    n_targets: usize,
+    owners_at: usize,
+    offsets_at: usize,
+    names_at: usize,
+    prefix_at: usize,
+    prefix_end: usize,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedRing<memmap2::Mmap> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedRing<memmap2::Mmap>, MapError> {
+        let file = std::fs::File::open(path)?;
+        // save_mapped never modifies a file in place, it replaces it, so
+        // the mapping can't change underneath us
+        let data = unsafe { memmap2::Mmap::map(&file)? };
+        return MappedRing::new(data);
+    }
+}
+
+impl MappedRing<Vec<u8>> {
+    pub fn from_packed(packed: &[u8]) -> Result<MappedRing<Vec<u8>>, MapError> {
+        return MappedRing::new(unpack(packed)?);
+    }
+}
+
+impl<B: AsRef<[u8]>> MappedRing<B> {
+    // Checks the layout up front, so that lookups can trust it
+    pub fn new(data: B) -> Result<MappedRing<B>, MapError> {
+        let bytes = data.as_ref();
+        if bytes.len() < HEADER_LEN || &bytes[0..8] != MAGIC {
+            return invalid("bad header");
+        }
+        let (hasher, key_len) = match bytes[8] {
+            #[cfg(feature = "crc")]
+            0 => (Hasher::Crc32, 0),
+            #[cfg(feature = "md5")]
+            1 => (Hasher::Md5, 0),
+            2 => (Hasher::Mock(read_u128(bytes, 16)), 0),
+            3 => (Hasher::Adler32, 0),
+            // the key itself is read once the layout has been checked
+            #[cfg(feature = "highway")]
+            4 => (Hasher::Highway([0; 4]), 32),
+            #[cfg(feature = "t1ha")]
+            5 => (Hasher::T1ha(read_u64(bytes, 16)), 0),
+            #[cfg(feature = "spooky")]
+            6 => (Hasher::Spooky, 0),
+            _ => return invalid("unknown hasher"),
+        };
+        let size = |at: usize| usize::try_from(read_u64(bytes, at)).ok();
+        let (n_positions, n_targets, names_len, prefix_len) =
+            match (size(32), size(40), size(48), size(56)) {
+                (Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+                _ => return invalid("sizes too large"),
+            };
+        let owners_at = n_positions
+            .checked_mul(16)
+            .and_then(|n| n.checked_add(HEADER_LEN));
+        let offsets_at = owners_at.and_then(|at| at.checked_add(n_positions.checked_mul(4)?));
+        let names_at =
+            offsets_at.and_then(|at| at.checked_add(n_targets.checked_add(1)?.checked_mul(4)?));
+        let prefix_at = names_at.and_then(|at| at.checked_add(names_len));
+        let prefix_end = prefix_at.and_then(|at| at.checked_add(prefix_len));
+        let end = prefix_end.and_then(|at| at.checked_add(key_len));
+        let (owners_at, offsets_at, names_at, prefix_at, prefix_end) =
+            match (owners_at, offsets_at, names_at, prefix_at, prefix_end, end) {
+                (Some(a), Some(b), Some(c), Some(d), Some(e), Some(end)) if end == bytes.len() => {
+                    (a, b, c, d, e)
+                }
+                _ => return invalid("length does not match header"),
+            };
+        #[cfg(feature = "highway")]
+        let hasher = match hasher {
+            Hasher::Highway(mut key) => {
+                for (i, lane) in key.iter_mut().enumerate() {
+                    *lane = read_u64(bytes, prefix_end + i * 8);
+                }
+                Hasher::Highway(key)
+            }
+            hasher => hasher,
+        };
+        let key_normalization = match bytes[11] {
+            0 => KeyNormalization::AsGiven,
+            #[cfg(feature = "unicode")]
+            1 => KeyNormalization::Nfc,
+            #[cfg(feature = "unicode")]
+            2 => KeyNormalization::Nfkc,
+            _ => return invalid("unknown key normalization"),
+        };
+        let hasher = match bytes[10] {
+            0 => hasher,
+            bits => match Hasher::truncated(hasher, bits as u32) {
+                Ok(hasher) => hasher,
+                Err(_) => return invalid("bad digest width"),
+            },
+        };
+
+        let ring = MappedRing {
+            hash_tags: bytes[9] != 0,
+            key_normalization,
+            data,
+            hasher,
+            n_positions,
+            n_targets,
+            owners_at,
+            offsets_at,
+            names_at,
+            prefix_at,
+            prefix_end,
+        };
+        let bytes = ring.data.as_ref();
+        for i in 1..n_positions {
+            if ring.position(i - 1) >= ring.position(i) {
+                return invalid("positions out of order");
+            }
+        }
+        for i in 0..n_positions {
+            if ring.owner(i) >= n_targets {
+                return invalid("owner out of range");
+            }
+        }
+        let mut previous = 0;
+        for i in 0..=n_targets {
+            let offset = read_u32(bytes, offsets_at + i * 4) as usize;
+            if offset < previous || offset > names_len {
+                return invalid("name offsets out of range");
+            }
+            previous = offset;
+        }
+        if read_u32(bytes, offsets_at) != 0 || previous != names_len {
+            return invalid("name offsets don't cover names");
+        }
+        for i in 0..n_targets {
+            if std::str::from_utf8(ring.name_bytes(i)).is_err() {
+                return invalid("target name is not utf-8");
+            }
+        }
+        if std::str::from_utf8(&bytes[prefix_at..prefix_end]).is_err() {
+            return invalid("key prefix is not utf-8");
+        }
+        return Ok(ring);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.n_positions;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.n_positions == 0;
+    }
+
+    pub fn get_all_targets(&self) -> Vec<&str> {
+        return (0..self.n_targets).map(|i| self.name(i)).collect();
+    }
+
+    pub fn lookup<K: ResourceKey>(&self, resource: K) -> &str {
+        return match self.lookup_list(resource, 1).first() {
+            Some(target) => target,
+            None => panic!("No targets set"),
+        };
+    }
+
+    // Same results as Flexihash::lookup_list on the ring this was made from
+    pub fn lookup_list<K: ResourceKey>(&self, resource: K, requested_count: u32) -> Vec<&str> {
+        if requested_count == 0 {
+            panic!("Need to request at least 1 resource");
+        }
+        if self.n_targets == 0 {
+            return Vec::new();
+        }
+        if self.n_targets == 1 {
+            return vec![self.name(0)];
+        }
+
+        let prefix = self.str_at(self.prefix_at, self.prefix_end);
+        let position = key_position(
+            &self.hasher,
+            prefix,
+            self.hash_tags,
+            self.key_normalization,
+            resource.ring_key().as_ref(),
+        );
+        let (mut lo, mut hi) = (0, self.n_positions);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.position(mid) < position {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let mut results: Vec<&str> = Vec::new();
+        for i in (lo..self.n_positions).chain(0..lo) {
+            let target = self.name(self.owner(i));
+            if !results.contains(&target) {
+                results.push(target);
+                if results.len() == requested_count as usize || results.len() == self.n_targets {
+                    break;
+                }
+            }
+        }
+        return results;
+    }
+
+    fn position(&self, i: usize) -> Position {
+        return read_u128(self.data.as_ref(), HEADER_LEN + i * 16);
+    }
+
+    fn owner(&self, i: usize) -> usize {
+        return read_u32(self.data.as_ref(), self.owners_at + i * 4) as usize;
+    }
+
+    fn name_bytes(&self, i: usize) -> &[u8] {
+        let bytes = self.data.as_ref();
+        let start = read_u32(bytes, self.offsets_at + i * 4) as usize;
+        let end = read_u32(bytes, self.offsets_at + i * 4 + 4) as usize;
+        return &bytes[self.names_at + start..self.names_at + end];
+    }
+
+    fn name(&self, i: usize) -> &str {
+        // validated in new()
+        return std::str::from_utf8(self.name_bytes(i)).unwrap_or_default();
+    }
+
+    fn str_at(&self, start: usize, end: usize) -> &str {
+        return std::str::from_utf8(&self.data.as_ref()[start..end]).unwrap_or_default();
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    return u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    return u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+}
+
+fn read_u128(bytes: &[u8], at: usize) -> u128 {
+    return u128::from_le_bytes(bytes[at..at + 16].try_into().unwrap());
+}
+
+#[cfg(test)]
+mod test_mapped {
+    use super::*;
+
+    fn ring(hasher: Hasher) -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(hasher);
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 2);
+        fh.add_target("t-ç", 1);
+        return fh;
+    }
+
+    #[test]
+    fn lookups_match_ring() {
+        #[allow(unused_mut)]
+        let mut hashers = vec![Hasher::Adler32];
+        #[cfg(feature = "crc")]
+        hashers.push(Hasher::Crc32);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::Md5);
+        #[cfg(feature = "highway")]
+        hashers.push(Hasher::Highway([7, 8, 9, 10]));
+        #[cfg(feature = "t1ha")]
+        hashers.push(Hasher::T1ha(42));
+        #[cfg(feature = "spooky")]
+        hashers.push(Hasher::Spooky);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::truncated(Hasher::Md5, 64).unwrap());
+        for hasher in hashers {
+            let mut fh = ring(hasher);
+            fh.set_key_prefix("ns:");
+            fh.set_hash_tags(true);
+            let mapped = MappedRing::new(fh.to_mapped_bytes()).unwrap();
+            assert_eq!(mapped.len(), fh.position_count());
+            assert_eq!(mapped.get_all_targets(), ["t-a", "t-b", "t-ç"]);
+            for i in 0..200 {
+                let key = format!("{{user{}}}:name", i);
+                assert_eq!(mapped.lookup(&key), fh.lookup(&key));
+                assert_eq!(mapped.lookup_list(&key, 2), fh.lookup_list(&key, 2));
+            }
+        }
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn key_normalization() {
+        let mut fh = ring(Hasher::default());
+        fh.set_key_normalization(KeyNormalization::Nfkc);
+        let mapped = MappedRing::new(fh.to_mapped_bytes()).unwrap();
+        for key in ["cafe\u{301}", "\u{fb01}le", "\u{ff21}"] {
+            assert_eq!(mapped.lookup(key), fh.lookup(key));
+        }
+    }
+
+    #[test]
+    fn empty_and_single() {
+        let mapped = MappedRing::new(Flexihash::new().to_mapped_bytes()).unwrap();
+        assert!(mapped.is_empty());
+        assert!(mapped.lookup_list("x", 1).is_empty());
+
+        let mut fh = Flexihash::new();
+        fh.add_target("only", 1);
+        let mapped = MappedRing::new(fh.to_mapped_bytes()).unwrap();
+        assert_eq!(mapped.lookup("x"), "only");
+    }
+
+    #[test]
+    fn packed_round_trip() {
+        #[allow(unused_mut)]
+        let mut hashers = vec![Hasher::Adler32];
+        #[cfg(feature = "crc")]
+        hashers.push(Hasher::Crc32);
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::Md5);
+        #[cfg(feature = "highway")]
+        hashers.push(Hasher::Highway([7, 8, 9, 10]));
+        #[cfg(feature = "md5")]
+        hashers.push(Hasher::truncated(Hasher::Md5, 64).unwrap());
+        for hasher in hashers {
+            let mut fh = ring(hasher);
+            fh.set_key_prefix("ns:");
+            let mapped = MappedRing::from_packed(&fh.to_packed_bytes()).unwrap();
+            assert_eq!(mapped.data, fh.to_mapped_bytes());
+        }
+        let empty = Flexihash::new().to_packed_bytes();
+        assert!(MappedRing::from_packed(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn packed_is_smaller() {
+        let mut fh = Flexihash::new();
+        for i in 0..200 {
+            fh.add_target(format!("cache-{}", i), 1);
+        }
+        let packed = fh.to_packed_bytes().len();
+        let mapped = fh.to_mapped_bytes().len();
+        assert!(packed * 3 < mapped, "{} vs {}", packed, mapped);
+    }
+
+    #[test]
+    fn packed_rejects_damage() {
+        let data = ring(Hasher::default()).to_packed_bytes();
+        assert!(MappedRing::from_packed(&data[..data.len() - 1]).is_err());
+        assert!(MappedRing::from_packed(&data[..HEADER_LEN + 3]).is_err());
+        assert!(MappedRing::from_packed(&ring(Hasher::default()).to_mapped_bytes()).is_err());
+
+        // a position count far beyond the data
+        let mut bad = data.clone();
+        bad[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(MappedRing::from_packed(&bad).is_err());
+
+        // a varint running past 128 bits
+        let mut bad = data[..HEADER_LEN].to_vec();
+        bad.extend_from_slice(&[0xff; 20]);
+        assert!(MappedRing::from_packed(&bad).is_err());
+    }
+
+    #[test]
+    fn rejects_damage() {
+        let data = ring(Hasher::default()).to_mapped_bytes();
+        assert!(MappedRing::new(&data[..]).is_ok());
+        assert!(MappedRing::new(&data[..data.len() - 1]).is_err());
+        assert!(MappedRing::new(&data[..10]).is_err());
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        assert!(MappedRing::new(bad).is_err());
+
+        let mut bad = data.clone();
+        bad[32] = 0xff; // n_positions no longer matches the length
+        assert!(MappedRing::new(bad).is_err());
+
+        let mut bad = data.clone();
+        bad[10] = 48; // not a digest width
+        assert!(MappedRing::new(bad).is_err());
+
+        let mut bad = data.clone();
+        bad[11] = 9; // not a key normalization
+        assert!(MappedRing::new(bad).is_err());
+
+        let mut bad = data.clone();
+        bad[HEADER_LEN..HEADER_LEN + 16].copy_from_slice(&u128::MAX.to_le_bytes());
+        assert!(MappedRing::new(bad).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("flexihash-{}-mapped", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ring.map");
+        #[cfg(feature = "md5")]
+        let fh = ring(Hasher::Md5);
+        #[cfg(not(feature = "md5"))]
+        let fh = ring(Hasher::Adler32);
+        fh.save_mapped(&path).unwrap();
+        let mapped = MappedRing::new(std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(mapped.lookup("resource"), fh.lookup("resource"));
+        #[cfg(feature = "mmap")]
+        assert_eq!(
+            MappedRing::open(&path).unwrap().lookup("resource"),
+            fh.lookup("resource")
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use crate::Target;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/*
+ * Hooks for reporting what a ring is doing, set with
+ * Flexihash::set_metrics_sink. Sinks are called inline, so should be cheap
+ * and must never block.
+ */
+pub trait MetricsSink: fmt::Debug + Send + Sync {
+    // The primary target chosen by each lookup / lookup_list
+    fn looked_up(&self, target: &str);
+    // After every change to the set of positions
+    fn rebuilt(&self, duration: Duration, positions: usize);
+    fn topology_changed(&self, targets: usize);
+}
+
+/*
+ * Sends plain statsd over UDP:
+ *
+ *   <prefix>.lookups.<target>:1|c|@<rate>
+ *   <prefix>.rebuild:<ms>|ms
+ *   <prefix>.positions:<n>|g
+ *   <prefix>.topology_changes:1|c
+ *   <prefix>.targets:<n>|g
+ *
+ * Lookups are sampled by counting (every Nth lookup for a rate of 1/N) so
+ * that there's no randomness on the lookup path.
+ */
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    sample_every: u64,
+    lookups: AtomicU64,
+}
+
+impl StatsdSink {
+    pub fn new<A: ToSocketAddrs, S: Into<String>>(address: A, prefix: S) -> io::Result<StatsdSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        return Ok(StatsdSink {
+            socket,
+            prefix: prefix.into(),
+            sample_every: 1,
+            lookups: AtomicU64::new(0),
+        });
+    }
+
+    pub fn with_sample_rate(mut self, rate: f64) -> StatsdSink {
+        if !(rate > 0.0 && rate <= 1.0) {
+            panic!("Sample rate must be in (0, 1], got {}", rate);
+        }
+        self.sample_every = (1.0 / rate).round() as u64;
+        return self;
+    }
+
+    fn send(&self, metric: String) {
+        // metrics are best-effort; a full buffer or absent server is not
+        // worth failing a lookup over
+        let _ = self.socket.send(metric.as_bytes());
+    }
+
+    fn name(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            return name.to_string();
+        }
+        return format!("{}.{}", self.prefix, name);
+    }
+}
+
+// Characters with meaning in the statsd line protocol, or in metric paths
+fn sanitize(target: &str) -> Target {
+    return target
+        .chars()
+        .map(|c| match c {
+            '.' | ':' | '|' | '@' | '#' | ' ' | '\n' => '_',
+            c => c,
+        })
+        .collect();
+}
+
+impl MetricsSink for StatsdSink {
+    fn looked_up(&self, target: &str) {
+        if !self
+            .lookups
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample_every)
+        {
+            return;
+        }
+        let name = self.name(&format!("lookups.{}", sanitize(target)));
+        if self.sample_every == 1 {
+            self.send(format!("{}:1|c", name));
+        } else {
+            self.send(format!("{}:1|c|@{}", name, 1.0 / self.sample_every as f64));
+        }
+    }
+
+    fn rebuilt(&self, duration: Duration, positions: usize) {
+        self.send(format!(
+            "{}:{}|ms",
+            self.name("rebuild"),
+            duration.as_secs_f64() * 1000.0
+        ));
+        self.send(format!("{}:{}|g", self.name("positions"), positions));
+    }
+
+    fn topology_changed(&self, targets: usize) {
+        self.send(format!("{}:1|c", self.name("topology_changes")));
+        self.send(format!("{}:{}|g", self.name("targets"), targets));
+    }
+}
+
+/*
+ * Reports through the `metrics` crate's facade, so whichever exporter the
+ * application has installed (Prometheus, OpenTelemetry, ...) picks rings'
+ * numbers up alongside everything else:
+ *
+ *   flexihash_lookups_total{target}    counter
+ *   flexihash_rebuild_seconds          histogram
+ *   flexihash_positions                gauge
+ *   flexihash_topology_changes_total   counter
+ *   flexihash_targets                  gauge
+ *
+ * with_label adds a label to all of them, eg to tell several rings apart.
+ * With no recorder installed, reporting costs next to nothing.
+ */
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Default)]
+pub struct FacadeSink {
+    labels: Vec<::metrics::Label>,
+}
+
+#[cfg(feature = "metrics")]
+impl FacadeSink {
+    pub fn new() -> FacadeSink {
+        ::metrics::describe_counter!(
+            "flexihash_lookups_total",
+            "Lookups, by the primary target chosen"
+        );
+        ::metrics::describe_histogram!(
+            "flexihash_rebuild_seconds",
+            ::metrics::Unit::Seconds,
+            "Time taken to rebuild the ring's positions"
+        );
+        ::metrics::describe_gauge!("flexihash_positions", "Positions on the ring");
+        ::metrics::describe_counter!(
+            "flexihash_topology_changes_total",
+            "Changes to the set of targets"
+        );
+        ::metrics::describe_gauge!("flexihash_targets", "Targets on the ring");
+        return FacadeSink::default();
+    }
+
+    pub fn with_label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> FacadeSink {
+        self.labels
+            .push(::metrics::Label::new(key.into(), value.into()));
+        return self;
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for FacadeSink {
+    fn looked_up(&self, target: &str) {
+        let mut labels = self.labels.clone();
+        labels.push(::metrics::Label::new("target", target.to_string()));
+        ::metrics::counter!("flexihash_lookups_total", labels).increment(1);
+    }
+
+    fn rebuilt(&self, duration: Duration, positions: usize) {
+        ::metrics::histogram!("flexihash_rebuild_seconds", self.labels.iter()).record(duration);
+        ::metrics::gauge!("flexihash_positions", self.labels.iter()).set(positions as f64);
+    }
+
+    fn topology_changed(&self, targets: usize) {
+        ::metrics::counter!("flexihash_topology_changes_total", self.labels.iter()).increment(1);
+        ::metrics::gauge!("flexihash_targets", self.labels.iter()).set(targets as f64);
+    }
+}
+
+/*
+ * Lookups per target, kept in-process, for when the question is just "is
+ * traffic balanced?"; turned on with Flexihash::enable_lookup_counts. Like
+ * a MetricsSink, this counts the primary target of each lookup, and is
+ * shared between clones of the ring.
+ */
+#[derive(Debug, Default)]
+pub struct LookupCounts {
+    counts: RwLock<HashMap<Target, AtomicU64>>,
+}
+
+impl LookupCounts {
+    pub fn record(&self, target: &str) {
+        // only the first lookup for each target needs the write lock
+        if let Some(count) = self
+            .counts
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(target)
+        {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.counts
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(target.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, target: &str) -> u64 {
+        return self
+            .counts
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(target)
+            .map_or(0, |c| c.load(Ordering::Relaxed));
+    }
+
+    pub fn counts(&self) -> HashMap<Target, u64> {
+        return self
+            .counts
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(t, c)| (t.clone(), c.load(Ordering::Relaxed)))
+            .collect();
+    }
+
+    // The counts so far, starting again from zero; no lookup is missed or
+    // counted twice between one take and the next
+    pub fn take(&self) -> HashMap<Target, u64> {
+        return self
+            .counts
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(t, c)| (t.clone(), c.swap(0, Ordering::Relaxed)))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+    use crate::Flexihash;
+    use std::sync::Arc;
+
+    fn receive(server: &UdpSocket) -> String {
+        let mut buf = [0; 512];
+        let n = server.recv(&mut buf).unwrap();
+        return String::from_utf8(buf[..n].to_vec()).unwrap();
+    }
+
+    #[test]
+    fn statsd_lines() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sink = StatsdSink::new(server.local_addr().unwrap(), "app.ring").unwrap();
+
+        let mut fh = Flexihash::new();
+        fh.set_metrics_sink(Arc::new(sink));
+        fh.add_target("cache.1:11211", 1);
+        assert!(receive(&server).starts_with("app.ring.rebuild:"));
+        assert_eq!(receive(&server), "app.ring.positions:64|g");
+        assert_eq!(receive(&server), "app.ring.topology_changes:1|c");
+        assert_eq!(receive(&server), "app.ring.targets:1|g");
+
+        fh.lookup("resource");
+        assert_eq!(receive(&server), "app.ring.lookups.cache_1_11211:1|c");
+    }
+
+    #[test]
+    fn sampled_lookups() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let sink = StatsdSink::new(server.local_addr().unwrap(), "")
+            .unwrap()
+            .with_sample_rate(0.25);
+        for _ in 0..8 {
+            sink.looked_up("t-a");
+        }
+        assert_eq!(receive(&server), "lookups.t-a:1|c|@0.25");
+        assert_eq!(receive(&server), "lookups.t-a:1|c|@0.25");
+        let mut buf = [0; 512];
+        assert!(server.recv(&mut buf).is_err());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn facade() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            let mut fh = Flexihash::new();
+            fh.set_metrics_sink(Arc::new(FacadeSink::new().with_label("ring", "cache")));
+            fh.add_target("t-a", 1);
+            fh.add_target("t-b", 1);
+            for i in 0..10 {
+                fh.lookup(i);
+            }
+        });
+
+        let mut lookups = 0;
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            let key = key.key();
+            let labels: Vec<_> = key.labels().map(|l| (l.key(), l.value())).collect();
+            assert_eq!(labels[0], ("ring", "cache"));
+            match (key.name(), value) {
+                ("flexihash_lookups_total", DebugValue::Counter(n)) => {
+                    assert_eq!(labels[1].0, "target");
+                    lookups += n;
+                }
+                ("flexihash_rebuild_seconds", DebugValue::Histogram(times)) => {
+                    assert_eq!(times.len(), 2)
+                }
+                ("flexihash_positions", DebugValue::Gauge(n)) => assert_eq!(n.into_inner(), 128.0),
+                ("flexihash_topology_changes_total", DebugValue::Counter(n)) => assert_eq!(n, 2),
+                ("flexihash_targets", DebugValue::Gauge(n)) => assert_eq!(n.into_inner(), 2.0),
+                (name, value) => panic!("Unexpected metric {} = {:?}", name, value),
+            }
+        }
+        assert_eq!(lookups, 10);
+    }
+
+    #[test]
+    fn lookup_counts() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        assert!(fh.lookup_counts().is_empty());
+
+        fh.enable_lookup_counts();
+        let clone = fh.clone();
+        let mut expected: HashMap<Target, u64> = HashMap::new();
+        for i in 0..100 {
+            *expected.entry(fh.lookup(i)).or_default() += 1;
+            clone.lookup_list(i, 2);
+        }
+        for count in expected.values_mut() {
+            *count *= 2;
+        }
+        assert_eq!(fh.lookup_counts(), expected);
+
+        fh.reset_lookup_counts();
+        assert!(fh.lookup_counts().values().all(|c| *c == 0));
+        fh.lookup("resource");
+        assert_eq!(fh.lookup_counts().values().sum::<u64>(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{Flexihash, ResourceKey, Target};
+
+/*
+ * Moving from one topology to another without a cold cache: writes go to
+ * a key's owner in the new ring, while reads try both rings (new first, by
+ * default) so that keys which haven't been copied over yet are still found
+ * where they used to be.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOrder {
+    NewThenOld,
+    OldThenNew,
+    // once everything has been copied over
+    NewOnly,
+}
+
+// A key whose owner differs between the two rings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Moved {
+    pub from: Target,
+    pub to: Target,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigratingRing {
+    old: Flexihash,
+    new: Flexihash,
+    read_order: ReadOrder,
+}
+
+impl MigratingRing {
+    pub fn new(old: Flexihash, new: Flexihash) -> MigratingRing {
+        return MigratingRing {
+            old,
+            new,
+            read_order: ReadOrder::NewThenOld,
+        };
+    }
+
+    pub fn with_read_order(mut self, read_order: ReadOrder) -> MigratingRing {
+        self.read_order = read_order;
+        return self;
+    }
+
+    pub fn set_read_order(&mut self, read_order: ReadOrder) {
+        self.read_order = read_order;
+    }
+
+    pub fn read_order(&self) -> ReadOrder {
+        return self.read_order;
+    }
+
+    pub fn old_ring(&self) -> &Flexihash {
+        return &self.old;
+    }
+
+    pub fn new_ring(&self) -> &Flexihash {
+        return &self.new;
+    }
+
+    pub fn write_target<K: ResourceKey>(&self, resource: K) -> Target {
+        return self.new.lookup(resource);
+    }
+
+    // Where to look, in order; just the one target if the key hasn't moved.
+    // Either ring may be empty, eg when migrating onto a fresh cluster.
+    pub fn read_targets<K: ResourceKey>(&self, resource: K) -> Vec<Target> {
+        let new = self.new.lookup_list(&resource, 1).pop();
+        let old = match self.read_order {
+            ReadOrder::NewOnly => None,
+            _ => self.old.lookup_list(&resource, 1).pop(),
+        };
+        let (first, second) = match self.read_order {
+            ReadOrder::OldThenNew => (old, new),
+            _ => (new, old),
+        };
+        let mut targets: Vec<Target> = first.into_iter().collect();
+        if let Some(second) = second {
+            if !targets.contains(&second) {
+                targets.push(second);
+            }
+        }
+        return targets;
+    }
+
+    pub fn moved<K: ResourceKey>(&self, resource: K) -> Option<Moved> {
+        let from = self.old.lookup_list(&resource, 1).pop()?;
+        let to = self.new.lookup_list(&resource, 1).pop()?;
+        if from == to {
+            return None;
+        }
+        return Some(Moved { from, to });
+    }
+
+    // Done migrating; carry on with the new ring alone
+    pub fn finish(self) -> Flexihash {
+        return self.new;
+    }
+}
+
+#[cfg(test)]
+mod test_migration {
+    use super::*;
+    use crate::Hasher;
+
+    // t-c takes over everything below 50, so "moved" lands at 40 and
+    // "stayed" at 150
+    fn rings() -> MigratingRing {
+        let mut old = Flexihash::new();
+        old.set_replicas(1);
+        for (target, position) in [("t-a", 100), ("t-b", 200)] {
+            old.set_hasher(Hasher::Mock(position));
+            old.add_target(target, 1);
+        }
+        let mut new = old.clone();
+        new.set_hasher(Hasher::Mock(50));
+        new.add_target("t-c", 1);
+        return MigratingRing::new(old, new);
+    }
+
+    fn at(ring: &MigratingRing, position: u128) -> MigratingRing {
+        let mut old = ring.old_ring().clone();
+        let mut new = ring.new_ring().clone();
+        old.set_hasher(Hasher::Mock(position));
+        new.set_hasher(Hasher::Mock(position));
+        return MigratingRing::new(old, new).with_read_order(ring.read_order());
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let moved = at(&rings(), 40);
+        assert_eq!(moved.write_target("key"), "t-c");
+        assert_eq!(moved.read_targets("key"), ["t-c", "t-a"]);
+        assert_eq!(
+            moved.moved("key"),
+            Some(Moved {
+                from: "t-a".to_string(),
+                to: "t-c".to_string()
+            })
+        );
+
+        let stayed = at(&rings(), 150);
+        assert_eq!(stayed.write_target("key"), "t-b");
+        assert_eq!(stayed.read_targets("key"), ["t-b"]);
+        assert_eq!(stayed.moved("key"), None);
+    }
+
+    #[test]
+    fn read_orders() {
+        let ring = rings().with_read_order(ReadOrder::OldThenNew);
+        assert_eq!(at(&ring, 40).read_targets("key"), ["t-a", "t-c"]);
+
+        let mut ring = ring;
+        ring.set_read_order(ReadOrder::NewOnly);
+        assert_eq!(ring.read_order(), ReadOrder::NewOnly);
+        assert_eq!(at(&ring, 40).read_targets("key"), ["t-c"]);
+    }
+
+    #[test]
+    fn onto_a_fresh_cluster() {
+        let mut new = Flexihash::new();
+        new.add_target("t-a", 1);
+        let ring = MigratingRing::new(Flexihash::new(), new);
+        assert_eq!(ring.read_targets("resource"), ["t-a"]);
+        assert_eq!(ring.moved("resource"), None);
+        assert_eq!(ring.finish().get_all_targets(), ["t-a"]);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::Position;
+use std::fmt;
+
+/*
+ * Hooks for things which need to keep step with a ring's targets (eg a
+ * connection pool per target), added with
+ * Flexihash::add_topology_observer. They're called synchronously, part-way
+ * through each change, so should be quick and must not panic.
+ *
+ * Replacing a target (DuplicatePolicy::Replace) shows up as a removal then
+ * an add. A weight change can leave the weight as it was when only the
+ * positions change, eg part-way through a drain.
+ */
+pub trait TopologyObserver: fmt::Debug + Send + Sync {
+    fn on_target_added(&self, _target: &str, _positions: &[Position]) {}
+    fn on_target_removed(&self, _target: &str, _positions: &[Position]) {}
+    fn on_weight_changed(
+        &self,
+        _target: &str,
+        _old_weight: u32,
+        _new_weight: u32,
+        _old_positions: &[Position],
+        _new_positions: &[Position],
+    ) {
+    }
+    fn on_tier_changed(&self, _target: &str, _old_tier: u32, _new_tier: u32) {}
+}
+
+#[cfg(test)]
+mod test_observer {
+    use super::*;
+    use crate::shared::SharedRing;
+    use crate::Flexihash;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Log(Mutex<Vec<String>>);
+
+    impl TopologyObserver for Log {
+        fn on_target_added(&self, target: &str, positions: &[Position]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("+{} {}", target, positions.len()));
+        }
+
+        fn on_target_removed(&self, target: &str, positions: &[Position]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("-{} {}", target, positions.len()));
+        }
+
+        fn on_weight_changed(
+            &self,
+            target: &str,
+            old_weight: u32,
+            new_weight: u32,
+            old_positions: &[Position],
+            new_positions: &[Position],
+        ) {
+            self.0.lock().unwrap().push(format!(
+                "~{} {}->{} {}->{}",
+                target,
+                old_weight,
+                new_weight,
+                old_positions.len(),
+                new_positions.len()
+            ));
+        }
+
+        fn on_tier_changed(&self, target: &str, old_tier: u32, new_tier: u32) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("^{} {}->{}", target, old_tier, new_tier));
+        }
+    }
+
+    impl Log {
+        fn take(&self) -> Vec<String> {
+            return std::mem::take(&mut *self.0.lock().unwrap());
+        }
+    }
+
+    #[test]
+    fn sees_every_change() {
+        let log = Arc::new(Log::default());
+        let mut fh = Flexihash::new();
+        fh.set_replicas(4);
+        fh.add_topology_observer(log.clone());
+
+        fh.add_target("t-a", 1);
+        fh.add_group("rack-1", vec!["t-b"]);
+        fh.update_target_weight("t-a", 2);
+        fh.remove_target("t-a");
+        fh.remove_group("rack-1");
+        assert_eq!(
+            log.take(),
+            ["+t-a 4", "+t-b 4", "~t-a 1->2 4->8", "-t-a 8", "-t-b 4"]
+        );
+
+        fh.add_target("t-a", 1);
+        fh.transaction(|tx| {
+            tx.add("t-c", 1).set_weight("t-a", 3).remove("t-c");
+        })
+        .unwrap();
+        assert_eq!(
+            log.take(),
+            ["+t-a 4", "+t-c 4", "~t-a 1->3 4->12", "-t-c 4"]
+        );
+
+        fh.set_duplicate_policy(crate::DuplicatePolicy::Replace);
+        fh.add_target("t-a", 1);
+        assert_eq!(log.take(), ["-t-a 12", "+t-a 4"]);
+
+        fh.set_target_tier("t-a", 1);
+        assert_eq!(log.take(), ["^t-a 0->1"]);
+    }
+
+    #[test]
+    fn clones_keep_observers() {
+        let log = Arc::new(Log::default());
+        let mut fh = Flexihash::new();
+        fh.add_topology_observer(log.clone());
+        let shared = Arc::new(SharedRing::new(fh));
+        shared.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        shared
+            .drain("t-a", std::time::Duration::from_millis(1), |_| {})
+            .wait();
+        let log = log.take();
+        assert_eq!(log.len(), 17);
+        assert_eq!(log[1], "~t-a 1->1 64->60");
+        assert_eq!(log[16], "~t-a 1->0 4->0");
+    }
+}
@@ -0,0 +1,413 @@
+use crate::{Flexihash, Position, Target};
+
+/*
+ * The ring as a list of contiguous, non-overlapping slices of the hash
+ * space, each with the target that owns it. A resource belongs to the
+ * first position at or after its own, so each position owns everything
+ * since the previous one; the slice after the last position wraps around
+ * to the first target, and is listed separately so no slice wraps.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub start: Position,
+    pub end: Position,
+    pub target: Target,
+    pub share: f64,
+}
+
+impl Flexihash {
+    pub fn partitions(&self) -> Vec<Partition> {
+        let max = self.hasher.max_position();
+        let size = max as f64 + 1.0;
+        let share = |start: Position, end: Position| (end - start) as f64 / size + 1.0 / size;
+
+        let mut partitions = Vec::with_capacity(self.sorted_position_to_target.len() + 1);
+        let mut start = 0;
+        for (position, target) in self.sorted_position_to_target.iter() {
+            partitions.push(Partition {
+                start,
+                end: *position,
+                target: target.clone(),
+                share: share(start, *position),
+            });
+            if *position == max {
+                return partitions;
+            }
+            start = position + 1;
+        }
+        if let Some((_, first)) = self.sorted_position_to_target.first() {
+            partitions.push(Partition {
+                start,
+                end: max,
+                target: first.clone(),
+                share: share(start, max),
+            });
+        }
+        return partitions;
+    }
+
+    pub fn partitions_csv(&self) -> String {
+        let mut out = String::from("position_start,position_end,target,share\n");
+        for p in self.partitions() {
+            out.push_str(&format!(
+                "{},{},{},{}\n",
+                p.start,
+                p.end,
+                csv_field(&p.target),
+                p.share
+            ));
+        }
+        return out;
+    }
+}
+
+/*
+ * The same ownership as explicit key ranges, for handing to stores which
+ * reshard by range (Vitess, HBase, ...). Neighbouring partitions with the
+ * same owner are merged; ranges are inclusive of both ends.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: Position,
+    pub end: Position,
+    pub target: Target,
+}
+
+impl Flexihash {
+    pub fn key_ranges(&self) -> Vec<KeyRange> {
+        let mut ranges: Vec<KeyRange> = Vec::new();
+        for p in self.partitions() {
+            if let Some(last) = ranges.last_mut() {
+                if last.target == p.target {
+                    last.end = p.end;
+                    continue;
+                }
+            }
+            ranges.push(KeyRange {
+                start: p.start,
+                end: p.end,
+                target: p.target,
+            });
+        }
+        return ranges;
+    }
+
+    // Vitess-style names for each range: half-open, as fixed-width hex of
+    // the hash, with the ends of the hash space left blank - eg "-80",
+    // "80-c0", "c0-" for crc32 rings, which are 8 hex digits wide
+    pub fn key_range_specs(&self) -> Vec<(String, Target)> {
+        let max = self.hasher.max_position();
+        let width = (128 - max.leading_zeros()).div_ceil(8) as usize * 2;
+        let hex = |p: Position| {
+            // whole bytes, minus any trailing zero ones
+            let mut digits = format!("{:0width$x}", p, width = width);
+            while digits.ends_with("00") {
+                digits.truncate(digits.len() - 2);
+            }
+            digits
+        };
+        return self
+            .key_ranges()
+            .into_iter()
+            .map(|r| {
+                let start = if r.start == 0 {
+                    String::new()
+                } else {
+                    hex(r.start)
+                };
+                let end = if r.end == max {
+                    String::new()
+                } else {
+                    hex(r.end + 1)
+                };
+                (format!("{}-{}", start, end), r.target)
+            })
+            .collect();
+    }
+}
+
+/*
+ * For keys which are already in order (log offsets, timestamps, ...) and
+ * would hash terribly: the ring's ownership, scaled down onto the u64 key
+ * space and used as-is, so each target owns one or more contiguous runs of
+ * keys. From there ranges can be split off to new owners, or merged back
+ * into their neighbours, without going back to the ring.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderedRange {
+    pub start: u64,
+    pub end: u64,
+    pub target: Target,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangePartitioner {
+    // contiguous, covering 0..=u64::MAX, with no neighbours sharing a target
+    ranges: Vec<OrderedRange>,
+}
+
+impl RangePartitioner {
+    pub fn from_ring(ring: &Flexihash) -> RangePartitioner {
+        let width = Position::BITS - ring.hasher.max_position().leading_zeros();
+        let scale = |p: Position| -> u64 {
+            if width >= 64 {
+                return (p >> (width - 64)) as u64;
+            }
+            return (p << (64 - width)) as u64;
+        };
+        let mut ranges: Vec<OrderedRange> = Vec::new();
+        for r in ring.key_ranges() {
+            let start = scale(r.start);
+            // too narrow to survive scaling down
+            if ranges.last().is_some_and(|last| last.start == start) {
+                ranges.pop();
+            }
+            ranges.push(OrderedRange {
+                start,
+                end: u64::MAX,
+                target: r.target,
+            });
+        }
+        let mut partitioner = RangePartitioner { ranges };
+        partitioner.tidy();
+        return partitioner;
+    }
+
+    pub fn ranges(&self) -> &[OrderedRange] {
+        return &self.ranges;
+    }
+
+    pub fn lookup(&self, key: u64) -> &str {
+        if self.ranges.is_empty() {
+            panic!("No targets set");
+        }
+        return &self.ranges[self.index_of(key)].target;
+    }
+
+    // Hand the keys from `at` to the end of its range over to `target`
+    pub fn split<S: Into<String>>(&mut self, at: u64, target: S) {
+        if self.ranges.is_empty() {
+            panic!("No targets set");
+        }
+        let i = self.index_of(at);
+        let range = &mut self.ranges[i];
+        let tail = OrderedRange {
+            start: at,
+            end: range.end,
+            target: target.into(),
+        };
+        if at == range.start {
+            *range = tail;
+        } else {
+            range.end = at - 1;
+            self.ranges.insert(i + 1, tail);
+        }
+        self.tidy();
+    }
+
+    // Give the range starting at `at` to the owner of the range before it
+    pub fn merge(&mut self, at: u64) {
+        let i = self.ranges.iter().position(|r| r.start == at);
+        match i {
+            Some(i) if i > 0 => {
+                self.ranges[i].target = self.ranges[i - 1].target.clone();
+                self.tidy();
+            }
+            _ => panic!("No range to merge starts at {}", at),
+        }
+    }
+
+    fn index_of(&self, key: u64) -> usize {
+        return self.ranges.partition_point(|r| r.start <= key) - 1;
+    }
+
+    // Fix up the ends, and merge neighbours with the same owner
+    fn tidy(&mut self) {
+        let mut ranges: Vec<OrderedRange> = Vec::with_capacity(self.ranges.len());
+        for range in self.ranges.drain(..) {
+            match ranges.last_mut() {
+                Some(last) if last.target == range.target => continue,
+                Some(last) => last.end = range.start - 1,
+                None => {}
+            }
+            ranges.push(range);
+        }
+        if let Some(last) = ranges.last_mut() {
+            last.end = u64::MAX;
+        }
+        self.ranges = ranges;
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+#[cfg(test)]
+mod test_partition {
+    use super::*;
+    use crate::Hasher;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        fh.set_hasher(Hasher::Mock(10));
+        fh.add_target("t1", 1);
+        fh.set_hasher(Hasher::Mock(20));
+        fh.add_target("t2", 1);
+        fh.set_hasher(Hasher::default());
+        return fh;
+    }
+
+    #[test]
+    fn partitions() {
+        let fh = ring();
+        let parts = fh.partitions();
+        let bounds: Vec<(Position, Position, &str)> = parts
+            .iter()
+            .map(|p| (p.start, p.end, p.target.as_str()))
+            .collect();
+        assert_eq!(
+            bounds,
+            [(0, 10, "t1"), (11, 20, "t2"), (21, u32::MAX as u128, "t1")]
+        );
+        assert_eq!(parts[1].share, 10.0 / (u32::MAX as f64 + 1.0));
+        let total: f64 = parts.iter().map(|p| p.share).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn partitions_agree_with_lookups() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let parts = fh.partitions();
+        for i in 0..100 {
+            let position = fh.resource_position(&i);
+            let part = parts
+                .iter()
+                .find(|p| p.start <= position && position <= p.end)
+                .unwrap();
+            assert_eq!(part.target, fh.lookup(i));
+        }
+    }
+
+    #[test]
+    fn empty_ring() {
+        assert_eq!(Flexihash::new().partitions(), []);
+    }
+
+    #[test]
+    fn key_ranges_merge_neighbours() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let ranges = fh.key_ranges();
+        assert!(ranges.len() < fh.partitions().len());
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(ranges[ranges.len() - 1].end, u32::MAX as u128);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end + 1, pair[1].start);
+            assert_ne!(pair[0].target, pair[1].target);
+        }
+        for i in 0..100 {
+            let position = fh.resource_position(&i);
+            let range = ranges
+                .iter()
+                .find(|r| r.start <= position && position <= r.end)
+                .unwrap();
+            assert_eq!(range.target, fh.lookup(i));
+        }
+    }
+
+    #[test]
+    fn key_range_specs() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (p, t) in [(0x7fffffff, "t1"), (0xbfffffff, "t2")] {
+            fh.set_hasher(Hasher::Mock(p));
+            fh.add_target(t, 1);
+        }
+        fh.set_hasher(Hasher::default());
+        let specs: Vec<(String, Target)> = fh.key_range_specs();
+        let specs: Vec<(&str, &str)> = specs
+            .iter()
+            .map(|(s, t)| (s.as_str(), t.as_str()))
+            .collect();
+        assert_eq!(specs, [("-80", "t1"), ("80-c0", "t2"), ("c0-", "t1")]);
+        assert!(Flexihash::new().key_range_specs().is_empty());
+    }
+
+    #[test]
+    fn csv() {
+        let mut fh = ring();
+        fh.set_hasher(Hasher::Mock(30));
+        fh.add_target("t,3", 1);
+        let csv = fh.partitions_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "position_start,position_end,target,share");
+        assert!(lines[3].starts_with("21,30,\"t,3\","));
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn range_partitioner_scales_the_ring() {
+        let fh = ring();
+        let rp = RangePartitioner::from_ring(&fh);
+        let ranges: Vec<(u64, u64, &str)> = rp
+            .ranges()
+            .iter()
+            .map(|r| (r.start, r.end, r.target.as_str()))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                (0, (11 << 32) - 1, "t1"),
+                (11 << 32, (21 << 32) - 1, "t2"),
+                (21 << 32, u64::MAX, "t1"),
+            ]
+        );
+        assert_eq!(rp.lookup(0), "t1");
+        assert_eq!(rp.lookup(11 << 32), "t2");
+        assert_eq!(rp.lookup(u64::MAX), "t1");
+
+        // md5 scales down rather than up
+        #[cfg(feature = "md5")]
+        {
+            let mut fh = Flexihash::new();
+            fh.set_hasher(Hasher::Md5);
+            fh.add_targets(vec!["t-a", "t-b"]);
+            let rp = RangePartitioner::from_ring(&fh);
+            assert_eq!(rp.ranges().len(), fh.key_ranges().len());
+        }
+    }
+
+    #[test]
+    fn range_partitioner_split_and_merge() {
+        let mut rp = RangePartitioner::from_ring(&ring());
+        rp.split(100, "t3");
+        rp.split(200, "t1");
+        assert_eq!(rp.lookup(99), "t1");
+        assert_eq!(rp.lookup(100), "t3");
+        assert_eq!(rp.lookup(199), "t3");
+        assert_eq!(rp.lookup(200), "t1");
+        assert_eq!(rp.ranges().len(), 5);
+
+        rp.merge(100);
+        assert_eq!(rp.lookup(150), "t1");
+        // and the neighbours merge too
+        assert_eq!(rp.ranges()[0].end, (11 << 32) - 1);
+        assert_eq!(rp.ranges(), RangePartitioner::from_ring(&ring()).ranges());
+
+        // splitting at the start of a range reassigns the lot
+        rp.split(11 << 32, "t1");
+        assert_eq!(rp.ranges().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "No range to merge starts at 0")]
+    fn range_partitioner_cant_merge_first() {
+        RangePartitioner::from_ring(&ring()).merge(0);
+    }
+}
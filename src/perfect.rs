@@ -0,0 +1,441 @@
+use crate::balance::mix;
+use crate::snapshot::{check_name, checksum, SnapshotError};
+use crate::{Exhausted, Flexihash, ResourceKey, Target};
+use std::collections::HashMap;
+
+/*
+ * For key sets which are fixed and known up front (a catalog of shard IDs,
+ * a list of tenants, ...): a minimal perfect hash of the keys, so each one
+ * gets a slot of its own in 0..n, and each slot an owner. Every target gets
+ * exactly its share of the keys by weight (give or take one for rounding),
+ * with no imbalance at all; and lookups are a few bit tests, whatever the
+ * number of targets.
+ *
+ * Keys go to their owner on the ring where there's room for them, and
+ * otherwise to the next target round the ring which has room, so most of
+ * them stay where the ring would put them.
+ *
+ * The hash is BBHash-style: each level is a bit array a little bigger than
+ * the keys left over, and a key which lands on a bit by itself takes it,
+ * while the ones which collide try again on the next level. Keys outside
+ * the set are told apart by a 64-bit fingerprint kept for each slot; and
+ * since keys are only ever known by that fingerprint, two keys which hash
+ * the same count as one.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PerfectMap {
+    targets: Vec<(Target, u32)>,
+    levels: Vec<Level>,
+    fingerprints: Vec<u64>,
+    owners: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Level {
+    bits: Vec<u64>,
+    // set bits in the words before each word, plus in all earlier levels
+    ranks: Vec<u64>,
+}
+
+// bits per key on each level; more builds faster but takes more space
+const GAMMA: f64 = 2.0;
+const MAX_LEVELS: usize = 64;
+
+const MAGIC: &str = "flexihash perfect 1";
+
+fn fingerprint(key: &[u8]) -> u64 {
+    return mix(checksum(key));
+}
+
+fn bit(hash: u64, level: usize, words: usize) -> usize {
+    let h = mix(hash ^ (level as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    return (h % (words as u64 * 64)) as usize;
+}
+
+impl Level {
+    fn new(bits: Vec<u64>, before: u64) -> Level {
+        let mut ranks = Vec::with_capacity(bits.len());
+        let mut rank = before;
+        for word in bits.iter() {
+            ranks.push(rank);
+            rank += word.count_ones() as u64;
+        }
+        return Level { bits, ranks };
+    }
+
+    fn slot(&self, hash: u64, level: usize) -> Option<usize> {
+        let b = bit(hash, level, self.bits.len());
+        let (word, offset) = (b / 64, b % 64);
+        if self.bits[word] & (1 << offset) == 0 {
+            return None;
+        }
+        let below = (self.bits[word] & ((1 << offset) - 1)).count_ones() as u64;
+        return Some((self.ranks[word] + below) as usize);
+    }
+
+    fn total(&self) -> u64 {
+        return self
+            .ranks
+            .last()
+            .map_or(0, |r| r + self.bits.last().unwrap().count_ones() as u64);
+    }
+}
+
+impl PerfectMap {
+    // Map the keys onto the ring's targets, by the targets' weights
+    pub fn build<K, I>(ring: &Flexihash, keys: I) -> PerfectMap
+    where
+        K: ResourceKey,
+        I: IntoIterator<Item = K>,
+    {
+        let mut keys: Vec<(u64, Vec<u8>)> = keys
+            .into_iter()
+            .map(|k| {
+                let key = k.ring_key().as_ref().to_vec();
+                (fingerprint(&key), key)
+            })
+            .collect();
+        keys.sort_by_key(|k| k.0);
+        keys.dedup_by_key(|k| k.0);
+
+        let mut levels: Vec<Level> = Vec::new();
+        let mut remaining: Vec<u64> = keys.iter().map(|k| k.0).collect();
+        while !remaining.is_empty() {
+            if levels.len() == MAX_LEVELS {
+                panic!("Couldn't find a perfect hash for {} keys", keys.len());
+            }
+            let level = levels.len();
+            let words = ((remaining.len() as f64 * GAMMA) as usize)
+                .div_ceil(64)
+                .max(1);
+            let mut seen = vec![0u64; words];
+            let mut collided = vec![0u64; words];
+            for hash in remaining.iter() {
+                let b = bit(*hash, level, words);
+                if seen[b / 64] & (1 << (b % 64)) != 0 {
+                    collided[b / 64] |= 1 << (b % 64);
+                }
+                seen[b / 64] |= 1 << (b % 64);
+            }
+            remaining.retain(|hash| {
+                let b = bit(*hash, level, words);
+                collided[b / 64] & (1 << (b % 64)) != 0
+            });
+            let bits = seen
+                .iter()
+                .zip(collided.iter())
+                .map(|(s, c)| s & !c)
+                .collect();
+            let before = levels.last().map_or(0, |l| l.total());
+            levels.push(Level::new(bits, before));
+        }
+
+        let mut targets: Vec<(Target, u32)> = ring
+            .target_info
+            .iter()
+            .map(|(target, info)| (target.clone(), info.weight))
+            .collect();
+        targets.sort();
+        let index: HashMap<&str, usize> = targets
+            .iter()
+            .enumerate()
+            .map(|(i, (t, _))| (t.as_str(), i))
+            .collect();
+        let mut room = shares(&targets, keys.len());
+
+        let mut map = PerfectMap {
+            levels,
+            fingerprints: vec![0; keys.len()],
+            owners: vec![0; keys.len()],
+            targets: Vec::new(),
+        };
+        let mut by_slot: Vec<Option<&[u8]>> = vec![None; keys.len()];
+        for (hash, key) in keys.iter() {
+            let slot = map.slot(*hash).unwrap();
+            map.fingerprints[slot] = *hash;
+            by_slot[slot] = Some(key);
+        }
+        for (slot, key) in by_slot.into_iter().enumerate() {
+            let owner = ring
+                .cycle_candidates(key.unwrap(), Exhausted::Stop)
+                .map(|t| index[t.as_str()])
+                .find(|i| room[*i] > 0)
+                .unwrap_or_else(|| panic!("No targets set"));
+            room[owner] -= 1;
+            map.owners[slot] = owner as u32;
+        }
+        map.targets = targets;
+        return map;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.fingerprints.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.fingerprints.is_empty();
+    }
+
+    // None for keys which weren't in the set
+    pub fn lookup<K: ResourceKey>(&self, key: K) -> Option<&str> {
+        let hash = fingerprint(key.ring_key().as_ref());
+        let slot = self.slot(hash)?;
+        if self.fingerprints[slot] != hash {
+            return None;
+        }
+        return Some(&self.targets[self.owners[slot] as usize].0);
+    }
+
+    // How many keys each target has, by name
+    pub fn counts(&self) -> Vec<(Target, usize)> {
+        let mut counts = vec![0; self.targets.len()];
+        for owner in self.owners.iter() {
+            counts[*owner as usize] += 1;
+        }
+        return self
+            .targets
+            .iter()
+            .map(|t| t.0.clone())
+            .zip(counts)
+            .collect();
+    }
+
+    fn slot(&self, hash: u64) -> Option<usize> {
+        return self
+            .levels
+            .iter()
+            .enumerate()
+            .find_map(|(level, l)| l.slot(hash, level));
+    }
+
+    /*
+     * Saved the same way as snapshots:
+     *
+     *   flexihash perfect 1
+     *   target <weight> <name>        for each target
+     *   level <hex words>             for each level, 16 digits a word
+     *   slot <fingerprint> <owner>    for each slot, owner indexing targets
+     *   checksum <fnv-1a of the above>
+     */
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        let mut out = String::new();
+        out.push_str(MAGIC);
+        out.push('\n');
+        for (target, weight) in self.targets.iter() {
+            check_name(target)?;
+            out.push_str(&format!("target {} {}\n", weight, target));
+        }
+        for level in self.levels.iter() {
+            out.push_str("level ");
+            for word in level.bits.iter() {
+                out.push_str(&format!("{:016x}", word));
+            }
+            out.push('\n');
+        }
+        for (fingerprint, owner) in self.fingerprints.iter().zip(self.owners.iter()) {
+            out.push_str(&format!("slot {:016x} {}\n", fingerprint, owner));
+        }
+        out.push_str(&format!("checksum {:016x}\n", checksum(out.as_bytes())));
+        return Ok(out.into_bytes());
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<PerfectMap, SnapshotError> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| SnapshotError::Parse(0, "Not valid UTF-8".to_string()))?;
+        let body_len = text
+            .trim_end_matches('\n')
+            .rfind('\n')
+            .map(|i| i + 1)
+            .ok_or(SnapshotError::Checksum)?;
+        let (body, trailer) = text.split_at(body_len);
+        match trailer.trim_end().strip_prefix("checksum ") {
+            Some(sum) if u64::from_str_radix(sum, 16).ok() == Some(checksum(body.as_bytes())) => {}
+            _ => return Err(SnapshotError::Checksum),
+        }
+
+        let mut map = PerfectMap {
+            targets: Vec::new(),
+            levels: Vec::new(),
+            fingerprints: Vec::new(),
+            owners: Vec::new(),
+        };
+        for (n, line) in body.lines().enumerate() {
+            let bad = |msg: &str| SnapshotError::Parse(n + 1, msg.to_string());
+            if n == 0 {
+                if line != MAGIC {
+                    return Err(bad("Not a flexihash perfect map"));
+                }
+                continue;
+            }
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad("Missing value"))?;
+            match key {
+                "target" => {
+                    let (weight, name) =
+                        value.split_once(' ').ok_or_else(|| bad("Missing name"))?;
+                    let weight = weight.parse().map_err(|_| bad("Invalid weight"))?;
+                    map.targets.push((name.to_string(), weight));
+                }
+                "level" => {
+                    if value.is_empty() || value.len() % 16 != 0 || !value.is_ascii() {
+                        return Err(bad("Invalid level"));
+                    }
+                    let bits = (0..value.len())
+                        .step_by(16)
+                        .map(|i| u64::from_str_radix(&value[i..i + 16], 16))
+                        .collect::<Result<Vec<u64>, _>>()
+                        .map_err(|_| bad("Invalid level"))?;
+                    let before = map.levels.last().map_or(0, |l| l.total());
+                    map.levels.push(Level::new(bits, before));
+                }
+                "slot" => {
+                    let (fingerprint, owner) =
+                        value.split_once(' ').ok_or_else(|| bad("Missing owner"))?;
+                    let fingerprint =
+                        u64::from_str_radix(fingerprint, 16).map_err(|_| bad("Invalid slot"))?;
+                    let owner: u32 = owner.parse().map_err(|_| bad("Invalid owner"))?;
+                    if owner as usize >= map.targets.len() {
+                        return Err(bad("No such target"));
+                    }
+                    map.fingerprints.push(fingerprint);
+                    map.owners.push(owner);
+                }
+                _ => return Err(bad("Unknown field")),
+            }
+        }
+        let slots = map.levels.last().map_or(0, |l| l.total());
+        if slots != map.fingerprints.len() as u64 {
+            return Err(SnapshotError::Parse(0, "Wrong number of slots".to_string()));
+        }
+        return Ok(map);
+    }
+}
+
+// Each target's share of n keys by weight, with what's left over after
+// rounding down going to the biggest fractions
+fn shares(targets: &[(Target, u32)], n: usize) -> Vec<usize> {
+    let total: u64 = targets.iter().map(|t| t.1 as u64).sum();
+    if total == 0 {
+        return vec![0; targets.len()];
+    }
+    let mut shares: Vec<usize> = targets
+        .iter()
+        .map(|t| (n as u64 * t.1 as u64 / total) as usize)
+        .collect();
+    let mut order: Vec<usize> = (0..targets.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(n as u64 * targets[*i].1 as u64 % total));
+    let left = n - shares.iter().sum::<usize>();
+    for i in order.into_iter().take(left) {
+        shares[i] += 1;
+    }
+    return shares;
+}
+
+impl Flexihash {
+    pub fn perfect_map<K, I>(&self, keys: I) -> PerfectMap
+    where
+        K: ResourceKey,
+        I: IntoIterator<Item = K>,
+    {
+        return PerfectMap::build(self, keys);
+    }
+}
+
+#[cfg(test)]
+mod test_perfect {
+    use super::*;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 1);
+        fh.add_target("t-c", 2);
+        return fh;
+    }
+
+    fn keys() -> Vec<String> {
+        return (0..1000).map(|i| format!("shard-{}", i)).collect();
+    }
+
+    #[test]
+    fn every_key_has_a_slot() {
+        let map = ring().perfect_map(keys());
+        assert_eq!(map.len(), 1000);
+        let mut fingerprints = map.fingerprints.clone();
+        fingerprints.sort();
+        fingerprints.dedup();
+        assert_eq!(fingerprints.len(), 1000);
+        for key in keys() {
+            assert!(map.lookup(&key).is_some(), "{}", key);
+        }
+        assert_eq!(map.lookup("shard-1000"), None);
+        assert_eq!(map.lookup("elsewhere"), None);
+    }
+
+    #[test]
+    fn shares_are_exact() {
+        let map = ring().perfect_map(keys());
+        assert_eq!(
+            map.counts(),
+            [
+                ("t-a".to_string(), 250),
+                ("t-b".to_string(), 250),
+                ("t-c".to_string(), 500)
+            ]
+        );
+        let map = ring().perfect_map((0..10).map(|i| i.to_string()));
+        let counts: Vec<usize> = map.counts().into_iter().map(|c| c.1).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 10);
+        assert!(counts[2] == 5 || counts[2] == 4, "{:?}", counts);
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn mostly_follows_the_ring() {
+        let fh = ring();
+        let map = fh.perfect_map(keys());
+        let same = keys()
+            .iter()
+            .filter(|k| map.lookup(*k).unwrap() == fh.lookup(*k))
+            .count();
+        assert!(same > 700, "{}", same);
+    }
+
+    #[test]
+    fn duplicates_and_empty_sets() {
+        let map = ring().perfect_map(vec!["a", "b", "a"]);
+        assert_eq!(map.len(), 2);
+        let empty = ring().perfect_map(Vec::<String>::new());
+        assert!(empty.is_empty());
+        assert_eq!(empty.lookup("a"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "No targets set")]
+    fn no_targets() {
+        Flexihash::new().perfect_map(vec!["a"]);
+    }
+
+    #[test]
+    fn saving_and_loading() {
+        let map = ring().perfect_map(keys());
+        let data = map.to_bytes().unwrap();
+        let loaded = PerfectMap::from_bytes(&data).unwrap();
+        assert_eq!(loaded, map);
+        for key in keys().iter().take(50) {
+            assert_eq!(loaded.lookup(key), map.lookup(key));
+        }
+
+        let empty = ring().perfect_map(Vec::<String>::new());
+        assert_eq!(
+            PerfectMap::from_bytes(&empty.to_bytes().unwrap()).unwrap(),
+            empty
+        );
+
+        let mut mangled = data.clone();
+        mangled[30] ^= 1;
+        assert!(matches!(
+            PerfectMap::from_bytes(&mangled),
+            Err(SnapshotError::Checksum)
+        ));
+    }
+}
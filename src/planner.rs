@@ -0,0 +1,463 @@
+use crate::partition::Partition;
+use crate::{Flexihash, Target};
+use std::collections::{BTreeMap, HashMap};
+
+/*
+ * Rebalancing advice: given how loaded each target currently is, suggest
+ * weight changes (and, with a per-target capacity, targets to add or
+ * remove), along with what the ring would look like afterwards.
+ *
+ * Predictions assume that load is spread evenly over each target's current
+ * slices of the hash space - so a slice which moves from one target to
+ * another takes its old owner's load density along with it.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    SetWeight(Target, u32),
+    Add(Target, u32),
+    Remove(Target),
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanOptions {
+    // How far above the mean the busiest target may be, eg 0.1 for 10%
+    pub max_imbalance: f64,
+    // Load one target can take; if set, targets are added or removed to fit
+    pub capacity: Option<f64>,
+    pub max_weight: u32,
+}
+
+impl Default for PlanOptions {
+    fn default() -> PlanOptions {
+        return PlanOptions {
+            max_imbalance: 0.1,
+            capacity: None,
+            max_weight: 100,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+    pub shares: BTreeMap<Target, f64>,
+    pub loads: BTreeMap<Target, f64>,
+    pub imbalance: f64,
+    // Fraction of the hash space which would change owner
+    pub remapped: f64,
+}
+
+const MAX_ROUNDS: usize = 10;
+
+fn apply_changes(fh: &Flexihash, changes: &[Change]) -> Flexihash {
+    let mut new = fh.clone();
+    for change in changes {
+        match change {
+            Change::SetWeight(target, weight) => {
+                new.update_target_weight(target.clone(), *weight);
+            }
+            Change::Add(target, weight) => {
+                new.add_target(target.clone(), *weight);
+            }
+            Change::Remove(target) => {
+                new.remove_target(target.clone());
+            }
+        }
+    }
+    return new;
+}
+
+fn imbalance(loads: &BTreeMap<Target, f64>) -> f64 {
+    if loads.is_empty() {
+        return 0.0;
+    }
+    let mean = loads.values().sum::<f64>() / loads.len() as f64;
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    return loads.values().cloned().fold(0.0, f64::max) / mean - 1.0;
+}
+
+fn set_weight(changes: &mut Vec<Change>, target: &Target, weight: u32) {
+    for change in changes.iter_mut() {
+        if let Change::Add(t, w) | Change::SetWeight(t, w) = change {
+            if t == target {
+                *w = weight;
+                return;
+            }
+        }
+    }
+    changes.push(Change::SetWeight(target.clone(), weight));
+}
+
+impl Flexihash {
+    // Predict the outcome of an arbitrary set of changes
+    pub fn evaluate_changes(&self, loads: &HashMap<Target, f64>, changes: &[Change]) -> Plan {
+        let new = apply_changes(self, changes);
+        let before = self.partitions();
+        let after = new.partitions();
+
+        let mut old_shares: HashMap<&Target, f64> = HashMap::new();
+        for p in before.iter() {
+            *old_shares.entry(&p.target).or_default() += p.share;
+        }
+        let density = |target: &Target| -> f64 {
+            let share = old_shares.get(target).cloned().unwrap_or(0.0);
+            if share <= 0.0 {
+                return 0.0;
+            }
+            return loads.get(target).cloned().unwrap_or(0.0) / share;
+        };
+
+        let mut shares: BTreeMap<Target, f64> = new.targets().map(|t| (t.clone(), 0.0)).collect();
+        let mut new_loads = shares.clone();
+        let mut remapped = 0.0;
+        let size = new.hasher.max_position() as f64 + 1.0;
+        for (old, new, overlap) in overlaps(&before, &after, size) {
+            *shares.entry(new.clone()).or_default() += overlap;
+            *new_loads.entry(new.clone()).or_default() += density(old) * overlap;
+            if old != new {
+                remapped += overlap;
+            }
+        }
+        // an empty ring before means everything is new
+        if before.is_empty() {
+            for p in after.iter() {
+                *shares.entry(p.target.clone()).or_default() += p.share;
+            }
+            remapped = if after.is_empty() { 0.0 } else { 1.0 };
+        }
+
+        return Plan {
+            changes: changes.to_vec(),
+            imbalance: imbalance(&new_loads),
+            shares,
+            loads: new_loads,
+            remapped,
+        };
+    }
+
+    // new_target names any targets the plan wants to add (numbered from 0)
+    pub fn plan_rebalance(
+        &self,
+        loads: &HashMap<Target, f64>,
+        options: &PlanOptions,
+        mut new_target: impl FnMut(usize) -> Target,
+    ) -> Plan {
+        let mut changes = Vec::new();
+        let load_of = |t: &Target| loads.get(t).cloned().unwrap_or(0.0);
+
+        if let Some(capacity) = options.capacity {
+            let total: f64 = self.targets().map(load_of).sum();
+            let needed = ((total / capacity).ceil() as usize).max(1);
+            let n = self.target_to_positions.len();
+            if needed > n {
+                let weights: Vec<u32> = self.target_info.values().map(|i| i.weight).collect();
+                let weight = match weights.len() {
+                    0 => 1,
+                    n => (weights.iter().sum::<u32>() as f64 / n as f64)
+                        .round()
+                        .max(1.0) as u32,
+                };
+                for i in 0..needed - n {
+                    changes.push(Change::Add(new_target(i), weight));
+                }
+            } else if needed < n {
+                let mut by_load: Vec<&Target> = self.targets().collect();
+                by_load.sort_by(|a, b| load_of(a).total_cmp(&load_of(b)));
+                for target in by_load.into_iter().take(n - needed) {
+                    changes.push(Change::Remove(target.clone()));
+                }
+            }
+        }
+
+        let mut best = self.evaluate_changes(loads, &changes);
+        for _ in 0..MAX_ROUNDS {
+            if best.imbalance <= options.max_imbalance || best.loads.is_empty() {
+                break;
+            }
+            let ring = apply_changes(self, &best.changes);
+            let mean = best.loads.values().sum::<f64>() / best.loads.len() as f64;
+            let weight_of = |t: &Target| ring.target_info.get(t).map_or(1, |i| i.weight);
+
+            let mut next = best.changes.clone();
+            for (target, load) in best.loads.iter() {
+                let weight = weight_of(target) as f64;
+                let wanted = if *load <= 0.0 {
+                    weight * 2.0
+                } else {
+                    weight * mean / load
+                };
+                let wanted = (wanted.round() as u32).clamp(1, options.max_weight);
+                if wanted != weight_of(target) {
+                    set_weight(&mut next, target, wanted);
+                }
+            }
+            // weights too coarse to adjust by rounding - give everything
+            // more resolution first
+            if next == best.changes {
+                for target in best.loads.keys() {
+                    let doubled = (weight_of(target) * 2).min(options.max_weight);
+                    set_weight(&mut next, target, doubled);
+                }
+            }
+            if next == best.changes {
+                break;
+            }
+            let candidate = self.evaluate_changes(loads, &next);
+            if candidate.imbalance >= best.imbalance {
+                break;
+            }
+            best = candidate;
+        }
+        return best;
+    }
+}
+
+/*
+ * Watches observed per-target request counts and, once the busiest target
+ * is more than threshold above the mean, proposes weight changes to fix
+ * it - via plan_rebalance, so each suggestion comes with its predicted
+ * loads and remap cost. Counts add up until reset(), eg once per
+ * reporting interval.
+ */
+#[derive(Debug, Clone)]
+pub struct SkewMonitor {
+    threshold: f64,
+    min_requests: u64,
+    max_weight: u32,
+    counts: HashMap<Target, u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SkewAlert {
+    pub hottest: Target,
+    pub imbalance: f64,
+    pub plan: Plan,
+}
+
+impl SkewMonitor {
+    pub fn new(threshold: f64) -> SkewMonitor {
+        return SkewMonitor {
+            threshold,
+            min_requests: 1000,
+            max_weight: PlanOptions::default().max_weight,
+            counts: HashMap::new(),
+        };
+    }
+
+    // Too few requests and skew is just noise
+    pub fn with_min_requests(mut self, min_requests: u64) -> SkewMonitor {
+        self.min_requests = min_requests;
+        return self;
+    }
+
+    pub fn with_max_weight(mut self, max_weight: u32) -> SkewMonitor {
+        self.max_weight = max_weight;
+        return self;
+    }
+
+    pub fn record<S: Into<String>>(&mut self, target: S, requests: u64) {
+        *self.counts.entry(target.into()).or_default() += requests;
+    }
+
+    pub fn observe(&mut self, counts: &HashMap<Target, u64>) {
+        for (target, requests) in counts.iter() {
+            self.record(target.clone(), *requests);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.clear();
+    }
+
+    // Targets which aren't in the ring are ignored, and ones which haven't
+    // been seen count as idle
+    pub fn check(&self, fh: &Flexihash) -> Option<SkewAlert> {
+        let loads: HashMap<Target, f64> = fh
+            .targets()
+            .map(|t| (t.clone(), self.counts.get(t).cloned().unwrap_or(0) as f64))
+            .collect();
+        let total: f64 = loads.values().sum();
+        if loads.is_empty() || total < self.min_requests as f64 {
+            return None;
+        }
+        let current = fh.evaluate_changes(&loads, &[]);
+        if current.imbalance <= self.threshold {
+            return None;
+        }
+        let hottest = current
+            .loads
+            .iter()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(t, _)| t.clone())?;
+        let options = PlanOptions {
+            max_imbalance: self.threshold,
+            capacity: None,
+            max_weight: self.max_weight,
+        };
+        return Some(SkewAlert {
+            hottest,
+            imbalance: current.imbalance,
+            plan: fh.plan_rebalance(&loads, &options, |_| unreachable!()),
+        });
+    }
+}
+
+// Walk two partition lists (each covering the whole hash space) together,
+// giving each overlapping slice with its owner in both
+fn overlaps<'a>(
+    before: &'a [Partition],
+    after: &'a [Partition],
+    size: f64,
+) -> Vec<(&'a Target, &'a Target, f64)> {
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut start = 0;
+    while i < before.len() && j < after.len() {
+        let end = before[i].end.min(after[j].end);
+        out.push((
+            &before[i].target,
+            &after[j].target,
+            (end - start) as f64 / size + 1.0 / size,
+        ));
+        if before[i].end == end {
+            i += 1;
+        }
+        if after[j].end == end {
+            j += 1;
+        }
+        if i >= before.len() || j >= after.len() {
+            break;
+        }
+        start = end + 1;
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod test_planner {
+    use super::*;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        return fh;
+    }
+
+    fn loads(values: &[(&str, f64)]) -> HashMap<Target, f64> {
+        return values.iter().map(|(t, l)| (t.to_string(), *l)).collect();
+    }
+
+    #[test]
+    fn no_changes_predicts_current_loads() {
+        let fh = ring();
+        let loads = loads(&[("t-a", 300.0), ("t-b", 100.0), ("t-c", 100.0)]);
+        let plan = fh.evaluate_changes(&loads, &[]);
+        assert_eq!(plan.remapped, 0.0);
+        for (target, load) in plan.loads.iter() {
+            assert!((load - loads[target]).abs() < 1e-6);
+        }
+        assert!((plan.imbalance - 0.8).abs() < 1e-6);
+        assert!((plan.shares.values().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn removal_hands_load_on() {
+        let fh = ring();
+        let loads = loads(&[("t-a", 100.0), ("t-b", 100.0), ("t-c", 100.0)]);
+        let plan = fh.evaluate_changes(&loads, &[Change::Remove("t-c".to_string())]);
+        assert_eq!(plan.loads.len(), 2);
+        assert!((plan.loads.values().sum::<f64>() - 300.0).abs() < 1e-6);
+        assert!(
+            (plan.remapped
+                - fh.partitions()
+                    .iter()
+                    .filter(|p| p.target == "t-c")
+                    .map(|p| p.share)
+                    .sum::<f64>())
+            .abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn reweights_hot_targets() {
+        let fh = ring();
+        let loads = loads(&[("t-a", 300.0), ("t-b", 100.0), ("t-c", 100.0)]);
+        let before = fh.evaluate_changes(&loads, &[]).imbalance;
+        let plan = fh.plan_rebalance(&loads, &PlanOptions::default(), |i| format!("new-{}", i));
+        assert!(plan.imbalance < before);
+        assert!(plan.remapped > 0.0);
+        assert!(plan
+            .changes
+            .iter()
+            .all(|c| matches!(c, Change::SetWeight(..))));
+    }
+
+    #[test]
+    fn capacity_adds_and_removes_targets() {
+        let fh = ring();
+        let busy = loads(&[("t-a", 150.0), ("t-b", 150.0), ("t-c", 150.0)]);
+        let options = PlanOptions {
+            max_imbalance: 10.0,
+            capacity: Some(100.0),
+            ..Default::default()
+        };
+        let plan = fh.plan_rebalance(&busy, &options, |i| format!("new-{}", i));
+        assert_eq!(
+            plan.changes,
+            [
+                Change::Add("new-0".to_string(), 1),
+                Change::Add("new-1".to_string(), 1)
+            ]
+        );
+        assert_eq!(plan.loads.len(), 5);
+
+        let quiet = loads(&[("t-a", 30.0), ("t-b", 10.0), ("t-c", 20.0)]);
+        let options = PlanOptions {
+            max_imbalance: 10.0,
+            capacity: Some(1000.0),
+            ..Default::default()
+        };
+        let plan = fh.plan_rebalance(&quiet, &options, |i| format!("new-{}", i));
+        assert_eq!(
+            plan.changes,
+            [
+                Change::Remove("t-b".to_string()),
+                Change::Remove("t-c".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn skew_monitor() {
+        let fh = ring();
+        let mut monitor = SkewMonitor::new(0.5).with_min_requests(100);
+        monitor.record("t-a", 30);
+        monitor.record("t-b", 10);
+        // not enough to go on yet
+        assert!(monitor.check(&fh).is_none());
+
+        monitor.observe(
+            &[("t-a".to_string(), 270), ("t-x".to_string(), 1000)]
+                .iter()
+                .cloned()
+                .collect(),
+        );
+        monitor.record("t-b", 90);
+        monitor.record("t-c", 100);
+        let alert = monitor.check(&fh).unwrap();
+        assert_eq!(alert.hottest, "t-a");
+        assert!((alert.imbalance - 0.8).abs() < 1e-6);
+        assert!(alert.plan.imbalance < alert.imbalance);
+        assert!(alert.plan.remapped > 0.0);
+        assert!(!alert.plan.changes.is_empty());
+
+        monitor.reset();
+        monitor.record("t-a", 100);
+        monitor.record("t-b", 100);
+        monitor.record("t-c", 100);
+        assert!(monitor.check(&fh).is_none());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{hash, Hasher};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/*
+ * Picking a hasher by how fast it runs on this machine. Which is quickest
+ * depends on the CPU (eg whether crc32 gets hardware help) as much as on
+ * the hasher, so a binary which runs on many kinds of hardware can time
+ * the hashers built into it at startup and take the quickest one that's
+ * good enough.
+ *
+ * Different hashers put targets in different places, so every process
+ * sharing a ring has to agree on one: probe once (eg when first writing
+ * out the config or a snapshot, which records the hasher) rather than
+ * letting each machine choose for itself, unless the ring is private to
+ * the process.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HashQuality {
+    // clusters similar short keys together (adler32, mocks)
+    Weak,
+    Good,
+    // passes SMHasher, or is cryptographic
+    Strong,
+}
+
+#[derive(Debug, Clone)]
+pub struct HasherProbe {
+    pub hasher: Hasher,
+    pub quality: HashQuality,
+    pub per_hash: Duration,
+}
+
+// Keys of the lengths which show up as resources, from "user:1" to a URL
+const KEY_LENGTHS: [usize; 8] = [6, 9, 12, 16, 24, 32, 48, 80];
+const PASSES: usize = 200;
+// the quickest of a few rounds, to see past the odd interruption
+const ROUNDS: usize = 3;
+
+impl Hasher {
+    pub fn quality(&self) -> HashQuality {
+        return match self {
+            #[cfg(feature = "crc")]
+            Hasher::Crc32 => HashQuality::Good,
+            #[cfg(feature = "md5")]
+            Hasher::Md5 => HashQuality::Strong,
+            Hasher::Adler32 => HashQuality::Weak,
+            #[cfg(feature = "highway")]
+            Hasher::Highway(_) => HashQuality::Strong,
+            #[cfg(feature = "spooky")]
+            Hasher::Spooky => HashQuality::Strong,
+            #[cfg(feature = "t1ha")]
+            Hasher::T1ha(_) => HashQuality::Strong,
+            Hasher::Truncated(inner, _) => inner.quality(),
+            Hasher::Mock(_) => HashQuality::Weak,
+        };
+    }
+
+    // Time every hasher built in, quickest first. Highway isn't included,
+    // since it needs a key of the application's own.
+    pub fn probe_available() -> Vec<HasherProbe> {
+        let keys: Vec<Vec<u8>> = KEY_LENGTHS
+            .iter()
+            .map(|len| (0..*len).map(|i| b"0123456789abcdef:/"[i % 18]).collect())
+            .collect();
+        let mut probes: Vec<HasherProbe> = candidates()
+            .into_iter()
+            .map(|hasher| {
+                let per_hash = (0..ROUNDS)
+                    .map(|_| time(&hasher, &keys))
+                    .min()
+                    .unwrap_or_default();
+                HasherProbe {
+                    quality: hasher.quality(),
+                    hasher,
+                    per_hash,
+                }
+            })
+            .collect();
+        probes.sort_by_key(|p| p.per_hash);
+        return probes;
+    }
+
+    // The quickest hasher here which is at least Good, or the default if
+    // none are (ie when built with nothing but adler32)
+    pub fn fastest_available() -> Hasher {
+        return Hasher::fastest_available_with_floor(HashQuality::Good).unwrap_or_default();
+    }
+
+    pub fn fastest_available_with_floor(floor: HashQuality) -> Option<Hasher> {
+        return Hasher::probe_available()
+            .into_iter()
+            .find(|p| p.quality >= floor)
+            .map(|p| p.hasher);
+    }
+}
+
+fn candidates() -> Vec<Hasher> {
+    return vec![
+        #[cfg(feature = "crc")]
+        Hasher::Crc32,
+        #[cfg(feature = "md5")]
+        Hasher::Md5,
+        Hasher::Adler32,
+        #[cfg(feature = "spooky")]
+        Hasher::Spooky,
+        #[cfg(feature = "t1ha")]
+        Hasher::T1ha(0),
+    ];
+}
+
+fn time(hasher: &Hasher, keys: &[Vec<u8>]) -> Duration {
+    let started = Instant::now();
+    for _ in 0..PASSES {
+        for key in keys.iter() {
+            black_box(hash(hasher, black_box(key)));
+        }
+    }
+    return started.elapsed() / (PASSES * keys.len()) as u32;
+}
+
+#[cfg(test)]
+mod test_probe {
+    use super::*;
+
+    #[test]
+    fn probes_everything_built_in() {
+        let probes = Hasher::probe_available();
+        assert_eq!(probes.len(), candidates().len());
+        assert!(probes.windows(2).all(|w| w[0].per_hash <= w[1].per_hash));
+        assert!(probes
+            .iter()
+            .any(|p| matches!(p.hasher, Hasher::Adler32) && p.quality == HashQuality::Weak));
+    }
+
+    #[test]
+    fn respects_the_floor() {
+        // anything will do
+        assert!(Hasher::fastest_available_with_floor(HashQuality::Weak).is_some());
+        let fastest = Hasher::fastest_available();
+        if candidates().len() > 1 {
+            assert!(fastest.quality() >= HashQuality::Good);
+        } else {
+            assert!(Hasher::fastest_available_with_floor(HashQuality::Good).is_none());
+            assert_eq!(fastest.to_string(), Hasher::default().to_string());
+        }
+    }
+
+    #[test]
+    fn quality() {
+        assert!(HashQuality::Weak < HashQuality::Good);
+        assert_eq!(Hasher::Mock(0).quality(), HashQuality::Weak);
+        #[cfg(feature = "md5")]
+        assert_eq!(
+            Hasher::truncated(Hasher::Md5, 32).unwrap().quality(),
+            HashQuality::Strong
+        );
+    }
+}
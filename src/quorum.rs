@@ -0,0 +1,133 @@
+use crate::{Flexihash, ResourceKey, Target};
+use std::convert::TryFrom;
+use std::fmt;
+
+/*
+ * Dynamo-style N/R/W placement: a resource is stored on its first n
+ * candidates, a write needs w of them to acknowledge it and a read asks r
+ * of them. With r + w > n every read quorum overlaps every write quorum,
+ * so a read always sees the latest successful write.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuorumSet {
+    pub replicas: Vec<Target>,
+    pub read_quorum: usize,
+    pub write_quorum: usize,
+}
+
+impl QuorumSet {
+    pub fn read_succeeded(&self, responses: usize) -> bool {
+        return responses >= self.read_quorum;
+    }
+
+    pub fn write_succeeded(&self, acks: usize) -> bool {
+        return acks >= self.write_quorum;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuorumError {
+    // n, r, w
+    NoOverlap(usize, usize, usize),
+    // a quorum of 0, or bigger than n
+    OutOfRange(usize, usize, usize),
+    // targets in the ring, n
+    NotEnoughTargets(usize, usize),
+}
+
+impl fmt::Display for QuorumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuorumError::NoOverlap(n, r, w) => write!(
+                f,
+                "Read and write quorums don't overlap: r + w must be more than n (n={}, r={}, w={})",
+                n, r, w
+            ),
+            QuorumError::OutOfRange(n, r, w) => write!(
+                f,
+                "Quorums must be between 1 and n (n={}, r={}, w={})",
+                n, r, w
+            ),
+            QuorumError::NotEnoughTargets(have, n) => {
+                write!(f, "Need {} targets, have {}", n, have)
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuorumError {}
+
+impl Flexihash {
+    pub fn quorum_set<K: ResourceKey>(
+        &self,
+        resource: K,
+        n: usize,
+        r: usize,
+        w: usize,
+    ) -> Result<QuorumSet, QuorumError> {
+        if r == 0 || w == 0 || r > n || w > n {
+            return Err(QuorumError::OutOfRange(n, r, w));
+        }
+        if r + w <= n {
+            return Err(QuorumError::NoOverlap(n, r, w));
+        }
+        // not just target_to_positions.len(), as drained targets don't count
+        let replicas = self.lookup_list(resource, u32::try_from(n).unwrap_or(u32::MAX));
+        if replicas.len() < n {
+            return Err(QuorumError::NotEnoughTargets(replicas.len(), n));
+        }
+        return Ok(QuorumSet {
+            replicas,
+            read_quorum: r,
+            write_quorum: w,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_quorum {
+    use super::*;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c", "t-d"]);
+        return fh;
+    }
+
+    #[test]
+    fn quorum_set() {
+        let fh = ring();
+        let set = fh.quorum_set("resource", 3, 2, 2).unwrap();
+        assert_eq!(set.replicas, fh.lookup_list("resource", 3));
+        assert_eq!((set.read_quorum, set.write_quorum), (2, 2));
+        assert!(!set.write_succeeded(1));
+        assert!(set.write_succeeded(2));
+        assert!(set.read_succeeded(3));
+    }
+
+    #[test]
+    fn validation() {
+        let mut fh = ring();
+        assert_eq!(
+            fh.quorum_set("resource", 3, 1, 2),
+            Err(QuorumError::NoOverlap(3, 1, 2))
+        );
+        assert_eq!(
+            fh.quorum_set("resource", 3, 0, 3),
+            Err(QuorumError::OutOfRange(3, 0, 3))
+        );
+        assert_eq!(
+            fh.quorum_set("resource", 3, 4, 1),
+            Err(QuorumError::OutOfRange(3, 4, 1))
+        );
+        assert_eq!(
+            fh.quorum_set("resource", 5, 3, 3).unwrap_err().to_string(),
+            "Need 5 targets, have 4"
+        );
+        fh.update_target_weight("t-d", 0);
+        assert_eq!(
+            fh.quorum_set("resource", 4, 3, 3),
+            Err(QuorumError::NotEnoughTargets(3, 4))
+        );
+    }
+}
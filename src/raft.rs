@@ -0,0 +1,399 @@
+use crate::shared::SharedRing;
+use crate::snapshot::{check_name, Snapshot, SnapshotError};
+use crate::{Error, Flexihash, FrozenFlexihash, Target};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/*
+ * Ring changes which go through a replicated log (eg Raft) instead of
+ * being made locally, so that every node in a cluster applies the same
+ * changes in the same order, and their rings can't drift apart in the
+ * first place.
+ *
+ * This doesn't do consensus itself - openraft, raft-rs and friends each
+ * want their own storage, network and runtime - it gives them the two
+ * ends to hook up:
+ *
+ * - ReplicatedRing turns changes into log entries, and hands them to a
+ *   Proposer (eg wrapping openraft's client_write) instead of applying them
+ * - RingStateMachine is what the consensus library hands committed entries
+ *   back to, on every node, in log order; and what it takes snapshots of
+ *   to compact the log
+ *
+ * Each entry is one or more commands, applied together as a transaction.
+ * An entry which fails against the ring (eg removing a target which has
+ * already gone) fails the same way on every node, so it's still counted as
+ * applied.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Add(Target, u32),
+    Remove(Target),
+    SetWeight(Target, u32),
+}
+
+#[derive(Debug)]
+pub enum RaftError {
+    Parse(usize, String),
+    InvalidName(String),
+    Snapshot(SnapshotError),
+    Ring(Error),
+    Propose(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for RaftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RaftError::Parse(line, msg) => write!(f, "Line {}: {}", line, msg),
+            RaftError::InvalidName(name) => write!(f, "Can't store name {:?}", name),
+            RaftError::Snapshot(e) => write!(f, "{}", e),
+            RaftError::Ring(e) => write!(f, "{}", e),
+            RaftError::Propose(e) => write!(f, "Proposal failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RaftError {}
+
+impl From<Error> for RaftError {
+    fn from(e: Error) -> RaftError {
+        return RaftError::Ring(e);
+    }
+}
+
+impl From<SnapshotError> for RaftError {
+    fn from(e: SnapshotError) -> RaftError {
+        return RaftError::Snapshot(e);
+    }
+}
+
+const MAGIC: &str = "flexihash raft 1";
+
+fn check(name: &str) -> Result<(), RaftError> {
+    return check_name(name).map_err(|_| RaftError::InvalidName(name.to_string()));
+}
+
+impl Command {
+    // One log entry's worth of commands
+    pub fn encode(commands: &[Command]) -> Result<Vec<u8>, RaftError> {
+        let mut out = String::new();
+        out.push_str(MAGIC);
+        out.push('\n');
+        for command in commands {
+            match command {
+                Command::Add(target, weight) => {
+                    check(target)?;
+                    out.push_str(&format!("add {} {}\n", weight, target));
+                }
+                Command::Remove(target) => {
+                    check(target)?;
+                    out.push_str(&format!("remove {}\n", target));
+                }
+                Command::SetWeight(target, weight) => {
+                    check(target)?;
+                    out.push_str(&format!("weight {} {}\n", weight, target));
+                }
+            }
+        }
+        return Ok(out.into_bytes());
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Vec<Command>, RaftError> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| RaftError::Parse(0, "Not valid UTF-8".to_string()))?;
+        if text.is_empty() {
+            return Err(RaftError::Parse(0, "Not a flexihash log entry".to_string()));
+        }
+        let mut commands = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let bad = |msg: &str| RaftError::Parse(n + 1, msg.to_string());
+            if n == 0 {
+                if line != MAGIC {
+                    return Err(bad("Not a flexihash log entry"));
+                }
+                continue;
+            }
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad("Missing value"))?;
+            match key {
+                "remove" => commands.push(Command::Remove(value.to_string())),
+                "add" | "weight" => {
+                    let (weight, name) =
+                        value.split_once(' ').ok_or_else(|| bad("Missing name"))?;
+                    let weight = weight.parse().map_err(|_| bad("Invalid weight"))?;
+                    if key == "add" {
+                        commands.push(Command::Add(name.to_string(), weight));
+                    } else {
+                        commands.push(Command::SetWeight(name.to_string(), weight));
+                    }
+                }
+                _ => return Err(bad("Unknown command")),
+            }
+        }
+        return Ok(commands);
+    }
+}
+
+/*
+ * The node's copy of the ring, kept up to date from the log. Indexes only
+ * ever go up; ones already applied are skipped, so replaying the log after
+ * a restart is harmless. Gaps are fine, since not every log entry is ours
+ * (eg Raft's own membership changes).
+ */
+#[derive(Debug)]
+pub struct RingStateMachine {
+    ring: Arc<SharedRing>,
+    last_applied: Mutex<u64>,
+}
+
+impl RingStateMachine {
+    pub fn new(ring: Arc<SharedRing>) -> RingStateMachine {
+        return RingStateMachine {
+            ring,
+            last_applied: Mutex::new(0),
+        };
+    }
+
+    pub fn ring(&self) -> &Arc<SharedRing> {
+        return &self.ring;
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        return *self.last_applied.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    pub fn apply(&self, index: u64, entry: &[u8]) -> Result<(), RaftError> {
+        let mut last_applied = self.last_applied.lock().unwrap_or_else(|e| e.into_inner());
+        if index <= *last_applied {
+            return Ok(());
+        }
+        // an entry which doesn't even parse would fail on every node too
+        *last_applied = index;
+        let commands = Command::decode(entry)?;
+        return self.ring.update(|fh| {
+            fh.transaction(|tx| {
+                for command in commands {
+                    match command {
+                        Command::Add(target, weight) => tx.add(target, weight),
+                        Command::Remove(target) => tx.remove(target),
+                        Command::SetWeight(target, weight) => tx.set_weight(target, weight),
+                    };
+                }
+            })?;
+            return Ok(());
+        });
+    }
+
+    // For log compaction: the last index applied, and the ring as of then
+    pub fn snapshot(&self) -> Result<(u64, Vec<u8>), RaftError> {
+        let last_applied = self.last_applied.lock().unwrap_or_else(|e| e.into_inner());
+        return Ok((*last_applied, self.ring.snapshot().snapshot().to_bytes()?));
+    }
+
+    // Install a snapshot from the leader, eg for a node which has fallen
+    // too far behind to catch up from the log
+    pub fn restore(&self, index: u64, data: &[u8]) -> Result<(), RaftError> {
+        let ring = Flexihash::try_from_snapshot(&Snapshot::from_bytes(data)?)?;
+        let mut last_applied = self.last_applied.lock().unwrap_or_else(|e| e.into_inner());
+        self.ring.replace(ring);
+        *last_applied = index;
+        return Ok(());
+    }
+}
+
+/*
+ * Hands an entry to the consensus layer; the log index it got, once it's
+ * committed. Entries from a node which isn't the leader should be
+ * forwarded or refused, whichever the library does.
+ */
+pub trait Proposer {
+    fn propose(&self, entry: Vec<u8>) -> Result<u64, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[derive(Debug)]
+pub struct ReplicatedRing<P: Proposer> {
+    proposer: P,
+    machine: Arc<RingStateMachine>,
+}
+
+impl<P: Proposer> ReplicatedRing<P> {
+    pub fn new(proposer: P, machine: Arc<RingStateMachine>) -> ReplicatedRing<P> {
+        return ReplicatedRing { proposer, machine };
+    }
+
+    // Lookups go to the local copy, which may be a little behind the log
+    pub fn ring(&self) -> Arc<FrozenFlexihash> {
+        return self.machine.ring().snapshot();
+    }
+
+    pub fn machine(&self) -> &Arc<RingStateMachine> {
+        return &self.machine;
+    }
+
+    pub fn add_target<S: Into<String>>(&self, target: S, weight: u32) -> Result<u64, RaftError> {
+        return self.propose(&[Command::Add(target.into(), weight)]);
+    }
+
+    pub fn remove_target<S: Into<String>>(&self, target: S) -> Result<u64, RaftError> {
+        return self.propose(&[Command::Remove(target.into())]);
+    }
+
+    pub fn update_target_weight<S: Into<String>>(
+        &self,
+        target: S,
+        weight: u32,
+    ) -> Result<u64, RaftError> {
+        return self.propose(&[Command::SetWeight(target.into(), weight)]);
+    }
+
+    // Several changes as one entry, applied all or nothing
+    pub fn propose(&self, commands: &[Command]) -> Result<u64, RaftError> {
+        let entry = Command::encode(commands)?;
+        return self.proposer.propose(entry).map_err(RaftError::Propose);
+    }
+}
+
+#[cfg(test)]
+mod test_raft {
+    use super::*;
+
+    // A stand-in for a cluster: commits everything straight away, and
+    // applies it to every node in order
+    struct Cluster {
+        log: Mutex<Vec<Vec<u8>>>,
+        nodes: Vec<Arc<RingStateMachine>>,
+    }
+
+    impl Proposer for Arc<Cluster> {
+        fn propose(&self, entry: Vec<u8>) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+            let mut log = self.log.lock().unwrap();
+            log.push(entry.clone());
+            let index = log.len() as u64;
+            for node in self.nodes.iter() {
+                let _ = node.apply(index, &entry);
+            }
+            return Ok(index);
+        }
+    }
+
+    fn cluster(n: usize) -> Arc<Cluster> {
+        let nodes = (0..n)
+            .map(|_| {
+                let mut fh = Flexihash::new();
+                fh.set_replicas(8);
+                Arc::new(RingStateMachine::new(Arc::new(SharedRing::new(fh))))
+            })
+            .collect();
+        return Arc::new(Cluster {
+            log: Mutex::new(Vec::new()),
+            nodes,
+        });
+    }
+
+    #[test]
+    fn every_node_applies_the_same_changes() {
+        let cluster = cluster(3);
+        let rings: Vec<ReplicatedRing<Arc<Cluster>>> = cluster
+            .nodes
+            .iter()
+            .map(|node| ReplicatedRing::new(cluster.clone(), node.clone()))
+            .collect();
+        assert_eq!(rings[0].add_target("t-a", 1).unwrap(), 1);
+        rings[1].add_target("t-b", 2).unwrap();
+        rings[2]
+            .propose(&[
+                Command::Remove("t-a".to_string()),
+                Command::Add("t-c".to_string(), 1),
+            ])
+            .unwrap();
+        rings[0].update_target_weight("t-c", 3).unwrap();
+
+        for ring in rings.iter() {
+            assert_eq!(
+                ring.ring().get_all_targets(),
+                rings[0].ring().get_all_targets()
+            );
+            assert_eq!(ring.ring().fingerprint(), rings[0].ring().fingerprint());
+            assert_eq!(ring.machine().last_applied(), 4);
+        }
+        assert_eq!(rings[1].ring().get_target_info("t-c").unwrap().weight, 3);
+    }
+
+    #[test]
+    fn failed_entries_still_count() {
+        let cluster = cluster(1);
+        let node = &cluster.nodes[0];
+        let entry = Command::encode(&[
+            Command::Add("t-a".to_string(), 1),
+            Command::Remove("t-x".to_string()),
+        ])
+        .unwrap();
+        assert!(matches!(
+            node.apply(5, &entry),
+            Err(RaftError::Ring(Error::TargetMissing(..)))
+        ));
+        assert_eq!(node.last_applied(), 5);
+        assert!(node.ring().snapshot().get_all_targets().is_empty());
+        assert!(matches!(
+            node.apply(6, b"nonsense"),
+            Err(RaftError::Parse(1, _))
+        ));
+        assert_eq!(node.last_applied(), 6);
+    }
+
+    #[test]
+    fn replays_are_skipped() {
+        let cluster = cluster(1);
+        let node = &cluster.nodes[0];
+        let add = Command::encode(&[Command::Add("t-a".to_string(), 1)]).unwrap();
+        node.apply(1, &add).unwrap();
+        node.apply(1, &add).unwrap();
+        node.apply(
+            3,
+            &Command::encode(&[Command::SetWeight("t-a".to_string(), 2)]).unwrap(),
+        )
+        .unwrap();
+        node.apply(2, &add).unwrap();
+        let ring = node.ring().snapshot();
+        assert_eq!(ring.get_all_targets(), ["t-a"]);
+        assert_eq!(ring.get_target_info("t-a").unwrap().weight, 2);
+    }
+
+    #[test]
+    fn snapshots() {
+        let cluster = cluster(2);
+        let leader = ReplicatedRing::new(cluster.clone(), cluster.nodes[0].clone());
+        leader.add_target("t-a", 1).unwrap();
+        leader.add_target("t-b", 1).unwrap();
+
+        let (index, data) = leader.machine().snapshot().unwrap();
+        assert_eq!(index, 2);
+        let mut fh = Flexihash::new();
+        fh.set_replicas(8);
+        let newcomer = RingStateMachine::new(Arc::new(SharedRing::new(fh)));
+        newcomer.restore(index, &data).unwrap();
+        assert_eq!(newcomer.last_applied(), 2);
+        assert_eq!(
+            newcomer.ring().snapshot().fingerprint(),
+            leader.ring().fingerprint()
+        );
+    }
+
+    #[test]
+    fn encoding() {
+        let commands = vec![
+            Command::Add("t-a".to_string(), 2),
+            Command::Remove("t b".to_string()),
+            Command::SetWeight("t-c".to_string(), 0),
+        ];
+        let entry = Command::encode(&commands).unwrap();
+        assert_eq!(Command::decode(&entry).unwrap(), commands);
+        assert!(matches!(
+            Command::encode(&[Command::Remove("t\na".to_string())]),
+            Err(RaftError::InvalidName(_))
+        ));
+        assert!(matches!(
+            Command::decode(b"flexihash raft 1\nadd x t-a\n"),
+            Err(RaftError::Parse(2, _))
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+use crate::changelog::Mutation;
+use crate::shared::SharedRing;
+use crate::snapshot::{Snapshot, SnapshotError};
+use crate::validate::RingWarning;
+use crate::{Error, Flexihash, Target};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/*
+ * Keeps a SharedRing in sync with a snapshot file (as written by
+ * Flexihash::save_to), reloading it whenever it changes on disk. Each new
+ * ring is validated as it's loaded, and anything risky about it reported
+ * along with the changes.
+ */
+#[derive(Debug)]
+pub enum ReloadEvent {
+    Reloaded {
+        added: Vec<Target>,
+        removed: Vec<Target>,
+        changed: Vec<Target>,
+        warnings: Vec<RingWarning>,
+    },
+    Failed(SnapshotError),
+}
+
+pub struct RingWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl RingWatcher {
+    pub fn new<P: AsRef<Path>, F: Fn(ReloadEvent) + Send + 'static>(
+        path: P,
+        ring: Arc<SharedRing>,
+        on_event: F,
+    ) -> Result<RingWatcher, notify::Error> {
+        let path = path.as_ref().to_path_buf();
+        // save_to renames a new file into place, so watch the directory
+        // rather than the (soon to be replaced) file itself
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = path.file_name().map(|n| n.to_os_string());
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                if let Ok(event) = res {
+                    if event.kind.is_access() {
+                        return;
+                    }
+                    if event
+                        .paths
+                        .iter()
+                        .any(|p| p.file_name() == file_name.as_deref())
+                    {
+                        if let Some(event) = reload(&path, &ring) {
+                            on_event(event);
+                        }
+                    }
+                }
+            })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        return Ok(RingWatcher { _watcher: watcher });
+    }
+}
+
+// Load the file and swap it in, returning what changed (or None if the file
+// says the same thing as the current ring)
+pub fn reload<P: AsRef<Path>>(path: P, ring: &SharedRing) -> Option<ReloadEvent> {
+    let snapshot = match std::fs::read(path) {
+        Ok(data) => match Snapshot::from_bytes(&data) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Some(ReloadEvent::Failed(e)),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => return Some(ReloadEvent::Failed(SnapshotError::Io(e))),
+    };
+    let old = ring.snapshot();
+    let new = match with_local_settings(&old, &snapshot) {
+        Ok(new) => new,
+        Err(e) => return Some(ReloadEvent::Failed(SnapshotError::Ring(e))),
+    };
+    let old_targets = old.get_all_targets();
+    let new_targets = new.get_all_targets();
+    let added: Vec<Target> = new_targets
+        .iter()
+        .filter(|t| !old_targets.contains(t))
+        .cloned()
+        .collect();
+    let removed: Vec<Target> = old_targets
+        .iter()
+        .filter(|t| !new_targets.contains(t))
+        .cloned()
+        .collect();
+    let changed: Vec<Target> = new_targets
+        .iter()
+        .filter(|t| old_targets.contains(t) && old.get_target_info(t) != new.get_target_info(t))
+        .cloned()
+        .collect();
+    // the hasher, replicas or salt can change the layout on their own
+    if added.is_empty()
+        && removed.is_empty()
+        && changed.is_empty()
+        && old.fingerprint() == new.fingerprint()
+    {
+        return None;
+    }
+    announce(&old, &new, &added, &removed, &changed);
+    let warnings = new.validate();
+    ring.replace(new);
+    return Some(ReloadEvent::Reloaded {
+        added,
+        removed,
+        changed,
+        warnings,
+    });
+}
+
+// The ring the snapshot describes, with everything which snapshots don't
+// carry (key prefix, rules, limits, hooks and so on) taken from the live
+// ring, so that reloading doesn't quietly undo the application's setup
+fn with_local_settings(live: &Flexihash, snapshot: &Snapshot) -> Result<Flexihash, Error> {
+    let mut new = Flexihash::new();
+    new.max_total_positions = live.max_total_positions;
+    new.load_snapshot(snapshot)?;
+    new.search = live.search;
+    new.duplicate_policy = live.duplicate_policy;
+    new.name_normalization = live.name_normalization;
+    new.key_prefix = live.key_prefix.clone();
+    new.hash_tags = live.hash_tags;
+    new.key_normalization = live.key_normalization;
+    new.rules = live.rules.clone();
+    new.metrics = live.metrics.clone();
+    new.lookup_counts = live.lookup_counts.clone();
+    new.observers = live.observers.clone();
+    new.changelog = live.changelog.clone();
+    return Ok(new);
+}
+
+// Tell the observers and changelog about the swap, as if the targets had
+// been changed one by one
+fn announce(
+    old: &Flexihash,
+    new: &Flexihash,
+    added: &[Target],
+    removed: &[Target],
+    changed: &[Target],
+) {
+    let positions =
+        |fh: &Flexihash, t: &str| fh.target_to_positions.get(t).cloned().unwrap_or_default();
+    let weight = |fh: &Flexihash, t: &str| fh.target_info.get(t).map_or(0, |info| info.weight);
+    for observer in new.observers.iter() {
+        for target in removed {
+            observer.on_target_removed(target, &positions(old, target));
+        }
+        for target in added {
+            observer.on_target_added(target, &positions(new, target));
+        }
+        for target in changed {
+            let (old_positions, new_positions) = (positions(old, target), positions(new, target));
+            if old_positions != new_positions {
+                observer.on_weight_changed(
+                    target,
+                    weight(old, target),
+                    weight(new, target),
+                    &old_positions,
+                    &new_positions,
+                );
+            }
+        }
+    }
+    if let Some(log) = &new.changelog {
+        for target in removed {
+            log.record(new.generation, Mutation::Remove(target.clone()));
+        }
+        for target in added {
+            log.record(
+                new.generation,
+                Mutation::Add(target.clone(), weight(new, target)),
+            );
+        }
+        for target in changed {
+            if weight(old, target) != weight(new, target) {
+                let mutation = Mutation::Reweight {
+                    target: target.clone(),
+                    weight: weight(new, target),
+                    positions: positions(new, target).len() as u64,
+                };
+                log.record(new.generation, mutation);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_reload {
+    use super::*;
+    use crate::rules::Rule;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("flexihash-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn reload_reports_changes() {
+        let path = temp_dir("reload_reports_changes").join("ring");
+        let shared = SharedRing::new(Flexihash::new());
+        shared.update(|fh| {
+            fh.add_targets(vec!["t-a", "t-b"]);
+        });
+
+        let mut fh = Flexihash::new();
+        fh.add_target("t-b", 2);
+        fh.add_target("t-c", 1);
+        fh.save_to(&path).unwrap();
+
+        match reload(&path, &shared) {
+            Some(ReloadEvent::Reloaded {
+                added,
+                removed,
+                changed,
+                ..
+            }) => {
+                assert_eq!(added, ["t-c"]);
+                assert_eq!(removed, ["t-a"]);
+                assert_eq!(changed, ["t-b"]);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-b", "t-c"]);
+        assert!(reload(&path, &shared).is_none());
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(matches!(
+            reload(&path, &shared),
+            Some(ReloadEvent::Failed(_))
+        ));
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-b", "t-c"]);
+
+        // swapped in regardless, but flagged
+        Flexihash::new().save_to(&path).unwrap();
+        match reload(&path, &shared) {
+            Some(ReloadEvent::Reloaded { warnings, .. }) => {
+                assert_eq!(warnings, [RingWarning::NoTargets]);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reload_picks_up_layout_changes() {
+        let path = temp_dir("reload_picks_up_layout_changes").join("ring");
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let shared = SharedRing::new(fh.clone());
+
+        // the same targets, but fewer replicas each
+        let mut fewer = Flexihash::new();
+        fewer.set_replicas(8);
+        fewer.add_targets(vec!["t-a", "t-b"]);
+        fewer.save_to(&path).unwrap();
+        match reload(&path, &shared) {
+            Some(ReloadEvent::Reloaded {
+                added,
+                removed,
+                changed,
+                ..
+            }) => {
+                assert!(added.is_empty() && removed.is_empty() && changed.is_empty());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(shared.snapshot().replicas(), 8);
+        assert_eq!(shared.snapshot().position_count(), 16);
+        assert!(reload(&path, &shared).is_none());
+    }
+
+    #[test]
+    fn reload_keeps_local_settings() {
+        let path = temp_dir("reload_keeps_local_settings").join("ring");
+        let shared = SharedRing::new(Flexihash::new());
+        shared.update(|fh| {
+            fh.add_target("t-a", 1);
+            fh.set_key_prefix("app:");
+            fh.set_hash_tags(true);
+            fh.add_rule(Rule::prefix("admin:", vec!["t-admin"]));
+            fh.enable_lookup_counts();
+        });
+
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        fh.save_to(&path).unwrap();
+        assert!(reload(&path, &shared).is_some());
+
+        let ring = shared.snapshot();
+        assert_eq!(ring.get_all_targets(), ["t-a", "t-b"]);
+        assert_eq!(ring.key_prefix(), "app:");
+        assert!(ring.hash_tags());
+        assert_eq!(ring.lookup("admin:1"), "t-admin");
+        assert_eq!(ring.lookup_counts()["t-admin"], 1);
+    }
+
+    #[test]
+    fn watcher_picks_up_saves() {
+        let path = temp_dir("watcher_picks_up_saves").join("ring");
+        let shared = Arc::new(SharedRing::new(Flexihash::new()));
+        let (tx, rx) = mpsc::channel();
+        let _watcher = RingWatcher::new(&path, shared.clone(), move |event| {
+            let _ = tx.send(event);
+        })
+        .unwrap();
+
+        let mut fh = Flexihash::new();
+        fh.add_target("t-a", 1);
+        fh.save_to(&path).unwrap();
+
+        let event = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(matches!(event, ReloadEvent::Reloaded { .. }));
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-a"]);
+    }
+}
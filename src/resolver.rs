@@ -0,0 +1,219 @@
+use crate::retry::RetryRouter;
+use crate::shared::SharedRing;
+use crate::{ResourceKey, Target};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+ * Turns a target's name into something to talk to - a connection from a
+ * pool, a client, a channel - eg by looking it up in DNS or service
+ * discovery and dialing it. Implementations can be written with
+ * `async fn resolve`; the future has to be Send so that routers can be
+ * used from multi-threaded runtimes.
+ */
+pub trait TargetResolver: Send + Sync {
+    type Conn;
+    type Error;
+
+    fn resolve(
+        &self,
+        target: &Target,
+    ) -> impl Future<Output = Result<Self::Conn, Self::Error>> + Send;
+}
+
+#[derive(Debug)]
+pub enum ResolveError<E> {
+    NoTargets,
+    // every candidate tried, in order, and why it couldn't be reached
+    Exhausted(Vec<(Target, E)>),
+}
+
+impl<E: fmt::Display> fmt::Display for ResolveError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NoTargets => write!(f, "No targets set"),
+            ResolveError::Exhausted(failures) => {
+                write!(f, "No target could be reached:")?;
+                for (target, e) in failures.iter() {
+                    write!(f, " {}: {};", target, e)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for ResolveError<E> {}
+
+/*
+ * The lookup / connect / fall back loop which every networked user of a
+ * ring ends up writing: resolve the resource's owner, and if that fails
+ * move on to its next candidate in ring order, until one connects or the
+ * attempts run out. Targets which fail are cooled down (see RetryRouter)
+ * so that other resources don't wait on them too.
+ */
+#[derive(Debug)]
+pub struct ConnectionRouter<R: TargetResolver> {
+    retry: RetryRouter,
+    resolver: R,
+    max_attempts: Option<usize>,
+}
+
+impl<R: TargetResolver> ConnectionRouter<R> {
+    pub fn new(ring: Arc<SharedRing>, resolver: R) -> ConnectionRouter<R> {
+        return ConnectionRouter {
+            retry: RetryRouter::new(ring),
+            resolver,
+            max_attempts: None,
+        };
+    }
+
+    pub fn with_cool_down(mut self, cool_down: Duration) -> ConnectionRouter<R> {
+        self.retry = self.retry.with_cool_down(cool_down);
+        return self;
+    }
+
+    // By default every target is tried before giving up
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> ConnectionRouter<R> {
+        if max_attempts == 0 {
+            panic!("Need to allow at least 1 attempt");
+        }
+        self.max_attempts = Some(max_attempts);
+        return self;
+    }
+
+    pub fn resolver(&self) -> &R {
+        return &self.resolver;
+    }
+
+    // The first of the resource's candidates which resolves, and which
+    // target that was
+    pub async fn get_connection<K: ResourceKey>(
+        &self,
+        resource: K,
+    ) -> Result<(Target, R::Conn), ResolveError<R::Error>> {
+        let mut failures: Vec<(Target, R::Error)> = Vec::new();
+        let mut attempted: Vec<Target> = Vec::new();
+        while self.max_attempts.is_none_or(|max| attempted.len() < max) {
+            let target = match self.retry.next_candidate(&resource, &attempted) {
+                Some(target) => target,
+                None => break,
+            };
+            match self.resolver.resolve(&target).await {
+                Ok(conn) => {
+                    self.retry.mark_succeeded(&target);
+                    return Ok((target, conn));
+                }
+                Err(e) => {
+                    self.retry.mark_failed(target.clone());
+                    attempted.push(target.clone());
+                    failures.push((target, e));
+                }
+            }
+        }
+        if failures.is_empty() {
+            return Err(ResolveError::NoTargets);
+        }
+        return Err(ResolveError::Exhausted(failures));
+    }
+
+    // For when a connection which resolved fine goes bad later on
+    pub fn mark_failed<S: Into<String>>(&self, target: S) {
+        self.retry.mark_failed(target);
+    }
+}
+
+#[cfg(test)]
+mod test_resolver {
+    use super::*;
+    use crate::Flexihash;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct Resolver {
+        down: Vec<Target>,
+        calls: Mutex<Vec<Target>>,
+    }
+
+    impl TargetResolver for Resolver {
+        type Conn = String;
+        type Error = String;
+
+        async fn resolve(&self, target: &Target) -> Result<String, String> {
+            self.calls.lock().unwrap().push(target.clone());
+            if self.down.contains(target) {
+                return Err(format!("{} is down", target));
+            }
+            return Ok(format!("conn to {}", target));
+        }
+    }
+
+    fn ring() -> Arc<SharedRing> {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        return Arc::new(SharedRing::new(fh));
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[tokio::test]
+    async fn connects_to_the_owner() {
+        let ring = ring();
+        let owner = ring.snapshot().lookup("resource");
+        let router = ConnectionRouter::new(ring, Resolver::default());
+        let connecting = router.get_connection("resource");
+        assert_send(&connecting);
+        let (target, conn) = connecting.await.unwrap();
+        assert_eq!(target, owner);
+        assert_eq!(conn, format!("conn to {}", owner));
+        assert_eq!(*router.resolver().calls.lock().unwrap(), vec![owner]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_in_ring_order() {
+        let ring = ring();
+        let order = ring.snapshot().lookup_list("resource", 3);
+        let resolver = Resolver {
+            down: order[..2].to_vec(),
+            ..Resolver::default()
+        };
+        let router = ConnectionRouter::new(ring, resolver).with_cool_down(Duration::from_secs(60));
+        let (target, _) = router.get_connection("resource").await.unwrap();
+        assert_eq!(target, order[2]);
+        assert_eq!(*router.resolver().calls.lock().unwrap(), order);
+
+        // the ones which failed are skipped while they cool down
+        router.resolver().calls.lock().unwrap().clear();
+        router.get_connection("resource").await.unwrap();
+        assert_eq!(
+            *router.resolver().calls.lock().unwrap(),
+            vec![order[2].clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn giving_up() {
+        let ring = ring();
+        let order = ring.snapshot().lookup_list("resource", 3);
+        let resolver = Resolver {
+            down: order.clone(),
+            ..Resolver::default()
+        };
+        let router = ConnectionRouter::new(ring, resolver).with_max_attempts(2);
+        match router.get_connection("resource").await {
+            Err(ResolveError::Exhausted(failures)) => {
+                let tried: Vec<Target> = failures.into_iter().map(|(t, _)| t).collect();
+                assert_eq!(tried, order[..2].to_vec());
+            }
+            other => panic!("Expected to give up, got {:?}", other),
+        }
+
+        let empty = Arc::new(SharedRing::new(Flexihash::new()));
+        let router = ConnectionRouter::new(empty, Resolver::default());
+        let err = router.get_connection("resource").await.unwrap_err();
+        assert_eq!(err.to_string(), "No targets set");
+    }
+}
@@ -0,0 +1,163 @@
+use crate::shared::SharedRing;
+use crate::{Exhausted, ResourceKey, Target};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+ * Picks where to try next when a request to a resource's owner fails:
+ * the next of its candidates, in ring order, which hasn't been tried yet.
+ *
+ * With a cool-down, a target which has just failed is passed over (for
+ * any resource) until the cool-down is up - unless every untried
+ * candidate is cooling down, in which case the one closest to being let
+ * back in is better than nothing.
+ */
+#[derive(Debug)]
+pub struct RetryRouter {
+    ring: Arc<SharedRing>,
+    cool_down: Option<Duration>,
+    // when each failed target's cool-down ends
+    cooling: Mutex<HashMap<Target, Instant>>,
+}
+
+impl RetryRouter {
+    pub fn new(ring: Arc<SharedRing>) -> RetryRouter {
+        return RetryRouter {
+            ring,
+            cool_down: None,
+            cooling: Mutex::new(HashMap::new()),
+        };
+    }
+
+    pub fn with_cool_down(mut self, cool_down: Duration) -> RetryRouter {
+        self.cool_down = Some(cool_down);
+        return self;
+    }
+
+    // None once every target has been tried
+    pub fn next_candidate<K: ResourceKey>(
+        &self,
+        resource: K,
+        attempted: &[Target],
+    ) -> Option<Target> {
+        return self.next_candidate_at(resource, attempted, Instant::now());
+    }
+
+    fn next_candidate_at<K: ResourceKey>(
+        &self,
+        resource: K,
+        attempted: &[Target],
+        now: Instant,
+    ) -> Option<Target> {
+        let ring = self.ring.snapshot();
+        let mut cooling = self.cooling.lock().unwrap_or_else(|e| e.into_inner());
+        cooling.retain(|_, until| *until > now);
+
+        let mut fallback: Option<(Instant, Target)> = None;
+        for target in ring.cycle_candidates(resource, Exhausted::Stop) {
+            if attempted.contains(&target) {
+                continue;
+            }
+            match cooling.get(&target) {
+                None => return Some(target),
+                Some(until) => {
+                    if fallback.as_ref().is_none_or(|(f, _)| until < f) {
+                        fallback = Some((*until, target));
+                    }
+                }
+            }
+        }
+        return fallback.map(|(_, target)| target);
+    }
+
+    pub fn mark_failed<S: Into<String>>(&self, target: S) {
+        self.mark_failed_at(target, Instant::now());
+    }
+
+    fn mark_failed_at<S: Into<String>>(&self, target: S, now: Instant) {
+        if let Some(cool_down) = self.cool_down {
+            self.cooling
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(target.into(), now + cool_down);
+        }
+    }
+
+    // Ends a cool-down early, eg when a health check passes
+    pub fn mark_succeeded<S: AsRef<str>>(&self, target: S) {
+        self.cooling
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(target.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod test_retry {
+    use super::*;
+    use crate::Flexihash;
+
+    fn router() -> RetryRouter {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        return RetryRouter::new(Arc::new(SharedRing::new(fh)));
+    }
+
+    #[test]
+    fn walks_the_candidates() {
+        let router = router();
+        let expected = router.ring.snapshot().lookup_list("resource", 3);
+        let mut attempted = Vec::new();
+        while let Some(target) = router.next_candidate("resource", &attempted) {
+            attempted.push(target);
+        }
+        assert_eq!(attempted, expected);
+    }
+
+    #[test]
+    fn cool_downs() {
+        let router = router().with_cool_down(Duration::from_secs(10));
+        let order = router.ring.snapshot().lookup_list("resource", 3);
+        let now = Instant::now();
+
+        router.mark_failed_at(order[0].clone(), now);
+        assert_eq!(
+            router.next_candidate_at("resource", &[], now),
+            Some(order[1].clone())
+        );
+        // and back in once the cool-down is over
+        let later = now + Duration::from_secs(11);
+        assert_eq!(
+            router.next_candidate_at("resource", &[], later),
+            Some(order[0].clone())
+        );
+
+        // everything left is cooling down: take the first one back
+        router.mark_failed_at(order[2].clone(), now);
+        router.mark_failed_at(order[1].clone(), now + Duration::from_secs(1));
+        assert_eq!(
+            router.next_candidate_at("resource", &[order[0].clone()], now),
+            Some(order[2].clone())
+        );
+
+        router.mark_succeeded(&order[1]);
+        assert_eq!(
+            router.next_candidate_at("resource", &[order[0].clone()], now),
+            Some(order[1].clone())
+        );
+    }
+
+    #[test]
+    fn no_cool_down_by_default() {
+        let router = router();
+        let first = router.next_candidate("resource", &[]).unwrap();
+        router.mark_failed(first.clone());
+        assert_eq!(router.next_candidate("resource", &[]), Some(first));
+        assert_eq!(
+            RetryRouter::new(Arc::new(SharedRing::new(Flexihash::new())))
+                .next_candidate("resource", &[]),
+            None
+        );
+    }
+}
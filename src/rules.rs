@@ -0,0 +1,203 @@
+use crate::{Exhausted, Flexihash, ResourceKey, Target};
+use std::fmt;
+use std::sync::Arc;
+
+/*
+ * Special cases which bypass the ring: a key matching a rule goes to that
+ * rule's targets (for lookup_list, as many of them as are asked for, in
+ * the order given) and nowhere else. Rules are tried in the order they
+ * were added, before any hashing, against the key as given - before the
+ * key prefix, hash tags or normalization.
+ *
+ * The targets don't have to be in the ring. The other lookups - traced,
+ * tiered, bounded, by weight - pick from a matching rule's targets too,
+ * and affinity ranks a target by its place in them. Walks round the ring
+ * itself don't look at rules: cycle_candidates and what's built on it
+ * (RetryRouter, lookup_leased_fallback, perfect maps), targets_in_range,
+ * lookup_list_with_capacity, and the position, replica and distance in
+ * an affinity report.
+ */
+pub type MatchFn = dyn Fn(&[u8]) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub enum Matcher {
+    Prefix(Vec<u8>),
+    Custom(Arc<MatchFn>),
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Prefix(prefix) => {
+                write!(f, "Prefix({:?})", String::from_utf8_lossy(prefix))
+            }
+            Matcher::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    matcher: Matcher,
+    targets: Vec<Target>,
+}
+
+impl Rule {
+    pub fn new<S: Into<String>>(matcher: Matcher, targets: Vec<S>) -> Rule {
+        if targets.is_empty() {
+            panic!("A rule needs at least one target");
+        }
+        return Rule {
+            matcher,
+            targets: targets.into_iter().map(|t| t.into()).collect(),
+        };
+    }
+
+    pub fn prefix<P: AsRef<[u8]>, S: Into<String>>(prefix: P, targets: Vec<S>) -> Rule {
+        return Rule::new(Matcher::Prefix(prefix.as_ref().to_vec()), targets);
+    }
+
+    pub fn matching<F, S>(matcher: F, targets: Vec<S>) -> Rule
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+        S: Into<String>,
+    {
+        return Rule::new(Matcher::Custom(Arc::new(matcher)), targets);
+    }
+
+    pub fn matcher(&self) -> &Matcher {
+        return &self.matcher;
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        return &self.targets;
+    }
+
+    pub fn matches(&self, key: &[u8]) -> bool {
+        return match &self.matcher {
+            Matcher::Prefix(prefix) => key.starts_with(prefix),
+            Matcher::Custom(f) => f(key),
+        };
+    }
+}
+
+impl Flexihash {
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+        // sub-rings carry a copy of the rules
+        self.sub_rings = Default::default();
+    }
+
+    pub fn clear_rules(&mut self) {
+        self.rules.clear();
+        self.sub_rings = Default::default();
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        return &self.rules;
+    }
+
+    // The targets of the first rule the key matches, if any
+    pub(crate) fn route(&self, key: &[u8]) -> Option<&[Target]> {
+        return self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(key))
+            .map(|rule| rule.targets());
+    }
+
+    // What a lookup picks from, in order: the targets of the rule the key
+    // matches, or else the walk round the ring
+    pub(crate) fn lookup_candidates<K: ResourceKey>(
+        &self,
+        resource: K,
+    ) -> Box<dyn Iterator<Item = Target> + '_> {
+        if !self.rules.is_empty() {
+            if let Some(targets) = self.route(resource.ring_key().as_ref()) {
+                return Box::new(targets.iter().cloned());
+            }
+        }
+        return Box::new(self.cycle_candidates(resource, Exhausted::Stop));
+    }
+}
+
+#[cfg(test)]
+mod test_rules {
+    use super::*;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        fh.add_rule(Rule::prefix("billing:", vec!["t-billing-1", "t-billing-2"]));
+        fh.add_rule(Rule::matching(|key| key.ends_with(b".tmp"), vec!["t-c"]));
+        return fh;
+    }
+
+    #[test]
+    fn rules_before_the_ring() {
+        let fh = ring();
+        assert_eq!(fh.lookup("billing:invoice-1"), "t-billing-1");
+        assert_eq!(
+            fh.lookup_list("billing:invoice-1", 5),
+            ["t-billing-1", "t-billing-2"]
+        );
+        assert_eq!(fh.lookup_list("billing:invoice-1", 1), ["t-billing-1"]);
+        assert_eq!(fh.lookup("scratch.tmp"), "t-c");
+        // first match wins
+        assert_eq!(fh.lookup("billing:scratch.tmp"), "t-billing-1");
+
+        // and everything else goes round the ring as usual
+        let mut plain = fh.clone();
+        plain.clear_rules();
+        assert_eq!(fh.lookup("user:1"), plain.lookup("user:1"));
+        assert_ne!(plain.lookup_list("billing:invoice-1", 1), ["t-billing-1"]);
+    }
+
+    #[test]
+    fn rules_see_the_key_as_given() {
+        let mut fh = ring();
+        fh.set_key_prefix("billing:");
+        assert_eq!(fh.rules().len(), 2);
+        assert_ne!(fh.lookup("user:1"), "t-billing-1");
+        assert_eq!(fh.lookup("billing:1"), "t-billing-1");
+        assert_eq!(
+            format!("{:?}", fh.rules()[0].matcher()),
+            "Prefix(\"billing:\")"
+        );
+    }
+
+    #[test]
+    fn other_lookups_follow_rules() {
+        let fh = ring();
+        assert_eq!(fh.lookup_by_weight("billing:1"), "t-billing-1");
+        assert_eq!(fh.lookup_bounded("billing:1", 1.25, |_| 0.0), "t-billing-1");
+        assert_eq!(
+            fh.lookup_tiered("billing:1", |t| t == "t-billing-1"),
+            Some("t-billing-2".to_string())
+        );
+        assert_eq!(
+            fh.lookup_list_tiered("billing:1", 5, |_| false),
+            ["t-billing-1", "t-billing-2"]
+        );
+
+        let (target, trace) = fh.lookup_traced("billing:1");
+        assert_eq!(target, "t-billing-1");
+        assert_eq!(trace.rule, Some(0));
+        assert!(trace.examined.is_empty());
+        let (targets, trace) = fh.lookup_list_traced("scratch.tmp", 2);
+        assert_eq!(targets, ["t-c"]);
+        assert_eq!(trace.rule, Some(1));
+        assert_eq!(fh.lookup_traced("user:1").1.rule, None);
+
+        assert_eq!(fh.affinity("scratch.tmp", "t-c").rank, Some(0));
+        assert_eq!(fh.affinity("scratch.tmp", "t-a").rank, None);
+        // but the ring positions are still the ring's
+        assert!(fh.affinity("scratch.tmp", "t-a").position.is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "A rule needs at least one target")]
+    fn rule_without_targets() {
+        Rule::prefix("billing:", Vec::<String>::new());
+    }
+}
@@ -0,0 +1,251 @@
+use crate::balance::mix;
+use crate::snapshot::checksum;
+use crate::{Exhausted, Flexihash, ResourceKey, Target};
+use std::fmt;
+use std::sync::Arc;
+
+/*
+ * How many positions a target of a given weight gets, set with
+ * Flexihash::set_replica_strategy. Linear (replicas x weight) is the
+ * default and what the other flexihash ports do; with a fleet whose
+ * weights run from 1 to 1000 it means the biggest targets alone take
+ * hundreds of thousands of positions, which the others trade away some
+ * accuracy to avoid. lookup_by_weight makes up the difference in traffic
+ * at lookup time.
+ *
+ * Like the replica count, a strategy only applies to targets added (or
+ * reweighted) afterwards. Snapshots record the built-in ones by name;
+ * a ring with a Custom strategy can't be saved.
+ */
+pub trait ReplicaStrategy: fmt::Debug + Send + Sync {
+    fn positions(&self, replicas: u32, weight: u32) -> u64;
+
+    // What snapshots call it, if from_name knows it
+    fn name(&self) -> Option<String> {
+        return None;
+    }
+}
+
+pub fn from_name(name: &str) -> Option<Arc<dyn ReplicaStrategy>> {
+    return match name {
+        "linear" => Some(Arc::new(Linear)),
+        "logarithmic" => Some(Arc::new(Logarithmic)),
+        _ => Some(Arc::new(Capped(
+            name.strip_prefix("capped:")?.parse().ok()?,
+        ))),
+    };
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Linear;
+
+// Widened so that a huge weight can't wrap round to a small count
+impl ReplicaStrategy for Linear {
+    fn positions(&self, replicas: u32, weight: u32) -> u64 {
+        return replicas as u64 * weight as u64;
+    }
+
+    fn name(&self) -> Option<String> {
+        return Some("linear".to_string());
+    }
+}
+
+// replicas x (1 + log2(weight)): a weight of 1024 gets 11 times the
+// positions of a weight of 1, rather than 1024 times
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Logarithmic;
+
+impl ReplicaStrategy for Logarithmic {
+    fn positions(&self, replicas: u32, weight: u32) -> u64 {
+        if weight == 0 {
+            return 0;
+        }
+        return replicas as u64 * (1 + weight.ilog2() as u64);
+    }
+
+    fn name(&self) -> Option<String> {
+        return Some("logarithmic".to_string());
+    }
+}
+
+// Linear, up to a limit per target
+#[derive(Debug, Clone, Copy)]
+pub struct Capped(pub u64);
+
+impl ReplicaStrategy for Capped {
+    fn positions(&self, replicas: u32, weight: u32) -> u64 {
+        return Linear.positions(replicas, weight).min(self.0);
+    }
+
+    fn name(&self) -> Option<String> {
+        return Some(format!("capped:{}", self.0));
+    }
+}
+
+// Anything else, as a closure of (replicas, weight)
+pub struct Custom<F>(pub F);
+
+impl<F> fmt::Debug for Custom<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Custom")
+    }
+}
+
+impl<F> ReplicaStrategy for Custom<F>
+where
+    F: Fn(u32, u32) -> u64 + Send + Sync,
+{
+    fn positions(&self, replicas: u32, weight: u32) -> u64 {
+        return (self.0)(replicas, weight);
+    }
+}
+
+/*
+ * A lookup for rings whose positions aren't in proportion to weight.
+ * Walking round from the key, each target is taken with probability (its
+ * weight per position) / (the most weight per position any target has),
+ * tossing a coin made from the key and the target, so that traffic
+ * follows weight rather than positions while a key still gets the same
+ * target every time. Weight changes move a few more keys than they would
+ * with Linear, since they shift everyone's odds.
+ *
+ * With Linear, everyone has the same weight per position and this is a
+ * plain lookup. Otherwise it walks about as many candidates as the
+ * biggest target has weight per position over the average.
+ */
+impl Flexihash {
+    pub fn lookup_by_weight<K: ResourceKey>(&self, resource: K) -> Target {
+        // as lookup does; the weights only decide between ring positions
+        if !self.rules.is_empty() {
+            if let Some(targets) = self.route(resource.ring_key().as_ref()) {
+                return targets[0].clone();
+            }
+        }
+        let density = |target: &str| {
+            let weight = self.target_info.get(target).map_or(1, |info| info.weight);
+            weight as f64 / self.target_positions(target) as f64
+        };
+        let most = self
+            .target_to_positions
+            .iter()
+            .filter(|(_, positions)| !positions.is_empty())
+            .map(|(target, _)| density(target))
+            .fold(0.0, f64::max);
+        let position = self.resource_position(&resource);
+        let key = position as u64 ^ (position >> 64) as u64;
+        let mut first = None;
+        for target in self.cycle_candidates(resource, Exhausted::Stop) {
+            let coin = mix(key ^ checksum(target.as_bytes()));
+            // uniform over [0, 1); the densest targets always win the toss
+            let toss = (coin >> 11) as f64 / (1u64 << 53) as f64;
+            if toss * most < density(&target) {
+                return target;
+            }
+            first.get_or_insert(target);
+        }
+        // only when every target is weighted 0
+        return first.unwrap_or_else(|| panic!("No targets set"));
+    }
+}
+
+#[cfg(test)]
+mod test_scaling {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn strategies() {
+        assert_eq!(Linear.positions(64, 3), 192);
+        assert_eq!(
+            Linear.positions(u32::MAX, u32::MAX),
+            (u32::MAX as u64).pow(2)
+        );
+        assert_eq!(Logarithmic.positions(64, 0), 0);
+        assert_eq!(Logarithmic.positions(64, 1), 64);
+        assert_eq!(Logarithmic.positions(64, 1024), 64 * 11);
+        assert_eq!(Capped(100).positions(64, 1), 64);
+        assert_eq!(Capped(100).positions(64, 2), 100);
+        assert_eq!(Custom(|r, w| (r + w) as u64).positions(64, 2), 66);
+    }
+
+    #[test]
+    fn names() {
+        for strategy in [
+            Arc::new(Linear) as Arc<dyn ReplicaStrategy>,
+            Arc::new(Logarithmic),
+            Arc::new(Capped(100)),
+        ] {
+            let name = strategy.name().unwrap();
+            assert_eq!(
+                format!("{:?}", from_name(&name).unwrap()),
+                format!("{:?}", strategy)
+            );
+        }
+        assert_eq!(Custom(|_, _| 1).name(), None);
+        assert!(from_name("capped:x").is_none());
+        assert!(from_name("quadratic").is_none());
+    }
+
+    #[test]
+    fn lookup_by_weight_is_plain_when_linear() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        fh.add_target("t-d", 3);
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            assert_eq!(fh.lookup_by_weight(&key), fh.lookup(&key));
+        }
+    }
+
+    // md5, since crc32 and adler32 hash runs of similar keys too
+    // unevenly for the shares to come out
+    #[cfg(feature = "md5")]
+    #[test]
+    fn lookup_by_weight_follows_weight() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(crate::Hasher::Md5);
+        fh.set_replicas(256);
+        fh.set_replica_strategy(Arc::new(Logarithmic));
+        for i in 0..4 {
+            fh.add_target(format!("t-small-{}", i), 1);
+        }
+        fh.add_target("t-big-0", 8);
+        fh.add_target("t-big-1", 8);
+        // the big targets' share between them
+        let big_share = |pick: &dyn Fn(&str) -> Target| {
+            let n = 20_000;
+            let hits = (0..n)
+                .filter(|i| pick(&format!("key{}", i)).starts_with("t-big"))
+                .count();
+            hits as f64 / n as f64
+        };
+        // by positions, 2/3; by weight, 16/20
+        let plain = big_share(&|k| fh.lookup(k));
+        let weighted = big_share(&|k| fh.lookup_by_weight(k));
+        assert!((plain - 2.0 / 3.0).abs() < 0.04, "{}", plain);
+        assert!((weighted - 0.8).abs() < 0.04, "{}", weighted);
+    }
+
+    #[test]
+    fn placement() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(4);
+        fh.set_replica_strategy(Arc::new(Logarithmic));
+        fh.add_target("t-a", 1);
+        fh.add_target("t-b", 1000);
+        assert_eq!(fh.target_positions("t-a"), 4);
+        assert_eq!(fh.target_positions("t-b"), 40);
+
+        fh.update_target_weight("t-b", 2);
+        assert_eq!(fh.target_positions("t-b"), 8);
+        assert_eq!(fh.get_target_info("t-b").unwrap().weight, 2);
+
+        // and the limit is checked against what the strategy asks for
+        fh.set_max_total_positions(40);
+        fh.set_replica_strategy(Arc::new(Custom(|_, w| w as u64 * 100)));
+        assert!(matches!(
+            fh.try_add_target("t-c", 1),
+            Err(Error::TooManyPositions(_, 112, 40))
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::drain::Drain;
+use crate::shared::SharedRing;
+use crate::{Error, Target};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/*
+ * Topology changes booked for later, eg for a maintenance window: "add
+ * cache-4 at 02:00, drain cache-1 over the hour after that". Nothing
+ * happens until tick() is called with a time at or after a change's, either
+ * by hand or every so often by start().
+ *
+ * Due changes are applied oldest first (and in the order they were booked,
+ * for the same time), each on its own; one failing, eg because its target
+ * has already gone, doesn't hold up the rest. Drains run in the background
+ * as usual, and are stopped if the Scheduler is dropped.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Add(Target, u32),
+    Remove(Target),
+    SetWeight(Target, u32),
+    Drain(Target, Duration),
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    ring: Arc<SharedRing>,
+    pending: Mutex<Vec<(SystemTime, Change)>>,
+    drains: Mutex<Vec<Drain>>,
+}
+
+impl Scheduler {
+    pub fn new(ring: Arc<SharedRing>) -> Scheduler {
+        return Scheduler {
+            ring,
+            pending: Mutex::new(Vec::new()),
+            drains: Mutex::new(Vec::new()),
+        };
+    }
+
+    pub fn schedule(&self, at: SystemTime, change: Change) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        // after anything else booked for the same time
+        let index = pending.partition_point(|(t, _)| *t <= at);
+        pending.insert(index, (at, change));
+    }
+
+    // Soonest first
+    pub fn pending(&self) -> Vec<(SystemTime, Change)> {
+        return self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+    }
+
+    // Everything which was due, and how it went
+    pub fn tick(&self, now: SystemTime) -> Vec<(Change, Result<(), Error>)> {
+        let due: Vec<(SystemTime, Change)> = {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            let n = pending.partition_point(|(t, _)| *t <= now);
+            pending.drain(..n).collect()
+        };
+        let mut drains = self.drains.lock().unwrap_or_else(|e| e.into_inner());
+        drains.retain(|d| !d.is_finished());
+        return due
+            .into_iter()
+            .map(|(_, change)| {
+                let result = self.apply(&change, &mut drains);
+                (change, result)
+            })
+            .collect();
+    }
+
+    fn apply(&self, change: &Change, drains: &mut Vec<Drain>) -> Result<(), Error> {
+        if let Change::Drain(target, duration) = change {
+            let ring = self.ring.snapshot();
+            if ring.get_target_info(target).is_none() {
+                return Err(ring.target_missing(target));
+            }
+            drains.push(self.ring.drain(target.as_str(), *duration, |_| {}));
+            return Ok(());
+        }
+        return self.ring.update(|fh| {
+            fh.transaction(|tx| {
+                match change {
+                    Change::Add(target, weight) => tx.add(target.as_str(), *weight),
+                    Change::Remove(target) => tx.remove(target.as_str()),
+                    Change::SetWeight(target, weight) => tx.set_weight(target.as_str(), *weight),
+                    Change::Drain(..) => unreachable!(),
+                };
+            })?;
+            return Ok(());
+        });
+    }
+
+    // Block until every drain started so far has finished
+    pub fn wait_for_drains(&self) {
+        let drains: Vec<Drain> = self
+            .drains
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain(..)
+            .collect();
+        for drain in drains {
+            drain.wait();
+        }
+    }
+
+    // Tick every interval on a thread of its own, until the returned
+    // driver is dropped
+    pub fn start<F>(self: &Arc<Self>, interval: Duration, on_applied: F) -> SchedulerDriver
+    where
+        F: Fn(Change, Result<(), Error>) + Send + 'static,
+    {
+        let scheduler = self.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            for (change, result) in scheduler.tick(SystemTime::now()) {
+                on_applied(change, result);
+            }
+        });
+        return SchedulerDriver {
+            _stop: stop,
+            _handle: handle,
+        };
+    }
+}
+
+#[derive(Debug)]
+pub struct SchedulerDriver {
+    // dropping this wakes the thread up and stops it
+    _stop: mpsc::Sender<()>,
+    _handle: JoinHandle<()>,
+}
+
+#[cfg(test)]
+mod test_schedule {
+    use super::*;
+    use crate::Flexihash;
+
+    fn scheduler() -> Arc<Scheduler> {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(8);
+        fh.add_targets(vec!["t-a", "t-b"]);
+        return Arc::new(Scheduler::new(Arc::new(SharedRing::new(fh))));
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        return SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn applies_changes_when_due() {
+        let scheduler = scheduler();
+        scheduler.schedule(at(200), Change::Remove("t-a".to_string()));
+        scheduler.schedule(at(100), Change::Add("t-c".to_string(), 1));
+        scheduler.schedule(at(100), Change::SetWeight("t-c".to_string(), 2));
+        assert_eq!(scheduler.pending()[2].0, at(200));
+
+        assert!(scheduler.tick(at(99)).is_empty());
+        let applied = scheduler.tick(at(150));
+        assert_eq!(
+            applied,
+            [
+                (Change::Add("t-c".to_string(), 1), Ok(())),
+                (Change::SetWeight("t-c".to_string(), 2), Ok(())),
+            ]
+        );
+        let ring = scheduler.ring.snapshot();
+        assert_eq!(ring.get_target_info("t-c").unwrap().weight, 2);
+        assert_eq!(scheduler.pending().len(), 1);
+
+        scheduler.ring.update(|fh| {
+            fh.remove_target("t-a");
+        });
+        assert_eq!(
+            scheduler.tick(at(200)),
+            [(
+                Change::Remove("t-a".to_string()),
+                Err(Error::TargetMissing(
+                    "t-a".to_string(),
+                    vec!["t-b".to_string(), "t-c".to_string()]
+                ))
+            )]
+        );
+        assert!(scheduler.pending().is_empty());
+    }
+
+    #[test]
+    fn scheduled_drain() {
+        let scheduler = scheduler();
+        scheduler.schedule(
+            at(100),
+            Change::Drain("t-b".to_string(), Duration::from_millis(8)),
+        );
+        scheduler.schedule(
+            at(100),
+            Change::Drain("t-x".to_string(), Duration::from_millis(8)),
+        );
+        let applied = scheduler.tick(at(100));
+        assert_eq!(applied[0].1, Ok(()));
+        assert_eq!(
+            applied[1].1,
+            Err(Error::TargetMissing(
+                "t-x".to_string(),
+                vec!["t-a".to_string(), "t-b".to_string()]
+            ))
+        );
+        scheduler.wait_for_drains();
+        let ring = scheduler.ring.snapshot();
+        assert_eq!(ring.get_target_info("t-b").unwrap().weight, 0);
+    }
+
+    #[test]
+    fn driver() {
+        let scheduler = scheduler();
+        scheduler.schedule(SystemTime::now(), Change::Add("t-c".to_string(), 1));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let driver = scheduler.start(Duration::from_millis(1), move |change, result| {
+            tx.lock().unwrap().send((change, result)).unwrap();
+        });
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (Change::Add("t-c".to_string(), 1), Ok(()))
+        );
+        drop(driver);
+        assert!(scheduler.ring.snapshot().get_target_info("t-c").is_some());
+    }
+}
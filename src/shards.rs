@@ -0,0 +1,227 @@
+use crate::balance::mix;
+use crate::snapshot::checksum;
+use crate::{Flexihash, ResourceKey, Target};
+use std::collections::BTreeSet;
+
+/*
+ * Two-level placement: keys hash to one of a fixed number of shards, and
+ * the ring places shards (as "shard:N") rather than keys on targets. Which
+ * shard a key is in never changes - it doesn't depend on the ring, its
+ * hasher or which hash features are built in - so rebalancing only ever
+ * moves whole shards from one target to another, and a shard's data can
+ * be copied as-is without being split up or rehashed.
+ *
+ * Pick enough shards for the biggest the cluster will ever be (a few per
+ * target at least) since the count can't change afterwards without moving
+ * nearly every key.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMap {
+    owners: Vec<Target>,
+    // moved by hand, and left alone by reassign
+    pinned: BTreeSet<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMove {
+    pub shard: u32,
+    pub from: Target,
+    pub to: Target,
+}
+
+impl ShardMap {
+    pub fn new(ring: &Flexihash, shards: u32) -> ShardMap {
+        if shards == 0 {
+            panic!("A shard map needs at least one shard");
+        }
+        return ShardMap {
+            owners: (0..shards)
+                .map(|shard| ring.lookup(("shard", shard)))
+                .collect(),
+            pinned: BTreeSet::new(),
+        };
+    }
+
+    pub fn shards(&self) -> u32 {
+        return self.owners.len() as u32;
+    }
+
+    pub fn shard_for<K: ResourceKey>(&self, resource: K) -> u32 {
+        let hash = mix(checksum(resource.ring_key().as_ref()));
+        return (hash % self.owners.len() as u64) as u32;
+    }
+
+    pub fn lookup<K: ResourceKey>(&self, resource: K) -> &str {
+        return self.owner(self.shard_for(resource));
+    }
+
+    pub fn owner(&self, shard: u32) -> &str {
+        return &self.owners[self.index(shard)];
+    }
+
+    // Every shard's owner, by shard number
+    pub fn owners(&self) -> &[Target] {
+        return &self.owners;
+    }
+
+    pub fn shards_of(&self, target: &str) -> Vec<u32> {
+        return (0..self.shards())
+            .filter(|shard| self.owner(*shard) == target)
+            .collect();
+    }
+
+    // Hand a shard to a target of our choosing, eg to move a hot shard off
+    // a busy node; it stays there until unpinned
+    pub fn move_shard<S: Into<String>>(&mut self, shard: u32, target: S) {
+        let index = self.index(shard);
+        self.owners[index] = target.into();
+        self.pinned.insert(shard);
+    }
+
+    pub fn unpin(&mut self, shard: u32) {
+        self.index(shard);
+        self.pinned.remove(&shard);
+    }
+
+    pub fn is_pinned(&self, shard: u32) -> bool {
+        return self.pinned.contains(&shard);
+    }
+
+    // Follow the ring after its targets change; the shards which moved
+    // (pinned ones aren't touched), for the data to follow
+    pub fn reassign(&mut self, ring: &Flexihash) -> Vec<ShardMove> {
+        let mut moves = Vec::new();
+        for shard in 0..self.shards() {
+            if self.pinned.contains(&shard) {
+                continue;
+            }
+            let to = ring.lookup(("shard", shard));
+            let owner = &mut self.owners[shard as usize];
+            if *owner != to {
+                moves.push(ShardMove {
+                    shard,
+                    from: std::mem::replace(owner, to.clone()),
+                    to,
+                });
+            }
+        }
+        return moves;
+    }
+
+    fn index(&self, shard: u32) -> usize {
+        if shard >= self.shards() {
+            panic!("Shard {} does not exist", shard);
+        }
+        return shard as usize;
+    }
+}
+
+impl Flexihash {
+    pub fn shard_map(&self, shards: u32) -> ShardMap {
+        return ShardMap::new(self, shards);
+    }
+}
+
+#[cfg(test)]
+mod test_shards {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        return fh;
+    }
+
+    #[test]
+    fn keys_stay_in_their_shard() {
+        let map = ring().shard_map(64);
+        let mut other = Flexihash::new();
+        other.set_hasher(crate::Hasher::Mock(0));
+        other.add_target("t-x", 1);
+        let other = other.shard_map(64);
+        for i in 0..100 {
+            assert_eq!(map.shard_for(i), other.shard_for(i));
+            assert_eq!(map.lookup(i), map.owner(map.shard_for(i)));
+        }
+        // fixed forever, whatever the ring
+        assert_eq!(map.shard_for("user:1"), 2);
+
+        let mut counts = HashMap::new();
+        for i in 0..6400 {
+            *counts.entry(map.shard_for(i)).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 64);
+        assert!(
+            counts.values().all(|c| (50..150).contains(c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn shards_go_where_the_ring_says() {
+        let fh = ring();
+        let map = fh.shard_map(16);
+        assert_eq!(map.shards(), 16);
+        for shard in 0..16 {
+            assert_eq!(map.owner(shard), fh.lookup(("shard", shard)));
+        }
+        let total: usize = ["t-a", "t-b", "t-c"]
+            .iter()
+            .map(|t| map.shards_of(t).len())
+            .sum();
+        assert_eq!(total, 16);
+    }
+
+    // adler32 puts all the "shard:N" keys on much the same part of the ring
+    #[cfg(feature = "crc")]
+    #[test]
+    fn reassign_moves_whole_shards() {
+        let mut fh = ring();
+        let mut map = fh.shard_map(64);
+        let before = map.clone();
+        fh.add_target("t-d", 1);
+        let moves = map.reassign(&fh);
+        assert!(!moves.is_empty());
+        for m in moves.iter() {
+            assert_eq!(m.to, "t-d");
+            assert_eq!(before.owner(m.shard), m.from);
+        }
+        for i in 0..1000 {
+            let shard = map.shard_for(i);
+            if moves.iter().all(|m| m.shard != shard) {
+                assert_eq!(map.lookup(i), before.lookup(i));
+            }
+        }
+        assert!(map.reassign(&fh).is_empty());
+    }
+
+    #[test]
+    fn pinning() {
+        let mut fh = ring();
+        let mut map = fh.shard_map(8);
+        map.move_shard(3, "t-special");
+        assert_eq!(map.owner(3), "t-special");
+        assert!(map.is_pinned(3));
+        fh.remove_target("t-a");
+        map.reassign(&fh);
+        assert_eq!(map.owner(3), "t-special");
+        map.unpin(3);
+        let moves = map.reassign(&fh);
+        assert!(moves.iter().any(|m| m.shard == 3 && m.from == "t-special"));
+        assert_eq!(map.owner(3), fh.lookup(("shard", 3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Shard 8 does not exist")]
+    fn missing_shard() {
+        ring().shard_map(8).owner(8);
+    }
+
+    #[test]
+    #[should_panic(expected = "A shard map needs at least one shard")]
+    fn no_shards() {
+        ring().shard_map(0);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{Flexihash, FrozenFlexihash, Target, TargetInfo};
+#[cfg(all(test, flexihash_loom))]
+use loom::sync::{Mutex, RwLock};
+use std::sync::Arc;
+#[cfg(not(all(test, flexihash_loom)))]
+use std::sync::{Mutex, RwLock};
+
+/*
+ * A ring shared between threads. Readers take a cheap snapshot and keep
+ * using it for as long as they like; writers build a whole new ring and
+ * swap it in, so nobody ever sees one half-way through a change.
+ *
+ * Writers take turns, but only hold up readers for the swap itself, never
+ * for the (maybe slow, for big rings) rebuild.
+ */
+#[derive(Debug)]
+pub struct SharedRing {
+    current: RwLock<Arc<FrozenFlexihash>>,
+    writer: Mutex<()>,
+}
+
+impl SharedRing {
+    pub fn new(ring: Flexihash) -> SharedRing {
+        return SharedRing {
+            current: RwLock::new(Arc::new(ring.freeze())),
+            writer: Mutex::new(()),
+        };
+    }
+
+    pub fn snapshot(&self) -> Arc<FrozenFlexihash> {
+        return self
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+    }
+
+    pub fn replace(&self, ring: Flexihash) -> Arc<FrozenFlexihash> {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        return self.swap(ring);
+    }
+
+    pub fn update<R, F: FnOnce(&mut Flexihash) -> R>(&self, f: F) -> R {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut ring = FrozenFlexihash::clone(&self.snapshot()).unfreeze();
+        let result = f(&mut ring);
+        self.swap(ring);
+        return result;
+    }
+
+    // update, on a thread of its own, for bulk changes which the caller
+    // doesn't want to wait for; lookups carry on against the old ring
+    // until the new one is ready
+    pub fn update_in_background<R, F>(self: &Arc<Self>, f: F) -> std::thread::JoinHandle<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Flexihash) -> R + Send + 'static,
+    {
+        let shared = self.clone();
+        return std::thread::spawn(move || shared.update(f));
+    }
+
+    fn swap(&self, ring: Flexihash) -> Arc<FrozenFlexihash> {
+        let ring = Arc::new(ring.freeze());
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        return std::mem::replace(&mut *current, ring);
+    }
+
+    // Take a target out of rotation until the guard is dropped, eg for a
+    // rolling restart
+    pub fn maintenance<S: Into<String>>(&self, target: S) -> MaintenanceGuard<'_> {
+        return self.start_maintenance(target.into(), None);
+    }
+
+    // Like maintenance, but leave the target in with a lower weight
+    pub fn maintenance_with_weight<S: Into<String>>(
+        &self,
+        target: S,
+        weight: u32,
+    ) -> MaintenanceGuard<'_> {
+        return self.start_maintenance(target.into(), Some(weight));
+    }
+
+    fn start_maintenance(&self, target: Target, weight: Option<u32>) -> MaintenanceGuard<'_> {
+        let (target, info, groups) = self.update(|fh| {
+            let target = fh.normalize(target);
+            let info = match fh.get_target_info(&target) {
+                Some(info) => info.clone(),
+                None => fh.missing_target(&target),
+            };
+            let groups: Vec<String> = fh
+                .groups
+                .iter()
+                .filter(|(_, members)| members.contains(&target))
+                .map(|(group, _)| group.clone())
+                .collect();
+            match weight {
+                Some(weight) => fh.update_target_weight(target.clone(), weight),
+                None => fh.remove_target(target.clone()),
+            };
+            (target, info, groups)
+        });
+        return MaintenanceGuard {
+            ring: self,
+            target,
+            info,
+            groups,
+            ejected: weight.is_none(),
+        };
+    }
+}
+
+/*
+ * Puts a target back the way it was when dropped - including when dropped
+ * by a panic unwinding past it. If something else has put the target back
+ * (or removed it for good) in the meantime, that wins.
+ */
+#[derive(Debug)]
+pub struct MaintenanceGuard<'a> {
+    ring: &'a SharedRing,
+    target: Target,
+    info: TargetInfo,
+    groups: Vec<String>,
+    ejected: bool,
+}
+
+impl MaintenanceGuard<'_> {
+    pub fn target(&self) -> &str {
+        return &self.target;
+    }
+}
+
+impl Drop for MaintenanceGuard<'_> {
+    fn drop(&mut self) {
+        self.ring.update(|fh| {
+            let present = fh.get_target_info(&self.target).is_some();
+            // these only fail if the ring has since grown past its
+            // max_total_positions, and a drop is no place to panic
+            if self.ejected && !present {
+                if fh
+                    .try_add_target(self.target.clone(), self.info.weight)
+                    .is_ok()
+                {
+                    Arc::make_mut(&mut fh.target_info)
+                        .insert(self.target.clone(), self.info.clone());
+                    for group in self.groups.iter() {
+                        if let Some(members) = fh.groups.get_mut(group) {
+                            members.push(self.target.clone());
+                        }
+                    }
+                }
+            } else if !self.ejected && present {
+                let _ = fh.transaction(|tx| {
+                    tx.set_weight(self.target.clone(), self.info.weight);
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_shared {
+    use super::*;
+
+    #[test]
+    fn update_and_replace() {
+        let shared = SharedRing::new(Flexihash::new());
+        let before = shared.snapshot();
+        shared.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        assert_eq!(before.get_all_targets().len(), 0);
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-a"]);
+
+        let mut fh = Flexihash::new();
+        fh.add_target("t-b", 1);
+        let old = shared.replace(fh);
+        assert_eq!(old.get_all_targets(), ["t-a"]);
+        assert_eq!(shared.snapshot().lookup("resource"), "t-b");
+    }
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn maintenance_restores_target() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        fh.add_group("rack-1", vec!["t-c"]);
+        let shared = SharedRing::new(fh);
+        let before = shared.snapshot();
+
+        {
+            let guard = shared.maintenance("t-c");
+            assert_eq!(guard.target(), "t-c");
+            assert_eq!(shared.snapshot().get_all_targets(), ["t-a", "t-b"]);
+        }
+        let after = shared.snapshot();
+        assert_eq!(
+            after.sorted_position_to_target,
+            before.sorted_position_to_target
+        );
+        assert_eq!(after.get_target_info("t-c"), before.get_target_info("t-c"));
+        assert_eq!(after.groups["rack-1"], ["t-c"]);
+
+        {
+            let _guard = shared.maintenance_with_weight("t-a", 0);
+            assert_eq!(shared.snapshot().target_to_positions["t-a"].len(), 0);
+        }
+        assert_eq!(
+            shared.snapshot().sorted_position_to_target,
+            before.sorted_position_to_target
+        );
+    }
+
+    #[test]
+    fn maintenance_restores_target_on_panic() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let shared = SharedRing::new(fh);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = shared.maintenance("t-a");
+            panic!("restart failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-a", "t-b"]);
+    }
+
+    #[test]
+    fn maintenance_leaves_later_changes_alone() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let shared = SharedRing::new(fh);
+        {
+            let _guard = shared.maintenance_with_weight("t-a", 0);
+            shared.update(|fh| {
+                fh.remove_target("t-a");
+            });
+        }
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-b"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 't-x' does not exist")]
+    fn maintenance_of_missing_target() {
+        let shared = SharedRing::new(Flexihash::new());
+        shared.maintenance("t-x");
+    }
+
+    #[test]
+    fn lookups_carry_on_during_background_update() {
+        let shared = Arc::new(SharedRing::new(Flexihash::new()));
+        shared.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        let (started, wait_for_start) = std::sync::mpsc::channel();
+        let (finish, wait_for_finish) = std::sync::mpsc::channel::<()>();
+        let handle = shared.update_in_background(move |fh| {
+            fh.add_target("t-b", 1);
+            started.send(()).unwrap();
+            wait_for_finish.recv().unwrap();
+            fh.get_all_targets().len()
+        });
+
+        // mid-rebuild, readers still get the old ring without blocking
+        wait_for_start.recv().unwrap();
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-a"]);
+        finish.send(()).unwrap();
+        assert_eq!(handle.join().unwrap(), 2);
+        assert_eq!(shared.snapshot().get_all_targets(), ["t-a", "t-b"]);
+    }
+
+    #[test]
+    fn readers_on_other_threads() {
+        let shared = Arc::new(SharedRing::new(Flexihash::new()));
+        shared.update(|fh| {
+            fh.add_targets(vec!["t-a", "t-b"]);
+        });
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || shared.snapshot().lookup("resource"))
+            })
+            .collect();
+        let expected = shared.snapshot().lookup("resource");
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+}
+
+// Every interleaving of readers and writers, rather than whichever ones
+// the scheduler happens to pick; see Cargo.toml for how to run these
+#[cfg(all(test, flexihash_loom))]
+mod test_loom {
+    use super::*;
+    use loom::thread;
+
+    fn ring(targets: &[&str]) -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(4);
+        fh.add_targets(targets.to_vec());
+        return fh;
+    }
+
+    #[test]
+    fn readers_never_see_half_an_update() {
+        loom::model(|| {
+            let shared = Arc::new(SharedRing::new(ring(&["t-a"])));
+            let writer = {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    shared.update(|fh| {
+                        fh.add_target("t-b", 1);
+                        fh.add_target("t-c", 1);
+                    });
+                })
+            };
+
+            let snapshot = shared.snapshot();
+            let expected = match snapshot.get_all_targets().len() {
+                1 => ring(&["t-a"]),
+                3 => ring(&["t-a", "t-b", "t-c"]),
+                n => panic!("Saw {} targets", n),
+            };
+            for key in ["r-1", "r-2", "r-3"] {
+                assert_eq!(snapshot.lookup(key), expected.lookup(key));
+            }
+            writer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn updates_are_not_lost_to_a_replace() {
+        loom::model(|| {
+            let shared = Arc::new(SharedRing::new(ring(&["t-a"])));
+            let replacer = {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    shared.replace(ring(&["t-x"]));
+                })
+            };
+            shared.update(|fh| {
+                fh.add_target("t-y", 1);
+            });
+            replacer.join().unwrap();
+
+            // the update went either entirely before the replace or
+            // entirely after it
+            let targets = shared.snapshot().get_all_targets();
+            assert!(targets == ["t-x"] || targets == ["t-x", "t-y"]);
+        });
+    }
+}
@@ -0,0 +1,542 @@
+use crate::scaling::{self, Linear, ReplicaStrategy};
+use crate::{Error, Flexihash, Hasher, KeyNormalization, Target, TargetInfo};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+/*
+ * The logical state of a ring - enough to place every target again.
+ *
+ * Targets are stored with the ring's current hasher, replica count,
+ * replica strategy and salt, so a ring built by switching those between
+ * adds won't round-trip. The key prefix, hash tag setting and key
+ * normalization come along, being part of where keys go.
+ *
+ * The generation (and causal token, if any) come along too, so that a ring
+ * loaded from a snapshot will still accept the deltas which follow it.
+ */
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub hasher: Hasher,
+    pub replicas: u32,
+    pub replica_strategy: Arc<dyn ReplicaStrategy>,
+    pub salt: String,
+    pub key_prefix: String,
+    pub hash_tags: bool,
+    pub key_normalization: KeyNormalization,
+    pub targets: Vec<(Target, TargetInfo)>,
+    pub generation: u64,
+    pub causal_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Parse(usize, String),
+    Checksum,
+    InvalidName(String),
+    // a Custom replica strategy, which there's no way to write down
+    UnnamedStrategy,
+    // more positions than the ring is allowed
+    Ring(Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Parse(line, msg) => write!(f, "Line {}: {}", line, msg),
+            SnapshotError::Checksum => write!(f, "Snapshot checksum does not match"),
+            SnapshotError::InvalidName(name) => write!(f, "Can't store name {:?}", name),
+            SnapshotError::UnnamedStrategy => write!(f, "Can't store a custom replica strategy"),
+            SnapshotError::Ring(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> SnapshotError {
+        return SnapshotError::Io(e);
+    }
+}
+
+const MAGIC: &str = "flexihash 1";
+
+// FNV-1a; not for security, just for catching truncated or mangled files
+pub(crate) fn checksum(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    return h;
+}
+
+pub(crate) fn check_name(name: &str) -> Result<(), SnapshotError> {
+    if name.is_empty() || name.contains('\n') {
+        return Err(SnapshotError::InvalidName(name.to_string()));
+    }
+    return Ok(());
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        let mut out = String::new();
+        out.push_str(MAGIC);
+        out.push('\n');
+        out.push_str(&format!("hasher {}\n", self.hasher));
+        out.push_str(&format!("replicas {}\n", self.replicas));
+        match self.replica_strategy.name() {
+            // older snapshots are all linear
+            Some(name) if name == "linear" => {}
+            Some(name) => out.push_str(&format!("strategy {}\n", name)),
+            None => return Err(SnapshotError::UnnamedStrategy),
+        }
+        if !self.salt.is_empty() {
+            check_name(&self.salt)?;
+            out.push_str(&format!("salt {}\n", self.salt));
+        }
+        if !self.key_prefix.is_empty() {
+            check_name(&self.key_prefix)?;
+            out.push_str(&format!("prefix {}\n", self.key_prefix));
+        }
+        if self.hash_tags {
+            out.push_str("hash-tags on\n");
+        }
+        match self.key_normalization {
+            KeyNormalization::AsGiven => {}
+            #[cfg(feature = "unicode")]
+            KeyNormalization::Nfc => out.push_str("normalization nfc\n"),
+            #[cfg(feature = "unicode")]
+            KeyNormalization::Nfkc => out.push_str("normalization nfkc\n"),
+        }
+        out.push_str(&format!("generation {}\n", self.generation));
+        if let Some(token) = &self.causal_token {
+            check_name(token)?;
+            out.push_str(&format!("token {}\n", token));
+        }
+        for (target, info) in self.targets.iter() {
+            check_name(target)?;
+            out.push_str(&format!("target {} {}\n", info.weight, target));
+            if let Some(zone) = &info.zone {
+                check_name(zone)?;
+                out.push_str(&format!("zone {}\n", zone));
+            }
+            if info.tier != 0 {
+                out.push_str(&format!("tier {}\n", info.tier));
+            }
+            for (key, value) in info.labels.iter() {
+                check_name(key)?;
+                check_name(value)?;
+                if key.contains('=') {
+                    return Err(SnapshotError::InvalidName(key.clone()));
+                }
+                out.push_str(&format!("label {}={}\n", key, value));
+            }
+        }
+        out.push_str(&format!("checksum {:016x}\n", checksum(out.as_bytes())));
+        return Ok(out.into_bytes());
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| SnapshotError::Parse(0, "Not valid UTF-8".to_string()))?;
+        let body_len = text
+            .trim_end_matches('\n')
+            .rfind('\n')
+            .map(|i| i + 1)
+            .ok_or(SnapshotError::Checksum)?;
+        let (body, trailer) = text.split_at(body_len);
+        match trailer.trim_end().strip_prefix("checksum ") {
+            Some(sum) if u64::from_str_radix(sum, 16).ok() == Some(checksum(body.as_bytes())) => {}
+            _ => return Err(SnapshotError::Checksum),
+        }
+
+        let mut snapshot = Snapshot {
+            hasher: Hasher::default(),
+            replicas: 64,
+            replica_strategy: Arc::new(Linear),
+            salt: String::new(),
+            key_prefix: String::new(),
+            hash_tags: false,
+            key_normalization: KeyNormalization::AsGiven,
+            targets: Vec::new(),
+            // older snapshots have neither
+            generation: 0,
+            causal_token: None,
+        };
+        for (n, line) in body.lines().enumerate() {
+            let bad = |msg: &str| SnapshotError::Parse(n + 1, msg.to_string());
+            if n == 0 {
+                if line != MAGIC {
+                    return Err(bad("Not a flexihash snapshot"));
+                }
+                continue;
+            }
+            let (key, value) = line.split_once(' ').ok_or_else(|| bad("Missing value"))?;
+            match key {
+                "hasher" => {
+                    snapshot.hasher = value.parse().map_err(|_| bad("Unknown hasher"))?;
+                }
+                "replicas" => {
+                    snapshot.replicas = value.parse().map_err(|_| bad("Invalid replicas"))?;
+                }
+                "strategy" => {
+                    snapshot.replica_strategy =
+                        scaling::from_name(value).ok_or_else(|| bad("Unknown replica strategy"))?;
+                }
+                "salt" => snapshot.salt = value.to_string(),
+                "prefix" => snapshot.key_prefix = value.to_string(),
+                "hash-tags" => {
+                    snapshot.hash_tags = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(bad("Invalid hash-tags")),
+                    };
+                }
+                "normalization" => {
+                    snapshot.key_normalization = match value {
+                        "as-given" => KeyNormalization::AsGiven,
+                        #[cfg(feature = "unicode")]
+                        "nfc" => KeyNormalization::Nfc,
+                        #[cfg(feature = "unicode")]
+                        "nfkc" => KeyNormalization::Nfkc,
+                        _ => return Err(bad("Unknown key normalization")),
+                    };
+                }
+                "generation" => {
+                    snapshot.generation = value.parse().map_err(|_| bad("Invalid generation"))?;
+                }
+                "token" => snapshot.causal_token = Some(value.to_string()),
+                "target" => {
+                    let (weight, name) =
+                        value.split_once(' ').ok_or_else(|| bad("Missing name"))?;
+                    let info = TargetInfo {
+                        weight: weight.parse().map_err(|_| bad("Invalid weight"))?,
+                        ..TargetInfo::default()
+                    };
+                    snapshot.targets.push((name.to_string(), info));
+                }
+                "zone" | "tier" | "label" => {
+                    let (_, info) = snapshot
+                        .targets
+                        .last_mut()
+                        .ok_or_else(|| bad("No target"))?;
+                    if key == "zone" {
+                        info.zone = Some(value.to_string());
+                    } else if key == "tier" {
+                        info.tier = value.parse().map_err(|_| bad("Invalid tier"))?;
+                    } else {
+                        let (k, v) = value.split_once('=').ok_or_else(|| bad("Invalid label"))?;
+                        info.labels.insert(k.to_string(), v.to_string());
+                    }
+                }
+                _ => return Err(bad("Unknown field")),
+            }
+        }
+        return Ok(snapshot);
+    }
+}
+
+impl Flexihash {
+    pub fn snapshot(&self) -> Snapshot {
+        let mut targets: Vec<(Target, TargetInfo)> = self
+            .target_info
+            .iter()
+            .map(|(t, info)| (t.clone(), info.clone()))
+            .collect();
+        targets.sort_by(|a, b| a.0.cmp(&b.0));
+        return Snapshot {
+            hasher: self.hasher.clone(),
+            replicas: self.replicas,
+            replica_strategy: self.replica_strategy.clone(),
+            salt: self.salt.clone(),
+            key_prefix: self.key_prefix.clone(),
+            hash_tags: self.hash_tags,
+            key_normalization: self.key_normalization,
+            targets,
+            generation: self.generation,
+            causal_token: self.causal_token.clone(),
+        };
+    }
+
+    pub fn from_snapshot(snapshot: &Snapshot) -> Flexihash {
+        match Flexihash::try_from_snapshot(snapshot) {
+            Ok(fh) => return fh,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // For snapshots from outside, which may ask for more positions than
+    // there's memory for
+    pub fn try_from_snapshot(snapshot: &Snapshot) -> Result<Flexihash, SnapshotError> {
+        let mut fh = Flexihash::new();
+        fh.load_snapshot(snapshot).map_err(SnapshotError::Ring)?;
+        return Ok(fh);
+    }
+
+    // Lay an empty ring out as the snapshot says, if it fits within
+    // max_total_positions
+    pub(crate) fn load_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        self.set_hasher(snapshot.hasher.clone());
+        self.set_replicas(snapshot.replicas);
+        self.set_replica_strategy(snapshot.replica_strategy.clone());
+        self.set_salt(snapshot.salt.clone());
+        self.set_key_prefix(snapshot.key_prefix.clone());
+        self.set_hash_tags(snapshot.hash_tags);
+        self.set_key_normalization(snapshot.key_normalization);
+        let mut total: u64 = 0;
+        for (target, info) in snapshot.targets.iter() {
+            total = total.saturating_add(self.positions_for(info.weight));
+            self.check_total_positions(target, total)?;
+        }
+        for (target, info) in snapshot.targets.iter() {
+            self.place_target(target.clone(), info.weight);
+            Arc::make_mut(&mut self.target_info).insert(target.clone(), info.clone());
+        }
+        self.rebuild();
+        self.generation = snapshot.generation;
+        self.causal_token = snapshot.causal_token.clone();
+        return Ok(());
+    }
+
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        write_atomically(path.as_ref(), &self.snapshot().to_bytes()?)?;
+        return Ok(());
+    }
+
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Flexihash, SnapshotError> {
+        let data = fs::read(path)?;
+        return Flexihash::try_from_snapshot(&Snapshot::from_bytes(&data)?);
+    }
+}
+
+// Write everything to a temporary file next to the real one, and only
+// rename it into place once it has reached the disk, so that readers see
+// either the old file or the new one, never half
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    // Persist the rename itself; not all platforms can open a directory
+    // for this, which is fine
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test_snapshot {
+    use super::*;
+    use crate::RingTarget;
+    use std::collections::BTreeMap;
+
+    struct Node;
+
+    impl RingTarget for Node {
+        fn name(&self) -> Target {
+            return "cache-3".to_string();
+        }
+
+        fn zone(&self) -> Option<String> {
+            return Some("rack-7".to_string());
+        }
+
+        fn tier(&self) -> u32 {
+            return 1;
+        }
+
+        fn labels(&self) -> BTreeMap<String, String> {
+            let mut labels = BTreeMap::new();
+            labels.insert("tier".to_string(), "ssd".to_string());
+            return labels;
+        }
+    }
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        #[cfg(feature = "md5")]
+        fh.set_hasher(Hasher::Md5);
+        #[cfg(not(feature = "md5"))]
+        fh.set_hasher(Hasher::Adler32);
+        fh.set_replicas(16);
+        fh.set_salt("prod 2");
+        fh.add_target("cache-1", 1);
+        fh.add_target("cache 2", 3);
+        fh.add_ring_target(&Node);
+        return fh;
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        return std::env::temp_dir().join(format!("flexihash-{}-{}", std::process::id(), name));
+    }
+
+    #[test]
+    fn round_trip() {
+        let fh = ring();
+        let data = fh.snapshot().to_bytes().unwrap();
+        let fh2 = Flexihash::from_snapshot(&Snapshot::from_bytes(&data).unwrap());
+
+        assert_eq!(fh2.get_all_targets(), fh.get_all_targets());
+        assert_eq!(fh2.sorted_position_to_target, fh.sorted_position_to_target);
+        assert_eq!(
+            fh2.get_target_info("cache-3"),
+            fh.get_target_info("cache-3")
+        );
+        assert_eq!(fh2.get_target_info("cache 2").unwrap().weight, 3);
+    }
+
+    #[test]
+    fn round_trip_generation() {
+        let mut fh = ring();
+        fh.set_causal_token("controller-2");
+        let data = fh.snapshot().to_bytes().unwrap();
+        let fh2 = Flexihash::from_snapshot(&Snapshot::from_bytes(&data).unwrap());
+        assert_eq!(fh2.generation(), fh.generation());
+        assert_eq!(fh2.causal_token(), Some("controller-2"));
+
+        // from before generations were stored
+        let body = "flexihash 1\nhasher adler32\nreplicas 4\ntarget 1 t-a\n";
+        let old = format!("{}checksum {:016x}\n", body, checksum(body.as_bytes()));
+        let snapshot = Snapshot::from_bytes(old.as_bytes()).unwrap();
+        assert_eq!(snapshot.generation, 0);
+        assert_eq!(snapshot.causal_token, None);
+    }
+
+    #[cfg(feature = "highway")]
+    #[test]
+    fn round_trip_highway_key() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(Hasher::truncated(Hasher::Highway([1, 2, 3, u64::MAX]), 64).unwrap());
+        fh.add_targets(vec!["t-a", "t-b"]);
+        let data = fh.snapshot().to_bytes().unwrap();
+        let fh2 = Flexihash::from_snapshot(&Snapshot::from_bytes(&data).unwrap());
+        assert_eq!(fh2.sorted_position_to_target, fh.sorted_position_to_target);
+        assert_eq!(fh2.hasher().redacted(), "highway:key=<secret>,bits=64");
+    }
+
+    #[test]
+    fn round_trip_key_settings() {
+        let mut fh = ring();
+        let plain = fh.fingerprint();
+        fh.set_key_prefix("app:");
+        assert_ne!(fh.fingerprint(), plain);
+        let prefixed = fh.fingerprint();
+        fh.set_hash_tags(true);
+        assert_ne!(fh.fingerprint(), prefixed);
+
+        let data = fh.snapshot().to_bytes().unwrap();
+        let fh2 = Flexihash::from_snapshot(&Snapshot::from_bytes(&data).unwrap());
+        assert_eq!(fh2.key_prefix(), "app:");
+        assert!(fh2.hash_tags());
+        assert_eq!(fh2.fingerprint(), fh.fingerprint());
+        assert_eq!(fh2.lookup("user:1"), fh.lookup("user:1"));
+        assert_eq!(fh2.lookup("{user:1}:x"), fh.lookup("user:1"));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn round_trip_key_normalization() {
+        let mut fh = ring();
+        let plain = fh.fingerprint();
+        fh.set_key_normalization(KeyNormalization::Nfkc);
+        assert_ne!(fh.fingerprint(), plain);
+
+        let data = fh.snapshot().to_bytes().unwrap();
+        let fh2 = Flexihash::from_snapshot(&Snapshot::from_bytes(&data).unwrap());
+        assert_eq!(fh2.fingerprint(), fh.fingerprint());
+        assert_eq!(fh2.lookup("\u{fb01}le"), fh.lookup("file"));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path("save_and_load");
+        let fh = ring();
+        fh.save_to(&path).unwrap();
+        let fh2 = Flexihash::load_from(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(fh2.sorted_position_to_target, fh.sorted_position_to_target);
+    }
+
+    #[test]
+    fn round_trip_replica_strategy() {
+        // only affects targets added afterwards
+        let mut fh = Flexihash::new();
+        fh.set_replica_strategy(Arc::new(scaling::Logarithmic));
+        fh.add_target("cache-1", 1);
+        fh.add_target("cache-4", 1000);
+        let data = fh.snapshot().to_bytes().unwrap();
+        let fh2 = Flexihash::from_snapshot(&Snapshot::from_bytes(&data).unwrap());
+        assert_eq!(fh2.sorted_position_to_target, fh.sorted_position_to_target);
+        assert_eq!(fh2.fingerprint(), fh.fingerprint());
+
+        fh.set_replica_strategy(Arc::new(scaling::Custom(|r, _| r as u64)));
+        assert!(matches!(
+            fh.snapshot().to_bytes(),
+            Err(SnapshotError::UnnamedStrategy)
+        ));
+    }
+
+    #[test]
+    fn load_refuses_oversized_rings() {
+        let path = temp_path("load_refuses_oversized_rings");
+        let body = "flexihash 1\nhasher adler32\nreplicas 64\ntarget 4000000000 t-a\n";
+        let data = format!("{}checksum {:016x}\n", body, checksum(body.as_bytes()));
+        fs::write(&path, data).unwrap();
+        let result = Flexihash::load_from(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(SnapshotError::Ring(Error::TooManyPositions(..)))
+        ));
+    }
+
+    #[test]
+    fn load_detects_corruption() {
+        let mut data = ring().snapshot().to_bytes().unwrap();
+        data[20] ^= 1;
+        assert!(matches!(
+            Snapshot::from_bytes(&data),
+            Err(SnapshotError::Checksum)
+        ));
+
+        let data = ring().snapshot().to_bytes().unwrap();
+        assert!(matches!(
+            Snapshot::from_bytes(&data[..data.len() - 30]),
+            Err(SnapshotError::Checksum)
+        ));
+    }
+
+    #[test]
+    fn load_missing_file() {
+        assert!(matches!(
+            Flexihash::load_from(temp_path("not-there")),
+            Err(SnapshotError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn unstorable_names() {
+        let mut fh = Flexihash::new();
+        fh.add_target("bad\nname", 1);
+        assert!(matches!(
+            fh.snapshot().to_bytes(),
+            Err(SnapshotError::InvalidName(_))
+        ));
+    }
+}
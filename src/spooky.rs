@@ -0,0 +1,154 @@
+/*
+ * SpookyHash V2 (http://burtleburtle.net/bob/hash/spooky.html), the
+ * one-shot 128-bit Hash128, ported from Bob Jenkins' reference C++. Input
+ * is read as little-endian words, as the reference does on x86.
+ */
+const NUM_VARS: usize = 12;
+const BLOCK_SIZE: usize = NUM_VARS * 8;
+const BUF_SIZE: usize = 2 * BLOCK_SIZE;
+const SC_CONST: u64 = 0xdeadbeefdeadbeef;
+
+fn word(bytes: &[u8], i: usize) -> u64 {
+    let mut w = [0u8; 8];
+    w.copy_from_slice(&bytes[i * 8..i * 8 + 8]);
+    return u64::from_le_bytes(w);
+}
+
+fn mix(data: &[u64; NUM_VARS], s: &mut [u64; NUM_VARS]) {
+    const ROTATIONS: [u32; NUM_VARS] = [11, 32, 43, 31, 17, 28, 39, 57, 55, 54, 22, 46];
+    for i in 0..NUM_VARS {
+        s[i] = s[i].wrapping_add(data[i]);
+        s[(i + 2) % NUM_VARS] ^= s[(i + 10) % NUM_VARS];
+        s[(i + 11) % NUM_VARS] ^= s[i];
+        s[i] = s[i].rotate_left(ROTATIONS[i]);
+        s[(i + 11) % NUM_VARS] = s[(i + 11) % NUM_VARS].wrapping_add(s[(i + 1) % NUM_VARS]);
+    }
+}
+
+fn end_partial(h: &mut [u64; NUM_VARS]) {
+    const ROTATIONS: [u32; NUM_VARS] = [44, 15, 34, 21, 38, 33, 10, 13, 38, 53, 42, 54];
+    for i in 0..NUM_VARS {
+        h[(i + 11) % NUM_VARS] = h[(i + 11) % NUM_VARS].wrapping_add(h[(i + 1) % NUM_VARS]);
+        h[(i + 2) % NUM_VARS] ^= h[(i + 11) % NUM_VARS];
+        h[(i + 1) % NUM_VARS] = h[(i + 1) % NUM_VARS].rotate_left(ROTATIONS[i]);
+    }
+}
+
+fn short_mix(h: &mut [u64; 4]) {
+    const ROTATIONS: [u32; 12] = [50, 52, 30, 41, 54, 48, 38, 37, 62, 34, 5, 36];
+    for (i, r) in ROTATIONS.iter().enumerate() {
+        let (x, y, z) = ((i + 2) % 4, (i + 3) % 4, i % 4);
+        h[x] = h[x].rotate_left(*r);
+        h[x] = h[x].wrapping_add(h[y]);
+        h[z] ^= h[x];
+    }
+}
+
+fn short_end(h: &mut [u64; 4]) {
+    const ROTATIONS: [u32; 11] = [15, 52, 26, 51, 28, 9, 47, 54, 32, 25, 63];
+    for (i, r) in ROTATIONS.iter().enumerate() {
+        let (x, y) = ((i + 3) % 4, (i + 2) % 4);
+        h[x] ^= h[y];
+        h[y] = h[y].rotate_left(*r);
+        h[x] = h[x].wrapping_add(h[y]);
+    }
+}
+
+// Messages under BUF_SIZE bytes
+fn short(message: &[u8], seed: (u64, u64)) -> (u64, u64) {
+    let mut h = [seed.0, seed.1, SC_CONST, SC_CONST];
+    let mut rest = message;
+    while rest.len() >= 32 {
+        h[2] = h[2].wrapping_add(word(rest, 0));
+        h[3] = h[3].wrapping_add(word(rest, 1));
+        short_mix(&mut h);
+        h[0] = h[0].wrapping_add(word(rest, 2));
+        h[1] = h[1].wrapping_add(word(rest, 3));
+        rest = &rest[32..];
+    }
+    if rest.len() >= 16 {
+        h[2] = h[2].wrapping_add(word(rest, 0));
+        h[3] = h[3].wrapping_add(word(rest, 1));
+        short_mix(&mut h);
+        rest = &rest[16..];
+    }
+
+    // the last 0..15 bytes, and the length
+    h[3] = h[3].wrapping_add((message.len() as u64) << 56);
+    if rest.is_empty() {
+        h[2] = h[2].wrapping_add(SC_CONST);
+        h[3] = h[3].wrapping_add(SC_CONST);
+    } else {
+        let mut tail = [0u8; 16];
+        tail[..rest.len()].copy_from_slice(rest);
+        h[2] = h[2].wrapping_add(word(&tail, 0));
+        h[3] = h[3].wrapping_add(word(&tail, 1));
+    }
+    short_end(&mut h);
+    return (h[0], h[1]);
+}
+
+pub(crate) fn spooky128(message: &[u8], seed: (u64, u64)) -> (u64, u64) {
+    if message.len() < BUF_SIZE {
+        return short(message, seed);
+    }
+
+    // seed.0, seed.1, SC_CONST, repeated across the state
+    let mut h = [
+        seed.0, seed.1, SC_CONST, seed.0, seed.1, SC_CONST, seed.0, seed.1, SC_CONST, seed.0,
+        seed.1, SC_CONST,
+    ];
+    let mut blocks = message.chunks_exact(BLOCK_SIZE);
+    let mut data = [0u64; NUM_VARS];
+    for block in &mut blocks {
+        for (i, w) in data.iter_mut().enumerate() {
+            *w = word(block, i);
+        }
+        mix(&data, &mut h);
+    }
+
+    // the last partial block, padded, with its length in the final byte
+    let rest = blocks.remainder();
+    let mut last = [0u8; BLOCK_SIZE];
+    last[..rest.len()].copy_from_slice(rest);
+    last[BLOCK_SIZE - 1] = rest.len() as u8;
+    for (i, w) in h.iter_mut().enumerate() {
+        *w = w.wrapping_add(word(&last, i));
+    }
+    end_partial(&mut h);
+    end_partial(&mut h);
+    end_partial(&mut h);
+    return (h[0], h[1]);
+}
+
+#[cfg(test)]
+mod test_spooky {
+    use super::*;
+
+    #[test]
+    fn reference_vectors() {
+        // Hash32 of the bytes 128.., from the reference test suite
+        let data: Vec<u8> = (0..512).map(|i| (i + 128) as u8).collect();
+        let hash32 = |n: usize| spooky128(&data[..n], (0, 0)).0 as u32;
+        assert_eq!(hash32(0), 0x6bf50919);
+        assert_eq!(hash32(1), 0x70de1d26);
+        assert_eq!(hash32(2), 0xa2b37298);
+        assert_eq!(hash32(3), 0x35bc5fbf);
+
+        // Hash128 of the bytes 0..n, covering each path through the tail
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let expected: [(usize, u64, u64); 8] = [
+            (0, 0x232706FC6BF50919, 0x8B72EE65B4E851C7),
+            (1, 0x8AE2F41804291280, 0x1F315D714E2D1D29),
+            (15, 0xD9AA86DE65DC278B, 0xDA240564552A4A10),
+            (31, 0xA3962A6C761FFE09, 0x678FC7DA4AC3197A),
+            (32, 0x57F50B68E2623FD2, 0x893924EFF994198B),
+            (191, 0x71043A03B5BB462F, 0xFE7D0AD0A893B638),
+            (192, 0x02D13F94B2A31A54, 0x0EA393DB758D85D3),
+            (288, 0xCD1959F5C2C45178, 0x431A8F2961D5E22B),
+        ];
+        for (n, h1, h2) in expected.iter() {
+            assert_eq!(spooky128(&data[..*n], (0, 0)), (*h1, *h2), "{} bytes", n);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * t1ha2_atonce (https://github.com/erthink/t1ha), the 64-bit "recommended"
+ * flavour of t1ha, ported from the reference C so that positions match
+ * anything else hashing with it. Always little-endian, whatever the host.
+ */
+const PRIME_0: u64 = 0xEC99BF0D8372CAAB;
+const PRIME_1: u64 = 0x82434FE90EDCEF39;
+const PRIME_2: u64 = 0xD4F06DB99D67BE4B;
+const PRIME_3: u64 = 0xBD9CACC22C6E9571;
+const PRIME_4: u64 = 0x9C06FAF4D023E3AB;
+const PRIME_5: u64 = 0xC060724A8424F345;
+const PRIME_6: u64 = 0xCB5AF53AE3AAAC31;
+
+fn fetch64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    return u64::from_le_bytes(word);
+}
+
+// The final 1..8 bytes, zero-extended
+fn tail64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word[..bytes.len()].copy_from_slice(bytes);
+    return u64::from_le_bytes(word);
+}
+
+// xor of the high and low halves of the full product
+fn mux64(v: u64, prime: u64) -> u64 {
+    let product = v as u128 * prime as u128;
+    return product as u64 ^ (product >> 64) as u64;
+}
+
+fn mixup64(a: &mut u64, b: &mut u64, v: u64, prime: u64) {
+    let product = b.wrapping_add(v) as u128 * prime as u128;
+    *a ^= product as u64;
+    *b = b.wrapping_add((product >> 64) as u64);
+}
+
+fn final64(a: u64, b: u64) -> u64 {
+    let x = a.wrapping_add(b.rotate_right(41)).wrapping_mul(PRIME_0);
+    let y = a.rotate_right(23).wrapping_add(b).wrapping_mul(PRIME_6);
+    return mux64(x ^ y, PRIME_5);
+}
+
+pub(crate) fn t1ha2_atonce(data: &[u8], seed: u64) -> u64 {
+    let length = data.len() as u64;
+    let (mut a, mut b) = (seed, length);
+    let mut data = data;
+
+    if data.len() > 32 {
+        let mut c = length.rotate_right(23).wrapping_add(!seed);
+        let mut d = (!length).wrapping_add(seed.rotate_right(19));
+        // whole 32-byte blocks, then whatever's left as the tail
+        while data.len() >= 32 {
+            let (w0, w1, w2, w3) = (
+                fetch64(&data[0..]),
+                fetch64(&data[8..]),
+                fetch64(&data[16..]),
+                fetch64(&data[24..]),
+            );
+            let d02 = w0.wrapping_add(w2.wrapping_add(d).rotate_right(56));
+            let c13 = w1.wrapping_add(w3.wrapping_add(c).rotate_right(19));
+            d ^= b.wrapping_add(w1.rotate_right(38));
+            c ^= a.wrapping_add(w0.rotate_right(57));
+            b ^= PRIME_6.wrapping_mul(c13.wrapping_add(w2));
+            a ^= PRIME_5.wrapping_mul(d02.wrapping_add(w3));
+            data = &data[32..];
+        }
+        a ^= PRIME_6.wrapping_mul(c.wrapping_add(d.rotate_right(23)));
+        b ^= PRIME_5.wrapping_mul(c.rotate_right(19).wrapping_add(d));
+    }
+
+    let len = data.len();
+    if len > 24 {
+        mixup64(&mut a, &mut b, fetch64(data), PRIME_4);
+        data = &data[8..];
+    }
+    if len > 16 {
+        mixup64(&mut b, &mut a, fetch64(data), PRIME_3);
+        data = &data[8..];
+    }
+    if len > 8 {
+        mixup64(&mut a, &mut b, fetch64(data), PRIME_2);
+        data = &data[8..];
+    }
+    if len > 0 {
+        mixup64(&mut b, &mut a, tail64(data), PRIME_1);
+    }
+    return final64(a, b);
+}
+
+#[cfg(test)]
+mod test_t1ha {
+    use super::*;
+
+    #[test]
+    fn reference_vectors() {
+        // from the reference C, hashing the bytes 0..n with seed 0 and 42
+        let data: Vec<u8> = (0..100).collect();
+        let expected: [(usize, u64, u64); 12] = [
+            (0, 0, 0x0000000000000000),
+            (0, 42, 0xBFAAA0C37E075AFE),
+            (1, 0, 0x5D7010803EACA6D3),
+            (7, 42, 0x2F1D65A266C3F9F5),
+            (8, 0, 0xEF651C74EB6396F8),
+            (16, 0, 0x5E7543C6CDF8A46E),
+            (24, 42, 0x3CBD6CDEF2AAFDF9),
+            (31, 0, 0x2FA90FF052CDB85E),
+            (32, 42, 0xB6B3F204844B9A9A),
+            (33, 0, 0xB260289A424BB4C6),
+            (64, 0, 0xAAE598ED4F666040),
+            (100, 42, 0x163E902582521DAB),
+        ];
+        for (n, seed, want) in expected.iter() {
+            assert_eq!(t1ha2_atonce(&data[..*n], *seed), *want, "{} bytes", n);
+        }
+    }
+}
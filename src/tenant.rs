@@ -0,0 +1,152 @@
+use crate::{Flexihash, FrozenFlexihash};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/*
+ * Per-tenant layouts of one ring. Every tenant's keys are named much the
+ * same ("user:1", "session:..."), so on a single ring all tenants' hot
+ * keys pile onto the same few targets. A sub-ring has the same targets,
+ * weights and position counts as its parent, but with the positions
+ * salted by the tenant, so each tenant's hot spots land somewhere else.
+ *
+ * Sub-rings are built on first use and kept until the parent changes;
+ * each costs about as much memory as the parent, so clear_sub_rings now
+ * and then if tenants come and go. A tenant's layout depends only on the
+ * parent and the tenant's name, so every process agrees on it.
+ */
+#[derive(Clone, Default)]
+pub(crate) struct SubRings(Arc<Mutex<HashMap<String, Arc<FrozenFlexihash>>>>);
+
+impl fmt::Debug for SubRings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rings = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tenants: Vec<&String> = rings.keys().collect();
+        tenants.sort();
+        write!(f, "SubRings({:?})", tenants)
+    }
+}
+
+// Length-prefixed, so that no tenant and target can run together to look
+// like another pair
+fn tenant_salt(salt: &str, tenant: &str) -> String {
+    return format!("{}tenant:{}:{}:", salt, tenant.len(), tenant);
+}
+
+impl Flexihash {
+    pub fn sub_ring<S: AsRef<str>>(&self, tenant: S) -> Arc<FrozenFlexihash> {
+        let tenant = tenant.as_ref();
+        let mut rings = self.sub_rings.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(ring) = rings.get(tenant) {
+            return ring.clone();
+        }
+        let ring = Arc::new(self.derive_sub_ring(tenant).freeze());
+        rings.insert(tenant.to_string(), ring.clone());
+        return ring;
+    }
+
+    pub fn clear_sub_rings(&self) {
+        self.sub_rings
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    fn derive_sub_ring(&self, tenant: &str) -> Flexihash {
+        let mut sub = self.clone();
+        // the parent's hooks are about the parent
+        sub.metrics = None;
+        sub.lookup_counts = None;
+        sub.observers = Vec::new();
+        sub.changelog = None;
+        sub.sub_rings = SubRings::default();
+        sub.salt = tenant_salt(&self.salt, tenant);
+        sub.position_to_target = Arc::default();
+        sub.target_to_positions = Arc::default();
+        for (target, positions) in self.target_to_positions.iter() {
+            let weight = self.target_info.get(target).map_or(1, |info| info.weight);
+            // as many as the parent has, whatever the replica settings are now
+            sub.place_replicas(target.clone(), weight, positions.len() as u64);
+        }
+        sub.rebuild();
+        sub.generation = self.generation;
+        return sub;
+    }
+}
+
+#[cfg(test)]
+mod test_tenant {
+    use super::*;
+
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        for i in 0..8 {
+            fh.add_target(format!("t-{}", i), 1);
+        }
+        fh.update_target_weight("t-0", 2);
+        return fh;
+    }
+
+    // adler32 barely moves replicas for a change of salt
+    #[cfg(feature = "crc")]
+    #[test]
+    fn same_targets_different_layout() {
+        let fh = ring();
+        let a = fh.sub_ring("tenant-a");
+        let b = fh.sub_ring("tenant-b");
+        assert_eq!(a.get_all_targets(), fh.get_all_targets());
+        assert_eq!(a.get_target_info("t-0").unwrap().weight, 2);
+        assert_eq!(a.position_count(), fh.position_count());
+        assert_eq!(a.generation(), fh.generation());
+        assert_eq!(a.salt(), "tenant:8:tenant-a:");
+
+        let differ = |x: &Flexihash, y: &Flexihash| {
+            (0..100)
+                .filter(|i| x.lookup(format!("key{}", i)) != y.lookup(format!("key{}", i)))
+                .count()
+        };
+        assert!(differ(&a, &fh) > 50);
+        assert!(differ(&a, &b) > 50);
+    }
+
+    #[test]
+    fn same_as_building_it_by_hand() {
+        let fh = ring();
+        let mut by_hand = Flexihash::new();
+        by_hand.set_hasher(fh.hasher().clone());
+        by_hand.set_salt("tenant:1:x:");
+        for i in 0..8 {
+            by_hand.add_target(format!("t-{}", i), if i == 0 { 2 } else { 1 });
+        }
+        assert_eq!(
+            fh.sub_ring("x").sorted_position_to_target,
+            by_hand.sorted_position_to_target
+        );
+    }
+
+    #[test]
+    fn cached_until_the_ring_changes() {
+        let mut fh = ring();
+        let first = fh.sub_ring("tenant-a");
+        assert!(Arc::ptr_eq(&first, &fh.sub_ring("tenant-a")));
+        assert!(Arc::ptr_eq(&first, &fh.clone().sub_ring("tenant-a")));
+        assert_eq!(format!("{:?}", fh.sub_rings), "SubRings([\"tenant-a\"])");
+
+        fh.add_target("t-new", 1);
+        let second = fh.sub_ring("tenant-a");
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(second.get_all_targets().contains(&"t-new".to_string()));
+
+        fh.set_key_prefix("app:");
+        assert_eq!(fh.sub_ring("tenant-a").key_prefix(), "app:");
+
+        fh.add_rule(crate::rules::Rule::prefix("billing:", vec!["t-billing"]));
+        assert_eq!(fh.sub_ring("tenant-a").lookup("billing:1"), "t-billing");
+        fh.clear_rules();
+        assert!(fh.sub_ring("tenant-a").rules().is_empty());
+
+        fh.clear_sub_rings();
+        assert!(!Arc::ptr_eq(&second, &fh.sub_ring("tenant-a")));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::changelog::Mutation;
+use crate::{Flexihash, ResourceKey, Target};
+
+/*
+ * Priority tiers, for a small overflow fleet which should only see
+ * traffic during an incident. Every target has a tier, 0 (the main pool)
+ * unless set otherwise, and the tiered lookups pick from the best tier
+ * which has anyone left once the excluded (eg unhealthy) targets are
+ * taken out, only spilling over to the next tier when all of this one's
+ * are gone.
+ *
+ * Targets of all tiers share the one ring, but walking it and skipping
+ * the other tiers gives each key the same main pool target as a ring of
+ * just the main pool would, so adding or removing overflow targets
+ * doesn't move anything between the others.
+ */
+impl Flexihash {
+    // A new generation like any other change, so it's logged and observed
+    pub fn set_target_tier<S: Into<String>>(&mut self, target: S, tier: u32) {
+        let target = self.normalize(target.into());
+        if !self.target_to_positions.contains_key(&target) {
+            self.missing_target(&target);
+        }
+        if self.tier_of(&target) == tier {
+            return;
+        }
+        self.retier_target(target, tier);
+        self.generation += 1;
+        // they carry a copy of the tiers
+        self.sub_rings = Default::default();
+    }
+
+    pub(crate) fn retier_target(&mut self, target: Target, tier: u32) {
+        let info = std::sync::Arc::make_mut(&mut self.target_info)
+            .entry(target.clone())
+            .or_default();
+        let old_tier = std::mem::replace(&mut info.tier, tier);
+        for observer in self.observers.iter() {
+            observer.on_tier_changed(&target, old_tier, tier);
+        }
+        if let Some(log) = &self.changelog {
+            log.record(self.generation + 1, Mutation::Tier(target, tier));
+        }
+    }
+
+    // None if the ring is empty or every target is excluded
+    pub fn lookup_tiered<K: ResourceKey>(
+        &self,
+        resource: K,
+        excluded: impl Fn(&str) -> bool,
+    ) -> Option<Target> {
+        // a rule's targets needn't be in the ring
+        let top = self.top_tier().unwrap_or(0);
+        let mut best: Option<(u32, Target)> = None;
+        for target in self.lookup_candidates(resource) {
+            if excluded(&target) {
+                continue;
+            }
+            let tier = self.tier_of(&target);
+            if tier == top {
+                return Some(target);
+            }
+            if best.as_ref().is_none_or(|(t, _)| tier < *t) {
+                best = Some((tier, target));
+            }
+        }
+        return best.map(|(_, target)| target);
+    }
+
+    // Up to requested_count targets which aren't excluded, best tier
+    // first and in ring order within a tier, so that lower tiers only make
+    // up the numbers when the higher ones can't
+    pub fn lookup_list_tiered<K: ResourceKey>(
+        &self,
+        resource: K,
+        requested_count: u32,
+        excluded: impl Fn(&str) -> bool,
+    ) -> Vec<Target> {
+        if requested_count == 0 {
+            panic!("Need to request at least 1 resource");
+        }
+        let top = self.top_tier().unwrap_or(0);
+        let mut candidates: Vec<(u32, Target)> = Vec::new();
+        let mut from_top = 0;
+        for target in self.lookup_candidates(resource) {
+            if excluded(&target) {
+                continue;
+            }
+            let tier = self.tier_of(&target);
+            candidates.push((tier, target));
+            if tier == top {
+                from_top += 1;
+                if from_top == requested_count {
+                    break;
+                }
+            }
+        }
+        // stable, so ring order is kept within each tier
+        candidates.sort_by_key(|c| c.0);
+        return candidates
+            .into_iter()
+            .take(requested_count as usize)
+            .map(|c| c.1)
+            .collect();
+    }
+
+    fn top_tier(&self) -> Option<u32> {
+        return self.target_info.values().map(|info| info.tier).min();
+    }
+
+    fn tier_of(&self, target: &str) -> u32 {
+        return self.target_info.get(target).map_or(0, |info| info.tier);
+    }
+}
+
+#[cfg(test)]
+mod test_tier {
+    use super::*;
+    use crate::Hasher;
+
+    // in ring order from "r": t2, t3, t4, t1
+    fn ring() -> Flexihash {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (p, target) in [(10, "t1"), (20, "t2"), (30, "t3"), (40, "t4")] {
+            fh.set_hasher(Hasher::Mock(p));
+            fh.add_target(target, 1);
+        }
+        fh.set_hasher(Hasher::Mock(15));
+        fh.set_target_tier("t3", 1);
+        fh.set_target_tier("t4", 2);
+        return fh;
+    }
+
+    #[test]
+    fn spills_over_only_when_a_tier_is_gone() {
+        let fh = ring();
+        let down = |targets: &'static [&'static str]| move |t: &str| targets.contains(&t);
+        assert_eq!(fh.lookup_tiered("r", down(&[])), Some("t2".to_string()));
+        // t1 is further round, but in the main pool
+        assert_eq!(fh.lookup_tiered("r", down(&["t2"])), Some("t1".to_string()));
+        assert_eq!(
+            fh.lookup_tiered("r", down(&["t1", "t2"])),
+            Some("t3".to_string())
+        );
+        assert_eq!(
+            fh.lookup_tiered("r", down(&["t1", "t2", "t3"])),
+            Some("t4".to_string())
+        );
+        assert_eq!(fh.lookup_tiered("r", down(&["t1", "t2", "t3", "t4"])), None);
+        assert_eq!(Flexihash::new().lookup_tiered("r", down(&[])), None);
+    }
+
+    #[test]
+    fn lists() {
+        let fh = ring();
+        let none = |_: &str| false;
+        assert_eq!(fh.lookup_list_tiered("r", 1, none), ["t2"]);
+        assert_eq!(fh.lookup_list_tiered("r", 3, none), ["t2", "t1", "t3"]);
+        assert_eq!(
+            fh.lookup_list_tiered("r", 3, |t| t == "t1"),
+            ["t2", "t3", "t4"]
+        );
+        assert_eq!(
+            fh.lookup_list_tiered("r", 9, none),
+            ["t2", "t1", "t3", "t4"]
+        );
+    }
+
+    #[test]
+    fn main_pool_placement_ignores_overflow() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        let before: Vec<Target> = (0..100).map(|i| fh.lookup(format!("key{}", i))).collect();
+        fh.add_target("t-spare", 1);
+        fh.set_target_tier("t-spare", 1);
+        for (i, target) in before.iter().enumerate() {
+            assert_eq!(
+                fh.lookup_tiered(format!("key{}", i), |_| false).as_ref(),
+                Some(target)
+            );
+        }
+        assert_eq!(fh.get_target_info("t-spare").unwrap().tier, 1);
+    }
+
+    #[test]
+    fn tier_changes_are_versioned() {
+        let mut fh = ring();
+        let log = fh.enable_changelog();
+        let before = fh.generation();
+        fh.set_target_tier("t1", 1);
+        assert_eq!(fh.generation(), before + 1);
+        // no change, no new generation
+        fh.set_target_tier("t1", 1);
+        assert_eq!(fh.generation(), before + 1);
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].generation, before + 1);
+        assert_eq!(entries[0].mutation, Mutation::Tier("t1".to_string(), 1));
+        let replayed = Flexihash::replay_to_generation(&log, before + 1);
+        assert_eq!(replayed.get_target_info("t1").unwrap().tier, 1);
+        let replayed = Flexihash::replay_to_generation(&log, before);
+        assert_eq!(replayed.get_target_info("t1").unwrap().tier, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Target 't-x' does not exist")]
+    fn tier_of_missing_target() {
+        ring().set_target_tier("t-x", 1);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{Flexihash, Target};
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+ * Ways a ring can be set up which work, but which probably aren't what
+ * was meant, and tend to show up as an overloaded node once traffic is
+ * on it. Flexihash::validate lists them; the config loaders (env vars,
+ * reloaded snapshots, consul and etcd) run it on every ring they build.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum RingWarning {
+    NoTargets,
+    // the replica count, and the least a ring with this many targets wants
+    TooFewReplicas {
+        replicas: u32,
+        targets: usize,
+        recommended: u32,
+    },
+    // the positions the targets have, over the ring's max_total_positions
+    // (eg after the limit was lowered)
+    OverPositionBudget {
+        positions: u64,
+        limit: u64,
+    },
+    // a target's share of the hash space, against what its positions
+    // should give it
+    Skewed {
+        target: Target,
+        share: f64,
+        expected: f64,
+    },
+    // positions which more than one replica hashed to; all but one of
+    // each are lost
+    DuplicatePositions(u64),
+}
+
+impl fmt::Display for RingWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RingWarning::NoTargets => write!(f, "No targets set"),
+            RingWarning::TooFewReplicas {
+                replicas,
+                targets,
+                recommended,
+            } => write!(
+                f,
+                "{} replicas is too few for {} targets, use at least {}",
+                replicas, targets, recommended
+            ),
+            RingWarning::OverPositionBudget { positions, limit } => write!(
+                f,
+                "Targets have {} positions, over the limit of {}",
+                positions, limit
+            ),
+            RingWarning::Skewed {
+                target,
+                share,
+                expected,
+            } => write!(
+                f,
+                "Target {} owns {:.1}% of the ring, expected {:.1}%",
+                target,
+                share * 100.0,
+                expected * 100.0
+            ),
+            RingWarning::DuplicatePositions(n) => {
+                write!(f, "{} replica positions collided", n)
+            }
+        }
+    }
+}
+
+// How far a target's share can stray from what's expected (either way, as
+// a ratio) before it counts as skewed; with the default 64 replicas, about
+// four standard deviations
+const SKEW_RATIO: f64 = 1.5;
+
+// Spread gets worse as targets are added (the busiest of n targets is
+// about sqrt(2 ln n / replicas) over its share), so the replicas needed to
+// keep the busiest within SKEW_RATIO grow with ln n
+fn recommended_replicas(targets: usize) -> u32 {
+    return (8.0 * (targets as f64).ln()).ceil() as u32;
+}
+
+impl Flexihash {
+    pub fn validate(&self) -> Vec<RingWarning> {
+        let mut warnings = Vec::new();
+        let targets = self.target_to_positions.len();
+        if targets == 0 {
+            warnings.push(RingWarning::NoTargets);
+            return warnings;
+        }
+
+        let recommended = recommended_replicas(targets);
+        if targets > 1 && self.replicas < recommended {
+            warnings.push(RingWarning::TooFewReplicas {
+                replicas: self.replicas,
+                targets,
+                recommended,
+            });
+        }
+
+        let positions = self.total_positions();
+        if positions > self.max_total_positions {
+            warnings.push(RingWarning::OverPositionBudget {
+                positions,
+                limit: self.max_total_positions,
+            });
+        }
+
+        let distinct = self.position_count() as u64;
+        if distinct < positions {
+            warnings.push(RingWarning::DuplicatePositions(positions - distinct));
+        }
+
+        if targets > 1 && distinct > 0 {
+            let mut shares: HashMap<Target, f64> = HashMap::new();
+            for partition in self.partitions() {
+                *shares.entry(partition.target).or_default() += partition.share;
+            }
+            for target in self.get_all_targets() {
+                let expected = self.target_positions(&target) as f64 / positions as f64;
+                if expected == 0.0 {
+                    continue;
+                }
+                let share = shares.get(&target).copied().unwrap_or_default();
+                if share > expected * SKEW_RATIO || share < expected / SKEW_RATIO {
+                    warnings.push(RingWarning::Skewed {
+                        target,
+                        share,
+                        expected,
+                    });
+                }
+            }
+        }
+        return warnings;
+    }
+}
+
+#[cfg(test)]
+mod test_validate {
+    use super::*;
+    use crate::Hasher;
+
+    #[cfg(feature = "crc")]
+    #[test]
+    fn a_good_ring() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c"]);
+        fh.add_target("t-d", 2);
+        assert_eq!(fh.validate(), vec![]);
+    }
+
+    #[test]
+    fn no_targets() {
+        assert_eq!(Flexihash::new().validate(), vec![RingWarning::NoTargets]);
+        assert_eq!(RingWarning::NoTargets.to_string(), "No targets set");
+    }
+
+    #[test]
+    fn too_few_replicas() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(4);
+        for i in 0..20 {
+            fh.add_target(format!("t-{}", i), 1);
+        }
+        let warning = RingWarning::TooFewReplicas {
+            replicas: 4,
+            targets: 20,
+            recommended: 24,
+        };
+        assert!(fh.validate().contains(&warning));
+        assert_eq!(
+            warning.to_string(),
+            "4 replicas is too few for 20 targets, use at least 24"
+        );
+    }
+
+    #[test]
+    fn over_budget() {
+        let mut fh = Flexihash::new();
+        fh.set_hasher(Hasher::Mock(0));
+        fh.set_replicas(10);
+        fh.add_target("t-a", 5);
+        fh.set_max_total_positions(20);
+        assert!(fh.validate().contains(&RingWarning::OverPositionBudget {
+            positions: 50,
+            limit: 20
+        }));
+    }
+
+    #[test]
+    fn skew_and_duplicates() {
+        let mut fh = Flexihash::new();
+        fh.set_replicas(1);
+        for (i, p) in [10, 20, 30, 30].iter().enumerate() {
+            fh.set_hasher(Hasher::Mock(*p));
+            fh.add_target(format!("t{}", i + 1), 1);
+        }
+        let warnings = fh.validate();
+        assert!(warnings.contains(&RingWarning::DuplicatePositions(1)));
+        // t1 gets everything past 30 too, which is nearly all of it
+        match warnings
+            .iter()
+            .find(|w| matches!(w, RingWarning::Skewed { target, .. } if target == "t1"))
+        {
+            Some(RingWarning::Skewed {
+                share, expected, ..
+            }) => {
+                assert!(*share > 0.99);
+                assert_eq!(*expected, 0.25);
+            }
+            other => panic!("Expected t1 to be skewed, got {:?}", other),
+        }
+    }
+}
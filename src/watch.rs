@@ -0,0 +1,140 @@
+use crate::{Flexihash, FrozenFlexihash};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/*
+ * One task owns the ring and publishes a frozen copy after every change;
+ * any number of subscribers can cheaply grab the latest copy.
+ */
+pub fn channel(ring: Flexihash) -> (RingPublisher, RingSubscriber) {
+    let (sender, receiver) = watch::channel(Arc::new(ring.clone().freeze()));
+    return (RingPublisher { ring, sender }, RingSubscriber { receiver });
+}
+
+#[derive(Debug)]
+pub struct RingPublisher {
+    ring: Flexihash,
+    sender: watch::Sender<Arc<FrozenFlexihash>>,
+}
+
+impl RingPublisher {
+    pub fn ring(&self) -> &Flexihash {
+        return &self.ring;
+    }
+
+    pub fn update<R, F: FnOnce(&mut Flexihash) -> R>(&mut self, f: F) -> R {
+        let result = f(&mut self.ring);
+        self.sender
+            .send_replace(Arc::new(self.ring.clone().freeze()));
+        return result;
+    }
+
+    pub fn subscribe(&self) -> RingSubscriber {
+        return RingSubscriber {
+            receiver: self.sender.subscribe(),
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RingSubscriber {
+    receiver: watch::Receiver<Arc<FrozenFlexihash>>,
+}
+
+impl RingSubscriber {
+    pub fn snapshot(&self) -> Arc<FrozenFlexihash> {
+        return self.receiver.borrow().clone();
+    }
+
+    pub async fn changed(&mut self) -> Result<Arc<FrozenFlexihash>, watch::error::RecvError> {
+        self.receiver.changed().await?;
+        return Ok(self.receiver.borrow_and_update().clone());
+    }
+
+    // For services to hold off on their first lookups until discovery has
+    // filled the ring in, rather than racing it to an empty ring
+    pub async fn wait_for_targets(
+        &mut self,
+        min: usize,
+    ) -> Result<Arc<FrozenFlexihash>, watch::error::RecvError> {
+        let ring = self
+            .receiver
+            .wait_for(|ring| ring.targets().count() >= min)
+            .await?;
+        return Ok(ring.clone());
+    }
+}
+
+#[cfg(test)]
+mod test_watch {
+    use super::*;
+
+    #[test]
+    fn snapshot_follows_updates() {
+        let (mut publisher, subscriber) = channel(Flexihash::new());
+        assert_eq!(subscriber.snapshot().get_all_targets().len(), 0);
+
+        publisher.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        assert_eq!(subscriber.snapshot().get_all_targets(), ["t-a"]);
+        assert_eq!(publisher.subscribe().snapshot().lookup("resource"), "t-a");
+    }
+
+    #[test]
+    fn old_snapshots_are_unchanged() {
+        let (mut publisher, subscriber) = channel(Flexihash::new());
+        publisher.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        let before = subscriber.snapshot();
+        publisher.update(|fh| {
+            fh.add_target("t-b", 1);
+        });
+        assert_eq!(before.get_all_targets(), ["t-a"]);
+        assert_eq!(subscriber.snapshot().get_all_targets(), ["t-a", "t-b"]);
+    }
+
+    #[tokio::test]
+    async fn changed_wakes_subscribers() {
+        let (mut publisher, mut subscriber) = channel(Flexihash::new());
+        let waiter = tokio::spawn(async move { subscriber.changed().await.unwrap() });
+        tokio::task::yield_now().await;
+        publisher.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        assert_eq!(waiter.await.unwrap().get_all_targets(), ["t-a"]);
+    }
+
+    #[tokio::test]
+    async fn wait_for_targets_waits_for_enough() {
+        let (mut publisher, mut subscriber) = channel(Flexihash::new());
+        publisher.update(|fh| {
+            fh.add_target("t-a", 1);
+        });
+        assert_eq!(
+            subscriber
+                .wait_for_targets(1)
+                .await
+                .unwrap()
+                .get_all_targets(),
+            ["t-a"]
+        );
+
+        let waiter = tokio::spawn(async move { subscriber.wait_for_targets(3).await });
+        for target in ["t-b", "t-c"] {
+            tokio::task::yield_now().await;
+            publisher.update(|fh| {
+                fh.add_target(target, 1);
+            });
+        }
+        assert_eq!(waiter.await.unwrap().unwrap().get_all_targets().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn wait_for_targets_fails_once_the_publisher_is_gone() {
+        let (publisher, mut subscriber) = channel(Flexihash::new());
+        drop(publisher);
+        assert!(subscriber.wait_for_targets(1).await.is_err());
+    }
+}
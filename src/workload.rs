@@ -0,0 +1,370 @@
+use crate::balance::Rng;
+use crate::{Flexihash, Target};
+use std::collections::BTreeMap;
+
+/*
+ * Made-up traffic for trying rings out: a stream of keys ("key0",
+ * "key1", ...) drawn from a fixed key space, in one of a few shapes, and
+ * the same stream every time for a given seed. Real traffic is rarely
+ * uniform, and a ring which looks balanced under uniform keys can still
+ * have one target buried under a handful of hot ones, so it's worth
+ * checking replica counts and the balancing lookups against Zipf too.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Uniform,
+    // Zipf with the given exponent: key n (from 0) turns up in proportion
+    // to 1 / (n + 1)^exponent, so "key0" is the hottest. Around 1 is
+    // typical of caches.
+    Zipf(f64),
+    // Every key in turn, round and round
+    Sequential,
+}
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    distribution: Distribution,
+    keys: u64,
+    seed: u64,
+    prefix: String,
+}
+
+/*
+ * How many requests each target got. Every target on the ring is listed,
+ * including any which got none.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Histogram {
+    pub counts: BTreeMap<Target, u64>,
+}
+
+impl Histogram {
+    pub fn total(&self) -> u64 {
+        return self.counts.values().sum();
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.counts.is_empty() {
+            return 0.0;
+        }
+        return self.total() as f64 / self.counts.len() as f64;
+    }
+
+    // The busiest target's count over the mean; 1.0 is perfectly even.
+    // Ignores weights, so compare like with like.
+    pub fn peak_to_mean(&self) -> f64 {
+        let mean = self.mean();
+        if mean == 0.0 {
+            return 0.0;
+        }
+        return self.counts.values().copied().max().unwrap_or(0) as f64 / mean;
+    }
+}
+
+impl Workload {
+    pub fn new(distribution: Distribution, keys: u64) -> Workload {
+        if keys == 0 {
+            panic!("A workload needs at least one key");
+        }
+        if let Distribution::Zipf(exponent) = distribution {
+            if !(exponent > 0.0 && exponent.is_finite()) {
+                panic!("Zipf exponent must be positive, got {}", exponent);
+            }
+        }
+        return Workload {
+            distribution,
+            keys,
+            seed: 0,
+            prefix: "key".to_string(),
+        };
+    }
+
+    pub fn uniform(keys: u64) -> Workload {
+        return Workload::new(Distribution::Uniform, keys);
+    }
+
+    pub fn zipf(keys: u64, exponent: f64) -> Workload {
+        return Workload::new(Distribution::Zipf(exponent), keys);
+    }
+
+    pub fn sequential(keys: u64) -> Workload {
+        return Workload::new(Distribution::Sequential, keys);
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Workload {
+        self.seed = seed;
+        return self;
+    }
+
+    // "key" by default
+    pub fn with_prefix<S: Into<String>>(mut self, prefix: S) -> Workload {
+        self.prefix = prefix.into();
+        return self;
+    }
+
+    pub fn distribution(&self) -> Distribution {
+        return self.distribution;
+    }
+
+    // Never runs out; take() as many as needed
+    pub fn keys(&self) -> Keys<'_> {
+        return Keys {
+            workload: self,
+            rng: Rng::seeded(self.seed),
+            zipf: match self.distribution {
+                Distribution::Zipf(exponent) => Some(Zipf::new(self.keys, exponent)),
+                _ => None,
+            },
+            next: 0,
+        };
+    }
+
+    // Look up this many keys on the ring
+    pub fn run(&self, ring: &Flexihash, requests: u64) -> Histogram {
+        return self.run_with(ring, requests, |key| ring.lookup(key));
+    }
+
+    // run, with the target for each key picked some other way, eg by one
+    // of the load-aware lookups
+    pub fn run_with(
+        &self,
+        ring: &Flexihash,
+        requests: u64,
+        mut pick: impl FnMut(&str) -> Target,
+    ) -> Histogram {
+        let mut counts: BTreeMap<Target, u64> =
+            ring.get_all_targets().into_iter().map(|t| (t, 0)).collect();
+        if counts.is_empty() {
+            return Histogram { counts };
+        }
+        for key in self.keys().take(requests as usize) {
+            *counts.entry(pick(&key)).or_insert(0) += 1;
+        }
+        return Histogram { counts };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keys<'a> {
+    workload: &'a Workload,
+    rng: Rng,
+    zipf: Option<Zipf>,
+    next: u64,
+}
+
+impl Iterator for Keys<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let n = match self.workload.distribution {
+            Distribution::Uniform => self.rng.next_u64() % self.workload.keys,
+            Distribution::Zipf(_) => match &self.zipf {
+                Some(zipf) => zipf.sample(&mut self.rng),
+                None => unreachable!(),
+            },
+            Distribution::Sequential => {
+                let n = self.next;
+                self.next = (self.next + 1) % self.workload.keys;
+                n
+            }
+        };
+        return Some(format!("{}{}", self.workload.prefix, n));
+    }
+}
+
+/*
+ * Rejection-inversion sampling (Hörmann and Derflinger, 1996), which
+ * takes constant time and space however big the key space is, rather
+ * than a table of every key's probability.
+ */
+#[derive(Debug, Clone)]
+struct Zipf {
+    n: f64,
+    exponent: f64,
+    h_integral_x1: f64,
+    h_integral_n: f64,
+    s: f64,
+}
+
+impl Zipf {
+    fn new(n: u64, exponent: f64) -> Zipf {
+        let mut zipf = Zipf {
+            n: n as f64,
+            exponent,
+            h_integral_x1: 0.0,
+            h_integral_n: 0.0,
+            s: 0.0,
+        };
+        zipf.h_integral_x1 = zipf.h_integral(1.5) - 1.0;
+        zipf.h_integral_n = zipf.h_integral(zipf.n + 0.5);
+        zipf.s = 2.0 - zipf.h_integral_inv(zipf.h_integral(2.5) - zipf.h(2.0));
+        return zipf;
+    }
+
+    // 0 to n - 1
+    fn sample(&self, rng: &mut Rng) -> u64 {
+        loop {
+            let uniform = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+            let u = self.h_integral_n + uniform * (self.h_integral_x1 - self.h_integral_n);
+            let x = self.h_integral_inv(u);
+            let k = (x + 0.5).floor().clamp(1.0, self.n);
+            if k - x <= self.s || u >= self.h_integral(k + 0.5) - self.h(k) {
+                return k as u64 - 1;
+            }
+        }
+    }
+
+    fn h(&self, x: f64) -> f64 {
+        return (-self.exponent * x.ln()).exp();
+    }
+
+    fn h_integral(&self, x: f64) -> f64 {
+        let log_x = x.ln();
+        return expm1_over_x((1.0 - self.exponent) * log_x) * log_x;
+    }
+
+    fn h_integral_inv(&self, x: f64) -> f64 {
+        let t = (x * (1.0 - self.exponent)).max(-1.0);
+        return (ln1p_over_x(t) * x).exp();
+    }
+}
+
+// (e^x - 1) / x and ln(1 + x) / x, which both head to 1 as x does; near
+// there, their Taylor series are more accurate than doing the division
+fn expm1_over_x(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        return x.exp_m1() / x;
+    }
+    return 1.0 + x / 2.0 * (1.0 + x / 3.0 * (1.0 + x / 4.0));
+}
+
+fn ln1p_over_x(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        return x.ln_1p() / x;
+    }
+    return 1.0 - x * (0.5 - x * (1.0 / 3.0 - x / 4.0));
+}
+
+#[cfg(test)]
+mod test_workload {
+    use super::*;
+    use crate::metrics::LookupCounts;
+    use std::collections::HashMap;
+
+    fn frequencies(workload: &Workload, n: usize) -> HashMap<String, f64> {
+        let mut counts: HashMap<String, f64> = HashMap::new();
+        for key in workload.keys().take(n) {
+            *counts.entry(key).or_default() += 1.0 / n as f64;
+        }
+        return counts;
+    }
+
+    #[test]
+    fn deterministic() {
+        let a: Vec<String> = Workload::zipf(1000, 1.1)
+            .with_seed(7)
+            .keys()
+            .take(50)
+            .collect();
+        let b: Vec<String> = Workload::zipf(1000, 1.1)
+            .with_seed(7)
+            .keys()
+            .take(50)
+            .collect();
+        let c: Vec<String> = Workload::zipf(1000, 1.1)
+            .with_seed(8)
+            .keys()
+            .take(50)
+            .collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn sequential() {
+        let keys: Vec<String> = Workload::sequential(3)
+            .with_prefix("user:")
+            .keys()
+            .take(4)
+            .collect();
+        assert_eq!(keys, ["user:0", "user:1", "user:2", "user:0"]);
+    }
+
+    #[test]
+    fn uniform() {
+        let freq = frequencies(&Workload::uniform(10), 100_000);
+        assert_eq!(freq.len(), 10);
+        assert!(
+            freq.values().all(|f| (0.09..0.11).contains(f)),
+            "{:?}",
+            freq
+        );
+    }
+
+    #[test]
+    fn zipf() {
+        let freq = frequencies(&Workload::zipf(100, 1.0), 100_000);
+        // 1/n over the 100th harmonic number
+        let harmonic: f64 = (1..=100).map(|n| 1.0 / n as f64).sum();
+        for (n, key) in ["key0", "key1", "key9"].iter().enumerate() {
+            let expected = 1.0 / ([1.0, 2.0, 10.0][n] * harmonic);
+            let got = freq[*key];
+            assert!(
+                (got - expected).abs() < 0.01,
+                "{} {} {}",
+                key,
+                got,
+                expected
+            );
+        }
+        assert!(freq
+            .keys()
+            .all(|k| k["key".len()..].parse::<u64>().unwrap() < 100));
+        // and a big key space costs nothing
+        let hot = frequencies(&Workload::zipf(u64::MAX, 1.5), 10_000);
+        assert!(hot["key0"] > 0.3);
+    }
+
+    #[test]
+    fn histograms() {
+        let mut fh = Flexihash::new();
+        fh.add_targets(vec!["t-a", "t-b", "t-c", "t-idle"]);
+        fh.update_target_weight("t-idle", 0);
+        let hist = Workload::uniform(1000).run(&fh, 3000);
+        assert_eq!(hist.total(), 3000);
+        assert_eq!(hist.counts["t-idle"], 0);
+        assert_eq!(hist.mean(), 750.0);
+        assert!(hist.peak_to_mean() > 1.0);
+        assert_eq!(
+            Workload::uniform(10).run(&Flexihash::new(), 10),
+            Histogram::default()
+        );
+    }
+
+    // the reason for having these: hot keys overload whoever owns them, and
+    // bounded loads share them out
+    #[test]
+    fn bounded_loads_under_zipf() {
+        let mut fh = Flexihash::new();
+        for i in 0..8 {
+            fh.add_target(format!("t-{}", i), 1);
+        }
+        let workload = Workload::zipf(10_000, 1.0).with_seed(1);
+        let plain = workload.run(&fh, 20_000);
+
+        let loads = LookupCounts::default();
+        let bounded = workload.run_with(&fh, 20_000, |key| {
+            let target = fh.lookup_bounded_by(key, 1.25, &loads);
+            loads.record(&target);
+            return target;
+        });
+        assert!(plain.peak_to_mean() > 1.25, "{:?}", plain);
+        assert!(bounded.peak_to_mean() <= 1.26, "{:?}", bounded);
+    }
+
+    #[test]
+    #[should_panic(expected = "Zipf exponent must be positive, got 0")]
+    fn bad_exponent() {
+        Workload::zipf(10, 0.0);
+    }
+}